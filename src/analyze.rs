@@ -0,0 +1,430 @@
+/*
+ * Copyright 2023 Anthony Oteri
+ *
+ * Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+ * http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+ * http://opensource.org/licenses/MIT>, at your option. This file may not be
+ * copied, modified, or distributed except according to those terms.
+ */
+
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use crate::error::ApiError;
+use crate::image::Image;
+use crate::layer;
+use crate::layer::Entry;
+use crate::layer::Kind;
+use crate::progress::human_bytes;
+use crate::reference::ImageRef;
+use crate::rootfs::OPAQUE_WHITEOUT;
+use crate::rootfs::WHITEOUT_PREFIX;
+
+/// Longest `created_by` shown in a report, in characters.
+const CREATED_BY_WIDTH: usize = 60;
+
+/// Why the contents of a file never reach the final filesystem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fate {
+    /// A later entry at the same path, or at one of its directories,
+    /// replaced the file.
+    Overwritten,
+    /// A later whiteout deleted the file or one of its directories.
+    Deleted,
+}
+
+impl Fate {
+    /// Past-tense verb used in reports.
+    fn label(self) -> &'static str {
+        match self {
+            Self::Overwritten => "overwritten",
+            Self::Deleted => "deleted",
+        }
+    }
+}
+
+/// A file whose bytes are shipped in a layer but hidden by a later one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WastedFile {
+    /// Path of the file.
+    pub path: String,
+    /// Size of the file in bytes.
+    pub size: u64,
+    /// Index of the layer that added the file.
+    pub layer: usize,
+    /// Index of the layer that overwrote or deleted the file.
+    pub by: usize,
+    /// Whether the file was overwritten or deleted.
+    pub fate: Fate,
+}
+
+/// Statistics for one layer of an image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LayerReport {
+    /// Digest of the compressed layer.
+    pub digest: String,
+    /// Size of the compressed layer in bytes.
+    pub size: u64,
+    /// Total size of the regular files in the layer.
+    pub bytes: u64,
+    /// Bytes of files in this layer that later layers overwrite or delete.
+    pub wasted: u64,
+    /// The history entry that created the layer, if the configuration
+    /// records one.
+    pub created_by: Option<String>,
+}
+
+/// The result of analysing every layer of an image for wasted space.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Analysis {
+    /// One report per layer, base layer first.
+    pub layers: Vec<LayerReport>,
+    /// Every wasted file, largest first.
+    pub wasted: Vec<WastedFile>,
+}
+
+impl Analysis {
+    /// Total size of the regular files in every layer.
+    pub fn total_bytes(&self) -> u64 {
+        self.layers.iter().map(|l| l.bytes).sum()
+    }
+
+    /// Total size of the files that never reach the final filesystem.
+    pub fn wasted_bytes(&self) -> u64 {
+        self.layers.iter().map(|l| l.wasted).sum()
+    }
+
+    /// Share of the file bytes in the layers that reach the final
+    /// filesystem, from `0.0` to `1.0`.  An image without files is fully
+    /// efficient.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn efficiency(&self) -> f64 {
+        match self.total_bytes() {
+            0 => 1.0,
+            total => (total - self.wasted_bytes()) as f64 / total as f64,
+        }
+    }
+
+    /// Write a table of the layers, the totals, and the `top` largest
+    /// wasted files with the history entries responsible to `buf`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ApiError::IOError`] if writing to `buf` fails.
+    pub fn write_to(&self, buf: &mut dyn Write, top: usize) -> Result<(), ApiError> {
+        writeln!(
+            buf,
+            "{:<5} {:>10} {:>10} {:>10}  CREATED BY",
+            "LAYER", "SIZE", "FILES", "WASTED"
+        )?;
+        for (index, layer) in self.layers.iter().enumerate() {
+            writeln!(
+                buf,
+                "{index:<5} {:>10} {:>10} {:>10}  {}",
+                human_bytes(layer.size),
+                human_bytes(layer.bytes),
+                human_bytes(layer.wasted),
+                self.created_by(index)
+            )?;
+        }
+        writeln!(buf)?;
+        writeln!(
+            buf,
+            "{} in files, {} wasted in {} files, efficiency {:.1}%",
+            human_bytes(self.total_bytes()),
+            human_bytes(self.wasted_bytes()),
+            self.wasted.len(),
+            self.efficiency() * 100.0
+        )?;
+
+        if top > 0 && !self.wasted.is_empty() {
+            writeln!(buf)?;
+            writeln!(buf, "Largest wasted files:")?;
+            for file in self.wasted.iter().take(top) {
+                writeln!(
+                    buf,
+                    "{:>10}  {}  (layer {}, {} by layer {}: {})",
+                    human_bytes(file.size),
+                    file.path,
+                    file.layer,
+                    file.fate.label(),
+                    file.by,
+                    self.created_by(file.by)
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The `created_by` of layer `index` on a single line, shortened to
+    /// [`CREATED_BY_WIDTH`] characters, or `-` when unknown.
+    fn created_by(&self, index: usize) -> String {
+        let Some(created_by) = self.layers.get(index).and_then(|l| l.created_by.as_ref()) else {
+            return String::from("-");
+        };
+        let line = created_by.split_whitespace().collect::<Vec<_>>().join(" ");
+        if line.chars().count() > CREATED_BY_WIDTH {
+            let short: String = line.chars().take(CREATED_BY_WIDTH - 3).collect();
+            format!("{short}...")
+        } else {
+            line
+        }
+    }
+}
+
+/// Accumulates an [`Analysis`] as layers are applied base layer first.
+#[derive(Debug, Default)]
+struct Tracker {
+    /// The regular files currently visible, with the index of the layer
+    /// that added each and its size.
+    files: BTreeMap<String, (usize, u64)>,
+    analysis: Analysis,
+}
+
+impl Tracker {
+    /// Apply the entries of layer `index`, recording every visible file it
+    /// hides.  Whiteouts are applied first, since they only ever hide the
+    /// layers below.
+    fn apply(&mut self, index: usize, entries: &[Entry]) {
+        for entry in entries {
+            let (dir, name) = entry.path.rsplit_once('/').unwrap_or(("", &entry.path));
+            if name == OPAQUE_WHITEOUT {
+                self.hide_below(dir, index, Fate::Deleted);
+            } else if let Some(deleted) = name.strip_prefix(WHITEOUT_PREFIX) {
+                let path = if dir.is_empty() {
+                    String::from(deleted)
+                } else {
+                    format!("{dir}/{deleted}")
+                };
+                self.hide(&path, index, Fate::Deleted);
+                self.hide_below(&path, index, Fate::Deleted);
+            }
+        }
+
+        let mut bytes = 0;
+        for entry in entries {
+            let name = entry.path.rsplit('/').next().unwrap_or_default();
+            if name.starts_with(WHITEOUT_PREFIX) {
+                continue;
+            }
+            let mut parent = entry.path.as_str();
+            while let Some((dir, _)) = parent.rsplit_once('/') {
+                self.hide(dir, index, Fate::Overwritten);
+                parent = dir;
+            }
+            self.hide(&entry.path, index, Fate::Overwritten);
+            if entry.kind != Kind::Directory {
+                self.hide_below(&entry.path, index, Fate::Overwritten);
+            }
+            if entry.kind == Kind::File {
+                bytes += entry.size;
+                self.files.insert(entry.path.clone(), (index, entry.size));
+            }
+        }
+        self.analysis.layers[index].bytes = bytes;
+    }
+
+    /// Hide the file at exactly `path`, if there is one.
+    fn hide(&mut self, path: &str, by: usize, fate: Fate) {
+        if let Some((layer, size)) = self.files.remove(path) {
+            self.waste(String::from(path), layer, size, by, fate);
+        }
+    }
+
+    /// Hide every file below the directory `dir`; the empty path is the
+    /// root.
+    fn hide_below(&mut self, dir: &str, by: usize, fate: Fate) {
+        let prefix = if dir.is_empty() {
+            String::new()
+        } else {
+            format!("{dir}/")
+        };
+        let below: Vec<String> = self
+            .files
+            .range(prefix.clone()..)
+            .take_while(|(path, _)| path.starts_with(&prefix))
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in below {
+            if let Some((layer, size)) = self.files.remove(&path) {
+                self.waste(path, layer, size, by, fate);
+            }
+        }
+    }
+
+    /// Record that `size` bytes at `path`, added by `layer`, were hidden by
+    /// layer `by`.
+    fn waste(&mut self, path: String, layer: usize, size: u64, by: usize, fate: Fate) {
+        log::debug!("{path} from layer {layer} {} by layer {by}", fate.label());
+        self.analysis.layers[layer].wasted += size;
+        self.analysis.wasted.push(WastedFile {
+            path,
+            size,
+            layer,
+            by,
+            fate,
+        });
+    }
+}
+
+/// Analyse `image` for files that are shipped in one layer but overwritten
+/// or deleted by a later one, like `dive`, without running anything.
+///
+/// Each layer is streamed from the repository of `reference` and only its
+/// tar headers are inspected.  The history entries of the configuration
+/// that produced layers are matched to the layers in order; when their
+/// number does not match the layers, no `created_by` is reported.
+///
+/// # Errors
+///
+/// Returns any error raised by [`layer::fetch`] while reading a layer.
+pub async fn analyze(
+    client: &reqwest::Client,
+    reference: &ImageRef,
+    image: &Image,
+) -> Result<Analysis, ApiError> {
+    log::trace!("analyze(reference: {reference}, image: {})", image.digest);
+
+    let history: Vec<Option<String>> = image
+        .config
+        .history
+        .iter()
+        .filter(|h| !h.empty_layer)
+        .map(|h| h.created_by.clone())
+        .collect();
+    let layers: Vec<_> = image.layers().collect();
+    if history.len() != layers.len() {
+        log::warn!(
+            "Image history lists {} layers, but the image has {}",
+            history.len(),
+            layers.len()
+        );
+    }
+
+    let mut tracker = Tracker::default();
+    for (index, (descriptor, _)) in layers.iter().enumerate() {
+        tracker.analysis.layers.push(LayerReport {
+            digest: descriptor.digest.clone(),
+            size: descriptor.size,
+            bytes: 0,
+            wasted: 0,
+            created_by: if history.len() == layers.len() {
+                history[index].clone()
+            } else {
+                None
+            },
+        });
+    }
+    for (index, (descriptor, diff_id)) in layers.into_iter().enumerate() {
+        log::debug!("Analysing layer {index} ({})", descriptor.digest);
+        let entries = layer::fetch(client, reference, descriptor, diff_id, layer::entries).await?;
+        tracker.apply(index, &entries);
+    }
+
+    let mut analysis = tracker.analysis;
+    analysis
+        .wasted
+        .sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.path.cmp(&b.path)));
+    Ok(analysis)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer::tests::tarball;
+
+    fn tracker(layers: &[&[(&str, &str)]]) -> Tracker {
+        let mut tracker = Tracker::default();
+        for (index, files) in layers.iter().enumerate() {
+            tracker.analysis.layers.push(LayerReport {
+                digest: format!("sha256:{index}"),
+                size: 0,
+                bytes: 0,
+                wasted: 0,
+                created_by: Some(format!("RUN step {index}")),
+            });
+            let tar = tarball(files);
+            tracker.apply(index, &layer::entries(&mut tar.as_slice()).unwrap());
+        }
+        tracker
+    }
+
+    /// Test that overwritten, deleted, opaque-hidden, and replaced-directory
+    /// files are attributed to the layers that added and hid them.
+    #[test]
+    fn test_tracker() {
+        let tracker = tracker(&[
+            &[
+                ("etc/config", "12345"),
+                ("tmp/", ""),
+                ("tmp/cache.bin", "1234567890"),
+                ("var/cache/", ""),
+                ("var/cache/a", "aaa"),
+                ("opt/", ""),
+                ("opt/x", "xx"),
+            ],
+            &[
+                ("etc/config", "123"),
+                ("tmp/.wh.cache.bin", ""),
+                ("var/cache/.wh..wh..opq", ""),
+                ("var/cache/b", "b"),
+                ("opt", "file"),
+            ],
+        ]);
+        let analysis = &tracker.analysis;
+        assert_eq!(analysis.layers[0].bytes, 20);
+        assert_eq!(analysis.layers[0].wasted, 20);
+        assert_eq!(analysis.layers[1].bytes, 8);
+        assert_eq!(analysis.layers[1].wasted, 0);
+        assert_eq!(analysis.wasted_bytes(), 20);
+        assert!((analysis.efficiency() - 8.0 / 28.0).abs() < 1e-9);
+
+        let mut wasted: Vec<(&str, Fate)> = analysis
+            .wasted
+            .iter()
+            .map(|f| (f.path.as_str(), f.fate))
+            .collect();
+        wasted.sort_unstable_by_key(|w| w.0);
+        assert_eq!(
+            wasted,
+            [
+                ("etc/config", Fate::Overwritten),
+                ("opt/x", Fate::Overwritten),
+                ("tmp/cache.bin", Fate::Deleted),
+                ("var/cache/a", Fate::Deleted),
+            ]
+        );
+    }
+
+    /// Test the report layout, including the largest wasted files and the
+    /// history entries responsible.
+    #[test]
+    fn test_write_to() {
+        let mut analysis = tracker(&[
+            &[("app.jar", "0123456789")],
+            &[("app.jar", "01234"), ("README", "x")],
+        ])
+        .analysis;
+        analysis.layers[1].created_by = Some(String::from("COPY   target/app.jar\n /app.jar"));
+
+        let mut buf = Vec::new();
+        analysis.write_to(&mut buf, 5).unwrap();
+        let expected = "\
+LAYER       SIZE      FILES     WASTED  CREATED BY
+0            0 B       10 B       10 B  RUN step 0
+1            0 B        6 B        0 B  COPY target/app.jar /app.jar
+
+16 B in files, 10 B wasted in 1 files, efficiency 37.5%
+
+Largest wasted files:
+      10 B  app.jar  (layer 0, overwritten by layer 1: COPY target/app.jar /app.jar)
+";
+        assert_eq!(String::from_utf8(buf).unwrap(), expected);
+    }
+
+    /// Test that an image without files is fully efficient.
+    #[test]
+    fn test_efficiency_empty() {
+        assert!((Analysis::default().efficiency() - 1.0).abs() < f64::EPSILON);
+    }
+}
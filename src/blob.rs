@@ -0,0 +1,1043 @@
+/*
+ * Copyright 2023 Anthony Oteri
+ *
+ * Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+ * http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+ * http://opensource.org/licenses/MIT>, at your option. This file may not be
+ * copied, modified, or distributed except according to those terms.
+ */
+
+use std::ffi::OsString;
+use std::future::Future;
+use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+
+use futures_util::TryStreamExt;
+use reqwest::header;
+use reqwest::StatusCode;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+use tokio::task::JoinSet;
+use url::Url;
+
+use crate::api;
+use crate::digest;
+use crate::error::ApiError;
+use crate::manifest::Descriptor;
+use crate::progress::Bar;
+use crate::reference::ImageRef;
+
+/// Smallest range worth fetching on its own connection.
+///
+/// Blobs smaller than two of these are always fetched as a single stream,
+/// regardless of the requested parallelism.
+const MIN_RANGE_SIZE: u64 = 8 * 1024 * 1024;
+
+/// An inclusive byte range `start..=end` of a blob.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ByteRange {
+    start: u64,
+    end: u64,
+}
+
+impl ByteRange {
+    /// Number of bytes covered by the range.
+    fn len(self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+/// Download the blob at `url` to `dest`, verifying it against `digest`.
+///
+/// Data is first written to `<dest>.partial`.  When that file already exists
+/// from an interrupted run, the download resumes from its current length
+/// with an HTTP `Range` request instead of starting over.
+///
+/// When `parallel` is greater than one and the registry honours range
+/// requests, large blobs are split into up to `parallel` ranges fetched
+/// concurrently into `<dest>.partial.<N>` files, each of which is resumable
+/// on its own.  The ranges are then concatenated in order.  A blob with an
+/// existing `<dest>.partial`, or one the registry cannot serve ranges of,
+/// such as an empty blob, is fetched as a single stream instead.
+///
+/// In every case the `sha256` digest is computed over the whole assembled
+/// file, and only a matching file is renamed to `dest`.  A mismatching
+/// partial file is removed so that the next attempt starts from scratch.
+///
+/// If `dest` already exists and matches `digest`, nothing is downloaded.
+///
+/// Every byte received, including those recovered from partial files, is
+/// reported to `bar`.
+///
+/// Returns the size of the blob in bytes.
+///
+/// # Errors
+///
+/// * [`ApiError::HttpError`] — a request failed at the transport layer.
+/// * [`ApiError::IOError`] — a partial or destination file could not be
+///   read or written.
+/// * [`ApiError::DigestMismatch`] — the downloaded content does not match
+///   `digest`.
+/// * [`ApiError::UnexpectedResponse`] — the registry answered a range
+///   request with a mismatching `Content-Range`.
+/// * Any variant returned by [`api::parse_response_status`] for non-success
+///   responses.
+pub async fn download(
+    client: &reqwest::Client,
+    url: &Url,
+    dest: &Path,
+    digest: &str,
+    parallel: usize,
+    bar: &Bar,
+) -> Result<u64, ApiError> {
+    log::trace!(
+        "download(url: {url}, dest: {}, digest: {digest}, parallel: {parallel})",
+        dest.display()
+    );
+
+    if let Ok(meta) = tokio::fs::metadata(dest).await {
+        if digest::sha256_file(dest).await? == digest {
+            log::debug!("{digest} already present at {}", dest.display());
+            return Ok(meta.len());
+        }
+        log::warn!(
+            "{} does not match {digest}; downloading again",
+            dest.display()
+        );
+    }
+
+    let partial = suffixed(dest, ".partial");
+    // A single-stream partial file from an earlier run is resumed as it is
+    // rather than thrown away for parallel ranges.
+    let ranges = if parallel > 1 && existing_len(&partial).await == 0 {
+        match probe_size(client, url).await? {
+            Some(size) => split_ranges(size, parallel, MIN_RANGE_SIZE),
+            None => Vec::new(),
+        }
+    } else {
+        Vec::new()
+    };
+
+    if ranges.len() > 1 {
+        fetch_ranges(client, url, &partial, &ranges, bar).await?;
+    } else {
+        fetch_resumable(client, url, &partial, bar).await?;
+    }
+
+    let actual = digest::sha256_file(&partial).await?;
+    if let Err(e) = digest::verify(digest, &actual) {
+        tokio::fs::remove_file(&partial).await?;
+        return Err(e);
+    }
+    tokio::fs::rename(&partial, dest).await?;
+    Ok(tokio::fs::metadata(dest).await?.len())
+}
+
+/// Return `path` with `suffix` appended to its final component.
+pub fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Return the length of the file at `path`, or `0` when it does not exist.
+async fn existing_len(path: &Path) -> u64 {
+    tokio::fs::metadata(path).await.map_or(0, |m| m.len())
+}
+
+/// Split a blob of `size` bytes into at most `parallel` contiguous ranges of
+/// at least `min_size` bytes each.
+fn split_ranges(size: u64, parallel: usize, min_size: u64) -> Vec<ByteRange> {
+    let by_size = (size / min_size.max(1)).max(1);
+    let count = by_size.min(parallel.max(1) as u64);
+    let chunk = size.div_ceil(count);
+
+    (0..count)
+        .map(|i| ByteRange {
+            start: i * chunk,
+            end: ((i + 1) * chunk).min(size) - 1,
+        })
+        .filter(|r| r.start < size)
+        .collect()
+}
+
+/// Determine the total size of the blob at `url` if the registry supports
+/// range requests for it.
+///
+/// Requests the first byte with `Range: bytes=0-0`; a `206 Partial Content`
+/// response carries the full size in its `Content-Range` header.  Returns
+/// `None` when the registry ignores the range and serves the whole blob,
+/// or answers `416 Range Not Satisfiable`, as it does for an empty blob.
+async fn probe_size(client: &reqwest::Client, url: &Url) -> Result<Option<u64>, ApiError> {
+    let resp = client
+        .get(url.as_ref())
+        .header(header::RANGE, "bytes=0-0")
+        .send()
+        .await?;
+    if resp.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        return Ok(None);
+    }
+    if resp.status() != StatusCode::PARTIAL_CONTENT {
+        check_blob_status(&resp)?;
+        return Ok(None);
+    }
+
+    let size = resp
+        .headers()
+        .get(header::CONTENT_RANGE)
+        .map(|v| v.to_str())
+        .transpose()?
+        .and_then(|v| v.rsplit_once('/'))
+        .and_then(|(_, total)| total.parse().ok());
+    Ok(size)
+}
+
+/// Validate the status of a blob `GET` response.
+///
+/// Blob responses are frequently redirected to a storage backend that does
+/// not send the `Docker-Distribution-API-Version` header, so success codes
+/// are accepted without it.  Everything else is delegated to
+/// [`api::parse_response_status`].
+fn check_blob_status(resp: &reqwest::Response) -> Result<(), ApiError> {
+    match resp.status() {
+        StatusCode::OK | StatusCode::PARTIAL_CONTENT => Ok(()),
+        _ => api::parse_response_status(resp),
+    }
+}
+
+/// Check that a `206 Partial Content` response starts at `offset`.
+fn check_content_range(resp: &reqwest::Response, offset: u64) -> Result<(), ApiError> {
+    let expected = format!("bytes {offset}-");
+    match resp.headers().get(header::CONTENT_RANGE) {
+        Some(v) if v.to_str()?.starts_with(&expected) => Ok(()),
+        Some(v) => Err(ApiError::UnexpectedResponse(format!(
+            "Content-Range {} does not start at byte {offset}",
+            v.to_str()?
+        ))),
+        None => Err(ApiError::UnexpectedResponse(String::from(
+            "Missing Content-Range header",
+        ))),
+    }
+}
+
+/// Stream the body of `resp` to the end of `file`, reporting each chunk to
+/// `bar`.
+async fn stream_to(
+    mut resp: reqwest::Response,
+    file: &mut tokio::fs::File,
+    bar: &Bar,
+) -> Result<(), ApiError> {
+    while let Some(chunk) = resp.chunk().await? {
+        file.write_all(&chunk).await?;
+        bar.inc(chunk.len() as u64);
+    }
+    file.flush().await?;
+    Ok(())
+}
+
+/// Fetch the whole blob into `partial` as a single stream, resuming from
+/// the current length of `partial` when it already exists.
+async fn fetch_resumable(
+    client: &reqwest::Client,
+    url: &Url,
+    partial: &Path,
+    bar: &Bar,
+) -> Result<(), ApiError> {
+    let offset = existing_len(partial).await;
+    let mut request = client.get(url.as_ref());
+    if offset > 0 {
+        log::info!("Resuming {} at byte {offset}", partial.display());
+        request = request.header(header::RANGE, format!("bytes={offset}-"));
+    }
+    let resp = request.send().await?;
+
+    let mut file = match resp.status() {
+        StatusCode::PARTIAL_CONTENT if offset > 0 => {
+            check_content_range(&resp, offset)?;
+            bar.inc(offset);
+            OpenOptions::new().append(true).open(partial).await?
+        }
+        // The partial file already holds every byte; the digest check
+        // decides whether it is actually complete.
+        StatusCode::RANGE_NOT_SATISFIABLE if offset > 0 => {
+            bar.inc(offset);
+            return Ok(());
+        }
+        _ => {
+            check_blob_status(&resp)?;
+            if offset > 0 {
+                log::info!("Registry ignored the range request; restarting download");
+            }
+            tokio::fs::File::create(partial).await?
+        }
+    };
+    stream_to(resp, &mut file, bar).await
+}
+
+/// Fetch a single range of the blob into `path`, resuming from the current
+/// length of `path` when it already exists.
+async fn fetch_range(
+    client: reqwest::Client,
+    url: Url,
+    path: PathBuf,
+    range: ByteRange,
+    bar: Bar,
+) -> Result<(), ApiError> {
+    let mut have = existing_len(&path).await;
+    if have > range.len() {
+        tokio::fs::File::create(&path).await?;
+        have = 0;
+    }
+    bar.inc(have);
+    if have == range.len() {
+        return Ok(());
+    }
+
+    let offset = range.start + have;
+    let resp = client
+        .get(url.as_ref())
+        .header(header::RANGE, format!("bytes={offset}-{}", range.end))
+        .send()
+        .await?;
+    if resp.status() != StatusCode::PARTIAL_CONTENT {
+        check_blob_status(&resp)?;
+        return Err(ApiError::UnexpectedResponse(String::from(
+            "Registry stopped honouring range requests",
+        )));
+    }
+    check_content_range(&resp, offset)?;
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .await?;
+    stream_to(resp, &mut file, &bar).await
+}
+
+/// Fetch `ranges` concurrently into per-range files, then concatenate them
+/// into `partial`.
+async fn fetch_ranges(
+    client: &reqwest::Client,
+    url: &Url,
+    partial: &Path,
+    ranges: &[ByteRange],
+    bar: &Bar,
+) -> Result<(), ApiError> {
+    let parts: Vec<PathBuf> = (0..ranges.len())
+        .map(|i| suffixed(partial, &format!(".{i}")))
+        .collect();
+
+    let mut tasks = JoinSet::new();
+    for (range, path) in ranges.iter().zip(&parts) {
+        tasks.spawn(fetch_range(
+            client.clone(),
+            url.clone(),
+            path.clone(),
+            *range,
+            bar.clone(),
+        ));
+    }
+    while let Some(result) = tasks.join_next().await {
+        result.map_err(|e| ApiError::UnexpectedResponse(e.to_string()))??;
+    }
+
+    let mut out = tokio::fs::File::create(partial).await?;
+    for path in &parts {
+        let mut part = tokio::fs::File::open(path).await?;
+        tokio::io::copy(&mut part, &mut out).await?;
+    }
+    out.flush().await?;
+    for path in &parts {
+        tokio::fs::remove_file(path).await?;
+    }
+    Ok(())
+}
+
+/// Return `true` if the blob with `digest` exists in the repository of
+/// `image`.
+///
+/// # Errors
+///
+/// * [`ApiError::HttpError`] — the request failed at the transport layer.
+/// * [`ApiError::UrlParseError`] — the blob URL could not be constructed.
+/// * Any variant returned by [`api::parse_response_status`] other than
+///   [`ApiError::NotFound`], which yields `Ok(false)`.
+pub async fn exists(
+    client: &reqwest::Client,
+    image: &ImageRef,
+    digest: &str,
+) -> Result<bool, ApiError> {
+    log::trace!("exists(image: {image}, digest: {digest})");
+    let resp = client.head(image.blob_url(digest)?).send().await?;
+    match resp.status() {
+        StatusCode::OK => Ok(true),
+        StatusCode::NOT_FOUND => Ok(false),
+        _ => api::parse_response_status(&resp).map(|()| true),
+    }
+}
+
+/// The outcome of a cross-repository blob mount request.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Mount {
+    /// The registry linked the existing blob into the target repository.
+    Mounted,
+    /// The registry declined the mount and opened an upload session at the
+    /// given location instead.
+    Upload(Url),
+}
+
+/// Ask the registry to mount the blob with `digest` from the repository
+/// `from` into the repository of `image`, without transferring any data.
+///
+/// Sends `POST /v2/<name>/blobs/uploads/?mount=<digest>&from=<from>`.  A
+/// `201 Created` response means the blob was mounted; a `202 Accepted`
+/// response means the registry fell back to opening a regular upload
+/// session, whose location is returned.
+///
+/// # Errors
+///
+/// * [`ApiError::HttpError`] — the request failed at the transport layer.
+/// * [`ApiError::UrlParseError`] — the upload URL could not be constructed.
+/// * [`ApiError::UnexpectedResponse`] — a `202` response has no `Location`.
+/// * Any variant returned by [`api::parse_response_status`].
+pub async fn mount(
+    client: &reqwest::Client,
+    image: &ImageRef,
+    digest: &str,
+    from: &str,
+) -> Result<Mount, ApiError> {
+    log::trace!("mount(image: {image}, digest: {digest}, from: {from})");
+    let mut url = uploads_url(image)?;
+    url.query_pairs_mut()
+        .append_pair("mount", digest)
+        .append_pair("from", from);
+
+    let resp = client.post(url).send().await?;
+    api::parse_response_status(&resp)?;
+    if resp.status() == StatusCode::CREATED {
+        Ok(Mount::Mounted)
+    } else {
+        Ok(Mount::Upload(upload_location(&image.registry, &resp)?))
+    }
+}
+
+/// Open a new blob upload session in the repository of `image` and return
+/// its location.
+///
+/// # Errors
+///
+/// * [`ApiError::HttpError`] — the request failed at the transport layer.
+/// * [`ApiError::UrlParseError`] — the upload URL could not be constructed.
+/// * [`ApiError::UnexpectedResponse`] — the response has no `Location`.
+/// * Any variant returned by [`api::parse_response_status`].
+pub async fn start_upload(client: &reqwest::Client, image: &ImageRef) -> Result<Url, ApiError> {
+    log::trace!("start_upload(image: {image})");
+    let resp = client.post(uploads_url(image)?).send().await?;
+    api::parse_response_status(&resp)?;
+    upload_location(&image.registry, &resp)
+}
+
+/// Complete the upload session at `location` with a single monolithic `PUT`
+/// carrying the whole blob.
+///
+/// The registry verifies that `body` hashes to `digest` and rejects the
+/// upload otherwise.
+///
+/// # Errors
+///
+/// * [`ApiError::HttpError`] — the request failed at the transport layer.
+/// * Any variant returned by [`api::parse_response_status`].
+pub async fn finish_upload(
+    client: &reqwest::Client,
+    mut location: Url,
+    digest: &str,
+    body: impl Into<reqwest::Body>,
+    size: u64,
+) -> Result<(), ApiError> {
+    log::trace!("finish_upload(location: {location}, digest: {digest}, size: {size})");
+    location.query_pairs_mut().append_pair("digest", digest);
+    let resp = client
+        .put(location)
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header(header::CONTENT_LENGTH, size)
+        .body(body)
+        .send()
+        .await?;
+    api::parse_response_status(&resp)
+}
+
+/// Stream the blob described by `descriptor` from the repository of `src`
+/// into the upload session at `location`.
+///
+/// The blob is never buffered in full; data flows from the source response
+/// straight into the destination request body, and is reported to `bar` as
+/// it passes through.
+///
+/// # Errors
+///
+/// * [`ApiError::HttpError`] — a request failed at the transport layer.
+/// * [`ApiError::UrlParseError`] — the source blob URL could not be built.
+/// * Any variant returned by [`api::parse_response_status`] for either
+///   registry.
+pub async fn stream(
+    src_client: &reqwest::Client,
+    src: &ImageRef,
+    dst_client: &reqwest::Client,
+    location: Url,
+    descriptor: &Descriptor,
+    bar: &Bar,
+) -> Result<(), ApiError> {
+    log::trace!("stream(src: {src}, digest: {})", descriptor.digest);
+    let resp = src_client
+        .get(src.blob_url(&descriptor.digest)?)
+        .send()
+        .await?;
+    check_blob_status(&resp)?;
+    let bar = bar.clone();
+    let body = reqwest::Body::wrap_stream(
+        resp.bytes_stream()
+            .inspect_ok(move |chunk| bar.inc(chunk.len() as u64)),
+    );
+    finish_upload(
+        dst_client,
+        location,
+        &descriptor.digest,
+        body,
+        descriptor.size,
+    )
+    .await
+}
+
+/// Fetch the small blob at `url`, such as an image configuration, into
+/// memory.
+///
+/// The content is not verified here; callers compare its digest.
+///
+/// # Errors
+///
+/// * [`ApiError::HttpError`] — the request failed at the transport layer.
+/// * Any variant returned by [`api::parse_response_status`] for non-success
+///   responses.
+pub async fn get(client: &reqwest::Client, url: &Url) -> Result<Vec<u8>, ApiError> {
+    log::trace!("get(url: {url})");
+    let resp = client.get(url.as_ref()).send().await?;
+    check_blob_status(&resp)?;
+    Ok(resp.bytes().await?.to_vec())
+}
+
+/// Number of chunks buffered between a [`BlobReader`] and its download.
+const READER_CHUNKS: usize = 16;
+
+/// A blocking [`Read`] over a blob that is being downloaded on the runtime.
+///
+/// Created by [`open`].  Reading blocks until the next chunk arrives, so a
+/// `BlobReader` must only be used from a blocking thread, e.g. inside
+/// [`tokio::task::spawn_blocking`].
+#[derive(Debug)]
+pub struct BlobReader {
+    rx: tokio::sync::mpsc::Receiver<std::io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for BlobReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.rx.blocking_recv() {
+                Some(chunk) => {
+                    self.chunk = chunk?;
+                    self.pos = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Start downloading the blob at `url` and return a blocking reader over
+/// its contents.
+///
+/// The response is consumed by a task on the runtime and handed to the
+/// reader in chunks, so the blob is never held in memory in full.  The
+/// download stops when the reader is dropped.  The content is not verified
+/// here; callers hash what they read.
+///
+/// # Errors
+///
+/// * [`ApiError::HttpError`] — the request failed at the transport layer.
+/// * Any variant returned by [`api::parse_response_status`] for non-success
+///   responses.
+pub async fn open(client: &reqwest::Client, url: &Url) -> Result<BlobReader, ApiError> {
+    log::trace!("open(url: {url})");
+    let mut resp = client.get(url.as_ref()).send().await?;
+    check_blob_status(&resp)?;
+
+    let (tx, rx) = tokio::sync::mpsc::channel(READER_CHUNKS);
+    tokio::spawn(async move {
+        loop {
+            let chunk = match resp.chunk().await {
+                Ok(Some(chunk)) => Ok(chunk.to_vec()),
+                Ok(None) => break,
+                Err(e) => Err(std::io::Error::other(e)),
+            };
+            let failed = chunk.is_err();
+            if tx.send(chunk).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+    Ok(BlobReader {
+        rx,
+        chunk: Vec::new(),
+        pos: 0,
+    })
+}
+
+/// Run `tasks` on the runtime, at most `jobs` at a time.
+///
+/// Tasks are started in order as earlier ones complete.  The first error
+/// aborts the remaining tasks and is returned.
+///
+/// # Errors
+///
+/// Returns the first error raised by a task, or
+/// [`ApiError::UnexpectedResponse`] if a task panicked.
+pub async fn run_bounded<F>(jobs: usize, tasks: impl IntoIterator<Item = F>) -> Result<(), ApiError>
+where
+    F: Future<Output = Result<(), ApiError>> + Send + 'static,
+{
+    let mut running = JoinSet::new();
+    for task in tasks {
+        if running.len() >= jobs.max(1) {
+            join_one(&mut running).await?;
+        }
+        running.spawn(task);
+    }
+    while !running.is_empty() {
+        join_one(&mut running).await?;
+    }
+    Ok(())
+}
+
+/// Wait for the next task in `running` to complete and return its result.
+async fn join_one(running: &mut JoinSet<Result<(), ApiError>>) -> Result<(), ApiError> {
+    match running.join_next().await {
+        Some(result) => result.map_err(|e| ApiError::UnexpectedResponse(e.to_string()))?,
+        None => Ok(()),
+    }
+}
+
+/// URL that opens an upload session in the repository of `image`.
+fn uploads_url(image: &ImageRef) -> Result<Url, ApiError> {
+    Ok(image
+        .registry
+        .join(&format!("/v2/{}/blobs/uploads/", image.repository))?)
+}
+
+/// Resolve the `Location` header of an upload response against `registry`.
+///
+/// Registries may answer with either an absolute URL or a path.
+fn upload_location(registry: &Url, resp: &reqwest::Response) -> Result<Url, ApiError> {
+    let location = resp
+        .headers()
+        .get(header::LOCATION)
+        .ok_or_else(|| ApiError::UnexpectedResponse(String::from("Missing Location header")))?
+        .to_str()?;
+    Ok(registry.join(location)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::Progress;
+
+    const BLOB: &[u8] = b"0123456789abcdefghij";
+
+    /// Create a unique scratch directory for a test.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dredge-blob-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A progress bar for a single test transfer.
+    fn bar() -> Bar {
+        Progress::new(1, BLOB.len() as u64).start("test", BLOB.len() as u64)
+    }
+
+    /// Test that a blob reader yields the whole body from a blocking thread.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_open() -> Result<(), Box<dyn std::error::Error>> {
+        let mut server = mockito::Server::new_async().await;
+        let body = BLOB.repeat(1000);
+        server
+            .mock("GET", "/v2/foo/blobs/sha256:abc")
+            .with_status(200)
+            .with_body(&body)
+            .create();
+
+        let url = Url::parse(&server.url())?.join("/v2/foo/blobs/sha256:abc")?;
+        let mut reader = open(&reqwest::Client::new(), &url).await?;
+        let read = tokio::task::spawn_blocking(move || {
+            let mut out = Vec::new();
+            reader.read_to_end(&mut out).map(|_| out)
+        })
+        .await??;
+        assert_eq!(read, body);
+        Ok(())
+    }
+
+    /// Test that no more than `jobs` tasks run at once and that every task
+    /// runs.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_run_bounded() -> Result<(), ApiError> {
+        use std::sync::atomic::AtomicUsize;
+        use std::sync::atomic::Ordering;
+        use std::sync::Arc;
+
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let done = Arc::new(AtomicUsize::new(0));
+        let tasks = (0..10).map(|_| {
+            let (running, peak, done) = (running.clone(), peak.clone(), done.clone());
+            async move {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                tokio::task::yield_now().await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                done.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            }
+        });
+
+        run_bounded(3, tasks).await?;
+        assert_eq!(done.load(Ordering::SeqCst), 10);
+        assert!(peak.load(Ordering::SeqCst) <= 3);
+        Ok(())
+    }
+
+    /// Test that the first failing task's error is returned.
+    #[tokio::test]
+    async fn test_run_bounded_error() {
+        let tasks = (0..3).map(|i| async move {
+            if i == 1 {
+                Err(ApiError::NotFound)
+            } else {
+                Ok(())
+            }
+        });
+        assert!(matches!(
+            run_bounded(2, tasks).await,
+            Err(ApiError::NotFound)
+        ));
+    }
+
+    /// Test that ranges cover the whole blob without gaps or overlap.
+    #[test]
+    fn test_split_ranges_covers_blob() {
+        let ranges = split_ranges(20, 3, 1);
+        assert_eq!(
+            ranges,
+            vec![
+                ByteRange { start: 0, end: 6 },
+                ByteRange { start: 7, end: 13 },
+                ByteRange { start: 14, end: 19 },
+            ]
+        );
+        assert_eq!(ranges.iter().map(|r| r.len()).sum::<u64>(), 20);
+    }
+
+    /// Test that blobs smaller than the minimum range size are not split.
+    #[test]
+    fn test_split_ranges_small_blob() {
+        assert_eq!(split_ranges(20, 4, 16).len(), 1);
+    }
+
+    /// Test a plain, non-resumed download of a blob.
+    #[tokio::test]
+    async fn test_download_full() -> Result<(), Box<dyn std::error::Error>> {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/v2/foo/blobs/x")
+            .with_status(200)
+            .with_body(BLOB)
+            .create();
+
+        let dir = scratch_dir("full");
+        let dest = dir.join("blob");
+        let url = Url::parse(&server.url())?.join("/v2/foo/blobs/x")?;
+        let client = reqwest::Client::new();
+        let size = download(&client, &url, &dest, &digest::sha256(BLOB), 1, &bar()).await?;
+
+        assert_eq!(size, 20);
+        assert_eq!(std::fs::read(&dest)?, BLOB);
+        assert!(!suffixed(&dest, ".partial").exists());
+        mock.assert();
+        Ok(())
+    }
+
+    /// Test that an existing partial file is resumed with a `Range` request
+    /// and the assembled result is verified.
+    #[tokio::test]
+    async fn test_download_resumes_partial() -> Result<(), Box<dyn std::error::Error>> {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/v2/foo/blobs/x")
+            .match_header("range", "bytes=8-")
+            .with_status(206)
+            .with_header("content-range", "bytes 8-19/20")
+            .with_body(&BLOB[8..])
+            .create();
+
+        let dir = scratch_dir("resume");
+        let dest = dir.join("blob");
+        std::fs::write(suffixed(&dest, ".partial"), &BLOB[..8])?;
+
+        let url = Url::parse(&server.url())?.join("/v2/foo/blobs/x")?;
+        let client = reqwest::Client::new();
+        download(&client, &url, &dest, &digest::sha256(BLOB), 1, &bar()).await?;
+
+        assert_eq!(std::fs::read(&dest)?, BLOB);
+        mock.assert();
+        Ok(())
+    }
+
+    /// Test that a parallel download of an empty blob, whose first byte
+    /// cannot be requested, falls back to a single stream.
+    #[tokio::test]
+    async fn test_download_parallel_empty() -> Result<(), Box<dyn std::error::Error>> {
+        let mut server = mockito::Server::new_async().await;
+        let probe = server
+            .mock("GET", "/v2/foo/blobs/x")
+            .match_header("range", "bytes=0-0")
+            .with_status(416)
+            .create();
+        let full = server
+            .mock("GET", "/v2/foo/blobs/x")
+            .match_header("range", mockito::Matcher::Missing)
+            .with_status(200)
+            .create();
+
+        let dir = scratch_dir("parallel-empty");
+        let dest = dir.join("blob");
+        let url = Url::parse(&server.url())?.join("/v2/foo/blobs/x")?;
+        let client = reqwest::Client::new();
+        let size = download(&client, &url, &dest, &digest::sha256(b""), 4, &bar()).await?;
+
+        assert_eq!(size, 0);
+        assert_eq!(std::fs::read(&dest)?, b"");
+        probe.assert();
+        full.assert();
+        Ok(())
+    }
+
+    /// Test that a parallel download resumes a single-stream partial file
+    /// instead of splitting the blob into ranges.
+    #[tokio::test]
+    async fn test_download_parallel_resumes_partial() -> Result<(), Box<dyn std::error::Error>> {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/v2/foo/blobs/x")
+            .match_header("range", "bytes=8-")
+            .with_status(206)
+            .with_header("content-range", "bytes 8-19/20")
+            .with_body(&BLOB[8..])
+            .expect(1)
+            .create();
+
+        let dir = scratch_dir("parallel-resume");
+        let dest = dir.join("blob");
+        std::fs::write(suffixed(&dest, ".partial"), &BLOB[..8])?;
+
+        let url = Url::parse(&server.url())?.join("/v2/foo/blobs/x")?;
+        let client = reqwest::Client::new();
+        download(&client, &url, &dest, &digest::sha256(BLOB), 4, &bar()).await?;
+
+        assert_eq!(std::fs::read(&dest)?, BLOB);
+        mock.assert();
+        Ok(())
+    }
+
+    /// Test that a corrupt download is rejected and its partial file removed.
+    #[tokio::test]
+    async fn test_download_digest_mismatch() -> Result<(), Box<dyn std::error::Error>> {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/v2/foo/blobs/x")
+            .with_status(200)
+            .with_body("corrupted")
+            .create();
+
+        let dir = scratch_dir("mismatch");
+        let dest = dir.join("blob");
+        let url = Url::parse(&server.url())?.join("/v2/foo/blobs/x")?;
+        let client = reqwest::Client::new();
+        let result = download(&client, &url, &dest, &digest::sha256(BLOB), 1, &bar()).await;
+
+        assert!(matches!(result, Err(ApiError::DigestMismatch { .. })));
+        assert!(!dest.exists());
+        assert!(!suffixed(&dest, ".partial").exists());
+        Ok(())
+    }
+
+    /// Test that parallel ranges are fetched, resumed, and concatenated in
+    /// order.
+    #[tokio::test]
+    async fn test_fetch_ranges() -> Result<(), Box<dyn std::error::Error>> {
+        let mut server = mockito::Server::new_async().await;
+        let first = server
+            .mock("GET", "/v2/foo/blobs/x")
+            .match_header("range", "bytes=0-9")
+            .with_status(206)
+            .with_header("content-range", "bytes 0-9/20")
+            .with_body(&BLOB[..10])
+            .create();
+        // The second range was interrupted after 4 bytes.
+        let second = server
+            .mock("GET", "/v2/foo/blobs/x")
+            .match_header("range", "bytes=14-19")
+            .with_status(206)
+            .with_header("content-range", "bytes 14-19/20")
+            .with_body(&BLOB[14..])
+            .create();
+
+        let dir = scratch_dir("ranges");
+        let partial = dir.join("blob.partial");
+        std::fs::write(suffixed(&partial, ".1"), &BLOB[10..14])?;
+
+        let url = Url::parse(&server.url())?.join("/v2/foo/blobs/x")?;
+        let client = reqwest::Client::new();
+        let ranges = split_ranges(20, 2, 1);
+        fetch_ranges(&client, &url, &partial, &ranges, &bar()).await?;
+
+        assert_eq!(std::fs::read(&partial)?, BLOB);
+        assert!(!suffixed(&partial, ".0").exists());
+        first.assert();
+        second.assert();
+        Ok(())
+    }
+
+    /// Parse a reference to `repository` on the mock `server`.
+    fn image_on(server: &mockito::Server, repository: &str) -> ImageRef {
+        let registry = Url::parse(&server.url()).unwrap();
+        ImageRef::parse(repository, &registry).unwrap()
+    }
+
+    /// Test that `exists` maps `200` to `true` and `404` to `false`.
+    #[tokio::test]
+    async fn test_exists() -> Result<(), ApiError> {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("HEAD", "/v2/foo/blobs/sha256:present")
+            .with_status(200)
+            .create();
+        server
+            .mock("HEAD", "/v2/foo/blobs/sha256:absent")
+            .with_status(404)
+            .create();
+
+        let image = image_on(&server, "foo");
+        let client = reqwest::Client::new();
+        assert!(exists(&client, &image, "sha256:present").await?);
+        assert!(!exists(&client, &image, "sha256:absent").await?);
+        Ok(())
+    }
+
+    /// Test a successful cross-repository mount.
+    #[tokio::test]
+    async fn test_mount_mounted() -> Result<(), ApiError> {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/v2/bar/blobs/uploads/")
+            .match_query(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("mount".into(), "sha256:x".into()),
+                mockito::Matcher::UrlEncoded("from".into(), "foo".into()),
+            ]))
+            .with_status(201)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .create();
+
+        let image = image_on(&server, "bar");
+        let client = reqwest::Client::new();
+        assert_eq!(
+            mount(&client, &image, "sha256:x", "foo").await?,
+            Mount::Mounted
+        );
+        mock.assert();
+        Ok(())
+    }
+
+    /// Test that a declined mount yields the upload session it opened, with a
+    /// relative `Location` resolved against the registry.
+    #[tokio::test]
+    async fn test_mount_declined() -> Result<(), ApiError> {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("POST", "/v2/bar/blobs/uploads/")
+            .match_query(mockito::Matcher::Any)
+            .with_status(202)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .with_header("location", "/v2/bar/blobs/uploads/session?_state=abc")
+            .create();
+
+        let image = image_on(&server, "bar");
+        let client = reqwest::Client::new();
+        let Mount::Upload(location) = mount(&client, &image, "sha256:x", "foo").await? else {
+            panic!("Expected an upload session");
+        };
+        assert_eq!(
+            location.as_str(),
+            format!("{}/v2/bar/blobs/uploads/session?_state=abc", server.url())
+        );
+        Ok(())
+    }
+
+    /// Test that `stream` pipes the source blob into the destination upload
+    /// session, completing it with the blob digest.
+    #[tokio::test]
+    async fn test_stream() -> Result<(), Box<dyn std::error::Error>> {
+        let mut src_server = mockito::Server::new_async().await;
+        let mut dst_server = mockito::Server::new_async().await;
+        let digest = digest::sha256(BLOB);
+
+        src_server
+            .mock("GET", &*format!("/v2/foo/blobs/{digest}"))
+            .with_status(200)
+            .with_body(BLOB)
+            .create();
+        let upload = dst_server
+            .mock("PUT", "/v2/bar/blobs/uploads/session")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "digest".into(),
+                digest.clone(),
+            ))
+            .match_body(BLOB.to_vec())
+            .with_status(201)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .create();
+
+        let src = image_on(&src_server, "foo");
+        let dst = image_on(&dst_server, "bar");
+        let location = dst.registry.join("/v2/bar/blobs/uploads/session")?;
+        let descriptor = Descriptor {
+            media_type: String::from("application/octet-stream"),
+            digest,
+            size: BLOB.len() as u64,
+            platform: None,
+            annotations: None,
+            urls: None,
+        };
+        let client = reqwest::Client::new();
+        stream(&client, &src, &client, location, &descriptor, &bar()).await?;
+
+        upload.assert();
+        Ok(())
+    }
+}
@@ -7,9 +7,19 @@
  * copied, modified, or distributed except according to those terms.
  */
 
+use std::path::PathBuf;
+
 use clap::Parser;
 use clap::Subcommand;
 use clap::ValueEnum;
+use semver::VersionReq;
+
+use crate::api::Credentials;
+use crate::manifest::Platform;
+use crate::mutate::Argv;
+use crate::mutate::KeyValue;
+use crate::pattern::Pattern;
+use crate::tags::SortOrder;
 
 /// Command-line interface for `dredge`.
 ///
@@ -94,28 +104,90 @@ pub enum Commands {
     /// List all repositories available in the registry catalog.
     ///
     /// Queries the `/v2/_catalog` endpoint and prints one repository name per
-    /// line.  Paginated responses are followed automatically.
+    /// line.  Paginated responses are followed automatically, and filters
+    /// are applied to each page as it arrives.
     ///
-    /// **Example:**
+    /// Patterns are shell-style wildcards matched against the whole name,
+    /// or regular expressions when written between slashes.
+    ///
+    /// **Examples:**
     /// ```text
     /// dredge registry.example.com catalog
+    /// dredge registry.example.com catalog --namespace myorg --match '*-api' --count
     /// ```
-    Catalog,
+    Catalog {
+        /// Only list repositories matching this pattern.  May be repeated;
+        /// defaults to every repository.
+        #[arg(long = "match", value_name = "PATTERN")]
+        include: Vec<Pattern>,
+        /// Never list repositories matching this pattern.  May be repeated.
+        #[arg(long, value_name = "PATTERN")]
+        exclude: Vec<Pattern>,
+        /// Only list repositories under this namespace (e.g. `myorg`).
+        #[arg(long)]
+        namespace: Option<String>,
+        /// Print only the number of selected repositories.
+        #[arg(long)]
+        count: bool,
+    },
 
     /// List all tags published for an image.
     ///
     /// Queries the `/v2/<NAME>/tags/list` endpoint and prints one tag per
-    /// line.  Paginated responses are followed automatically.
+    /// line.  Paginated responses are followed automatically, and filters
+    /// are applied to each page as it arrives.
     ///
-    /// **Example:**
+    /// Tags such as `v1.4`, `1.4.2`, or `2.0.0-rc.1` are read as semantic
+    /// versions.  With `--sort semver`, versions are listed lowest first and
+    /// other tags follow; `--latest` prints only the highest release.
+    ///
+    /// **Examples:**
     /// ```text
     /// dredge registry.example.com tags myorg/backend
+    /// dredge registry.example.com tags myorg/backend --match '/^pr-[0-9]+$/' --count
+    /// dredge registry.example.com tags myorg/backend --semver '>=1.4, <2' --latest
+    /// dredge registry.example.com tags myorg/backend --long --sort date
     /// ```
     #[command(arg_required_else_help = true)]
     Tags {
         /// The repository name whose tags should be listed
         /// (e.g. `myorg/backend`).
         name: String,
+        /// Only list tags matching this pattern.  May be repeated; defaults
+        /// to every tag.
+        #[arg(long = "match", value_name = "PATTERN")]
+        include: Vec<Pattern>,
+        /// Never list tags matching this pattern.  May be repeated.
+        #[arg(long, value_name = "PATTERN")]
+        exclude: Vec<Pattern>,
+        /// Only list versions satisfying this constraint
+        /// (e.g. `'>=1.4, <2'`).
+        #[arg(long, value_name = "CONSTRAINT")]
+        semver: Option<VersionReq>,
+        /// Order of the tags; the registry order by default.
+        #[arg(long, value_enum)]
+        sort: Option<SortOrder>,
+        /// Print only the last tag in order: the highest release, or the
+        /// newest image with `--sort date`.
+        #[arg(long)]
+        latest: bool,
+        /// Print the digest, size, platforms, and creation time of each
+        /// tag.
+        #[arg(short, long, conflicts_with = "count")]
+        long: bool,
+        /// Print only the number of selected tags.
+        #[arg(long)]
+        count: bool,
+        /// Maximum number of tags to inspect concurrently for `--long` and
+        /// `--sort date` (1–64).
+        #[arg(
+            short,
+            long,
+            value_name = "N",
+            default_value_t = 8,
+            value_parser = clap::value_parser!(u16).range(1..=64)
+        )]
+        jobs: u16,
     },
 
     /// Show detailed manifest information for a tagged image.
@@ -140,11 +212,14 @@ pub enum Commands {
         tag: Option<String>,
     },
 
-    /// Delete a tagged image from the registry.
+    /// Delete tagged images, or manifests by digest, from the registry.
     ///
-    /// Resolves the tag to its content digest via a `HEAD` request, then
-    /// sends a `DELETE` request for that digest to the
-    /// `/v2/<IMAGE>/manifests/<DIGEST>` endpoint.
+    /// Selects the tags given as arguments, those matching `--match`, and
+    /// those listed in `--from-file`.  Each tag is resolved to its content
+    /// digest via a `HEAD` request, and each distinct manifest is deleted
+    /// once by sending a `DELETE` request for its digest to the
+    /// `/v2/<IMAGE>/manifests/<DIGEST>` endpoint.  `<IMAGE>@<DIGEST>`
+    /// deletes that manifest directly.
     ///
     /// Requires the registry to have storage deletion enabled (set
     /// `REGISTRY_STORAGE_DELETE_ENABLED=true` on the registry container).
@@ -152,17 +227,757 @@ pub enum Commands {
     /// `405 Method Not Allowed` response.
     ///
     /// Only the manifest is removed; unreferenced layer blobs remain on disk
-    /// until the registry garbage collector is run.
+    /// until the registry garbage collector is run.  Every other tag that
+    /// points at the same manifest is removed with it.
     ///
-    /// **Example:**
+    /// With `--tag-only`, only the tag is deleted, as OCI 1.1 registries
+    /// allow.  When the registry cannot delete tags, the manifest is deleted
+    /// only if no other tag points at it; otherwise those tags are listed
+    /// and nothing is deleted unless `--force` is given.
+    ///
+    /// With `--cascade`, deleting a multi-platform index also deletes the
+    /// platform manifests it lists that no remaining tag refers to.
+    ///
+    /// Prints whether each tag was deleted, and exits with an error if any
+    /// tag could not be.
+    ///
+    /// **Examples:**
     /// ```text
     /// dredge registry.example.com delete myorg/backend v1.0.0
+    /// dredge registry.example.com delete myorg/backend v1.0.0 v1.0.1 --tag-only
+    /// dredge registry.example.com delete myorg/backend --match 'pr-*'
+    /// cat stale.txt | dredge registry.example.com delete myorg/backend --from-file -
+    /// dredge registry.example.com delete myorg/backend@sha256:0259571889ac87efbf... --cascade
     /// ```
     #[command(arg_required_else_help = true)]
     Delete {
-        /// The repository name of the image to delete (e.g. `myorg/backend`).
+        /// The repository name of the image to delete (e.g. `myorg/backend`),
+        /// optionally with a tag or `@sha256:` digest to delete.
+        image: String,
+        /// The tags to delete (e.g. `v1.0.0`).
+        tags: Vec<String>,
+        /// Also delete the tags matching a wildcard or `/regex/`; repeatable.
+        #[arg(long = "match", value_name = "PATTERN")]
+        include: Vec<Pattern>,
+        /// Also delete the tags listed in a file, one per line; `-` reads
+        /// standard input.
+        #[arg(long, value_name = "FILE")]
+        from_file: Option<PathBuf>,
+        /// Delete only the tag, not the manifest other tags may share.
+        #[arg(long)]
+        tag_only: bool,
+        /// With `--tag-only`, delete the manifest even if other tags point
+        /// at it when the registry cannot delete tags.
+        #[arg(long, requires = "tag_only")]
+        force: bool,
+        /// Also delete the platform manifests of deleted indexes that no
+        /// remaining tag refers to.
+        #[arg(long)]
+        cascade: bool,
+        /// Maximum number of requests in flight (1–64).
+        #[arg(
+            short,
+            long,
+            value_name = "N",
+            default_value_t = 8,
+            value_parser = clap::value_parser!(u16).range(1..=64)
+        )]
+        jobs: u16,
+    },
+
+    /// Download a tagged image into a local OCI image layout directory.
+    ///
+    /// Stores the manifest, image configuration, and every layer under
+    /// `<OUTPUT>/blobs/sha256/` and records the tag in `<OUTPUT>/index.json`.
+    /// Every platform of a multi-platform image is pulled.  Prints the
+    /// manifest digest on success.
+    ///
+    /// Interrupted downloads leave `.partial` files behind; running the same
+    /// command again resumes them with HTTP `Range` requests.  Every blob is
+    /// verified against its digest before it is accepted.
+    ///
+    /// When `[TAG]` is omitted, `latest` is used.
+    ///
+    /// **Examples:**
+    /// ```text
+    /// dredge registry.example.com pull myorg/backend v2.0.0 --output ./backend
+    /// dredge registry.example.com pull myorg/backend --output ./backend --parallel 4 --jobs 8
+    /// ```
+    #[command(arg_required_else_help = true)]
+    Pull {
+        /// The repository name of the image to pull (e.g. `myorg/backend`).
+        image: String,
+        /// The tag to pull.  Defaults to `latest` when omitted.
+        #[arg(default_missing_value = "latest")]
+        tag: Option<String>,
+        /// Directory of the OCI image layout to write.  Created when missing.
+        #[arg(short, long, value_name = "DIR")]
+        output: PathBuf,
+        /// Fetch each large blob as up to `N` concurrent HTTP ranges.
+        #[arg(
+            long,
+            value_name = "N",
+            default_value_t = 1,
+            value_parser = clap::value_parser!(u16).range(1..=64)
+        )]
+        parallel: u16,
+        /// Maximum number of blobs to transfer concurrently (1–64).
+        #[arg(
+            short,
+            long,
+            value_name = "N",
+            default_value_t = 4,
+            value_parser = clap::value_parser!(u16).range(1..=64)
+        )]
+        jobs: u16,
+    },
+
+    /// Copy an image between repositories and registries.
+    ///
+    /// Copies the manifest, image configuration, and every layer, including
+    /// every platform of a multi-platform image.  Manifests are uploaded
+    /// byte-for-byte, so the digest is preserved.  Prints the manifest
+    /// digest on success.
+    ///
+    /// References take the form `[REGISTRY/]REPOSITORY[:TAG|@DIGEST]`; a
+    /// reference without a registry lives on `<REGISTRY>`.  When the
+    /// destination names no tag, the source tag is reused.
+    ///
+    /// Within one registry, blobs are linked with a cross-repository blob
+    /// mount and never transferred.  Across registries they are streamed
+    /// from the source to the destination.  Blobs the destination already
+    /// has are skipped.
+    ///
+    /// **Examples:**
+    /// ```text
+    /// dredge staging.example.com copy myorg/backend:v2.0.0 prod.example.com/myorg/backend
+    /// dredge registry.example.com copy myorg/backend:rc myorg/backend-archive:rc --dry-run
+    /// ```
+    #[command(arg_required_else_help = true)]
+    Copy {
+        /// Reference of the image to copy (e.g. `myorg/backend:v2.0.0`).
+        source: String,
+        /// Reference to copy the image to (e.g.
+        /// `prod.example.com/myorg/backend`).
+        destination: String,
+        /// Basic credentials for the source registry.
+        #[arg(
+            long,
+            value_name = "USER:PASSWORD",
+            env = "DREDGE_SRC_CREDS",
+            hide_env_values = true
+        )]
+        src_creds: Option<Credentials>,
+        /// Basic credentials for the destination registry.
+        #[arg(
+            long,
+            value_name = "USER:PASSWORD",
+            env = "DREDGE_DEST_CREDS",
+            hide_env_values = true
+        )]
+        dest_creds: Option<Credentials>,
+        /// Print the blobs and bytes that would be transferred, then exit.
+        #[arg(long)]
+        dry_run: bool,
+        /// Maximum number of blobs to transfer concurrently (1–64).
+        #[arg(
+            short,
+            long,
+            value_name = "N",
+            default_value_t = 4,
+            value_parser = clap::value_parser!(u16).range(1..=64)
+        )]
+        jobs: u16,
+    },
+
+    /// Mirror the tags of a repository into another repository.
+    ///
+    /// Copies every source tag selected by `--include` and `--exclude` to
+    /// the destination, as with `copy`.  Tags that already point at the same
+    /// digest on both sides are skipped, so repeated runs only transfer what
+    /// changed.  With `--prune`, selected destination tags that no longer
+    /// exist at the source are deleted.
+    ///
+    /// References take the form `[REGISTRY/]REPOSITORY`; a reference without
+    /// a registry lives on `<REGISTRY>`.
+    ///
+    /// **Examples:**
+    /// ```text
+    /// dredge mirror.example.com sync docker.example.com/library/nginx library/nginx --include '1.*'
+    /// dredge registry.example.com sync myorg/backend backup.example.com/myorg/backend --prune --dry-run
+    /// ```
+    #[command(arg_required_else_help = true)]
+    Sync {
+        /// Repository to mirror (e.g. `docker.example.com/library/nginx`).
+        source: String,
+        /// Repository to mirror into (e.g. `library/nginx`).
+        destination: String,
+        /// Only sync tags matching this pattern: a wildcard (`*` and `?`),
+        /// or a regular expression between slashes.  May be repeated;
+        /// defaults to every tag.
+        #[arg(long, value_name = "PATTERN")]
+        include: Vec<Pattern>,
+        /// Never sync tags matching this pattern.  May be repeated.
+        #[arg(long, value_name = "PATTERN")]
+        exclude: Vec<Pattern>,
+        /// Delete selected destination tags that no longer exist at the
+        /// source.
+        #[arg(long)]
+        prune: bool,
+        /// Basic credentials for the source registry.
+        #[arg(
+            long,
+            value_name = "USER:PASSWORD",
+            env = "DREDGE_SRC_CREDS",
+            hide_env_values = true
+        )]
+        src_creds: Option<Credentials>,
+        /// Basic credentials for the destination registry.
+        #[arg(
+            long,
+            value_name = "USER:PASSWORD",
+            env = "DREDGE_DEST_CREDS",
+            hide_env_values = true
+        )]
+        dest_creds: Option<Credentials>,
+        /// Print what would be copied and pruned, then exit.
+        #[arg(long)]
+        dry_run: bool,
+        /// Maximum number of blobs to transfer concurrently (1–64).
+        #[arg(
+            short,
+            long,
+            value_name = "N",
+            default_value_t = 4,
+            value_parser = clap::value_parser!(u16).range(1..=64)
+        )]
+        jobs: u16,
+    },
+
+    /// List the files in one layer of an image.
+    ///
+    /// Prints one line per tar entry with its mode, owner, size, and path,
+    /// like `tar -tv`.  The layer is decompressed according to its media
+    /// type (`tar`, `tar+gzip`, or `tar+zstd`) and verified against both its
+    /// digest and the diff ID in the image configuration before anything is
+    /// printed.
+    ///
+    /// **Examples:**
+    /// ```text
+    /// dredge registry.example.com layer myorg/backend:v2.0.0 0
+    /// dredge registry.example.com layer myorg/backend:v2.0.0 sha256:a3ed95caeb02... --platform linux/arm64
+    /// ```
+    #[command(arg_required_else_help = true)]
+    Layer {
+        /// Reference of the image (e.g. `myorg/backend:v2.0.0`).
+        image: String,
+        /// Index of the layer counting from `0` for the base layer, or the
+        /// digest or diff ID of the layer.
+        layer: String,
+        /// Platform to select from a multi-platform image.  Defaults to
+        /// `linux/amd64`.
+        #[arg(long, value_name = "OS/ARCH[/VARIANT]")]
+        platform: Option<Platform>,
+    },
+
+    /// List a path in the filesystem of an image.
+    ///
+    /// Applies every layer in order, honouring whiteouts and opaque
+    /// directories, to build the filesystem a container would start with,
+    /// without extracting anything to disk.  Lists the children of a
+    /// directory, or the path itself for anything else.
+    ///
+    /// **Examples:**
+    /// ```text
+    /// dredge registry.example.com ls myorg/backend:v2.0.0
+    /// dredge registry.example.com ls myorg/backend:v2.0.0 /etc -l
+    /// dredge registry.example.com ls myorg/backend:v2.0.0 /usr/local -lR --platform linux/arm64
+    /// ```
+    #[command(arg_required_else_help = true)]
+    Ls {
+        /// Reference of the image (e.g. `myorg/backend:v2.0.0`).
+        image: String,
+        /// Path to list.  Defaults to the root.
+        path: Option<String>,
+        /// Show the mode, owner, size, and modification time of each entry.
+        #[arg(short, long)]
+        long: bool,
+        /// List every entry below a directory.
+        #[arg(short = 'R', long)]
+        recursive: bool,
+        /// Platform to select from a multi-platform image.  Defaults to
+        /// `linux/amd64`.
+        #[arg(long, value_name = "OS/ARCH[/VARIANT]")]
+        platform: Option<Platform>,
+    },
+
+    /// Print a file from the filesystem of an image.
+    ///
+    /// Streams layers from the top down and stops at the topmost layer
+    /// containing the file, so lower layers are never downloaded.  Symbolic
+    /// links are followed and whiteouts are respected.  The contents are
+    /// written once the layer has been verified.
+    ///
+    /// **Examples:**
+    /// ```text
+    /// dredge registry.example.com cat myorg/backend:v2.0.0 /etc/os-release
+    /// dredge registry.example.com cat myorg/backend:v2.0.0 /app/config.yaml --platform linux/arm64
+    /// ```
+    #[command(arg_required_else_help = true)]
+    Cat {
+        /// Reference of the image (e.g. `myorg/backend:v2.0.0`).
+        image: String,
+        /// Path of the file in the image.
+        path: String,
+        /// Platform to select from a multi-platform image.  Defaults to
+        /// `linux/amd64`.
+        #[arg(long, value_name = "OS/ARCH[/VARIANT]")]
+        platform: Option<Platform>,
+    },
+
+    /// Extract a file from the filesystem of an image into a directory.
+    ///
+    /// Finds the file like `cat` and writes it to `<DIR>/<NAME>` with the
+    /// permission bits it has in the image, without the setuid, setgid and
+    /// sticky bits, then prints the path written.
+    ///
+    /// **Examples:**
+    /// ```text
+    /// dredge registry.example.com extract myorg/tools:v1.4.0 /usr/local/bin/migrate --to ./bin
+    /// ```
+    #[command(arg_required_else_help = true)]
+    Extract {
+        /// Reference of the image (e.g. `myorg/backend:v2.0.0`).
+        image: String,
+        /// Path of the file in the image.
+        path: String,
+        /// Directory to extract the file into; created if needed.
+        #[arg(long, value_name = "DIR")]
+        to: PathBuf,
+        /// Platform to select from a multi-platform image.  Defaults to
+        /// `linux/amd64`.
+        #[arg(long, value_name = "OS/ARCH[/VARIANT]")]
+        platform: Option<Platform>,
+    },
+
+    /// Write the filesystem of an image to a single tar file.
+    ///
+    /// Produces the same result as `docker export` on a fresh container,
+    /// without a daemon: every whiteout is resolved and only the files a
+    /// container would see are written.  Each layer is streamed once, from
+    /// the top layer down.  Only one platform of a multi-platform image is
+    /// exported.
+    ///
+    /// **Examples:**
+    /// ```text
+    /// dredge registry.example.com export myorg/backend:v2.0.0 -o rootfs.tar
+    /// dredge registry.example.com export myorg/backend:v2.0.0 -o rootfs-arm64.tar --platform linux/arm64
+    /// ```
+    #[command(arg_required_else_help = true)]
+    Export {
+        /// Reference of the image (e.g. `myorg/backend:v2.0.0`).
+        image: String,
+        /// Path of the tar file to write.
+        #[arg(short, long, value_name = "FILE")]
+        output: PathBuf,
+        /// Platform to select from a multi-platform image.  Defaults to
+        /// `linux/amd64`.
+        #[arg(long, value_name = "OS/ARCH[/VARIANT]")]
+        platform: Option<Platform>,
+    },
+
+    /// Report the space an image wastes on files hidden by later layers.
+    ///
+    /// For each layer, shows its size, the bytes of its files, and how many
+    /// of those bytes a later layer overwrites or deletes, with the history
+    /// entry that created it.  Then lists the largest wasted files and the
+    /// history entries responsible.  Only tar headers are inspected; nothing
+    /// is run or extracted.
+    ///
+    /// With `--min-efficiency`, exits with an error when the share of file
+    /// bytes that reach the final filesystem is lower, so CI can fail a
+    /// build.
+    ///
+    /// **Examples:**
+    /// ```text
+    /// dredge registry.example.com analyze myorg/backend:v2.0.0
+    /// dredge registry.example.com analyze myorg/backend:v2.0.0 --top 20 --min-efficiency 95
+    /// ```
+    #[command(arg_required_else_help = true)]
+    Analyze {
+        /// Reference of the image (e.g. `myorg/backend:v2.0.0`).
+        image: String,
+        /// Number of wasted files to list.
+        #[arg(long, value_name = "N", default_value_t = 10)]
+        top: usize,
+        /// Fail when the efficiency is below this percentage (0–100).
+        #[arg(
+            long,
+            value_name = "PERCENT",
+            value_parser = clap::value_parser!(u8).range(0..=100)
+        )]
+        min_efficiency: Option<u8>,
+        /// Platform to select from a multi-platform image.  Defaults to
+        /// `linux/amd64`.
+        #[arg(long, value_name = "OS/ARCH[/VARIANT]")]
+        platform: Option<Platform>,
+    },
+
+    /// Compare two images.
+    ///
+    /// Reports the layers the images share and those only one of them has
+    /// (compared by diff ID), changes to the creation time, user, working
+    /// directory, entrypoint, command, environment variables, and labels,
+    /// and the files added, removed, or modified in the merged filesystem.
+    /// Files are compared by mode, owner, size, content, link target, and
+    /// modification time.
+    ///
+    /// **Examples:**
+    /// ```text
+    /// dredge registry.example.com diff myorg/backend:v2.0.0 myorg/backend:v2.0.1
+    /// dredge registry.example.com diff myorg/backend:v2.0.0 mirror.local/myorg/backend:v2.0.0 --json
+    /// ```
+    #[command(arg_required_else_help = true)]
+    Diff {
+        /// Reference of the first image (e.g. `myorg/backend:v2.0.0`).
+        image_a: String,
+        /// Reference of the second image.
+        image_b: String,
+        /// Write the differences as JSON.
+        #[arg(long)]
+        json: bool,
+        /// Platform to select from multi-platform images.  Defaults to
+        /// `linux/amd64`.
+        #[arg(long, value_name = "OS/ARCH[/VARIANT]")]
+        platform: Option<Platform>,
+    },
+
+    /// List the packages installed in an image.
+    ///
+    /// Reads the package databases in the filesystem of the image, without
+    /// starting a container, and prints the name, version, architecture,
+    /// and source package of each.  Supports dpkg (`status` and distroless
+    /// `status.d`), apk (`installed`), and RPM (sqlite and ndb) databases.
+    ///
+    /// **Examples:**
+    /// ```text
+    /// dredge registry.example.com packages myorg/backend:v2.0.0
+    /// dredge registry.example.com packages myorg/backend:v2.0.0 --json > inventory.json
+    /// ```
+    #[command(arg_required_else_help = true)]
+    Packages {
+        /// Reference of the image (e.g. `myorg/backend:v2.0.0`).
+        image: String,
+        /// Write the packages as JSON.
+        #[arg(long)]
+        json: bool,
+        /// Platform to select from a multi-platform image.  Defaults to
+        /// `linux/amd64`.
+        #[arg(long, value_name = "OS/ARCH[/VARIANT]")]
+        platform: Option<Platform>,
+    },
+
+    /// Add a local tar file as a new layer of an image and push the result.
+    ///
+    /// The tarball is compressed and uploaded as a new top layer, and the
+    /// image configuration gains its diff ID and a history entry.  Layers
+    /// of the base image are mounted or copied into the target repository
+    /// only when missing there.  Only one platform of a multi-platform base
+    /// is used.  Prints the digest of the new manifest.
+    ///
+    /// **Examples:**
+    /// ```text
+    /// dredge registry.example.com append vendor/nginx:1.25 ca-certs.tar --tag myorg/nginx:1.25-ca
+    /// dredge registry.example.com append vendor/nginx:1.25 conf.tar --tag myorg/nginx:1.25-conf --platform linux/arm64
+    /// ```
+    #[command(arg_required_else_help = true)]
+    Append {
+        /// Reference of the image to extend (e.g. `vendor/nginx:1.25`).
+        base: String,
+        /// Uncompressed tar file to add as the top layer.
+        #[arg(value_name = "LAYER.TAR")]
+        layer: PathBuf,
+        /// Reference to push the new image to (e.g. `myorg/nginx:1.25-ca`).
+        #[arg(long, value_name = "NEW_REF")]
+        tag: String,
+        /// Platform to select from a multi-platform image.  Defaults to
+        /// `linux/amd64`.
+        #[arg(long, value_name = "OS/ARCH[/VARIANT]")]
+        platform: Option<Platform>,
+        /// Maximum number of layers to transfer concurrently (1–64).
+        #[arg(
+            short,
+            long,
+            value_name = "N",
+            default_value_t = 4,
+            value_parser = clap::value_parser!(u16).range(1..=64)
+        )]
+        jobs: u16,
+    },
+
+    /// Change the configuration of an image and push it under a new tag.
+    ///
+    /// Rewrites the labels, environment, entrypoint, command, user, or
+    /// working directory of the image configuration and pushes a manifest
+    /// that reuses every existing layer, so no layer is downloaded or
+    /// rebuilt.  Configuration fields that are not changed are kept as
+    /// they are.  Only one platform of a multi-platform image is used.
+    /// Prints the digest of the new manifest.
+    ///
+    /// **Examples:**
+    /// ```text
+    /// dredge registry.example.com mutate vendor/nginx:1.25 --label org.example.owner=web --tag myorg/nginx:1.25
+    /// dredge registry.example.com mutate myorg/app:v1 --env LOG_LEVEL=debug --user 1000:1000 --tag myorg/app:v1-debug
+    /// dredge registry.example.com mutate myorg/app:v1 --entrypoint '["/app", "--serve"]' --cmd '' --tag myorg/app:v1-serve
+    /// ```
+    #[command(arg_required_else_help = true)]
+    Mutate {
+        /// Reference of the image to change (e.g. `vendor/nginx:1.25`).
+        image: String,
+        /// Add or replace a label.  May be repeated.
+        #[arg(long, value_name = "KEY=VALUE")]
+        label: Vec<KeyValue>,
+        /// Add or replace an environment variable.  May be repeated.
+        #[arg(long, value_name = "NAME=VALUE")]
+        env: Vec<KeyValue>,
+        /// Replace the entrypoint, as a JSON array or as words separated by
+        /// spaces.  An empty value clears it.
+        #[arg(long, value_name = "COMMAND")]
+        entrypoint: Option<Argv>,
+        /// Replace the default arguments, as a JSON array or as words
+        /// separated by spaces.  An empty value clears them.
+        #[arg(long, value_name = "COMMAND")]
+        cmd: Option<Argv>,
+        /// Replace the user, and optionally group, the process runs as.
+        #[arg(long, value_name = "USER[:GROUP]")]
+        user: Option<String>,
+        /// Replace the working directory.
+        #[arg(long, value_name = "DIR")]
+        workdir: Option<String>,
+        /// Reference to push the new image to (e.g. `myorg/nginx:1.25`).
+        #[arg(long, value_name = "NEW_REF")]
+        tag: String,
+        /// Platform to select from a multi-platform image.  Defaults to
+        /// `linux/amd64`.
+        #[arg(long, value_name = "OS/ARCH[/VARIANT]")]
+        platform: Option<Platform>,
+        /// Maximum number of layers to transfer concurrently (1–64).
+        #[arg(
+            short,
+            long,
+            value_name = "N",
+            default_value_t = 4,
+            value_parser = clap::value_parser!(u16).range(1..=64)
+        )]
+        jobs: u16,
+    },
+
+    /// Merge all layers of an image into one and push the result.
+    ///
+    /// Whiteouts are applied, so files that one layer added and a later
+    /// layer deleted, such as build secrets, are not in the result.  The
+    /// history of the image is replaced by a single entry; the container
+    /// defaults are kept.  Only one platform of a multi-platform image is
+    /// used.  Prints the digest of the new manifest.
+    ///
+    /// **Examples:**
+    /// ```text
+    /// dredge registry.example.com flatten myorg/tools:v3 --tag myorg/tools:v3-flat
+    /// ```
+    #[command(arg_required_else_help = true)]
+    Flatten {
+        /// Reference of the image to flatten (e.g. `myorg/tools:v3`).
+        image: String,
+        /// Reference to push the flattened image to (e.g.
+        /// `myorg/tools:v3-flat`).
+        #[arg(long, value_name = "NEW_REF")]
+        tag: String,
+        /// Platform to select from a multi-platform image.  Defaults to
+        /// `linux/amd64`.
+        #[arg(long, value_name = "OS/ARCH[/VARIANT]")]
+        platform: Option<Platform>,
+        /// Maximum number of layers to transfer concurrently (1–64).
+        #[arg(
+            short,
+            long,
+            value_name = "N",
+            default_value_t = 4,
+            value_parser = clap::value_parser!(u16).range(1..=64)
+        )]
+        jobs: u16,
+    },
+
+    /// Move an image onto a new base image and push the result.
+    ///
+    /// Checks that the image was built on the old base, replaces the old
+    /// base layers with those of the new base, and merges the
+    /// configuration: settings the image inherited from the old base follow
+    /// the new base, settings the image made itself are kept.  The layers
+    /// the image added are reused without being downloaded.  Only one
+    /// platform of multi-platform images is used.  Prints the digest of the
+    /// new manifest.
+    ///
+    /// **Examples:**
+    /// ```text
+    /// dredge registry.example.com rebase myorg/app:v1 --old-base library/debian:12.4 --new-base library/debian:12.5 --tag myorg/app:v1-patched
+    /// ```
+    #[command(arg_required_else_help = true)]
+    Rebase {
+        /// Reference of the image to rebase (e.g. `myorg/app:v1`).
         image: String,
-        /// The tag to delete (e.g. `v1.0.0`).
+        /// Reference of the base the image was built on.
+        #[arg(long, value_name = "REF")]
+        old_base: String,
+        /// Reference of the base to move the image to.
+        #[arg(long, value_name = "REF")]
+        new_base: String,
+        /// Reference to push the rebased image to (e.g.
+        /// `myorg/app:v1-patched`).
+        #[arg(long, value_name = "NEW_REF")]
+        tag: String,
+        /// Platform to select from multi-platform images.  Defaults to
+        /// `linux/amd64`.
+        #[arg(long, value_name = "OS/ARCH[/VARIANT]")]
+        platform: Option<Platform>,
+        /// Maximum number of layers to transfer concurrently (1–64).
+        #[arg(
+            short,
+            long,
+            value_name = "N",
+            default_value_t = 4,
+            value_parser = clap::value_parser!(u16).range(1..=64)
+        )]
+        jobs: u16,
+    },
+
+    /// Delete the tags a retention policy does not keep.
+    ///
+    /// The YAML policy holds rules for repository patterns; each repository
+    /// in the catalog follows the first rule matching it.  A rule keeps tags
+    /// matching given patterns, the highest release of each major or minor
+    /// version, images newer than a given age, and the newest images, and
+    /// deletes the rest.  The plan is always printed first; with
+    /// `--dry-run`, nothing is deleted.  Otherwise the deletes must be
+    /// confirmed at the terminal, or `--yes` given.
+    ///
+    /// **Examples:**
+    /// ```text
+    /// dredge registry.example.com prune --policy retention.yaml --dry-run
+    /// dredge registry.example.com prune --policy retention.yaml --cascade
+    /// dredge registry.example.com prune --policy retention.yaml --yes
+    /// ```
+    #[command(arg_required_else_help = true)]
+    Prune {
+        /// Path of the YAML retention policy.
+        #[arg(long, value_name = "FILE")]
+        policy: PathBuf,
+        /// Print the plan, then exit without deleting anything.
+        #[arg(long)]
+        dry_run: bool,
+        /// Delete without asking for confirmation, as required when
+        /// standard input is not a terminal.
+        #[arg(short, long)]
+        yes: bool,
+        /// Also delete the platform manifests of deleted indexes that no
+        /// remaining tag refers to.
+        #[arg(long)]
+        cascade: bool,
+        /// Maximum number of requests in flight (1–64).
+        #[arg(
+            short,
+            long,
+            value_name = "N",
+            default_value_t = 8,
+            value_parser = clap::value_parser!(u16).range(1..=64)
+        )]
+        jobs: u16,
+    },
+
+    /// List the tags that point at the same manifest as a tag or digest.
+    ///
+    /// Every tag of the repository is resolved to its digest, so this shows
+    /// what else a delete would remove.  With `--children`, tags of indexes
+    /// that contain the manifest, and tags of the platforms of an index,
+    /// are listed too.
+    ///
+    /// **Examples:**
+    /// ```text
+    /// dredge registry.example.com aliases myorg/backend v2.0.1
+    /// dredge registry.example.com aliases myorg/backend sha256:0259571889ac87efbf... --children
+    /// ```
+    #[command(arg_required_else_help = true)]
+    Aliases {
+        /// The repository to search (e.g. `myorg/backend`).
+        name: String,
+        /// Tag or `sha256:` digest of the manifest to look for.
+        reference: String,
+        /// Also list tags related through the children of an index.
+        #[arg(long)]
+        children: bool,
+        /// Maximum number of tags to resolve concurrently (1–64).
+        #[arg(
+            short,
+            long,
+            value_name = "N",
+            default_value_t = 8,
+            value_parser = clap::value_parser!(u16).range(1..=64)
+        )]
+        jobs: u16,
+    },
+
+    /// Move every tag of a repository to a new repository name.
+    ///
+    /// The registry API has no rename, so every tag is copied as with
+    /// `sync`, mounting blobs within one registry, and then resolved again
+    /// at the destination to check that it points at the same digest.  With
+    /// `--delete`, the source tags are deleted only after every tag has been
+    /// verified.
+    ///
+    /// **Examples:**
+    /// ```text
+    /// dredge registry.example.com move team-a/backend platform/backend --dry-run
+    /// dredge registry.example.com move team-a/backend platform/backend --delete
+    /// ```
+    #[command(arg_required_else_help = true)]
+    Move {
+        /// Repository to move (e.g. `team-a/backend`).
+        source: String,
+        /// New repository name (e.g. `platform/backend`).
+        destination: String,
+        /// Delete the source tags once the copy has been verified.
+        #[arg(long)]
+        delete: bool,
+        /// Print the tags that would be moved, then exit.
+        #[arg(long)]
+        dry_run: bool,
+        /// Maximum number of blobs to transfer concurrently (1–64).
+        #[arg(
+            short,
+            long,
+            value_name = "N",
+            default_value_t = 4,
+            value_parser = clap::value_parser!(u16).range(1..=64)
+        )]
+        jobs: u16,
+    },
+
+    /// Add a tag to an existing manifest, without transferring any blob.
+    ///
+    /// The manifest is fetched with its exact bytes and media type and
+    /// uploaded under the new tag in the same repository, so its digest is
+    /// unchanged.  A multi-platform index is tagged as a whole.  Prints the
+    /// digest.
+    ///
+    /// **Examples:**
+    /// ```text
+    /// dredge registry.example.com tag myorg/backend:rc stable
+    /// dredge registry.example.com tag myorg/backend@sha256:0259571889ac87efbf... v2.0.1
+    /// ```
+    #[command(arg_required_else_help = true)]
+    Tag {
+        /// Reference of the manifest to tag (e.g. `myorg/backend:rc`).
+        image: String,
+        /// The new tag (e.g. `stable`).
         tag: String,
     },
 
@@ -251,7 +1066,106 @@ mod tests {
         let cli = Cli::parse_from(args);
 
         assert_eq!(cli.registry, String::from("registry.local"));
-        assert_eq!(cli.command, Commands::Catalog);
+        assert_eq!(
+            cli.command,
+            Commands::Catalog {
+                include: vec![],
+                exclude: vec![],
+                namespace: None,
+                count: false,
+            }
+        );
+    }
+
+    /// Test that given the "catalog" command with filters, the glob and
+    /// regular expression patterns, namespace, and count are received.
+    #[test]
+    fn test_catalog_command_filters() {
+        let args = vec![
+            "dredge",
+            "registry.local",
+            "catalog",
+            "--match",
+            "*-api",
+            "--exclude",
+            "/-(test|tmp)$/",
+            "--namespace",
+            "myorg",
+            "--count",
+        ];
+        let cli = Cli::parse_from(args);
+
+        assert_eq!(
+            cli.command,
+            Commands::Catalog {
+                include: vec!["*-api".parse().unwrap()],
+                exclude: vec!["/-(test|tmp)$/".parse().unwrap()],
+                namespace: Some(String::from("myorg")),
+                count: true,
+            }
+        );
+    }
+
+    /// Test that the "tags" command accepts a sort order, a version
+    /// constraint, and `--latest`.
+    #[test]
+    fn test_tags_command_semver() {
+        let args = vec![
+            "dredge",
+            "registry.local",
+            "tags",
+            "foo",
+            "--sort",
+            "semver",
+            "--semver",
+            ">=1.4, <2",
+            "--latest",
+        ];
+        let cli = Cli::parse_from(args);
+
+        assert_eq!(
+            cli.command,
+            Commands::Tags {
+                name: String::from("foo"),
+                include: vec![],
+                exclude: vec![],
+                semver: Some(">=1.4, <2".parse().unwrap()),
+                sort: Some(SortOrder::Semver),
+                latest: true,
+                long: false,
+                count: false,
+                jobs: 8,
+            }
+        );
+
+        let args = vec!["dredge", "registry.local", "tags", "foo", "--semver", "~>1"];
+        assert!(Cli::try_parse_from(args).is_err());
+    }
+
+    /// Test that `-l` selects the long listing and cannot be combined with
+    /// `--count`.
+    #[test]
+    fn test_tags_command_long() {
+        let args = vec!["dredge", "registry.local", "tags", "foo", "-l", "-j", "16"];
+        let cli = Cli::parse_from(args);
+        assert!(matches!(
+            cli.command,
+            Commands::Tags {
+                long: true,
+                jobs: 16,
+                ..
+            }
+        ));
+
+        let args = vec!["dredge", "registry.local", "tags", "foo", "-l", "--count"];
+        assert!(Cli::try_parse_from(args).is_err());
+    }
+
+    /// Test that an invalid regular expression is rejected at parse time.
+    #[test]
+    fn test_tags_command_invalid_pattern() {
+        let args = vec!["dredge", "registry.local", "tags", "foo", "--match", "/(/"];
+        assert!(Cli::try_parse_from(args).is_err());
     }
 
     /// Test that given the <REGISTRY> argument and the "tags" command with a
@@ -265,7 +1179,15 @@ mod tests {
         assert_eq!(
             cli.command,
             Commands::Tags {
-                name: String::from("foobar")
+                name: String::from("foobar"),
+                include: vec![],
+                exclude: vec![],
+                semver: None,
+                sort: None,
+                latest: false,
+                long: false,
+                count: false,
+                jobs: 8,
             }
         );
     }
@@ -316,7 +1238,679 @@ mod tests {
             cli.command,
             Commands::Delete {
                 image: String::from("foo"),
-                tag: String::from("bar"),
+                tags: vec![String::from("bar")],
+                include: Vec::new(),
+                from_file: None,
+                tag_only: false,
+                force: false,
+                cascade: false,
+                jobs: 8,
+            }
+        );
+    }
+
+    /// Test that "delete" accepts several tags, patterns, and a file, or a
+    /// digest with `--cascade`.
+    #[test]
+    fn test_delete_command_bulk() {
+        let args = vec![
+            "dredge",
+            "registry.local",
+            "delete",
+            "foo",
+            "a",
+            "b",
+            "--match",
+            "pr-*",
+            "--from-file",
+            "-",
+        ];
+        let cli = Cli::parse_from(args);
+
+        assert_eq!(
+            cli.command,
+            Commands::Delete {
+                image: String::from("foo"),
+                tags: vec![String::from("a"), String::from("b")],
+                include: vec!["pr-*".parse().unwrap()],
+                from_file: Some(PathBuf::from("-")),
+                tag_only: false,
+                force: false,
+                cascade: false,
+                jobs: 8,
+            }
+        );
+
+        let args = vec![
+            "dredge",
+            "registry.local",
+            "delete",
+            "foo@sha256:0259571889ac87efbfca5b79a0abe9baf626d058ec5f9a5744bace2229d9ed50",
+            "--cascade",
+        ];
+        let cli = Cli::parse_from(args);
+        assert!(matches!(
+            cli.command,
+            Commands::Delete { tags, cascade: true, .. } if tags.is_empty()
+        ));
+    }
+
+    /// Test that `--force` is accepted with `--tag-only` and rejected
+    /// without it.
+    #[test]
+    fn test_delete_command_tag_only() {
+        let args = vec![
+            "dredge",
+            "registry.local",
+            "delete",
+            "foo",
+            "bar",
+            "--tag-only",
+            "--force",
+        ];
+        let cli = Cli::parse_from(args);
+
+        assert_eq!(
+            cli.command,
+            Commands::Delete {
+                image: String::from("foo"),
+                tags: vec![String::from("bar")],
+                include: Vec::new(),
+                from_file: None,
+                tag_only: true,
+                force: true,
+                cascade: false,
+                jobs: 8,
+            }
+        );
+
+        let args = vec![
+            "dredge",
+            "registry.local",
+            "delete",
+            "foo",
+            "bar",
+            "--force",
+        ];
+        assert!(Cli::try_parse_from(args).is_err());
+    }
+
+    /// Test that given the <REGISTRY> argument and the "pull" command with an
+    /// image, tag, output directory, and parallelism, the expected values are
+    /// received.
+    #[test]
+    fn test_pull_command() {
+        let args = vec![
+            "dredge",
+            "registry.local",
+            "pull",
+            "foo",
+            "bar",
+            "--output",
+            "out",
+            "--parallel",
+            "4",
+            "-j",
+            "8",
+        ];
+        let cli = Cli::parse_from(args);
+
+        assert_eq!(
+            cli.command,
+            Commands::Pull {
+                image: String::from("foo"),
+                tag: Some(String::from("bar")),
+                output: PathBuf::from("out"),
+                parallel: 4,
+                jobs: 8,
+            }
+        );
+    }
+
+    /// Test that the "pull" command rejects a parallelism of zero.
+    #[test]
+    fn test_pull_command_zero_parallel() {
+        let args = vec![
+            "dredge",
+            "registry.local",
+            "pull",
+            "foo",
+            "-o",
+            "out",
+            "--parallel",
+            "0",
+        ];
+        assert!(Cli::try_parse_from(args).is_err());
+    }
+
+    /// Test that given the <REGISTRY> argument and the "copy" command with
+    /// source and destination references, credentials, and `--dry-run`, the
+    /// expected values are received.
+    #[test]
+    fn test_copy_command() {
+        let args = vec![
+            "dredge",
+            "registry.local",
+            "copy",
+            "foo:v1",
+            "prod.local/foo",
+            "--src-creds",
+            "alice:secret",
+            "--dry-run",
+        ];
+        let cli = Cli::parse_from(args);
+
+        assert_eq!(
+            cli.command,
+            Commands::Copy {
+                source: String::from("foo:v1"),
+                destination: String::from("prod.local/foo"),
+                src_creds: Some("alice:secret".parse().unwrap()),
+                dest_creds: None,
+                dry_run: true,
+                jobs: 4,
+            }
+        );
+    }
+
+    /// Test that malformed credentials are rejected at parse time.
+    #[test]
+    fn test_copy_command_invalid_credentials() {
+        let args = vec![
+            "dredge",
+            "registry.local",
+            "copy",
+            "foo",
+            "bar",
+            "--dest-creds",
+            "no-colon",
+        ];
+        assert!(Cli::try_parse_from(args).is_err());
+    }
+
+    /// Test that given the <REGISTRY> argument and the "sync" command with
+    /// repeated patterns and `--prune`, the expected values are received.
+    #[test]
+    fn test_sync_command() {
+        let args = vec![
+            "dredge",
+            "registry.local",
+            "sync",
+            "upstream.local/foo",
+            "foo",
+            "--include",
+            "v1.*",
+            "--include",
+            "v2.*",
+            "--exclude",
+            "*-rc*",
+            "--prune",
+            "--jobs",
+            "2",
+        ];
+        let cli = Cli::parse_from(args);
+
+        assert_eq!(
+            cli.command,
+            Commands::Sync {
+                source: String::from("upstream.local/foo"),
+                destination: String::from("foo"),
+                include: vec!["v1.*".parse().unwrap(), "v2.*".parse().unwrap()],
+                exclude: vec!["*-rc*".parse().unwrap()],
+                prune: true,
+                src_creds: None,
+                dest_creds: None,
+                dry_run: false,
+                jobs: 2,
+            }
+        );
+    }
+
+    /// Test that given the <REGISTRY> argument and the "layer" command with
+    /// an image, a layer, and a platform, the expected values are received.
+    #[test]
+    fn test_layer_command() {
+        let args = vec![
+            "dredge",
+            "registry.local",
+            "layer",
+            "foo:v1",
+            "0",
+            "--platform",
+            "linux/arm64",
+        ];
+        let cli = Cli::parse_from(args);
+
+        assert_eq!(
+            cli.command,
+            Commands::Layer {
+                image: String::from("foo:v1"),
+                layer: String::from("0"),
+                platform: Some("linux/arm64".parse().unwrap()),
+            }
+        );
+    }
+
+    /// Test that given the <REGISTRY> argument and the "ls" command with an
+    /// image, a path, and combined short flags, the expected values are
+    /// received.
+    #[test]
+    fn test_ls_command() {
+        let args = vec!["dredge", "registry.local", "ls", "foo:v1", "/etc", "-lR"];
+        let cli = Cli::parse_from(args);
+
+        assert_eq!(
+            cli.command,
+            Commands::Ls {
+                image: String::from("foo:v1"),
+                path: Some(String::from("/etc")),
+                long: true,
+                recursive: true,
+                platform: None,
+            }
+        );
+    }
+
+    /// Test that given the <REGISTRY> argument and the "cat" command with an
+    /// image and a path, the expected values are received.
+    #[test]
+    fn test_cat_command() {
+        let args = vec![
+            "dredge",
+            "registry.local",
+            "cat",
+            "foo:v1",
+            "/etc/os-release",
+        ];
+        let cli = Cli::parse_from(args);
+
+        assert_eq!(
+            cli.command,
+            Commands::Cat {
+                image: String::from("foo:v1"),
+                path: String::from("/etc/os-release"),
+                platform: None,
+            }
+        );
+    }
+
+    /// Test that given the <REGISTRY> argument and the "extract" command
+    /// with an image, a path, and a target directory, the expected values
+    /// are received.
+    #[test]
+    fn test_extract_command() {
+        let args = vec![
+            "dredge",
+            "registry.local",
+            "extract",
+            "foo:v1",
+            "/usr/bin/tool",
+            "--to",
+            "./bin",
+        ];
+        let cli = Cli::parse_from(args);
+
+        assert_eq!(
+            cli.command,
+            Commands::Extract {
+                image: String::from("foo:v1"),
+                path: String::from("/usr/bin/tool"),
+                to: PathBuf::from("./bin"),
+                platform: None,
+            }
+        );
+    }
+
+    /// Test that given the <REGISTRY> argument and the "export" command with
+    /// an image, an output file, and a platform, the expected values are
+    /// received.
+    #[test]
+    fn test_export_command() {
+        let args = vec![
+            "dredge",
+            "registry.local",
+            "export",
+            "foo:v1",
+            "-o",
+            "rootfs.tar",
+            "--platform",
+            "linux/arm64",
+        ];
+        let cli = Cli::parse_from(args);
+
+        assert_eq!(
+            cli.command,
+            Commands::Export {
+                image: String::from("foo:v1"),
+                output: PathBuf::from("rootfs.tar"),
+                platform: Some("linux/arm64".parse().unwrap()),
+            }
+        );
+    }
+
+    /// Test that given the <REGISTRY> argument and the "analyze" command with
+    /// a top count and a minimum efficiency, the expected values are
+    /// received, and that percentages outside 0–100 are rejected.
+    #[test]
+    fn test_analyze_command() {
+        let args = vec![
+            "dredge",
+            "registry.local",
+            "analyze",
+            "foo:v1",
+            "--top",
+            "3",
+            "--min-efficiency",
+            "92",
+        ];
+        let cli = Cli::parse_from(args);
+
+        assert_eq!(
+            cli.command,
+            Commands::Analyze {
+                image: String::from("foo:v1"),
+                top: 3,
+                min_efficiency: Some(92),
+                platform: None,
+            }
+        );
+
+        let args = vec![
+            "dredge",
+            "registry.local",
+            "analyze",
+            "foo:v1",
+            "--min-efficiency",
+            "120",
+        ];
+        assert!(Cli::try_parse_from(args).is_err());
+    }
+
+    /// Test that given the <REGISTRY> argument and the "diff" command with two
+    /// images and `--json`, the expected values are received.
+    #[test]
+    fn test_diff_command() {
+        let args = vec![
+            "dredge",
+            "registry.local",
+            "diff",
+            "foo:v1",
+            "foo:v2",
+            "--json",
+        ];
+        let cli = Cli::parse_from(args);
+
+        assert_eq!(
+            cli.command,
+            Commands::Diff {
+                image_a: String::from("foo:v1"),
+                image_b: String::from("foo:v2"),
+                json: true,
+                platform: None,
+            }
+        );
+    }
+
+    /// Test that given the <REGISTRY> argument and the "packages" command
+    /// with an image and a platform, the expected values are received.
+    #[test]
+    fn test_packages_command() {
+        let args = vec![
+            "dredge",
+            "registry.local",
+            "packages",
+            "foo:v1",
+            "--platform",
+            "linux/arm64",
+        ];
+        let cli = Cli::parse_from(args);
+
+        assert_eq!(
+            cli.command,
+            Commands::Packages {
+                image: String::from("foo:v1"),
+                json: false,
+                platform: Some("linux/arm64".parse().unwrap()),
+            }
+        );
+    }
+
+    /// Test that given the <REGISTRY> argument and the "append" command with
+    /// a base image, a tarball, a tag, and `--jobs`, the expected values are
+    /// received.
+    #[test]
+    fn test_append_command() {
+        let args = vec![
+            "dredge",
+            "registry.local",
+            "append",
+            "foo:v1",
+            "certs.tar",
+            "--tag",
+            "bar:v2",
+            "--jobs",
+            "2",
+        ];
+        let cli = Cli::parse_from(args);
+
+        assert_eq!(
+            cli.command,
+            Commands::Append {
+                base: String::from("foo:v1"),
+                layer: PathBuf::from("certs.tar"),
+                tag: String::from("bar:v2"),
+                platform: None,
+                jobs: 2,
+            }
+        );
+    }
+
+    /// Test that given the <REGISTRY> argument and the "mutate" command with
+    /// repeated labels and a JSON entrypoint, the expected values are
+    /// received.
+    #[test]
+    fn test_mutate_command() {
+        let args = vec![
+            "dredge",
+            "registry.local",
+            "mutate",
+            "foo:v1",
+            "--label",
+            "a=1",
+            "--label",
+            "b=2",
+            "--entrypoint",
+            r#"["/app", "--serve"]"#,
+            "--tag",
+            "foo:v2",
+        ];
+        let cli = Cli::parse_from(args);
+
+        assert_eq!(
+            cli.command,
+            Commands::Mutate {
+                image: String::from("foo:v1"),
+                label: vec!["a=1".parse().unwrap(), "b=2".parse().unwrap()],
+                env: vec![],
+                entrypoint: Some(Argv(vec![String::from("/app"), String::from("--serve")])),
+                cmd: None,
+                user: None,
+                workdir: None,
+                tag: String::from("foo:v2"),
+                platform: None,
+                jobs: 4,
+            }
+        );
+    }
+
+    /// Test that given the <REGISTRY> argument and the "flatten" command
+    /// with an image, a tag, and a platform, the expected values are
+    /// received.
+    #[test]
+    fn test_flatten_command() {
+        let args = vec![
+            "dredge",
+            "registry.local",
+            "flatten",
+            "foo:v1",
+            "--tag",
+            "foo:flat",
+            "--platform",
+            "linux/arm64",
+        ];
+        let cli = Cli::parse_from(args);
+
+        assert_eq!(
+            cli.command,
+            Commands::Flatten {
+                image: String::from("foo:v1"),
+                tag: String::from("foo:flat"),
+                platform: Some("linux/arm64".parse().unwrap()),
+                jobs: 4,
+            }
+        );
+    }
+
+    /// Test that given the <REGISTRY> argument and the "rebase" command with
+    /// both bases and a tag, the expected values are received.
+    #[test]
+    fn test_rebase_command() {
+        let args = vec![
+            "dredge",
+            "registry.local",
+            "rebase",
+            "app:v1",
+            "--old-base",
+            "debian:12.4",
+            "--new-base",
+            "debian:12.5",
+            "--tag",
+            "app:v1-patched",
+        ];
+        let cli = Cli::parse_from(args);
+
+        assert_eq!(
+            cli.command,
+            Commands::Rebase {
+                image: String::from("app:v1"),
+                old_base: String::from("debian:12.4"),
+                new_base: String::from("debian:12.5"),
+                tag: String::from("app:v1-patched"),
+                platform: None,
+                jobs: 4,
+            }
+        );
+    }
+
+    /// Test that given the <REGISTRY> argument and the "prune" command with a
+    /// policy file and `--dry-run`, the expected values are received.
+    #[test]
+    fn test_prune_command() {
+        let args = vec![
+            "dredge",
+            "registry.local",
+            "prune",
+            "--policy",
+            "retention.yaml",
+            "--dry-run",
+        ];
+        let cli = Cli::parse_from(args);
+
+        assert_eq!(
+            cli.command,
+            Commands::Prune {
+                policy: PathBuf::from("retention.yaml"),
+                dry_run: true,
+                yes: false,
+                cascade: false,
+                jobs: 8,
+            }
+        );
+    }
+
+    /// Test that given the <REGISTRY> argument and the "prune" command with
+    /// `-y`, the deletes need no confirmation.
+    #[test]
+    fn test_prune_command_yes() {
+        let args = vec![
+            "dredge",
+            "registry.local",
+            "prune",
+            "--policy",
+            "retention.yaml",
+            "-y",
+        ];
+        let cli = Cli::parse_from(args);
+
+        assert_eq!(
+            cli.command,
+            Commands::Prune {
+                policy: PathBuf::from("retention.yaml"),
+                dry_run: false,
+                yes: true,
+                cascade: false,
+                jobs: 8,
+            }
+        );
+    }
+
+    /// Test that given the <REGISTRY> argument and the "aliases" command
+    /// with a repository, a tag, and `--children`, the expected values are
+    /// received.
+    #[test]
+    fn test_aliases_command() {
+        let args = vec![
+            "dredge",
+            "registry.local",
+            "aliases",
+            "foo",
+            "v1",
+            "--children",
+        ];
+        let cli = Cli::parse_from(args);
+
+        assert_eq!(
+            cli.command,
+            Commands::Aliases {
+                name: String::from("foo"),
+                reference: String::from("v1"),
+                children: true,
+                jobs: 8,
+            }
+        );
+    }
+
+    /// Test that given the <REGISTRY> argument and the "move" command with
+    /// two repositories and `--delete`, the expected values are received.
+    #[test]
+    fn test_move_command() {
+        let args = vec!["dredge", "registry.local", "move", "old", "new", "--delete"];
+        let cli = Cli::parse_from(args);
+
+        assert_eq!(
+            cli.command,
+            Commands::Move {
+                source: String::from("old"),
+                destination: String::from("new"),
+                delete: true,
+                dry_run: false,
+                jobs: 4,
+            }
+        );
+    }
+
+    /// Test that given the <REGISTRY> argument and the "tag" command with an
+    /// image and a new tag, the expected values are received.
+    #[test]
+    fn test_tag_command() {
+        let args = vec!["dredge", "registry.local", "tag", "foo:rc", "stable"];
+        let cli = Cli::parse_from(args);
+
+        assert_eq!(
+            cli.command,
+            Commands::Tag {
+                image: String::from("foo:rc"),
+                tag: String::from("stable"),
             }
         );
     }
@@ -7,14 +7,54 @@
  * copied, modified, or distributed except according to those terms.
  */
 
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::io::BufRead;
+use std::io::IsTerminal;
 use std::io::Write;
+use std::path::Path;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
 use serde::Deserialize;
 use serde::Serialize;
 use url::Url;
 
+use crate::analyze;
 use crate::api;
+use crate::api::Credentials;
+use crate::blob;
+use crate::copy;
+use crate::copy::Endpoint;
+use crate::delete;
+use crate::delete::DeleteOptions;
+use crate::diff;
+use crate::digest;
 use crate::error::ApiError;
+use crate::image;
+use crate::layer;
+use crate::layout::OciLayout;
+use crate::manifest::Descriptor;
+use crate::manifest::History;
+use crate::manifest::Manifest;
+use crate::manifest::Platform;
+use crate::mutate::Mutation;
+use crate::packages;
+use crate::pattern::Filter;
+use crate::pattern::Pattern;
+use crate::progress;
+use crate::progress::Progress;
+use crate::push;
+use crate::rebase;
+use crate::reference::ImageRef;
+use crate::reference::Reference;
+use crate::retention::Policy;
+use crate::retention::PrunePlan;
+use crate::rootfs;
+use crate::sync;
+use crate::tags;
+use crate::tags::Query;
+use crate::tags::SortOrder;
 
 /// Deserialized body of a `/v2/_catalog` response page.
 #[derive(Deserialize)]
@@ -22,12 +62,6 @@ struct CatalogResponse {
     repositories: Vec<String>,
 }
 
-/// Deserialized body of a `/v2/<name>/tags/list` response page.
-#[derive(Deserialize)]
-struct TagsResponse {
-    tags: Vec<String>,
-}
-
 /// A single filesystem layer entry within a V1 image manifest.
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -56,15 +90,24 @@ struct ManifestResponse {
     etag: String,
 }
 
-/// Fetch all repository names from the registry catalog and write them to `buf`.
+/// Fetch the repository names from the registry catalog and write the
+/// selected ones to `buf`.
 ///
-/// Queries `/v2/_catalog` via [`api::fetch_paginated`], collects all pages,
-/// and writes one repository name per line to the provided writer.
+/// Queries `/v2/_catalog` via [`api::for_each_page`] and filters each page
+/// as it arrives, so output starts before the last page is fetched.  A
+/// repository is selected when it lies under `namespace`, if given, and is
+/// selected by `filter`.  One repository name is written per line, or with
+/// `count`, only the number of selected repositories.
 ///
 /// # Arguments
 ///
 /// * `buf` — Output sink (typically stdout or a test buffer).
 /// * `registry_url` — Base URL of the Docker Registry.
+/// * `filter` — Match and exclude patterns selecting repositories.
+/// * `namespace` — Only select repositories under this namespace
+///   (e.g. `"myorg"` selects `"myorg/backend"`).
+/// * `count` — Write the number of selected repositories instead of their
+///   names.
 ///
 /// # Errors
 ///
@@ -81,24 +124,62 @@ struct ManifestResponse {
 /// * [`ApiError::NotFound`] — the catalog endpoint does not exist.
 /// * [`ApiError::MethodNotAllowed`] — the registry rejected the request method.
 /// * [`ApiError::IOError`] — writing a repository name to `buf` failed.
-pub async fn catalog_handler(buf: &mut dyn Write, registry_url: &Url) -> Result<(), ApiError> {
-    log::trace!("catalog_handler(registry_url: {registry_url:?})");
+pub async fn catalog_handler(
+    buf: &mut dyn Write,
+    registry_url: &Url,
+    filter: &Filter,
+    namespace: Option<&str>,
+    count: bool,
+) -> Result<(), ApiError> {
+    log::trace!(
+        "catalog_handler(registry_url: {registry_url:?}, namespace: {namespace:?}, count: {count})"
+    );
 
     let client = api::build_client()?;
-    let responses: Vec<CatalogResponse> =
-        api::fetch_paginated(&client, registry_url, "v2/_catalog").await?;
+    let prefix = namespace.map(|ns| format!("{}/", ns.trim_end_matches('/')));
+    let mut total = 0;
+    api::for_each_page(
+        &client,
+        registry_url,
+        "v2/_catalog",
+        |page: CatalogResponse| {
+            for repo in page.repositories {
+                if prefix.as_ref().is_some_and(|p| !repo.starts_with(p)) || !filter.matches(&repo) {
+                    continue;
+                }
+                total += 1;
+                if !count {
+                    writeln!(buf, "{repo}")?;
+                }
+            }
+            Ok(())
+        },
+    )
+    .await?;
 
-    for repo in responses.iter().flat_map(|r| r.repositories.iter()) {
-        writeln!(buf, "{repo}")?;
+    if count {
+        writeln!(buf, "{total}")?;
     }
-
     Ok(())
 }
 
-/// Fetch all tags for an image from the registry and write them to `buf`.
+/// Fetch the tags of an image from the registry and write the selected ones
+/// to `buf`.
+///
+/// Queries `/v2/<name>/tags/list` via [`api::for_each_tag`] and selects
+/// tags with [`Query::matches`].  Without an order or `long`, tags are
+/// written as each page arrives; otherwise they are collected and arranged
+/// with [`Query::arrange`].
 ///
-/// Queries `/v2/<name>/tags/list` via [`api::fetch_paginated`], collects all
-/// pages, and writes one tag name per line to the provided writer.
+/// With `long`, every tag written is described with [`tags::describe_all`]:
+/// its digest, size, platforms, and creation time are written as a table
+/// by [`tags::write_long`], with `-` for a tag that cannot be described.
+/// Ordering by date needs the same descriptions, so then every selected
+/// tag is described before arranging, and one that cannot be described
+/// counts as undated.  Up to
+/// `jobs` tags are described at a time.  With `count`, only the number of
+/// tags that would have been written is written, and none is described
+/// unless the order requires it.
 ///
 /// # Arguments
 ///
@@ -106,6 +187,10 @@ pub async fn catalog_handler(buf: &mut dyn Write, registry_url: &Url) -> Result<
 /// * `registry_url` — Base URL of the Docker Registry.
 /// * `name` — The repository name whose tags should be listed
 ///   (e.g. `"myorg/backend"`).
+/// * `query` — Which tags to select and how to order them.
+/// * `long` — Write what each tag points at, not only its name.
+/// * `count` — Write the number of selected tags instead of their names.
+/// * `jobs` — Maximum number of tags described concurrently.
 ///
 /// # Errors
 ///
@@ -121,22 +206,71 @@ pub async fn catalog_handler(buf: &mut dyn Write, registry_url: &Url) -> Result<
 /// * [`ApiError::AuthorizationFailed`] — the registry requires authentication.
 /// * [`ApiError::NotFound`] — the image does not exist in the registry.
 /// * [`ApiError::MethodNotAllowed`] — the registry rejected the request method.
+/// * [`ApiError::NoMatchingTag`] — only the latest tag was requested and no
+///   tag qualifies.
 /// * [`ApiError::IOError`] — writing a tag name to `buf` failed.
+/// * Any error returned by [`tags::describe_all`].
 pub async fn tags_handler(
     buf: &mut dyn Write,
     registry_url: &Url,
     name: &str,
+    query: &Query,
+    long: bool,
+    count: bool,
+    jobs: usize,
 ) -> Result<(), ApiError> {
-    log::trace!("tags_handler(registry_url: {registry_url:?}, name: {name})");
+    log::trace!(
+        "tags_handler(registry_url: {registry_url:?}, name: {name}, query: {query:?}, long: {long}, count: {count})"
+    );
 
     let client = api::build_client()?;
-    let responses: Vec<TagsResponse> =
-        api::fetch_paginated(&client, registry_url, &format!("/v2/{name}/tags/list")).await?;
-
-    for tag in responses.iter().flat_map(|r| r.tags.iter()) {
-        writeln!(buf, "{tag}")?;
+    let mut total = 0;
+    let mut selected = Vec::new();
+    let streaming = query.order().is_none() && !long;
+    api::for_each_tag(&client, registry_url, name, |tag| {
+        if !query.matches(&tag) {
+            return Ok(());
+        }
+        if streaming {
+            total += 1;
+            if !count {
+                writeln!(buf, "{tag}")?;
+            }
+        } else {
+            selected.push(tag);
+        }
+        Ok(())
+    })
+    .await?;
+
+    if !streaming {
+        let repository = parse_repository(name, registry_url)?;
+        let mut infos = if query.order() == Some(SortOrder::Date) {
+            tags::describe_all(&client, &repository, &selected, jobs).await?
+        } else {
+            BTreeMap::new()
+        };
+        let created = infos
+            .iter()
+            .map(|(tag, info)| (tag.clone(), info.as_ref().and_then(|i| i.created)))
+            .collect();
+        let arranged = query.arrange(selected, &created)?;
+        total = arranged.len();
+        if long && !count {
+            if infos.is_empty() {
+                infos = tags::describe_all(&client, &repository, &arranged, jobs).await?;
+            }
+            tags::write_long(&arranged, &infos, buf)?;
+        } else if !count {
+            for tag in arranged {
+                writeln!(buf, "{tag}")?;
+            }
+        }
     }
 
+    if count {
+        writeln!(buf, "{total}")?;
+    }
     Ok(())
 }
 
@@ -218,432 +352,3728 @@ pub async fn show_handler(
     Ok(())
 }
 
-/// Delete the manifest for a tagged image from the registry.
+/// Delete tagged images, or manifests by digest, from the registry.
 ///
-/// Resolves `tag` to its content digest by sending a `HEAD` request to
-/// `/v2/<image>/manifests/<tag>`, then deletes the manifest by digest via
-/// `DELETE /v2/<image>/manifests/<digest>`.
+/// The selected tags are `tags`, the tag of `image` when it names one, and,
+/// when `patterns` is not empty, every tag of the repository matching one
+/// of them.  They are deleted with [`delete::delete_tags`]: each tag is
+/// resolved to its content digest with a `HEAD` request, and each distinct
+/// manifest is deleted once via `DELETE /v2/<image>/manifests/<digest>`.
+/// Every other tag that points at a deleted manifest goes with it.  When
+/// `image` names a digest, as in `myorg/backend@sha256:...`, that manifest
+/// is deleted as well.
+///
+/// With `options.tag_only`, the tags alone are deleted with `DELETE
+/// /v2/<image>/manifests/<tag>`, as the OCI distribution specification
+/// allows since 1.1.  When the registry does not support it, a manifest is
+/// deleted by digest only when no other tag points at it, or with
+/// `options.force`.
+///
+/// With `options.cascade`, deleting an index also deletes the platform
+/// manifests it lists that no remaining tag refers to, directly or through
+/// another index.  Without it, those manifests stay behind untagged, and
+/// the registry garbage collector never reclaims them.
+///
+/// One line per tag or manifest, saying whether it was deleted, is written
+/// to `buf`, followed by a summary.  One that cannot be deleted does not
+/// stop the others.
 ///
 /// The registry must have storage deletion enabled.  Set the environment
 /// variable `REGISTRY_STORAGE_DELETE_ENABLED=true` on the registry container.
-/// If deletion is not enabled the registry returns `405 Method Not Allowed`
-/// and this function returns [`ApiError::MethodNotAllowed`].
+/// If deletion is not enabled the registry returns `405 Method Not Allowed`,
+/// which is reported for each tag.
 ///
-/// Only the manifest is removed.  Unreferenced layer blobs remain on disk
+/// Only the manifests are removed.  Unreferenced layer blobs remain on disk
 /// until the registry garbage collector is run separately.
 ///
 /// # Arguments
 ///
-/// * `_buf` — Unused output sink (reserved for future use).
+/// * `buf` — Output sink (typically stdout or a test buffer).
+/// * `registry_url` — Base URL of the Docker Registry.
+/// * `image` — The repository name (e.g. `"myorg/backend"`), optionally
+///   with a tag or digest to delete.
+/// * `tags` — Tags to delete (e.g. `"v1.0.0"`).
+/// * `patterns` — Also delete the tags matching any of these patterns.
+/// * `options` — How the tags are deleted.
+///
+/// # Errors
+///
+/// * [`ApiError::HttpError`] — the HTTP client could not be constructed.
+/// * [`ApiError::InvalidReference`] — `image` could not be parsed, or
+///   nothing was selected to delete.
+/// * [`ApiError::IOError`] — writing to `buf` failed.
+/// * [`ApiError::DeleteFailed`] — at least one tag or manifest could not be
+///   deleted.
+/// * Any error returned by [`api::list_tags`] when matching `patterns`, or
+///   by [`delete::delete_tags`].
+pub async fn delete_handler(
+    buf: &mut dyn Write,
+    registry_url: &Url,
+    image: &str,
+    tags: &[String],
+    patterns: &[Pattern],
+    options: &DeleteOptions,
+) -> Result<(), ApiError> {
+    log::trace!(
+        "delete_handler(registry_url: {registry_url:?}, image: {image}, tags: {tags:?}, patterns: {patterns:?}, options: {options:?})"
+    );
+
+    let mut repository = ImageRef::parse(image, registry_url)?;
+    let mut selected: BTreeSet<String> = tags.iter().cloned().collect();
+    let mut digests = Vec::new();
+    match repository.reference.take() {
+        Some(Reference::Tag(tag)) => {
+            selected.insert(tag);
+        }
+        Some(Reference::Digest(digest)) => digests.push(digest),
+        None => {}
+    }
+    if selected.is_empty() && patterns.is_empty() && digests.is_empty() {
+        return Err(ApiError::InvalidReference(format!(
+            "{image}: expected a tag, pattern, or digest to delete"
+        )));
+    }
+    let endpoint = Endpoint {
+        client: api::build_client()?,
+        image: repository,
+    };
+
+    if !patterns.is_empty() {
+        let all = api::list_tags(
+            &endpoint.client,
+            &endpoint.image.registry,
+            &endpoint.image.repository,
+        )
+        .await?;
+        selected.extend(
+            all.into_iter()
+                .filter(|t| patterns.iter().any(|p| p.matches(t))),
+        );
+    }
+    let selected: Vec<String> = selected.into_iter().collect();
+    log::debug!(
+        "Deleting {} tags and {} digests from {}",
+        selected.len(),
+        digests.len(),
+        endpoint.image
+    );
+
+    let report = delete::delete_tags(&endpoint, &selected, &digests, options).await?;
+    report.write_to(buf)?;
+    match report.failed() {
+        0 => Ok(()),
+        failed => Err(ApiError::DeleteFailed(format!(
+            "{failed} of {}",
+            report.outcomes.len()
+        ))),
+    }
+}
+
+/// Download a tagged image into a local OCI image layout directory.
+///
+/// Fetches the manifest at `/v2/<image>/manifests/<tag>` and stores it, its
+/// image configuration, and every layer under `output/blobs/sha256/`.  When
+/// the tag points to a multi-platform index, every platform is pulled.  The
+/// manifest is then recorded in `output/index.json` under `tag`, and its
+/// digest is written to `buf`.
+///
+/// Blobs are downloaded with [`blob::download`], so re-running an
+/// interrupted pull resumes each partially downloaded blob rather than
+/// starting over, and blobs already present in the layout are skipped.
+/// Up to `jobs` blobs are downloaded at once, with progress reported as
+/// described for [`Progress`].
+///
+/// # Arguments
+///
+/// * `buf` — Output sink (typically stdout or a test buffer).
 /// * `registry_url` — Base URL of the Docker Registry.
 /// * `image` — The repository name (e.g. `"myorg/backend"`).
-/// * `tag` — The tag to delete (e.g. `"v1.0.0"`).
+/// * `tag` — The tag to pull (e.g. `"v2.0.0"`).
+/// * `output` — Directory of the OCI image layout; created when missing.
+/// * `parallel` — Maximum number of concurrent HTTP ranges per blob.
+/// * `jobs` — Maximum number of blobs downloaded concurrently.
 ///
 /// # Errors
 ///
 /// * [`ApiError::HttpError`] — the HTTP client could not be constructed, or a
 ///   request failed at the transport layer.
-/// * [`ApiError::UrlParseError`] — a manifest URL could not be constructed.
-/// * [`ApiError::ResponseHeaderParseError`] — a response header contains
-///   non-UTF-8 bytes.
-/// * [`ApiError::UnexpectedResponse`] — the `docker-content-digest` header is
-///   absent from the `HEAD` response, or a required version header is missing.
-/// * [`ApiError::UnsupportedVersion`] — the registry version header has an
-///   unexpected value.
-/// * [`ApiError::AuthorizationFailed`] — the registry requires authentication.
-/// * [`ApiError::NotFound`] — the image or tag does not exist in the registry.
-/// * [`ApiError::MethodNotAllowed`] — the registry does not permit deletion;
-///   ensure `REGISTRY_STORAGE_DELETE_ENABLED=true` is set on the registry.
-#[allow(clippy::unused_async)]
-pub async fn delete_handler(
-    _buf: &mut dyn Write,
+/// * [`ApiError::UrlParseError`] — a manifest or blob URL could not be
+///   constructed.
+/// * [`ApiError::JsonError`] — a manifest is malformed.
+/// * [`ApiError::DigestMismatch`] — a manifest or blob does not match the
+///   digest it was referenced by.
+/// * [`ApiError::UnsupportedDigest`] — a descriptor uses a digest algorithm
+///   other than `sha256`.
+/// * [`ApiError::IOError`] — the layout could not be written.
+/// * Any variant returned by [`api::parse_response_status`], e.g.
+///   [`ApiError::NotFound`] when the image or tag does not exist.
+pub async fn pull_handler(
+    buf: &mut dyn Write,
     registry_url: &Url,
     image: &str,
     tag: &str,
+    output: &Path,
+    parallel: usize,
+    jobs: usize,
 ) -> Result<(), ApiError> {
-    log::trace!("delete_handler(registry_url: {registry_url:?}, image: {image}, tag: {tag})");
+    log::trace!(
+        "pull_handler(registry_url: {registry_url:?}, image: {image}, tag: {tag}, output: {}, jobs: {jobs})",
+        output.display()
+    );
+
+    let client = api::build_transfer_client(None)?;
+    let layout = OciLayout::create(output).await?;
 
-    let client = api::build_client()?;
     let url = registry_url.join(&format!("/v2/{image}/manifests/{tag}"))?;
-    let digest = api::get_digest(&client, &url).await?;
+    let top = api::get_manifest(&client, &url).await?;
+    layout.write_blob(&top.digest, &top.bytes).await?;
+
+    let images = match top.parse()? {
+        Manifest::Image(m) => vec![m],
+        Manifest::Index(index) => {
+            let mut images = Vec::with_capacity(index.manifests.len());
+            for child in &index.manifests {
+                let url = registry_url.join(&format!("/v2/{image}/manifests/{}", child.digest))?;
+                let raw = api::get_manifest(&client, &url).await?;
+                digest::verify(&child.digest, &raw.digest)?;
+                layout.write_blob(&raw.digest, &raw.bytes).await?;
+                match raw.parse()? {
+                    Manifest::Image(m) => images.push(m),
+                    Manifest::Index(_) => log::warn!("Skipping nested index {}", raw.digest),
+                }
+            }
+            images
+        }
+    };
 
-    log::debug!("Deleting digest {digest}");
-    let url = registry_url.join(&format!("/v2/{image}/manifests/{digest}"))?;
-    let resp = client.delete(url).send().await?;
-    api::parse_response_status(&resp)?;
+    let mut blobs: Vec<&Descriptor> = Vec::new();
+    for desc in images
+        .iter()
+        .flat_map(|m| std::iter::once(&m.config).chain(&m.layers))
+    {
+        if !blobs.iter().any(|b| b.digest == desc.digest) {
+            blobs.push(desc);
+        }
+    }
+
+    let progress = Progress::new(blobs.len(), blobs.iter().map(|d| d.size).sum());
+    let mut tasks = Vec::with_capacity(blobs.len());
+    for desc in blobs {
+        let url = registry_url.join(&format!("/v2/{image}/blobs/{}", desc.digest))?;
+        let dest = layout.blob_path(&desc.digest)?;
+        let (client, progress) = (client.clone(), progress.clone());
+        let (digest, size) = (desc.digest.clone(), desc.size);
+        tasks.push(async move {
+            let bar = progress.start(&digest, size);
+            blob::download(&client, &url, &dest, &digest, parallel, &bar).await?;
+            bar.finish();
+            Ok(())
+        });
+    }
+    let result = blob::run_bounded(jobs, tasks).await;
+    progress.clear();
+    result?;
 
+    layout.tag(top.descriptor()?, tag).await?;
+    writeln!(buf, "{}", top.digest)?;
     Ok(())
 }
 
-/// Verify that the registry endpoint implements Docker Distribution API v2.
+/// Copy an image, including every platform of an index, to another
+/// repository on the same or a different registry.
 ///
-/// Sends a `GET` request to `/v2` and validates the response with
-/// [`api::parse_response_status`].  On success writes `"Ok\n"` to `buf`.
+/// `source` and `destination` are image references of the form
+/// `[REGISTRY/]REPOSITORY[:TAG|@DIGEST]`; references without a registry
+/// live on `registry_url`.  See [`copy::plan`] and [`copy::execute`] for how
+/// blobs are transferred: within one registry they are mounted with a
+/// cross-repository blob mount, otherwise they are streamed from source to
+/// destination.  Blobs already present at the destination are skipped.
+///
+/// With `dry_run`, the transfer plan is written to `buf` and nothing is
+/// uploaded.  Otherwise the digest of the copied manifest is written.
 ///
 /// # Arguments
 ///
 /// * `buf` — Output sink (typically stdout or a test buffer).
-/// * `registry_url` — Base URL of the Docker Registry.
+/// * `registry_url` — Default registry for references that name none.
+/// * `source` — Reference of the image to copy.
+/// * `destination` — Reference to copy the image to.
+/// * `src_credentials` — Basic credentials for the source registry.
+/// * `dst_credentials` — Basic credentials for the destination registry.
+/// * `dry_run` — Print the plan instead of copying.
+/// * `jobs` — Maximum number of blobs transferred concurrently.
 ///
 /// # Errors
 ///
-/// * [`ApiError::HttpError`] — the HTTP client could not be constructed, or the
+/// * [`ApiError::InvalidReference`] — a reference could not be parsed.
+/// * [`ApiError::InvalidCredentials`] — credentials could not be encoded.
+/// * [`ApiError::HttpError`] — a client could not be constructed, or a
 ///   request failed at the transport layer.
-/// * [`ApiError::UrlParseError`] — the `/v2` URL could not be constructed.
-/// * [`ApiError::ResponseHeaderParseError`] — the version header contains
-///   non-UTF-8 bytes.
-/// * [`ApiError::UnexpectedResponse`] — the `Docker-Distribution-API-Version`
-///   header is absent from the response.
-/// * [`ApiError::UnsupportedVersion`] — the version header has a value other
-///   than `"registry/2.0"`.
-/// * [`ApiError::AuthorizationFailed`] — the registry requires authentication.
-/// * [`ApiError::NotFound`] — the `/v2` endpoint does not exist.
-/// * [`ApiError::MethodNotAllowed`] — the registry rejected the request method.
-/// * [`ApiError::IOError`] — writing `"Ok\n"` to `buf` failed.
-pub async fn check_handler(buf: &mut dyn Write, registry_url: &Url) -> Result<(), ApiError> {
-    log::trace!("check_handler(registry_url: {registry_url:?})");
+/// * [`ApiError::JsonError`] — a source manifest is malformed.
+/// * [`ApiError::DigestMismatch`] — a child manifest does not match its
+///   digest.
+/// * [`ApiError::IOError`] — writing to `buf` failed.
+/// * Any variant returned by [`api::parse_response_status`] for either
+///   registry, e.g. [`ApiError::AuthorizationFailed`] when credentials are
+///   missing or wrong.
+#[allow(clippy::too_many_arguments)]
+pub async fn copy_handler(
+    buf: &mut dyn Write,
+    registry_url: &Url,
+    source: &str,
+    destination: &str,
+    src_credentials: Option<&Credentials>,
+    dst_credentials: Option<&Credentials>,
+    dry_run: bool,
+    jobs: usize,
+) -> Result<(), ApiError> {
+    log::trace!(
+        "copy_handler(registry_url: {registry_url:?}, source: {source}, destination: {destination}, dry_run: {dry_run})"
+    );
 
-    let path = "/v2";
-    let url = registry_url.join(path)?;
+    let src = Endpoint {
+        client: api::build_transfer_client(src_credentials)?,
+        image: ImageRef::parse(source, registry_url)?,
+    };
+    let dst = Endpoint {
+        client: api::build_transfer_client(dst_credentials)?,
+        image: ImageRef::parse(destination, registry_url)?,
+    };
 
-    let client = api::build_client()?;
-    let resp = client.get(url).send().await?;
-    api::parse_response_status(&resp)?;
-    writeln!(buf, "Ok")?;
+    let plan = copy::plan(&src, &dst).await?;
+    if dry_run {
+        return plan.write_to(buf);
+    }
+
+    let digest = copy::execute(&src, &dst, &plan, jobs).await?;
+    writeln!(buf, "{digest}")?;
     Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use std::error::Error;
+/// Mirror the tags of one repository into another, on the same or a
+/// different registry.
+///
+/// `source` and `destination` are repository references of the form
+/// `[REGISTRY/]REPOSITORY`; references without a registry live on
+/// `registry_url`.  Only tags selected by `filter` are considered.  See
+/// [`sync::plan`] for how tags are compared and pruned.  Each tag is copied
+/// as by [`copy_handler`], so blobs shared between tags are transferred
+/// once.
+///
+/// The plan is written to `buf`, one line per tag followed by a summary.
+/// With `dry_run`, nothing is copied or deleted.
+///
+/// # Arguments
+///
+/// * `buf` — Output sink (typically stdout or a test buffer).
+/// * `registry_url` — Default registry for references that name none.
+/// * `source` — Repository to mirror.
+/// * `destination` — Repository to mirror into.
+/// * `filter` — Include and exclude patterns selecting tags.
+/// * `prune` — Delete selected destination tags missing at the source.
+/// * `src_credentials` — Basic credentials for the source registry.
+/// * `dst_credentials` — Basic credentials for the destination registry.
+/// * `dry_run` — Print the plan instead of syncing.
+/// * `jobs` — Maximum number of blobs transferred concurrently.
+///
+/// # Errors
+///
+/// * [`ApiError::InvalidReference`] — a reference could not be parsed, or
+///   names a tag or digest.
+/// * [`ApiError::IOError`] — writing to `buf` failed.
+/// * Any error returned by [`copy_handler`] for a copied tag, or by
+///   [`api::parse_response_status`] while listing, resolving, or deleting.
+#[allow(clippy::too_many_arguments)]
+pub async fn sync_handler(
+    buf: &mut dyn Write,
+    registry_url: &Url,
+    source: &str,
+    destination: &str,
+    filter: &Filter,
+    prune: bool,
+    src_credentials: Option<&Credentials>,
+    dst_credentials: Option<&Credentials>,
+    dry_run: bool,
+    jobs: usize,
+) -> Result<(), ApiError> {
+    log::trace!(
+        "sync_handler(registry_url: {registry_url:?}, source: {source}, destination: {destination}, prune: {prune}, dry_run: {dry_run})"
+    );
 
-    use indoc::indoc;
-    use url::Url;
+    let src = Endpoint {
+        client: api::build_transfer_client(src_credentials)?,
+        image: parse_repository(source, registry_url)?,
+    };
+    let dst = Endpoint {
+        client: api::build_transfer_client(dst_credentials)?,
+        image: parse_repository(destination, registry_url)?,
+    };
 
-    use crate::error;
+    let plan = sync::plan(&src, &dst, filter, prune).await?;
+    if !dry_run {
+        sync::execute(&src, &dst, &plan, jobs).await?;
+    }
+    plan.write_to(buf)
+}
 
-    use super::*;
+/// Parse `input` as a repository reference that names no tag or digest.
+fn parse_repository(input: &str, registry_url: &Url) -> Result<ImageRef, ApiError> {
+    let image = ImageRef::parse(input, registry_url)?;
+    if image.reference.is_some() {
+        return Err(ApiError::InvalidReference(format!(
+            "{input}: expected a repository without a tag or digest"
+        )));
+    }
+    Ok(image)
+}
 
-    /// Validate the happy path for the catalog handler.
-    ///
-    /// This test spins up a mock server, and makes a request to the catalog
-    /// endpoint.  It checks that the handler both called the request the
-    /// expected number of times, and did not return an error.
-    #[tokio::test]
-    async fn test_catalog_handler() {
-        let mut server = mockito::Server::new_async().await;
-        let path = "/v2/_catalog";
+/// Move every tag of a repository to a new repository name.
+///
+/// The distribution API has no rename, so the tags are copied as by
+/// [`sync_handler`]: within one registry the blobs are mounted rather than
+/// transferred.  Once copied, every tag is resolved again at the
+/// destination with [`sync::verify`] and must point at its source digest.
+/// Only then, with `delete`, are the manifests of the source repository
+/// deleted, each distinct digest once.  A failure at any step leaves the
+/// source untouched.
+///
+/// The plan is written to `buf`, one line per tag, followed by a summary of
+/// what was verified and deleted.  With `dry_run`, only the plan is
+/// written.
+///
+/// # Arguments
+///
+/// * `buf` — Output sink (typically stdout or a test buffer).
+/// * `registry_url` — Default registry for references that name none.
+/// * `source` — Repository to move.
+/// * `destination` — New repository name.
+/// * `delete` — Delete the source tags once the copy is verified.
+/// * `dry_run` — Print the plan instead of moving.
+/// * `jobs` — Maximum number of blobs transferred concurrently.
+///
+/// # Errors
+///
+/// * [`ApiError::InvalidReference`] — a reference could not be parsed,
+///   names a tag or digest, or both name the same repository.
+/// * [`ApiError::NotFound`] — the source repository has no tags.
+/// * [`ApiError::DigestMismatch`] — a destination tag does not resolve to
+///   its source digest after the copy.
+/// * [`ApiError::IOError`] — writing to `buf` failed.
+/// * Any error returned by [`sync::plan`], [`sync::execute`], or
+///   [`sync::delete_manifest`].
+pub async fn move_handler(
+    buf: &mut dyn Write,
+    registry_url: &Url,
+    source: &str,
+    destination: &str,
+    delete: bool,
+    dry_run: bool,
+    jobs: usize,
+) -> Result<(), ApiError> {
+    log::trace!(
+        "move_handler(registry_url: {registry_url:?}, source: {source}, destination: {destination}, delete: {delete}, dry_run: {dry_run})"
+    );
+
+    let client = api::build_transfer_client(None)?;
+    let src = Endpoint {
+        client: client.clone(),
+        image: parse_repository(source, registry_url)?,
+    };
+    let dst = Endpoint {
+        client,
+        image: parse_repository(destination, registry_url)?,
+    };
+    if src.image == dst.image {
+        return Err(ApiError::InvalidReference(format!(
+            "{destination}: the same repository as {source}"
+        )));
+    }
 
-        let registry_url = Url::parse(&server.url()).expect("Failed to parse registry URL");
-        let mock_response = server
-            .mock("GET", path)
-            .with_status(http::status::StatusCode::OK.as_u16().into())
-            .with_header(http::header::CONTENT_TYPE.as_str(), "application/json")
-            .with_header("Docker-Distribution-API-Version", "registry/2.0")
-            .with_body(r#"{"repositories": ["image1", "image2", "image3"]}"#)
-            .create();
+    let plan = sync::plan(&src, &dst, &Filter::default(), false).await?;
+    if plan.tags.is_empty() {
+        return Err(ApiError::NotFound);
+    }
+    plan.write_to(buf)?;
+    if dry_run {
+        return Ok(());
+    }
 
-        let mut buf: Vec<u8> = Vec::new();
-        let result = catalog_handler(&mut buf, &registry_url).await;
-        assert!(result.is_ok(), "{:?}", result.unwrap_err());
-        assert_eq!(String::from_utf8(buf).unwrap(), *"image1\nimage2\nimage3\n");
+    sync::execute(&src, &dst, &plan, jobs).await?;
+    let verified = sync::verify(&dst, &plan).await?;
+    writeln!(buf, "{verified} tags verified in {}", dst.image)?;
 
-        mock_response.assert();
+    if delete {
+        let digests: BTreeSet<&str> = plan.tags.iter().map(|t| t.digest.as_str()).collect();
+        for digest in &digests {
+            log::info!("Deleting {}@{digest}", src.image.repository);
+            sync::delete_manifest(&src, digest).await?;
+        }
+        writeln!(
+            buf,
+            "{} manifests deleted from {}",
+            digests.len(),
+            src.image
+        )?;
     }
+    Ok(())
+}
 
-    /// Validate the pagination of the catalog handler.
-    ///
-    /// This test spins up a mock server, and makes a request to the catalog
-    /// endpoint.  The response includes a pagination link, which the handler
-    /// should follow, resulting in the combined list.  It checks that the
-    /// handler both called the request the expected number of times, and did
-    /// not return an error.
-    #[tokio::test]
-    async fn test_catalog_handler_with_pagination() {
-        let mut server = mockito::Server::new_async().await;
-        let path = "/v2/_catalog";
-        let path2 = "/v2/_catalog?n=2,last=image2";
-
-        let registry_url = Url::parse(&server.url()).expect("Failed to parse registry URL");
-        let mock_response = server
-            .mock("GET", path)
-            .with_status(http::status::StatusCode::OK.as_u16().into())
-            .with_header(http::header::CONTENT_TYPE.as_str(), "application/json")
-            .with_header("Docker-Distribution-API-Version", "registry/2.0")
-            .with_header(
-                http::header::LINK.as_str(),
-                &format!(r"<{path2}>; rel=next"),
-            )
-            .with_body(r#"{"repositories": ["image1", "image2"]}"#)
-            .create();
+/// List the entries of one layer of an image, like `tar -tv`.
+///
+/// `image` is a reference of the form `[REGISTRY/]REPOSITORY[:TAG|@DIGEST]`
+/// resolved with [`image::resolve`]; for a multi-platform image, `platform`
+/// selects the manifest.  `layer` is either an index counting from `0` for
+/// the base layer, or the digest or diff ID of a layer.
+///
+/// The layer is streamed and decompressed with [`layer::fetch`], and its
+/// entries are written to `buf` only after both the compressed digest and
+/// the diff ID have been verified.
+///
+/// # Arguments
+///
+/// * `buf` — Output sink (typically stdout or a test buffer).
+/// * `registry_url` — Default registry for references that name none.
+/// * `image` — Reference of the image.
+/// * `layer` — Index, digest, or diff ID of the layer to list.
+/// * `platform` — Platform to select from a multi-platform image.
+///
+/// # Errors
+///
+/// * [`ApiError::InvalidReference`] — `image` could not be parsed.
+/// * [`ApiError::LayerNotFound`] — no layer matches `layer`.
+/// * [`ApiError::UnsupportedMediaType`] — the layer compression is not
+///   supported.
+/// * [`ApiError::DigestMismatch`] — the layer does not match its digest or
+///   diff ID.
+/// * [`ApiError::IOError`] — the layer is not a valid tar stream, or
+///   writing to `buf` failed.
+/// * Any error returned by [`image::resolve`].
+pub async fn layer_handler(
+    buf: &mut dyn Write,
+    registry_url: &Url,
+    image: &str,
+    layer: &str,
+    platform: Option<&Platform>,
+) -> Result<(), ApiError> {
+    log::trace!("layer_handler(registry_url: {registry_url:?}, image: {image}, layer: {layer})");
+
+    let client = api::build_transfer_client(None)?;
+    let reference = ImageRef::parse(image, registry_url)?;
+    let image = image::resolve(&client, &reference, platform).await?;
+    log::debug!("Resolved {reference} to {}", image.digest);
+
+    let (descriptor, diff_id) = match layer.parse::<usize>() {
+        Ok(index) => image.layers().nth(index),
+        Err(_) => image
+            .layers()
+            .find(|(d, diff_id)| d.digest == layer || *diff_id == layer),
+    }
+    .ok_or_else(|| ApiError::LayerNotFound(String::from(layer)))?;
 
-        let mock_response2 = server
-            .mock("GET", path2)
-            .with_status(http::status::StatusCode::OK.as_u16().into())
-            .with_header(http::header::CONTENT_TYPE.as_str(), "application/json")
-            .with_header("Docker-Distribution-API-Version", "registry/2.0")
-            .with_body(r#"{"repositories": ["image3"]}"#)
-            .create();
+    let entries = layer::fetch(&client, &reference, descriptor, diff_id, layer::entries).await?;
+    for entry in entries {
+        writeln!(buf, "{entry}")?;
+    }
+    Ok(())
+}
 
-        let mut buf: Vec<u8> = Vec::new();
-        let result = catalog_handler(&mut buf, &registry_url).await;
-        assert!(result.is_ok(), "{:?}", result.unwrap_err());
-        assert_eq!(String::from_utf8(buf).unwrap(), *"image1\nimage2\nimage3\n");
+/// List a path in the merged filesystem of an image, like `ls`.
+///
+/// `image` is a reference of the form `[REGISTRY/]REPOSITORY[:TAG|@DIGEST]`
+/// resolved with [`image::resolve`]; for a multi-platform image, `platform`
+/// selects the manifest.  Every layer is streamed and applied in order with
+/// [`rootfs::build`], honouring whiteouts and opaque directories, so the
+/// listing shows the filesystem a container would start with.  Nothing is
+/// extracted to disk.
+///
+/// `path` defaults to the root.  For a directory, its children are written
+/// one per line, or with `recursive` every entry below it, relative to the
+/// directory.  For anything else, the path itself is written.  Symbolic
+/// links in the directory components of `path` are followed; a link in the
+/// last component is only followed without `long`, as `ls` does.  With
+/// `long`, each line shows the mode, owner, size, and modification time.
+///
+/// # Arguments
+///
+/// * `buf` — Output sink (typically stdout or a test buffer).
+/// * `registry_url` — Default registry for references that name none.
+/// * `image` — Reference of the image.
+/// * `path` — Path to list; `None` lists the root.
+/// * `long` — Write `ls -l` style lines.
+/// * `recursive` — List every entry below a directory.
+/// * `platform` — Platform to select from a multi-platform image.
+///
+/// # Errors
+///
+/// * [`ApiError::InvalidReference`] — `image` could not be parsed.
+/// * [`ApiError::PathNotFound`] — `path` does not exist in the image.
+/// * [`ApiError::UnsupportedMediaType`] — a layer compression is not
+///   supported.
+/// * [`ApiError::DigestMismatch`] — a layer does not match its digest or
+///   diff ID.
+/// * [`ApiError::IOError`] — a layer is not a valid tar stream, or writing
+///   to `buf` failed.
+/// * Any error returned by [`image::resolve`].
+pub async fn ls_handler(
+    buf: &mut dyn Write,
+    registry_url: &Url,
+    image: &str,
+    path: Option<&str>,
+    long: bool,
+    recursive: bool,
+    platform: Option<&Platform>,
+) -> Result<(), ApiError> {
+    log::trace!(
+        "ls_handler(registry_url: {registry_url:?}, image: {image}, path: {path:?}, long: {long}, recursive: {recursive})"
+    );
+
+    let client = api::build_transfer_client(None)?;
+    let reference = ImageRef::parse(image, registry_url)?;
+    let image = image::resolve(&client, &reference, platform).await?;
+    log::debug!("Resolved {reference} to {}", image.digest);
+    let rootfs = rootfs::build(&client, &reference, &image, false).await?;
+
+    let path = path.unwrap_or("/");
+    let node = rootfs.lookup(path, !long)?;
+    let listing: Vec<(&layer::Entry, &str)> = if node.is_dir() {
+        let prefix = format!("{}/", node.entry.path);
+        let nodes = if recursive {
+            node.descendants()
+        } else {
+            node.children.values().collect()
+        };
+        nodes
+            .into_iter()
+            .map(|n| {
+                let name = n.entry.path.as_str();
+                (&n.entry, name.strip_prefix(&prefix).unwrap_or(name))
+            })
+            .collect()
+    } else {
+        vec![(&node.entry, path)]
+    };
 
-        mock_response.assert();
-        mock_response2.assert();
+    for (entry, name) in listing {
+        if long {
+            writeln!(buf, "{}", entry.long(name))?;
+        } else {
+            writeln!(buf, "{name}")?;
+        }
     }
+    Ok(())
+}
 
-    /// Validate the happy path for the tags handler.
-    ///
-    /// This test spins up a mock server, and makes a request to the tags
-    /// endpoint.  It checks that the handler both called the request the
-    /// expected number of times, and did not return an error.
-    #[tokio::test]
-    async fn test_tags_handler() {
-        let mut server = mockito::Server::new_async().await;
-        let path = "/v2/some_image/tags/list";
-
-        // Mock the HTTP response for the Docker Registry API
-        let registry_url = Url::parse(&server.url()).expect("Failed to parse registry URL");
-        let mock_response = server
-            .mock("GET", path)
-            .with_status(http::status::StatusCode::OK.as_u16().into())
-            .with_header(http::header::CONTENT_TYPE.as_str(), "application/json")
-            .with_header("Docker-Distribution-API-Version", "registry/2.0")
-            .with_body(r#"{"tags": ["tag1", "tag2", "tag3"]}"#)
-            .create();
+/// Write the contents of a file in the filesystem of an image to `buf`.
+///
+/// `image` is a reference of the form `[REGISTRY/]REPOSITORY[:TAG|@DIGEST]`
+/// resolved with [`image::resolve`]; for a multi-platform image, `platform`
+/// selects the manifest.  The file is found with [`rootfs::open`], which
+/// streams layers from the top down and stops at the topmost layer
+/// containing `path`, following links and respecting whiteouts.  Nothing is
+/// written until that layer has been verified against its digest and diff
+/// ID.
+///
+/// # Arguments
+///
+/// * `buf` — Output sink (typically stdout or a test buffer).
+/// * `registry_url` — Default registry for references that name none.
+/// * `image` — Reference of the image.
+/// * `path` — Path of the file in the image.
+/// * `platform` — Platform to select from a multi-platform image.
+///
+/// # Errors
+///
+/// * [`ApiError::InvalidReference`] — `image` could not be parsed.
+/// * [`ApiError::PathNotFound`] — `path` does not exist in the image.
+/// * [`ApiError::NotAFile`] — `path` is a directory or a special file.
+/// * [`ApiError::DigestMismatch`] — a layer does not match its digest or
+///   diff ID.
+/// * [`ApiError::IOError`] — a layer is not a valid tar stream, or writing
+///   to `buf` failed.
+/// * Any error returned by [`image::resolve`].
+pub async fn cat_handler(
+    buf: &mut dyn Write,
+    registry_url: &Url,
+    image: &str,
+    path: &str,
+    platform: Option<&Platform>,
+) -> Result<(), ApiError> {
+    log::trace!("cat_handler(registry_url: {registry_url:?}, image: {image}, path: {path})");
+
+    let client = api::build_transfer_client(None)?;
+    let reference = ImageRef::parse(image, registry_url)?;
+    let image = image::resolve(&client, &reference, platform).await?;
+    log::debug!("Resolved {reference} to {}", image.digest);
+
+    let (_, contents) = rootfs::open(&client, &reference, &image, path, |_, file| {
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        Ok(contents)
+    })
+    .await?;
+    buf.write_all(&contents)?;
+    Ok(())
+}
+
+/// Extract a file from the filesystem of an image into the directory `to`.
+///
+/// The file is found as described for [`cat_handler`] and written to
+/// `<to>/<name>`, where `name` is the last component of `path`, with the
+/// permission bits it has in the image, without the setuid, setgid and
+/// sticky bits.  `to` is created if needed.  The
+/// contents are streamed into `<to>/<name>.partial`, which is only renamed
+/// once the layer has been verified, and removed otherwise.  On success the
+/// path of the extracted file is written to `buf`.
+///
+/// # Arguments
+///
+/// * `buf` — Output sink (typically stdout or a test buffer).
+/// * `registry_url` — Default registry for references that name none.
+/// * `image` — Reference of the image.
+/// * `path` — Path of the file in the image.
+/// * `to` — Directory to extract the file into.
+/// * `platform` — Platform to select from a multi-platform image.
+///
+/// # Errors
+///
+/// * [`ApiError::InvalidReference`] — `image` could not be parsed.
+/// * [`ApiError::PathNotFound`] — `path` does not exist in the image.
+/// * [`ApiError::NotAFile`] — `path` is a directory or a special file.
+/// * [`ApiError::DigestMismatch`] — a layer does not match its digest or
+///   diff ID.
+/// * [`ApiError::IOError`] — a layer is not a valid tar stream, the file
+///   could not be written, or writing to `buf` failed.
+/// * Any error returned by [`image::resolve`].
+pub async fn extract_handler(
+    buf: &mut dyn Write,
+    registry_url: &Url,
+    image: &str,
+    path: &str,
+    to: &Path,
+    platform: Option<&Platform>,
+) -> Result<(), ApiError> {
+    log::trace!(
+        "extract_handler(registry_url: {registry_url:?}, image: {image}, path: {path}, to: {})",
+        to.display()
+    );
+
+    let name = path
+        .rsplit('/')
+        .find(|c| !c.is_empty() && *c != "." && *c != "..")
+        .ok_or_else(|| ApiError::NotAFile(String::from(path)))?;
+    let client = api::build_transfer_client(None)?;
+    let reference = ImageRef::parse(image, registry_url)?;
+    let image = image::resolve(&client, &reference, platform).await?;
+    log::debug!("Resolved {reference} to {}", image.digest);
+
+    tokio::fs::create_dir_all(to).await?;
+    let dest = to.join(name);
+    let partial = blob::suffixed(&dest, ".partial");
+    let target = partial.clone();
+    let result = rootfs::open(&client, &reference, &image, path, move |_, file| {
+        let mut out = std::fs::File::create(&target)?;
+        Ok(std::io::copy(file, &mut out)?)
+    })
+    .await;
+    let (entry, size) = match result {
+        Ok(found) => found,
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Err(e);
+        }
+    };
+
+    // Like `tar` for a user other than root, leave out the setuid, setgid
+    // and sticky bits, which an untrusted image should not get to set.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = entry.mode & 0o777;
+        tokio::fs::set_permissions(&partial, std::fs::Permissions::from_mode(mode)).await?;
+    }
+    tokio::fs::rename(&partial, &dest).await?;
+    log::info!("Extracted {} ({size} bytes)", entry.path);
+    writeln!(buf, "{}", dest.display())?;
+    Ok(())
+}
+
+/// Write the merged filesystem of one platform of an image to a tar file,
+/// like `docker export` without a container or daemon.
+///
+/// `image` is a reference of the form `[REGISTRY/]REPOSITORY[:TAG|@DIGEST]`
+/// resolved with [`image::resolve`]; for a multi-platform image, `platform`
+/// selects the manifest.  The archive is produced by [`rootfs::export`]
+/// with every whiteout resolved, so it only contains what a container
+/// would see.  It is written to `<output>.partial` and renamed to `output`
+/// once every layer has been verified; on failure the partial file is
+/// removed.  A summary line is written to `buf`.
+///
+/// # Arguments
+///
+/// * `buf` — Output sink (typically stdout or a test buffer).
+/// * `registry_url` — Default registry for references that name none.
+/// * `image` — Reference of the image.
+/// * `output` — Path of the tar file to write.
+/// * `platform` — Platform to select from a multi-platform image.
+///
+/// # Errors
+///
+/// * [`ApiError::InvalidReference`] — `image` could not be parsed.
+/// * [`ApiError::UnsupportedMediaType`] — a layer compression is not
+///   supported.
+/// * [`ApiError::DigestMismatch`] — a layer does not match its digest or
+///   diff ID.
+/// * [`ApiError::IOError`] — a layer is not a valid tar stream, `output`
+///   could not be written, or writing to `buf` failed.
+/// * Any error returned by [`image::resolve`].
+pub async fn export_handler(
+    buf: &mut dyn Write,
+    registry_url: &Url,
+    image: &str,
+    output: &Path,
+    platform: Option<&Platform>,
+) -> Result<(), ApiError> {
+    log::trace!(
+        "export_handler(registry_url: {registry_url:?}, image: {image}, output: {})",
+        output.display()
+    );
+
+    let client = api::build_transfer_client(None)?;
+    let reference = ImageRef::parse(image, registry_url)?;
+    let image = image::resolve(&client, &reference, platform).await?;
+    log::debug!("Resolved {reference} to {}", image.digest);
+
+    let partial = blob::suffixed(output, ".partial");
+    let file = tokio::fs::File::create(&partial).await?.into_std().await;
+    let file = std::io::BufWriter::new(file);
+    let count = match rootfs::export(&client, &reference, &image, file).await {
+        Ok((count, _)) => count,
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Err(e);
+        }
+    };
+    tokio::fs::rename(&partial, output).await?;
+    let size = tokio::fs::metadata(output).await?.len();
+    writeln!(
+        buf,
+        "{count} entries, {} written to {}",
+        progress::human_bytes(size),
+        output.display()
+    )?;
+    Ok(())
+}
+
+/// Report the space an image wastes on files that one layer adds and a
+/// later layer overwrites or deletes, like `dive`.
+///
+/// `image` is a reference of the form `[REGISTRY/]REPOSITORY[:TAG|@DIGEST]`
+/// resolved with [`image::resolve`]; for a multi-platform image, `platform`
+/// selects the manifest.  The layers are analysed with
+/// [`analyze::analyze`], and the report, listing the `top` largest wasted
+/// files, is written to `buf` with [`analyze::Analysis::write_to`].
+///
+/// With `min_efficiency`, a percentage, the report is still written but the
+/// handler fails when the image is less efficient, so CI can reject it.
+///
+/// # Arguments
+///
+/// * `buf` — Output sink (typically stdout or a test buffer).
+/// * `registry_url` — Default registry for references that name none.
+/// * `image` — Reference of the image.
+/// * `top` — Number of wasted files to list.
+/// * `min_efficiency` — Minimum efficiency, in percent, to accept.
+/// * `platform` — Platform to select from a multi-platform image.
+///
+/// # Errors
+///
+/// * [`ApiError::BelowThreshold`] — the efficiency is below
+///   `min_efficiency`.
+/// * [`ApiError::InvalidReference`] — `image` could not be parsed.
+/// * [`ApiError::DigestMismatch`] — a layer does not match its digest or
+///   diff ID.
+/// * [`ApiError::IOError`] — a layer is not a valid tar stream, or writing
+///   to `buf` failed.
+/// * Any error returned by [`image::resolve`].
+pub async fn analyze_handler(
+    buf: &mut dyn Write,
+    registry_url: &Url,
+    image: &str,
+    top: usize,
+    min_efficiency: Option<u8>,
+    platform: Option<&Platform>,
+) -> Result<(), ApiError> {
+    log::trace!(
+        "analyze_handler(registry_url: {registry_url:?}, image: {image}, top: {top}, min_efficiency: {min_efficiency:?})"
+    );
+
+    let client = api::build_transfer_client(None)?;
+    let reference = ImageRef::parse(image, registry_url)?;
+    let image = image::resolve(&client, &reference, platform).await?;
+    log::debug!("Resolved {reference} to {}", image.digest);
+
+    let analysis = analyze::analyze(&client, &reference, &image).await?;
+    analysis.write_to(buf, top)?;
+
+    let efficiency = analysis.efficiency() * 100.0;
+    match min_efficiency {
+        Some(min) if efficiency < f64::from(min) => Err(ApiError::BelowThreshold(format!(
+            "efficiency {efficiency:.1}% is below the minimum of {min}%"
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Compare two images: their layers, their configuration, and their merged
+/// filesystems.
+///
+/// `image_a` and `image_b` are references of the form
+/// `[REGISTRY/]REPOSITORY[:TAG|@DIGEST]` resolved with [`image::resolve`];
+/// for multi-platform images, `platform` selects the manifest of both.
+/// Layers are compared by diff ID with [`diff::compare_layers`] and the
+/// configurations with [`diff::compare_config`].  Both filesystems are
+/// built with [`rootfs::build`], hashing every file, and compared with
+/// [`diff::compare_files`], so files are compared by content.
+///
+/// The result is written to `buf` in a human-readable form, or as pretty
+/// JSON with `json`.
+///
+/// # Arguments
+///
+/// * `buf` — Output sink (typically stdout or a test buffer).
+/// * `registry_url` — Default registry for references that name none.
+/// * `image_a` — Reference of the first image.
+/// * `image_b` — Reference of the second image.
+/// * `json` — Write JSON instead of the human-readable form.
+/// * `platform` — Platform to select from multi-platform images.
+///
+/// # Errors
+///
+/// * [`ApiError::InvalidReference`] — a reference could not be parsed.
+/// * [`ApiError::DigestMismatch`] — a layer does not match its digest or
+///   diff ID.
+/// * [`ApiError::JsonError`] — the result could not be serialised.
+/// * [`ApiError::IOError`] — a layer is not a valid tar stream, or writing
+///   to `buf` failed.
+/// * Any error returned by [`image::resolve`].
+pub async fn diff_handler(
+    buf: &mut dyn Write,
+    registry_url: &Url,
+    image_a: &str,
+    image_b: &str,
+    json: bool,
+    platform: Option<&Platform>,
+) -> Result<(), ApiError> {
+    log::trace!(
+        "diff_handler(registry_url: {registry_url:?}, image_a: {image_a}, image_b: {image_b}, json: {json})"
+    );
+
+    let client = api::build_transfer_client(None)?;
+    let a_ref = ImageRef::parse(image_a, registry_url)?;
+    let b_ref = ImageRef::parse(image_b, registry_url)?;
+    let a = image::resolve(&client, &a_ref, platform).await?;
+    let b = image::resolve(&client, &b_ref, platform).await?;
+    log::debug!("Comparing {} with {}", a.digest, b.digest);
+    let a_fs = rootfs::build(&client, &a_ref, &a, true).await?;
+    let b_fs = rootfs::build(&client, &b_ref, &b, true).await?;
+
+    let result = diff::ImageDiff {
+        a: diff::Side {
+            reference: String::from(image_a),
+            digest: a.digest.clone(),
+        },
+        b: diff::Side {
+            reference: String::from(image_b),
+            digest: b.digest.clone(),
+        },
+        layers: diff::compare_layers(&a, &b),
+        config: diff::compare_config(&a.config, &b.config),
+        files: diff::compare_files(&a_fs, &b_fs),
+    };
+    if json {
+        serde_json::to_writer_pretty(&mut *buf, &result)?;
+        writeln!(buf)?;
+    } else {
+        result.write_to(buf)?;
+    }
+    Ok(())
+}
+
+/// List the packages installed in an image, read from the package
+/// databases in its merged filesystem.
+///
+/// `image` is a reference of the form `[REGISTRY/]REPOSITORY[:TAG|@DIGEST]`
+/// resolved with [`image::resolve`]; for a multi-platform image, `platform`
+/// selects the manifest.  The dpkg, apk, and RPM databases are read with
+/// [`rootfs::collect`], which streams every layer once, and parsed with
+/// [`packages::parse`].  Nothing is run inside the image.
+///
+/// The packages are written to `buf` as a table, or as pretty JSON with
+/// `json`.  An image without any supported database yields an empty list
+/// and a warning.
+///
+/// # Arguments
+///
+/// * `buf` — Output sink (typically stdout or a test buffer).
+/// * `registry_url` — Default registry for references that name none.
+/// * `image` — Reference of the image.
+/// * `json` — Write JSON instead of a table.
+/// * `platform` — Platform to select from a multi-platform image.
+///
+/// # Errors
+///
+/// * [`ApiError::InvalidReference`] — `image` could not be parsed.
+/// * [`ApiError::InvalidDatabase`] — an RPM database could not be read.
+/// * [`ApiError::DigestMismatch`] — a layer does not match its digest or
+///   diff ID.
+/// * [`ApiError::IOError`] — a layer is not a valid tar stream, or writing
+///   to `buf` failed.
+/// * Any error returned by [`image::resolve`].
+pub async fn packages_handler(
+    buf: &mut dyn Write,
+    registry_url: &Url,
+    image: &str,
+    json: bool,
+    platform: Option<&Platform>,
+) -> Result<(), ApiError> {
+    log::trace!("packages_handler(registry_url: {registry_url:?}, image: {image}, json: {json})");
+
+    let client = api::build_transfer_client(None)?;
+    let reference = ImageRef::parse(image, registry_url)?;
+    let image = image::resolve(&client, &reference, platform).await?;
+    log::debug!("Resolved {reference} to {}", image.digest);
+
+    let databases = rootfs::collect(&client, &reference, &image, packages::is_database).await?;
+    if databases.is_empty() {
+        log::warn!("No dpkg, apk, or RPM database found in {reference}");
+    }
+    let packages = tokio::task::spawn_blocking(move || packages::parse(&databases))
+        .await
+        .map_err(|e| ApiError::UnexpectedResponse(e.to_string()))??;
+
+    if json {
+        serde_json::to_writer_pretty(&mut *buf, &packages)?;
+        writeln!(buf)?;
+    } else {
+        packages::write_to(&packages, buf)?;
+    }
+    Ok(())
+}
+
+/// Add a local tar file as a new top layer of an image and push the result
+/// under a new reference, without a Docker build.
+///
+/// `base` and `target` are references of the form
+/// `[REGISTRY/]REPOSITORY[:TAG|@DIGEST]`; `target` must name a tag or none,
+/// in which case `latest` is used.  For a multi-platform base, `platform`
+/// selects the manifest and the result is a single-platform image.
+///
+/// The tarball is checked to be a valid tar archive, gzip-compressed with
+/// [`push::compress`], and appended with [`push::Draft::append_layer`]: its
+/// diff ID is added to `rootfs.diff_ids`, a history entry is recorded, and
+/// the creation time is updated.  Every other field of the configuration
+/// is kept as is.  [`push::Draft::push`] then mounts or copies the base
+/// layers that the target repository lacks, uploads the new layer and
+/// configuration, and tags the manifest.  Its digest is written to `buf`.
+///
+/// # Arguments
+///
+/// * `buf` — Output sink (typically stdout or a test buffer).
+/// * `registry_url` — Default registry for references that name none.
+/// * `base` — Reference of the image to extend.
+/// * `layer` — Path of the uncompressed tar file to add.
+/// * `target` — Reference to push the new image to.
+/// * `platform` — Platform to select from a multi-platform base.
+/// * `jobs` — Maximum number of layers transferred concurrently.
+///
+/// # Errors
+///
+/// * [`ApiError::InvalidReference`] — a reference could not be parsed, or
+///   `target` names a digest.
+/// * [`ApiError::IOError`] — `layer` could not be read or is not a tar
+///   archive, or writing to `buf` failed.
+/// * [`ApiError::UnexpectedResponse`] — the base configuration has no
+///   `rootfs.diff_ids`.
+/// * Any error returned by [`image::resolve`] or [`push::Draft::push`].
+pub async fn append_handler(
+    buf: &mut dyn Write,
+    registry_url: &Url,
+    base: &str,
+    layer: &Path,
+    target: &str,
+    platform: Option<&Platform>,
+    jobs: usize,
+) -> Result<(), ApiError> {
+    log::trace!(
+        "append_handler(registry_url: {registry_url:?}, base: {base}, layer: {}, target: {target})",
+        layer.display()
+    );
+
+    let client = api::build_transfer_client(None)?;
+    let reference = ImageRef::parse(base, registry_url)?;
+    let target = ImageRef::parse(target, registry_url)?;
+    let image = image::resolve(&client, &reference, platform).await?;
+    log::debug!("Resolved {reference} to {}", image.digest);
+
+    let mut draft = push::Draft::new(&reference, &image)?;
+    let media_type = push::gzip_layer_type(&draft.manifest);
+    let path = layer.to_path_buf();
+    let compressed = tokio::task::spawn_blocking(move || {
+        crate::layer::entries(&mut std::fs::File::open(&path)?)?;
+        push::compress(&mut std::fs::File::open(&path)?, media_type)
+    })
+    .await
+    .map_err(|e| ApiError::UnexpectedResponse(e.to_string()))??;
+
+    let created = push::now();
+    let name = layer.file_name().map_or_else(
+        || layer.display().to_string(),
+        |n| n.to_string_lossy().into_owned(),
+    );
+    let history = History {
+        created: Some(created.clone()),
+        created_by: Some(format!("dredge append {name}")),
+        ..History::default()
+    };
+    draft.append_layer(compressed, &history)?;
+    draft.config["created"] = serde_json::Value::from(created);
+
+    let digest = draft.push(&client, &target, jobs).await?;
+    log::info!("Pushed {target} ({digest})");
+    writeln!(buf, "{digest}")?;
+    Ok(())
+}
+
+/// Change the container defaults of an image and push the result under a
+/// new reference, reusing every layer.
+///
+/// `image` and `target` are references of the form
+/// `[REGISTRY/]REPOSITORY[:TAG|@DIGEST]`; `target` must name a tag or none,
+/// in which case `latest` is used.  For a multi-platform image, `platform`
+/// selects the manifest and the result is a single-platform image.
+///
+/// The configuration is rewritten with [`mutate::Mutation::apply`]; every
+/// field the mutation does not touch, including those `dredge` does not
+/// model, is kept.  [`push::Draft::push`] then mounts or copies the layers
+/// that the target repository lacks, uploads the new configuration, and
+/// tags the manifest.  No layer is downloaded when the target is on the
+/// same registry.  The digest of the new manifest is written to `buf`.
+///
+/// # Arguments
+///
+/// * `buf` — Output sink (typically stdout or a test buffer).
+/// * `registry_url` — Default registry for references that name none.
+/// * `image` — Reference of the image to change.
+/// * `mutation` — The changes to make.
+/// * `target` — Reference to push the new image to.
+/// * `platform` — Platform to select from a multi-platform image.
+/// * `jobs` — Maximum number of layers transferred concurrently.
+///
+/// # Errors
+///
+/// * [`ApiError::InvalidReference`] — a reference could not be parsed, or
+///   `target` names a digest.
+/// * [`ApiError::UnexpectedResponse`] — the configuration has a field the
+///   mutation changes with an unexpected JSON type.
+/// * [`ApiError::IOError`] — writing to `buf` failed.
+/// * Any error returned by [`image::resolve`] or [`push::Draft::push`].
+pub async fn mutate_handler(
+    buf: &mut dyn Write,
+    registry_url: &Url,
+    image: &str,
+    mutation: &Mutation,
+    target: &str,
+    platform: Option<&Platform>,
+    jobs: usize,
+) -> Result<(), ApiError> {
+    log::trace!(
+        "mutate_handler(registry_url: {registry_url:?}, image: {image}, mutation: {mutation:?}, target: {target})"
+    );
+    if mutation.is_empty() {
+        log::warn!("No changes requested; {target} will get the same configuration");
+    }
+
+    let client = api::build_transfer_client(None)?;
+    let reference = ImageRef::parse(image, registry_url)?;
+    let target = ImageRef::parse(target, registry_url)?;
+    let image = image::resolve(&client, &reference, platform).await?;
+    log::debug!("Resolved {reference} to {}", image.digest);
+
+    let mut draft = push::Draft::new(&reference, &image)?;
+    mutation.apply(&mut draft.config)?;
+
+    let digest = draft.push(&client, &target, jobs).await?;
+    log::info!("Pushed {target} ({digest})");
+    writeln!(buf, "{digest}")?;
+    Ok(())
+}
+
+/// Merge every layer of an image into one and push the result under a new
+/// reference.
+///
+/// `image` and `target` are references of the form
+/// `[REGISTRY/]REPOSITORY[:TAG|@DIGEST]`; `target` must name a tag or none,
+/// in which case `latest` is used.  For a multi-platform image, `platform`
+/// selects the manifest and the result is a single-platform image.
+///
+/// The merged filesystem is produced by [`rootfs::export`], so whiteouts
+/// are applied and files deleted by a later layer are gone from the
+/// result, and is compressed with a [`push::LayerWriter`].  The draft then
+/// replaces every layer with this one via [`push::Draft::clear_layers`]:
+/// the diff IDs and the history are reduced to a single entry and the
+/// creation time is updated, while the container defaults are kept.
+/// [`push::Draft::push`] uploads the layer and configuration and tags the
+/// manifest, whose digest is written to `buf`.
+///
+/// # Arguments
+///
+/// * `buf` — Output sink (typically stdout or a test buffer).
+/// * `registry_url` — Default registry for references that name none.
+/// * `image` — Reference of the image to flatten.
+/// * `target` — Reference to push the flattened image to.
+/// * `platform` — Platform to select from a multi-platform image.
+/// * `jobs` — Maximum number of layers transferred concurrently.
+///
+/// # Errors
+///
+/// * [`ApiError::InvalidReference`] — a reference could not be parsed, or
+///   `target` names a digest.
+/// * [`ApiError::DigestMismatch`] — a layer does not match its digest or
+///   diff ID.
+/// * [`ApiError::IOError`] — a layer is not a valid tar stream, the
+///   temporary layer file could not be written, or writing to `buf`
+///   failed.
+/// * Any error returned by [`image::resolve`] or [`push::Draft::push`].
+pub async fn flatten_handler(
+    buf: &mut dyn Write,
+    registry_url: &Url,
+    image: &str,
+    target: &str,
+    platform: Option<&Platform>,
+    jobs: usize,
+) -> Result<(), ApiError> {
+    log::trace!(
+        "flatten_handler(registry_url: {registry_url:?}, image: {image}, target: {target})"
+    );
+
+    let client = api::build_transfer_client(None)?;
+    let reference = ImageRef::parse(image, registry_url)?;
+    let target = ImageRef::parse(target, registry_url)?;
+    let image = image::resolve(&client, &reference, platform).await?;
+    log::debug!("Resolved {reference} to {}", image.digest);
+
+    let mut draft = push::Draft::new(&reference, &image)?;
+    let writer = push::LayerWriter::create(push::gzip_layer_type(&draft.manifest))?;
+    let (count, writer) = rootfs::export(&client, &reference, &image, writer).await?;
+    let layer = tokio::task::spawn_blocking(move || writer.finish())
+        .await
+        .map_err(|e| ApiError::UnexpectedResponse(e.to_string()))??;
+    log::info!(
+        "Flattened {} layers into {count} entries, {}",
+        image.manifest.layers.len(),
+        progress::human_bytes(layer.descriptor.size)
+    );
+
+    let created = push::now();
+    let history = History {
+        created: Some(created.clone()),
+        created_by: Some(format!("dredge flatten {reference}")),
+        ..History::default()
+    };
+    draft.clear_layers()?;
+    draft.append_layer(layer, &history)?;
+    draft.config["created"] = serde_json::Value::from(created);
+
+    let digest = draft.push(&client, &target, jobs).await?;
+    log::info!("Pushed {target} ({digest})");
+    writeln!(buf, "{digest}")?;
+    Ok(())
+}
+
+/// Move an image from one base image to another and push the result under
+/// a new reference, without rebuilding it.
+///
+/// `image`, `old_base`, `new_base`, and `target` are references of the form
+/// `[REGISTRY/]REPOSITORY[:TAG|@DIGEST]`; `target` must name a tag or none,
+/// in which case `latest` is used.  All three images are resolved for the
+/// same `platform`, and the result is a single-platform image.
+///
+/// [`rebase::check_base`] verifies that the layers of the image start with
+/// those of the old base.  The draft then swaps them for the layers and
+/// diff IDs of the new base with [`push::Draft::replace_base`], replaces
+/// the leading history entries of the old base (see
+/// [`rebase::base_history_len`]) with the history of the new base, as
+/// returned by [`rebase::base_history`], and
+/// merges the container defaults with [`rebase::merge_config`].
+/// [`push::Draft::push`] mounts or copies the layers the target repository
+/// lacks, so the application layers are never downloaded when everything
+/// lives on one registry.  The digest of the new manifest is written to
+/// `buf`.
+///
+/// The application layers are reused as they are; rebasing is only safe
+/// when they do not depend on files that differ between the two bases.
+///
+/// # Arguments
+///
+/// * `buf` — Output sink (typically stdout or a test buffer).
+/// * `registry_url` — Default registry for references that name none.
+/// * `image` — Reference of the image to rebase.
+/// * `old_base` — Reference of the base the image was built on.
+/// * `new_base` — Reference of the base to move the image to.
+/// * `target` — Reference to push the rebased image to.
+/// * `platform` — Platform to select from multi-platform images.
+/// * `jobs` — Maximum number of layers transferred concurrently.
+///
+/// # Errors
+///
+/// * [`ApiError::InvalidReference`] — a reference could not be parsed, or
+///   `target` names a digest.
+/// * [`ApiError::NotBasedOn`] — the image does not start with the layers
+///   of `old_base`.
+/// * [`ApiError::IOError`] — writing to `buf` failed.
+/// * Any error returned by [`image::resolve`] or [`push::Draft::push`].
+#[allow(clippy::too_many_arguments)]
+pub async fn rebase_handler(
+    buf: &mut dyn Write,
+    registry_url: &Url,
+    image: &str,
+    old_base: &str,
+    new_base: &str,
+    target: &str,
+    platform: Option<&Platform>,
+    jobs: usize,
+) -> Result<(), ApiError> {
+    log::trace!(
+        "rebase_handler(registry_url: {registry_url:?}, image: {image}, old_base: {old_base}, new_base: {new_base}, target: {target})"
+    );
+
+    let client = api::build_transfer_client(None)?;
+    let reference = ImageRef::parse(image, registry_url)?;
+    let old_ref = ImageRef::parse(old_base, registry_url)?;
+    let new_ref = ImageRef::parse(new_base, registry_url)?;
+    let target = ImageRef::parse(target, registry_url)?;
+    let image = image::resolve(&client, &reference, platform).await?;
+    let old = image::resolve(&client, &old_ref, platform).await?;
+    let new = image::resolve(&client, &new_ref, platform).await?;
+    log::debug!(
+        "Resolved {reference} to {}, {old_ref} to {}, {new_ref} to {}",
+        image.digest,
+        old.digest,
+        new.digest
+    );
+    rebase::check_base(&image, &old, &old_ref.to_string())?;
+
+    let mut draft = push::Draft::new(&reference, &image)?;
+    draft.replace_base(old.manifest.layers.len(), &new_ref, &new)?;
+
+    let old_config: serde_json::Value = serde_json::from_slice(&old.raw_config)?;
+    let new_config: serde_json::Value = serde_json::from_slice(&new.raw_config)?;
+    rebase::merge_config(&mut draft.config, &old_config, &new_config);
+    let stale = rebase::base_history_len(&image, &old);
+    if let Some(history) = draft.config["history"].as_array_mut() {
+        let base_history = rebase::base_history(&new_config, new.manifest.layers.len());
+        history.splice(..stale.min(history.len()), base_history);
+    }
+
+    let digest = draft.push(&client, &target, jobs).await?;
+    log::info!(
+        "Pushed {target} ({digest}): {} base layers replaced by {}",
+        old.manifest.layers.len(),
+        new.manifest.layers.len()
+    );
+    writeln!(buf, "{digest}")?;
+    Ok(())
+}
+
+/// Delete the tags that a retention policy does not keep.
+///
+/// Every repository in the catalog that a rule of `policy` applies to is
+/// considered in turn: its tags are described with [`tags::describe_all`],
+/// up to `jobs` at a time, and [`crate::retention::Rule::decide`] picks the tags to keep.
+/// A tag that cannot be described is kept as undated.  The whole plan is
+/// written to `buf`, one line per tag followed by a summary, and `buf` is
+/// flushed; it should be standard output itself rather than a buffer, so
+/// that the plan is seen before anything is deleted.  With `dry_run`, that
+/// is all.
+///
+/// Unless `yes` is set, the deletes must then be confirmed with
+/// [`confirm`] on standard input, which must be a terminal.
+///
+/// Otherwise the manifests that the tags of each repository that are not
+/// kept pointed at are deleted by digest with [`delete::delete_tags`], as
+/// by [`delete_handler`], and each is reported.  The tags are not resolved
+/// again, so one that moved meanwhile cannot take a kept manifest with it.
+/// The kept tags are resolved again instead, and a manifest one of them
+/// has moved onto is not deleted.  A repository whose manifests cannot all
+/// be deleted does not stop the others.
+///
+/// # Arguments
+///
+/// * `buf` — Output sink (typically stdout or a test buffer).
+/// * `registry_url` — Base URL of the Docker Registry.
+/// * `policy` — The retention policy.
+/// * `dry_run` — Only write the plan.
+/// * `yes` — Delete without asking for confirmation.
+/// * `cascade` — Also delete the platform manifests of deleted indexes
+///   that no remaining tag refers to.
+/// * `jobs` — Maximum number of requests in flight.
+///
+/// # Errors
+///
+/// * [`ApiError::HttpError`] — the HTTP client could not be constructed.
+/// * [`ApiError::IOError`] — writing to `buf` or reading the answer failed.
+/// * [`ApiError::NotConfirmed`] — `yes` is not set and standard input is
+///   not a terminal.
+/// * [`ApiError::DeleteFailed`] — the manifests of some repositories could
+///   not all be deleted.
+/// * Any error returned while listing the catalog or the tags of a
+///   repository, or by [`tags::describe_all`], in which case nothing is
+///   deleted.
+pub async fn prune_handler(
+    buf: &mut dyn Write,
+    registry_url: &Url,
+    policy: &Policy,
+    dry_run: bool,
+    yes: bool,
+    cascade: bool,
+    jobs: usize,
+) -> Result<(), ApiError> {
+    log::trace!(
+        "prune_handler(registry_url: {registry_url:?}, rules: {}, dry_run: {dry_run}, yes: {yes}, cascade: {cascade})",
+        policy.rules.len()
+    );
+
+    let client = api::build_client()?;
+    let pages: Vec<CatalogResponse> =
+        api::fetch_paginated(&client, registry_url, "v2/_catalog").await?;
+    let repositories: Vec<String> = pages
+        .into_iter()
+        .flat_map(|page| page.repositories)
+        .filter(|r| policy.rule_for(r).is_some())
+        .collect();
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let mut plan = PrunePlan::default();
+    for name in repositories {
+        let Some(rule) = policy.rule_for(&name) else {
+            continue;
+        };
+        let repository = parse_repository(&name, registry_url)?;
+        let all = match api::list_tags(&client, &repository.registry, &repository.repository).await
+        {
+            Err(ApiError::NotFound) => continue,
+            result => result?,
+        };
+        log::info!("Describing {} tags of {name}", all.len());
+        let infos = tags::describe_all(&client, &repository, &all, jobs).await?;
+        plan.repositories.push((name, rule.decide(&infos, now)));
+    }
+    plan.write_to(buf)?;
+    buf.flush()?;
+    let deletions = plan.deletions();
+    let count: usize = deletions.iter().map(|d| d.digests.len()).sum();
+    if dry_run || count == 0 {
+        return Ok(());
+    }
+    if !yes {
+        let stdin = std::io::stdin();
+        let question = format!("Delete {count} manifests?");
+        if !confirm(&mut stdin.lock(), stdin.is_terminal(), &question)? {
+            log::info!("Nothing deleted");
+            return Ok(());
+        }
+    }
+
+    let options = DeleteOptions {
+        tag_only: false,
+        force: false,
+        cascade,
+        jobs,
+    };
+    let mut failed = Vec::new();
+    for deletions in deletions {
+        let repository = deletions.repository;
+        let endpoint = Endpoint {
+            client: client.clone(),
+            image: parse_repository(repository, registry_url)?,
+        };
+        match prune_repository(&endpoint, &deletions.digests, &deletions.kept, &options).await {
+            Ok(report) => {
+                report.write_to(buf)?;
+                if report.failed() > 0 {
+                    failed.push(repository);
+                }
+            }
+            Err(e) => {
+                log::error!("{repository}: {e}");
+                failed.push(repository);
+            }
+        }
+    }
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(ApiError::DeleteFailed(format!(
+            "manifests in {}",
+            failed.join(", ")
+        )))
+    }
+}
+
+/// Ask `question` on standard error and read the answer from `input`.
+///
+/// Returns whether the answer is `y` or `yes`, in any case.  Nothing is
+/// asked when `input` is not `interactive`, since nobody could answer.
+///
+/// # Errors
+///
+/// * [`ApiError::NotConfirmed`] — `input` is not `interactive`.
+/// * [`ApiError::IOError`] — reading the answer failed.
+fn confirm(input: &mut dyn BufRead, interactive: bool, question: &str) -> Result<bool, ApiError> {
+    if !interactive {
+        return Err(ApiError::NotConfirmed(format!(
+            "{question} Standard input is not a terminal; pass --yes to proceed"
+        )));
+    }
+    eprint!("{question} [y/N] ");
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Delete the manifests of `digests` from the repository of `endpoint`
+/// with [`delete::delete_tags`], except those that a tag of `kept` now
+/// points at, which are reported as failed.
+async fn prune_repository(
+    endpoint: &Endpoint,
+    digests: &[String],
+    kept: &[String],
+    options: &DeleteOptions,
+) -> Result<delete::Report, ApiError> {
+    let current = tags::lookup_all(kept, options.jobs, |tag| {
+        let client = endpoint.client.clone();
+        let url = endpoint.image.manifest_url(&tag);
+        async move {
+            match api::resolve_digest(&client, &url?).await {
+                Err(ApiError::NotFound) => Ok(None),
+                result => result.map(Some),
+            }
+        }
+    })
+    .await?;
+    let mut moved: BTreeMap<&String, &String> = BTreeMap::new();
+    for (tag, digest) in &current {
+        if let Some(digest) = digest {
+            moved.entry(digest).or_insert(tag);
+        }
+    }
+
+    let (skipped, digests): (Vec<&String>, Vec<&String>) =
+        digests.iter().partition(|d| moved.contains_key(d));
+    let digests: Vec<String> = digests.into_iter().cloned().collect();
+    let mut report = delete::delete_tags(endpoint, &[], &digests, options).await?;
+    for digest in skipped {
+        let tag = moved[digest];
+        log::warn!("Not deleting {digest}, since {tag} now points at it");
+        report.outcomes.push(delete::Outcome {
+            tag: digest.clone(),
+            digest: None,
+            error: Some(format!("{tag} now points at it, and is kept")),
+        });
+    }
+    report.outcomes.sort_by(|a, b| a.tag.cmp(&b.tag));
+    Ok(report)
+}
+
+/// List every tag of a repository that points at the same manifest as
+/// `reference`.
+///
+/// `reference` is either a tag, resolved with [`api::resolve_digest`], or a
+/// `sha256:` digest.  Every tag of `name` is resolved with
+/// [`tags::resolve_all`], up to `jobs` at a time, and those with the same
+/// digest are written to `buf`, one per line in name order.  Since the
+/// registry deletes manifests by digest, these are the tags a delete takes
+/// with it.
+///
+/// With `children`, every distinct manifest is also fetched with
+/// [`tags::index_entries`] to follow the children of indexes: a tag whose
+/// index lists the manifest as one of its platforms is written as
+/// `TAG (index DIGEST)`, and when the manifest is itself an index, a tag
+/// pointing at one of its platforms is written as `TAG (platform
+/// PLATFORM)`.
+///
+/// # Arguments
+///
+/// * `buf` — Output sink (typically stdout or a test buffer).
+/// * `registry_url` — Default registry for references that name none.
+/// * `name` — The repository to search (e.g. `"myorg/backend"`).
+/// * `reference` — Tag or digest of the manifest to look for.
+/// * `children` — Also follow the children of indexes.
+/// * `jobs` — Maximum number of tags resolved concurrently.
+///
+/// # Errors
+///
+/// * [`ApiError::InvalidReference`] — `name` could not be parsed, or
+///   `reference` is neither a tag nor a digest.
+/// * [`ApiError::UnsupportedDigest`] — `reference` is a malformed digest.
+/// * [`ApiError::IOError`] — writing to `buf` failed.
+/// * Any error returned by [`api::list_tags`], [`tags::resolve_all`], or
+///   [`tags::index_entries`].
+pub async fn aliases_handler(
+    buf: &mut dyn Write,
+    registry_url: &Url,
+    name: &str,
+    reference: &str,
+    children: bool,
+    jobs: usize,
+) -> Result<(), ApiError> {
+    log::trace!(
+        "aliases_handler(registry_url: {registry_url:?}, name: {name}, reference: {reference}, children: {children})"
+    );
+
+    let client = api::build_client()?;
+    let repository = parse_repository(name, registry_url)?;
+    let target = if reference.contains(':') {
+        digest::encoded(reference)?;
+        String::from(reference)
+    } else if crate::reference::is_valid_tag(reference) {
+        api::resolve_digest(&client, &repository.manifest_url(reference)?).await?
+    } else {
+        return Err(ApiError::InvalidReference(format!(
+            "{reference}: expected a tag or digest"
+        )));
+    };
+    log::debug!("Looking for tags of {target}");
+
+    let all = api::list_tags(&client, &repository.registry, &repository.repository).await?;
+    let digests = tags::resolve_all(&client, &repository, &all, jobs).await?;
+
+    let entries = if children {
+        let distinct: BTreeSet<String> =
+            digests.values().cloned().chain([target.clone()]).collect();
+        let distinct: Vec<String> = distinct.into_iter().collect();
+        tags::index_entries(&client, &repository, &distinct, jobs).await?
+    } else {
+        BTreeMap::new()
+    };
+    let platforms = entries.get(&target).map_or(&[][..], Vec::as_slice);
+
+    for (tag, digest) in &digests {
+        if *digest == target {
+            writeln!(buf, "{tag}")?;
+        } else if entries
+            .get(digest)
+            .is_some_and(|e| e.iter().any(|d| d.digest == target))
+        {
+            writeln!(buf, "{tag} (index {digest})")?;
+        } else if let Some(child) = platforms.iter().find(|d| d.digest == *digest) {
+            let platform = child
+                .platform
+                .as_ref()
+                .map_or_else(|| String::from("unknown"), ToString::to_string);
+            writeln!(buf, "{tag} (platform {platform})")?;
+        }
+    }
+    Ok(())
+}
+
+/// Give an existing manifest another tag in the same repository, entirely
+/// on the registry.
+///
+/// `image` is a reference of the form `[REGISTRY/]REPOSITORY[:TAG|@DIGEST]`.
+/// Its manifest is fetched with [`api::get_manifest`] and uploaded,
+/// byte for byte and with the same media type, to
+/// `/v2/<repository>/manifests/<tag>` with [`api::put_manifest`].  No blob is
+/// transferred, and for an index every platform keeps working because the
+/// child manifests are already in the repository.  The digest is therefore
+/// unchanged; it is verified against the digest the registry reports and
+/// written to `buf`.
+///
+/// # Arguments
+///
+/// * `buf` — Output sink (typically stdout or a test buffer).
+/// * `registry_url` — Default registry for references that name none.
+/// * `image` — Reference of the manifest to tag.
+/// * `tag` — The new tag.
+///
+/// # Errors
+///
+/// * [`ApiError::InvalidReference`] — `image` could not be parsed, or
+///   `tag` is not a valid tag.
+/// * [`ApiError::DigestMismatch`] — the registry stored the manifest under
+///   a different digest.
+/// * [`ApiError::IOError`] — writing to `buf` failed.
+/// * Any error returned by [`api::get_manifest`] or [`api::put_manifest`].
+pub async fn tag_handler(
+    buf: &mut dyn Write,
+    registry_url: &Url,
+    image: &str,
+    tag: &str,
+) -> Result<(), ApiError> {
+    log::trace!("tag_handler(registry_url: {registry_url:?}, image: {image}, tag: {tag})");
+
+    if !crate::reference::is_valid_tag(tag) {
+        return Err(ApiError::InvalidReference(format!(
+            "{tag}: not a valid tag"
+        )));
+    }
+    let client = api::build_transfer_client(None)?;
+    let reference = ImageRef::parse(image, registry_url)?;
+    let raw = api::get_manifest(
+        &client,
+        &reference.manifest_url(&reference.reference_or_latest())?,
+    )
+    .await?;
+    log::debug!("Tagging {} as {tag}", raw.digest);
+
+    let media_type = raw.descriptor()?.media_type;
+    let url = reference.manifest_url(tag)?;
+    let digest = api::put_manifest(&client, &url, &media_type, &raw.bytes).await?;
+    digest::verify(&raw.digest, &digest)?;
+    writeln!(buf, "{digest}")?;
+    Ok(())
+}
+
+/// Verify that the registry endpoint implements Docker Distribution API v2.
+///
+/// Sends a `GET` request to `/v2` and validates the response with
+/// [`api::parse_response_status`].  On success writes `"Ok\n"` to `buf`.
+///
+/// # Arguments
+///
+/// * `buf` — Output sink (typically stdout or a test buffer).
+/// * `registry_url` — Base URL of the Docker Registry.
+///
+/// # Errors
+///
+/// * [`ApiError::HttpError`] — the HTTP client could not be constructed, or the
+///   request failed at the transport layer.
+/// * [`ApiError::UrlParseError`] — the `/v2` URL could not be constructed.
+/// * [`ApiError::ResponseHeaderParseError`] — the version header contains
+///   non-UTF-8 bytes.
+/// * [`ApiError::UnexpectedResponse`] — the `Docker-Distribution-API-Version`
+///   header is absent from the response.
+/// * [`ApiError::UnsupportedVersion`] — the version header has a value other
+///   than `"registry/2.0"`.
+/// * [`ApiError::AuthorizationFailed`] — the registry requires authentication.
+/// * [`ApiError::NotFound`] — the `/v2` endpoint does not exist.
+/// * [`ApiError::MethodNotAllowed`] — the registry rejected the request method.
+/// * [`ApiError::IOError`] — writing `"Ok\n"` to `buf` failed.
+pub async fn check_handler(buf: &mut dyn Write, registry_url: &Url) -> Result<(), ApiError> {
+    log::trace!("check_handler(registry_url: {registry_url:?})");
+
+    let path = "/v2";
+    let url = registry_url.join(path)?;
+
+    let client = api::build_client()?;
+    let resp = client.get(url).send().await?;
+    api::parse_response_status(&resp)?;
+    writeln!(buf, "Ok")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use indoc::indoc;
+    use url::Url;
+
+    use crate::error;
+
+    use super::*;
+
+    /// Validate the happy path for the catalog handler.
+    ///
+    /// This test spins up a mock server, and makes a request to the catalog
+    /// endpoint.  It checks that the handler both called the request the
+    /// expected number of times, and did not return an error.
+    #[tokio::test]
+    async fn test_catalog_handler() {
+        let mut server = mockito::Server::new_async().await;
+        let path = "/v2/_catalog";
+
+        let registry_url = Url::parse(&server.url()).expect("Failed to parse registry URL");
+        let mock_response = server
+            .mock("GET", path)
+            .with_status(http::status::StatusCode::OK.as_u16().into())
+            .with_header(http::header::CONTENT_TYPE.as_str(), "application/json")
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .with_body(r#"{"repositories": ["image1", "image2", "image3"]}"#)
+            .create();
+
+        let mut buf: Vec<u8> = Vec::new();
+        let result =
+            catalog_handler(&mut buf, &registry_url, &Filter::default(), None, false).await;
+        assert!(result.is_ok(), "{:?}", result.unwrap_err());
+        assert_eq!(String::from_utf8(buf).unwrap(), *"image1\nimage2\nimage3\n");
+
+        mock_response.assert();
+    }
+
+    /// Validate the pagination of the catalog handler.
+    ///
+    /// This test spins up a mock server, and makes a request to the catalog
+    /// endpoint.  The response includes a pagination link, which the handler
+    /// should follow, resulting in the combined list.  It checks that the
+    /// handler both called the request the expected number of times, and did
+    /// not return an error.
+    #[tokio::test]
+    async fn test_catalog_handler_with_pagination() {
+        let mut server = mockito::Server::new_async().await;
+        let path = "/v2/_catalog";
+        let path2 = "/v2/_catalog?n=2,last=image2";
+
+        let registry_url = Url::parse(&server.url()).expect("Failed to parse registry URL");
+        let mock_response = server
+            .mock("GET", path)
+            .with_status(http::status::StatusCode::OK.as_u16().into())
+            .with_header(http::header::CONTENT_TYPE.as_str(), "application/json")
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .with_header(
+                http::header::LINK.as_str(),
+                &format!(r"<{path2}>; rel=next"),
+            )
+            .with_body(r#"{"repositories": ["image1", "image2"]}"#)
+            .create();
+
+        let mock_response2 = server
+            .mock("GET", path2)
+            .with_status(http::status::StatusCode::OK.as_u16().into())
+            .with_header(http::header::CONTENT_TYPE.as_str(), "application/json")
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .with_body(r#"{"repositories": ["image3"]}"#)
+            .create();
+
+        let mut buf: Vec<u8> = Vec::new();
+        let result =
+            catalog_handler(&mut buf, &registry_url, &Filter::default(), None, false).await;
+        assert!(result.is_ok(), "{:?}", result.unwrap_err());
+        assert_eq!(String::from_utf8(buf).unwrap(), *"image1\nimage2\nimage3\n");
+
+        mock_response.assert();
+        mock_response2.assert();
+    }
+
+    /// Validate the happy path for the tags handler.
+    ///
+    /// This test spins up a mock server, and makes a request to the tags
+    /// endpoint.  It checks that the handler both called the request the
+    /// expected number of times, and did not return an error.
+    #[tokio::test]
+    async fn test_tags_handler() {
+        let mut server = mockito::Server::new_async().await;
+        let path = "/v2/some_image/tags/list";
+
+        // Mock the HTTP response for the Docker Registry API
+        let registry_url = Url::parse(&server.url()).expect("Failed to parse registry URL");
+        let mock_response = server
+            .mock("GET", path)
+            .with_status(http::status::StatusCode::OK.as_u16().into())
+            .with_header(http::header::CONTENT_TYPE.as_str(), "application/json")
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .with_body(r#"{"tags": ["tag1", "tag2", "tag3"]}"#)
+            .create();
+
+        let mut buf: Vec<u8> = Vec::new();
+        let result = tags_handler(
+            &mut buf,
+            &registry_url,
+            "some_image",
+            &Query::default(),
+            false,
+            false,
+            1,
+        )
+        .await;
+        assert!(result.is_ok(), "{:?}", result.unwrap_err());
+        assert_eq!(String::from_utf8(buf).unwrap(), *"tag1\ntag2\ntag3\n");
+
+        mock_response.assert();
+    }
+
+    /// Validate the pagination of the tags handler.
+    ///
+    /// This test spins up a mock server, and makes a request to the tags
+    /// endpoint.  The response includes a pagination link, which the handler
+    /// should follow, resulting in the combined list.  It checks that the
+    /// handler both called the request the expected number of times, and did
+    /// not return an error.
+    #[tokio::test]
+    async fn test_tags_handler_with_pagination() {
+        let mut server = mockito::Server::new_async().await;
+        let path = "/v2/some_image/tags/list";
+        let path2 = "/v2/some_image/tags/list?n=2,last=tag2";
+
+        // Mock the HTTP response for the Docker Registry API
+        let registry_url = Url::parse(&server.url()).expect("Failed to parse registry URL");
+        let mock_response = server
+            .mock("GET", path)
+            .with_status(http::status::StatusCode::OK.as_u16().into())
+            .with_header(http::header::CONTENT_TYPE.as_str(), "application/json")
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .with_header(
+                http::header::LINK.as_str(),
+                &format!(r"<{path2}>; rel=next"),
+            )
+            .with_body(r#"{"tags": ["tag1", "tag2"]}"#)
+            .create();
+
+        let mock_response2 = server
+            .mock("GET", path2)
+            .with_status(http::status::StatusCode::OK.as_u16().into())
+            .with_header(http::header::CONTENT_TYPE.as_str(), "application/json")
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .with_body(r#"{"tags": ["tag3"]}"#)
+            .create();
+
+        let mut buf: Vec<u8> = Vec::new();
+        let result = tags_handler(
+            &mut buf,
+            &registry_url,
+            "some_image",
+            &Query::default(),
+            false,
+            false,
+            1,
+        )
+        .await;
+        assert!(result.is_ok(), "{:?}", result.unwrap_err());
+        assert_eq!(String::from_utf8(buf).unwrap(), *"tag1\ntag2\ntag3\n");
+
+        mock_response.assert();
+        mock_response2.assert();
+    }
+
+    /// Test that the catalog is filtered by namespace and patterns, and
+    /// that `count` writes only the number of selected repositories.
+    #[tokio::test]
+    async fn test_catalog_handler_filters() -> Result<(), Box<dyn Error>> {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/v2/_catalog")
+            .with_status(200)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .with_body(
+                r#"{"repositories": ["myorg/api", "myorg/api-test", "myorg/web", "myorgx/api", "other/api"]}"#,
+            )
+            .create();
+        let registry_url = Url::parse(&server.url())?;
+        let filter = Filter {
+            include: vec!["*api*".parse()?],
+            exclude: vec!["/-test$/".parse()?],
+        };
+
+        let mut buf = Vec::new();
+        catalog_handler(&mut buf, &registry_url, &filter, Some("myorg/"), false).await?;
+        assert_eq!(String::from_utf8(buf)?, "myorg/api\n");
+
+        let mut buf = Vec::new();
+        catalog_handler(&mut buf, &registry_url, &filter, None, true).await?;
+        assert_eq!(String::from_utf8(buf)?, "3\n");
+        Ok(())
+    }
+
+    /// Test that tags are filtered across pages and counted.
+    #[tokio::test]
+    async fn test_tags_handler_filters() -> Result<(), Box<dyn Error>> {
+        let mut server = mockito::Server::new_async().await;
+        let next = "/v2/foo/tags/list?last=pr-2";
+        server
+            .mock("GET", "/v2/foo/tags/list")
+            .with_status(200)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .with_header("link", &format!("<{next}>; rel=next"))
+            .with_body(r#"{"tags": ["latest", "pr-1", "pr-2"]}"#)
+            .create();
+        server
+            .mock("GET", next)
+            .with_status(200)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .with_body(r#"{"tags": ["pr-3-wip", "v1"]}"#)
+            .create();
+        let registry_url = Url::parse(&server.url())?;
+        let query = Query {
+            filter: Filter {
+                include: vec!["pr-*".parse()?],
+                exclude: vec!["*-wip".parse()?],
+            },
+            ..Query::default()
+        };
+
+        let mut buf = Vec::new();
+        tags_handler(&mut buf, &registry_url, "foo", &query, false, false, 1).await?;
+        assert_eq!(String::from_utf8(buf)?, "pr-1\npr-2\n");
+
+        let mut buf = Vec::new();
+        tags_handler(
+            &mut buf,
+            &registry_url,
+            "foo",
+            &Query::default(),
+            false,
+            true,
+            1,
+        )
+        .await?;
+        assert_eq!(String::from_utf8(buf)?, "5\n");
+        Ok(())
+    }
+
+    /// Test that `--sort date` orders tags by the creation time of their
+    /// images, and that `--long` describes each tag, looking through an
+    /// index to its platforms and skipping attestations.  The registry only
+    /// answers a `HEAD` whose `Accept` header lists the manifest's media
+    /// type, so the index tag must resolve to the index digest.  A tag that
+    /// cannot be described is listed last, with `-` for what it points at.
+    #[tokio::test]
+    async fn test_tags_handler_long_sort_date() -> Result<(), Box<dyn Error>> {
+        fn mock_manifest(
+            server: &mut mockito::Server,
+            references: &[&str],
+            media_type: &str,
+            body: &str,
+        ) -> String {
+            let digest = digest::sha256(body.as_bytes());
+            for reference in references.iter().copied().chain([digest.as_str()]) {
+                let path = format!("/v2/foo/manifests/{reference}");
+                server
+                    .mock("HEAD", &*path)
+                    .match_header(
+                        "accept",
+                        mockito::Matcher::Regex(media_type.replace('.', r"\.").replace('+', r"\+")),
+                    )
+                    .with_status(200)
+                    .with_header("Docker-Distribution-API-Version", "registry/2.0")
+                    .with_header("docker-content-digest", &digest)
+                    .create();
+                server
+                    .mock("GET", &*path)
+                    .with_status(200)
+                    .with_header("content-type", media_type)
+                    .with_header("Docker-Distribution-API-Version", "registry/2.0")
+                    .with_body(body)
+                    .create();
+            }
+            digest
+        }
+
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/v2/foo/tags/list")
+            .with_status(200)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .with_body(r#"{"tags": ["new", "old", "multi", "gone", "ignored"]}"#)
+            .create();
+        server
+            .mock("HEAD", "/v2/foo/manifests/gone")
+            .with_status(404)
+            .create();
+        let mut digests = Vec::new();
+        for (tag, created) in [
+            ("new", "2024-03-01T00:00:00Z"),
+            ("old", "2023-01-01T00:00:00Z"),
+            ("child", "2024-01-01T00:00:00+02:00"),
+        ] {
+            let config = format!(
+                r#"{{"os":"linux","architecture":"amd64","created":"{created}","rootfs":{{"type":"layers","diff_ids":[]}}}}"#
+            );
+            let config_digest = digest::sha256(config.as_bytes());
+            server
+                .mock("GET", &*format!("/v2/foo/blobs/{config_digest}"))
+                .with_status(200)
+                .with_body(config)
+                .create();
+            let manifest = format!(
+                r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","config":{{"mediaType":"application/vnd.oci.image.config.v1+json","digest":"{config_digest}","size":100}},"layers":[]}}"#
+            );
+            let media_type = "application/vnd.oci.image.manifest.v1+json";
+            digests.push(mock_manifest(&mut server, &[tag], media_type, &manifest));
+        }
+        let index = format!(
+            r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.index.v1+json","manifests":[
+                {{"mediaType":"application/vnd.oci.image.manifest.v1+json","digest":"{}","size":1,"platform":{{"os":"linux","architecture":"arm64","variant":"v8"}}}},
+                {{"mediaType":"application/vnd.oci.image.manifest.v1+json","digest":"{}","size":1,"platform":{{"os":"unknown","architecture":"unknown"}}}}]}}"#,
+            digests[2],
+            digest::sha256(b"attestation"),
+        );
+        let media_type = "application/vnd.oci.image.index.v1+json";
+        digests.push(mock_manifest(&mut server, &["multi"], media_type, &index));
+        let registry_url = Url::parse(&server.url())?;
+        let query = Query {
+            filter: Filter {
+                include: vec![],
+                exclude: vec!["ignored".parse()?],
+            },
+            sort: Some(SortOrder::Date),
+            ..Query::default()
+        };
+
+        let mut buf = Vec::new();
+        tags_handler(&mut buf, &registry_url, "foo", &query, false, false, 2).await?;
+        assert_eq!(String::from_utf8(buf)?, "old\nmulti\nnew\ngone\n");
+
+        let mut buf = Vec::new();
+        tags_handler(&mut buf, &registry_url, "foo", &query, true, false, 2).await?;
+        let expected = format!(
+            "TAG    {:<71}   SIZE  PLATFORMS       CREATED\n\
+             old    {}  100 B  linux/amd64     2023-01-01 00:00\n\
+             multi  {}  100 B  linux/arm64/v8  2023-12-31 22:00\n\
+             new    {}  100 B  linux/amd64     2024-03-01 00:00\n\
+             gone   {:<71}      -  -               -\n",
+            "DIGEST", digests[1], digests[3], digests[0], "-"
+        );
+        assert_eq!(String::from_utf8(buf)?, expected);
+        Ok(())
+    }
+
+    /// Validate the happy path for the check handler.
+    ///
+    /// This test spins up a mock server, and makes a request to the check
+    /// endpoint.  It checks that the handler both called the request the
+    /// expected number of times, and did not return an error.
+    #[tokio::test]
+    async fn test_check_handler() {
+        let mut server = mockito::Server::new_async().await;
+        let path = "/v2";
+
+        // Mock the HTTP response for the Docker Registry API
+        let registry_url = Url::parse(&server.url()).expect("Failed to parse registry URL");
+        let mock_response = server
+            .mock("GET", path)
+            .with_status(http::status::StatusCode::OK.as_u16().into())
+            .with_header(http::header::CONTENT_TYPE.as_str(), "application/json")
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .create();
+
+        let mut buf: Vec<u8> = Vec::new();
+        let result = check_handler(&mut buf, &registry_url).await;
+        assert!(result.is_ok(), "{:?}", result.unwrap_err());
+        assert_eq!(String::from_utf8(buf).unwrap(), *"Ok\n");
+
+        mock_response.assert();
+    }
+
+    /// Validate the check handler when the API version header is missing.
+    ///
+    /// This validates that if the "Docker-Distribution-API-Version" header
+    /// is missing in the response, the appropriate error is returned.
+    #[tokio::test]
+    async fn test_check_handler_missing_api_version() -> Result<(), Box<dyn Error>> {
+        let mut server = mockito::Server::new_async().await;
+        let path = "/v2";
+
+        // Mock the HTTP response for the Docker Registry API
+        let registry_url = Url::parse(&server.url()).expect("Failed to parse registry URL");
+        let mock_response = server
+            .mock("GET", path)
+            .with_status(http::status::StatusCode::OK.as_u16().into())
+            .with_header(http::header::CONTENT_TYPE.as_str(), "application/json")
+            .create();
+
+        let mut buf: Vec<u8> = Vec::new();
+        let result = check_handler(&mut buf, &registry_url).await;
+
+        // Ensure that we got the correct error type.
+        assert!(result.is_err());
+        let err = result.unwrap_err();
+        match err {
+            error::ApiError::UnexpectedResponse(_) => Ok(()),
+            e => Err(e),
+        }?;
+
+        mock_response.assert();
+        Ok(())
+    }
+
+    /// Validate the check handler when the API version header has an unexpected value.
+    ///
+    /// This validates that if the "Docker-Distribution-API-Version" header
+    /// is present in the response but contains an unexpected value, the
+    /// appropriate error is returned.
+    #[tokio::test]
+    async fn test_check_handler_invalid_api_version() -> Result<(), Box<dyn Error>> {
+        let mut server = mockito::Server::new_async().await;
+        let path = "/v2";
+
+        // Mock the HTTP response for the Docker Registry API
+        let registry_url = Url::parse(&server.url()).expect("Failed to parse registry URL");
+        let mock_response = server
+            .mock("GET", path)
+            .with_status(http::status::StatusCode::OK.as_u16().into())
+            .with_header(http::header::CONTENT_TYPE.as_str(), "application/json")
+            .with_header("Docker-Distribution-API-Version", "registry/1.0")
+            .create();
+
+        let mut buf: Vec<u8> = Vec::new();
+        let result = check_handler(&mut buf, &registry_url).await;
+
+        // Ensure that we got the correct error type.
+        assert!(result.is_err());
+        let err = result.unwrap_err();
+        match err {
+            error::ApiError::UnsupportedVersion(_) => Ok(()),
+            e => Err(e),
+        }?;
+
+        mock_response.assert();
+        Ok(())
+    }
+
+    /// Validate the happy path for the show handler.
+    ///
+    /// This test spins up a mock server, and makes a request to the image
+    /// manifests endpoint.  It checks that the handler both called the request
+    /// the expected number of times, and did not return an error.
+    #[tokio::test]
+    async fn test_show_handler() {
+        let mut server = mockito::Server::new_async().await;
+        let path = "/v2/foo/manifests/latest";
+
+        let response_body = r#"
+        {
+               "schemaVersion": 1,
+               "name": "foo",
+               "tag": "latest",
+               "architecture": "amd64",
+               "fsLayers": [
+                  {
+                     "blobSum": "sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4"
+                  },
+                  {
+                     "blobSum": "sha256:7d97e254a0461b0a30b3f443f1daa0d620a3cc6ff4e2714cc1cfd96ace5b7a7e"
+                  }
+               ],
+               "history": [
+                  {
+                     "v1Compatibility": "{\"id\":\"7fe38ce3fe63caeaacf6be64933d0d55adc5c5f48762b20ec6129d1a41691a84\",\"parent\":\"8ca907037d044ff942e9c95562b786f1913d3b05a4bda16ad3ed3e7ee67e8c76\",\"created\":\"2023-09-07T00:21:13.838729514Z\",\"container_config\":{\"Cmd\":[\"/bin/sh -c #(nop)  CMD [\\\"bash\\\"]\"]},\"throwaway\":true}"
+                  },
+                  {
+                     "v1Compatibility": "{\"id\":\"8ca907037d044ff942e9c95562b786f1913d3b05a4bda16ad3ed3e7ee67e8c76\",\"created\":\"2023-09-07T00:21:13.444807009Z\",\"container_config\":{\"Cmd\":[\"/bin/sh -c #(nop) ADD file:cb5fcc80c057b356a31492a20c6e3a75b70ed70a663506c8e97ad730ae32a02d in / \"]}}"
+                  }
+               ],
+               "signatures": [
+                  {
+                     "header": {
+                        "jwk": {
+                           "crv": "P-256",
+                           "kid": "7ZLW:DJCO:GYG4:DCZD:TRO6:QW3Y:Q7Q3:PTXB:JDQX:4DLY:NB2B:4GJJ",
+                           "kty": "EC",
+                           "x": "LXquBoF1_XI3fawa-7UW9Y1Le7j7FiDGS3KB_4gF5hY",
+                           "y": "UT5SniKpELMqL-j9YwL2fZLUHmRIFwori9rUBG18b_k"
+                        },
+                        "alg": "ES256"
+                     },
+                     "signature": "5_paRRhUCmwkAZJrjBfbvOJ341atEjUQuhG7i4kITyG3e_U2yuDqs9X7bHHMtmUTbChSp59NHi124uauAjoxIg",
+                     "protected": "eyJmb3JtYXRMZW5ndGgiOjI3MDIsImZvcm1hdFRhaWwiOiJDbjAiLCJ0aW1lIjoiMjAyMy0wOS0yN1QxMzoyMTo1MloifQ"
+                  }
+               ]
+            }
+        "#;
+        // Mock the HTTP response for the Docker Registry API
+        let registry_url = Url::parse(&server.url()).expect("Failed to parse registry URL");
+        let mock_response = server
+            .mock("GET", path)
+            .with_status(http::status::StatusCode::OK.as_u16().into())
+            .with_header(http::header::CONTENT_TYPE.as_str(), "application/json")
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .with_header(
+                "docker-content-digest",
+                "sha256:0259571889ac87efbfca5b79a0abe9baf626d058ec5f9a5744bace2229d9ed50",
+            )
+            .with_header(
+                "etag",
+                "sha256:0259571889ac87efbfca5b79a0abe9baf626d058ec5f9a5744bace2229d9ed50",
+            )
+            .with_body(response_body)
+            .create();
 
         let mut buf: Vec<u8> = Vec::new();
-        let result = tags_handler(&mut buf, &registry_url, "some_image").await;
+        let result = show_handler(&mut buf, &registry_url, "foo", "latest").await;
+
+        let expected_body = indoc! {"
+        name: foo
+        tag: latest
+        architecture: amd64
+        fsLayers:
+        - blobSum: sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4
+        - blobSum: sha256:7d97e254a0461b0a30b3f443f1daa0d620a3cc6ff4e2714cc1cfd96ace5b7a7e
+        digest: sha256:0259571889ac87efbfca5b79a0abe9baf626d058ec5f9a5744bace2229d9ed50
+        etag: sha256:0259571889ac87efbfca5b79a0abe9baf626d058ec5f9a5744bace2229d9ed50\n"
+        };
+
         assert!(result.is_ok(), "{:?}", result.unwrap_err());
-        assert_eq!(String::from_utf8(buf).unwrap(), *"tag1\ntag2\ntag3\n");
+        assert_eq!(String::from_utf8(buf).unwrap(), *expected_body);
 
         mock_response.assert();
     }
 
-    /// Validate the pagination of the tags handler.
-    ///
-    /// This test spins up a mock server, and makes a request to the tags
-    /// endpoint.  The response includes a pagination link, which the handler
-    /// should follow, resulting in the combined list.  It checks that the
-    /// handler both called the request the expected number of times, and did
-    /// not return an error.
+    /// Validate the happy path for the pull handler.
+    ///
+    /// This test serves an image manifest, its config, and a single layer
+    /// from a mock server, and checks that the handler writes a complete OCI
+    /// image layout and prints the manifest digest.
+    #[tokio::test]
+    async fn test_pull_handler() -> Result<(), Box<dyn Error>> {
+        let mut server = mockito::Server::new_async().await;
+
+        let config = br#"{"architecture":"amd64","os":"linux"}"#;
+        let layer = b"not really a tarball";
+        let manifest = format!(
+            r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","config":{{"mediaType":"application/vnd.oci.image.config.v1+json","digest":"{}","size":{}}},"layers":[{{"mediaType":"application/vnd.oci.image.layer.v1.tar+gzip","digest":"{}","size":{}}}]}}"#,
+            digest::sha256(config),
+            config.len(),
+            digest::sha256(layer),
+            layer.len()
+        );
+
+        let registry_url = Url::parse(&server.url()).expect("Failed to parse registry URL");
+        let manifest_mock = server
+            .mock("GET", "/v2/foo/manifests/v1")
+            .with_status(http::status::StatusCode::OK.as_u16().into())
+            .with_header(
+                http::header::CONTENT_TYPE.as_str(),
+                "application/vnd.oci.image.manifest.v1+json",
+            )
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .with_body(&manifest)
+            .create();
+        let config_mock = server
+            .mock("GET", &*format!("/v2/foo/blobs/{}", digest::sha256(config)))
+            .with_status(http::status::StatusCode::OK.as_u16().into())
+            .with_body(config)
+            .create();
+        let layer_mock = server
+            .mock("GET", &*format!("/v2/foo/blobs/{}", digest::sha256(layer)))
+            .with_status(http::status::StatusCode::OK.as_u16().into())
+            .with_body(layer)
+            .create();
+
+        let output = std::env::temp_dir().join(format!("dredge-pull-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&output);
+
+        let mut buf: Vec<u8> = Vec::new();
+        let result = pull_handler(&mut buf, &registry_url, "foo", "v1", &output, 1, 2).await;
+        assert!(result.is_ok(), "{:?}", result.unwrap_err());
+
+        let manifest_digest = digest::sha256(manifest.as_bytes());
+        assert_eq!(String::from_utf8(buf)?, format!("{manifest_digest}\n"));
+        let blobs = output.join("blobs").join("sha256");
+        assert_eq!(
+            std::fs::read(blobs.join(digest::encoded(&manifest_digest)?))?,
+            manifest.as_bytes()
+        );
+        assert_eq!(
+            std::fs::read(blobs.join(digest::encoded(&digest::sha256(layer))?))?,
+            layer
+        );
+        let index = std::fs::read_to_string(output.join("index.json"))?;
+        assert!(index.contains(&manifest_digest));
+        assert!(index.contains(r#""org.opencontainers.image.ref.name": "v1""#));
+
+        manifest_mock.assert();
+        config_mock.assert();
+        layer_mock.assert();
+        Ok(())
+    }
+
+    /// Test that a layer is selected by index or digest and its entries
+    /// listed after verification.
+    #[tokio::test]
+    async fn test_layer_handler() -> Result<(), Box<dyn Error>> {
+        let mut server = mockito::Server::new_async().await;
+        let base = crate::layer::tests::tarball(&[("etc/", ""), ("etc/os-release", "ID=test\n")]);
+        let top = crate::layer::tests::tarball(&[("app/run", "#!/bin/sh\n")]);
+        let top_blob = crate::layer::tests::gzip(&top);
+        crate::image::tests::serve_image(
+            &mut server,
+            "foo",
+            "v1",
+            &[
+                ("application/vnd.oci.image.layer.v1.tar", base.clone(), base),
+                (
+                    "application/vnd.oci.image.layer.v1.tar+gzip",
+                    top_blob.clone(),
+                    top,
+                ),
+            ],
+        );
+        let registry_url = Url::parse(&server.url())?;
+
+        let mut buf = Vec::new();
+        layer_handler(&mut buf, &registry_url, "foo:v1", "1", None).await?;
+        assert_eq!(
+            String::from_utf8(buf)?,
+            "-rw-r--r--         0/0         10 app/run\n"
+        );
+
+        let mut buf = Vec::new();
+        layer_handler(&mut buf, &registry_url, "foo:v1", "0", None).await?;
+        assert_eq!(String::from_utf8(buf)?.lines().count(), 2);
+
+        let digest = crate::digest::sha256(&top_blob);
+        let mut buf = Vec::new();
+        layer_handler(&mut buf, &registry_url, "foo:v1", &digest, None).await?;
+        assert!(String::from_utf8(buf)?.contains("app/run"));
+
+        let result = layer_handler(&mut Vec::new(), &registry_url, "foo:v1", "2", None).await;
+        assert!(matches!(result, Err(ApiError::LayerNotFound(_))));
+        Ok(())
+    }
+
+    /// Test that `ls` lists the merged filesystem, honouring whiteouts, and
+    /// supports long, recursive, and single-file listings.
+    #[tokio::test]
+    async fn test_ls_handler() -> Result<(), Box<dyn Error>> {
+        let mut server = mockito::Server::new_async().await;
+        let base = crate::layer::tests::tarball(&[
+            ("etc/", ""),
+            ("etc/os-release", "ID=test\n"),
+            ("etc/shadow", "root:*\n"),
+            ("bin/sh", "->/bin/busybox"),
+        ]);
+        let top =
+            crate::layer::tests::tarball(&[("etc/.wh.shadow", ""), ("app/run", "#!/bin/sh\n")]);
+        crate::image::tests::serve_image(
+            &mut server,
+            "foo",
+            "v1",
+            &[
+                ("application/vnd.oci.image.layer.v1.tar", base.clone(), base),
+                ("application/vnd.oci.image.layer.v1.tar", top.clone(), top),
+            ],
+        );
+        let registry_url = Url::parse(&server.url())?;
+
+        let mut buf = Vec::new();
+        ls_handler(&mut buf, &registry_url, "foo:v1", None, false, false, None).await?;
+        assert_eq!(String::from_utf8(buf)?, "app\nbin\netc\n");
+
+        let mut buf = Vec::new();
+        ls_handler(
+            &mut buf,
+            &registry_url,
+            "foo:v1",
+            Some("/etc"),
+            true,
+            false,
+            None,
+        )
+        .await?;
+        assert_eq!(
+            String::from_utf8(buf)?,
+            "-rw-r--r--         0/0          8 1970-01-01 00:00 os-release\n"
+        );
+
+        let mut buf = Vec::new();
+        ls_handler(&mut buf, &registry_url, "foo:v1", None, false, true, None).await?;
+        assert_eq!(
+            String::from_utf8(buf)?,
+            "app\napp/run\nbin\nbin/sh\netc\netc/os-release\n"
+        );
+
+        let mut buf = Vec::new();
+        ls_handler(
+            &mut buf,
+            &registry_url,
+            "foo:v1",
+            Some("bin/sh"),
+            true,
+            false,
+            None,
+        )
+        .await?;
+        assert!(String::from_utf8(buf)?.ends_with(" bin/sh -> /bin/busybox\n"));
+
+        let result = ls_handler(
+            &mut Vec::new(),
+            &registry_url,
+            "foo:v1",
+            Some("etc/shadow"),
+            false,
+            false,
+            None,
+        )
+        .await;
+        assert!(matches!(result, Err(ApiError::PathNotFound(_))));
+        Ok(())
+    }
+
+    /// Serve an image whose top layer deletes one file and adds a symlink
+    /// into the base layer.
+    fn serve_layered_image(server: &mut mockito::Server) {
+        let base = crate::layer::tests::tarball(&[
+            ("usr/lib/", ""),
+            ("usr/lib/os-release", "ID=test\n"),
+            ("etc/", ""),
+            ("etc/shadow", "root:*\n"),
+            ("opt/", ""),
+            ("opt/tool", "v1"),
+        ]);
+        let top = crate::layer::tests::tarball(&[
+            ("etc/.wh.shadow", ""),
+            ("etc/os-release", "->../usr/lib/os-release"),
+            ("opt/tool", "v2"),
+        ]);
+        crate::image::tests::serve_image(
+            server,
+            "foo",
+            "v1",
+            &[
+                ("application/vnd.oci.image.layer.v1.tar", base.clone(), base),
+                (
+                    "application/vnd.oci.image.layer.v1.tar+gzip",
+                    crate::layer::tests::gzip(&top),
+                    top,
+                ),
+            ],
+        );
+    }
+
+    /// Test that `cat` reads the topmost copy of a file, follows symbolic
+    /// links into lower layers, and respects whiteouts.
+    #[tokio::test]
+    async fn test_cat_handler() -> Result<(), Box<dyn Error>> {
+        let mut server = mockito::Server::new_async().await;
+        serve_layered_image(&mut server);
+        let registry_url = Url::parse(&server.url())?;
+
+        let mut buf = Vec::new();
+        cat_handler(&mut buf, &registry_url, "foo:v1", "/opt/tool", None).await?;
+        assert_eq!(buf, b"v2");
+
+        let mut buf = Vec::new();
+        cat_handler(&mut buf, &registry_url, "foo:v1", "etc/os-release", None).await?;
+        assert_eq!(buf, b"ID=test\n");
+
+        let result =
+            cat_handler(&mut Vec::new(), &registry_url, "foo:v1", "etc/shadow", None).await;
+        assert!(matches!(result, Err(ApiError::PathNotFound(_))));
+        let result = cat_handler(&mut Vec::new(), &registry_url, "foo:v1", "etc", None).await;
+        assert!(matches!(result, Err(ApiError::NotAFile(_))));
+        Ok(())
+    }
+
+    /// Test that `extract` leaves out the setuid, setgid and sticky bits of
+    /// the file in the image.
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_extract_handler_setuid() -> Result<(), Box<dyn Error>> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_mode(0o7755);
+        header.set_size(4);
+        builder.append_data(&mut header, "bin/su", &b"root"[..])?;
+        let layer = builder.into_inner()?;
+        let mut server = mockito::Server::new_async().await;
+        crate::image::tests::serve_image(
+            &mut server,
+            "foo",
+            "v1",
+            &[(
+                "application/vnd.oci.image.layer.v1.tar",
+                layer.clone(),
+                layer,
+            )],
+        );
+        let registry_url = Url::parse(&server.url())?;
+        let to = std::env::temp_dir().join(format!("dredge-extract-su-{}", std::process::id()));
+
+        extract_handler(
+            &mut Vec::new(),
+            &registry_url,
+            "foo:v1",
+            "bin/su",
+            &to,
+            None,
+        )
+        .await?;
+        let mode = std::fs::metadata(to.join("su"))?.permissions().mode();
+        std::fs::remove_dir_all(&to)?;
+        assert_eq!(mode & 0o7777, 0o755);
+        Ok(())
+    }
+
+    /// Test that `extract` writes the file into the target directory with
+    /// its mode and leaves no partial file behind.
+    #[tokio::test]
+    async fn test_extract_handler() -> Result<(), Box<dyn Error>> {
+        let mut server = mockito::Server::new_async().await;
+        serve_layered_image(&mut server);
+        let registry_url = Url::parse(&server.url())?;
+        let to = std::env::temp_dir().join(format!("dredge-extract-{}", std::process::id()));
+
+        let mut buf = Vec::new();
+        extract_handler(
+            &mut buf,
+            &registry_url,
+            "foo:v1",
+            "etc/os-release",
+            &to,
+            None,
+        )
+        .await?;
+        let dest = to.join("os-release");
+        assert_eq!(String::from_utf8(buf)?, format!("{}\n", dest.display()));
+        assert_eq!(std::fs::read(&dest)?, b"ID=test\n");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(
+                std::fs::metadata(&dest)?.permissions().mode() & 0o7777,
+                0o644
+            );
+        }
+        assert!(!to.join("os-release.partial").exists());
+
+        let result = extract_handler(
+            &mut Vec::new(),
+            &registry_url,
+            "foo:v1",
+            "etc/shadow",
+            &to,
+            None,
+        )
+        .await;
+        assert!(matches!(result, Err(ApiError::PathNotFound(_))));
+        assert!(!to.join("shadow.partial").exists());
+
+        std::fs::remove_dir_all(&to)?;
+        Ok(())
+    }
+
+    /// Test that `export` writes only the entries a container would see,
+    /// with their contents from the topmost layer.
+    #[tokio::test]
+    async fn test_export_handler() -> Result<(), Box<dyn Error>> {
+        let mut server = mockito::Server::new_async().await;
+        serve_layered_image(&mut server);
+        let registry_url = Url::parse(&server.url())?;
+        let output = std::env::temp_dir().join(format!("dredge-export-{}.tar", std::process::id()));
+
+        let mut buf = Vec::new();
+        export_handler(&mut buf, &registry_url, "foo:v1", &output, None).await?;
+        assert!(String::from_utf8(buf)?.starts_with("6 entries, "));
+
+        let mut archive = tar::Archive::new(std::fs::File::open(&output)?);
+        let mut files = Vec::new();
+        for item in archive.entries()? {
+            let mut item = item?;
+            let mut contents = String::new();
+            std::io::Read::read_to_string(&mut item, &mut contents)?;
+            files.push(format!("{} {contents}", item.path()?.display()));
+        }
+        files.sort();
+        assert_eq!(
+            files,
+            [
+                "etc ",
+                "etc/os-release ",
+                "opt ",
+                "opt/tool v2",
+                "usr/lib ",
+                "usr/lib/os-release ID=test\n",
+            ]
+        );
+        assert!(!blob::suffixed(&output, ".partial").exists());
+        std::fs::remove_file(&output)?;
+        Ok(())
+    }
+
+    /// Test that `analyze` reports wasted bytes and fails below the minimum
+    /// efficiency after writing the report.
+    #[tokio::test]
+    async fn test_analyze_handler() -> Result<(), Box<dyn Error>> {
+        let mut server = mockito::Server::new_async().await;
+        serve_layered_image(&mut server);
+        let registry_url = Url::parse(&server.url())?;
+
+        let mut buf = Vec::new();
+        analyze_handler(&mut buf, &registry_url, "foo:v1", 10, Some(50), None).await?;
+        let report = String::from_utf8(buf)?;
+        assert!(report.contains("19 B in files, 9 B wasted in 2 files, efficiency 52.6%"));
+        assert!(report.contains("7 B  etc/shadow  (layer 0, deleted by layer 1: -)"));
+
+        let mut buf = Vec::new();
+        let result = analyze_handler(&mut buf, &registry_url, "foo:v1", 0, Some(90), None).await;
+        assert!(matches!(result, Err(ApiError::BelowThreshold(_))));
+        assert!(!String::from_utf8(buf)?.contains("Largest wasted files"));
+        Ok(())
+    }
+
+    /// Test that `diff` reports layer, config, and file changes between two
+    /// tags, in both human and JSON form.
+    #[tokio::test]
+    async fn test_diff_handler() -> Result<(), Box<dyn Error>> {
+        let mut server = mockito::Server::new_async().await;
+        let base = crate::layer::tests::tarball(&[("etc/", ""), ("etc/os-release", "ID=test\n")]);
+        let app_v1 = crate::layer::tests::tarball(&[("app/run", "v1"), ("app/old", "x")]);
+        let app_v2 = crate::layer::tests::tarball(&[("app/run", "v2"), ("app/new", "y")]);
+        let layer = |tar: &Vec<u8>| {
+            (
+                "application/vnd.oci.image.layer.v1.tar",
+                tar.clone(),
+                tar.clone(),
+            )
+        };
+        crate::image::tests::serve_image(&mut server, "foo", "v1", &[layer(&base), layer(&app_v1)]);
+        crate::image::tests::serve_image(&mut server, "foo", "v2", &[layer(&base), layer(&app_v2)]);
+        let registry_url = Url::parse(&server.url())?;
+
+        let mut buf = Vec::new();
+        diff_handler(&mut buf, &registry_url, "foo:v1", "foo:v2", false, None).await?;
+        let output = String::from_utf8(buf)?;
+        assert!(output.contains("Layers: 1 shared, 1 only in a, 1 only in b\n"));
+        assert!(output.contains("Config: 0 changed\n"));
+        assert!(output.contains(
+            "Files: 1 added, 1 removed, 1 modified\n  + app/new\n  - app/old\n  ~ app/run (content)\n"
+        ));
+
+        let mut buf = Vec::new();
+        diff_handler(&mut buf, &registry_url, "foo:v1", "foo:v2", true, None).await?;
+        let json: serde_json::Value = serde_json::from_slice(&buf)?;
+        assert_eq!(json["layers"]["shared"].as_array().map(Vec::len), Some(1));
+        assert_eq!(json["files"]["modified"][0]["changes"][0], "content");
+        Ok(())
+    }
+
+    /// Test that `packages` reads the topmost dpkg database and lists its
+    /// installed packages.
+    #[tokio::test]
+    async fn test_packages_handler() -> Result<(), Box<dyn Error>> {
+        let mut server = mockito::Server::new_async().await;
+        let base = crate::layer::tests::tarball(&[(
+            "var/lib/dpkg/status",
+            "Package: base-files\nStatus: install ok installed\nVersion: 12.4\nArchitecture: amd64\n",
+        )]);
+        let top = crate::layer::tests::tarball(&[(
+            "var/lib/dpkg/status",
+            "Package: base-files\nStatus: install ok installed\nVersion: 12.4+deb12u5\nArchitecture: amd64\n\n\
+             Package: libc6\nStatus: install ok installed\nVersion: 2.36-9\nArchitecture: amd64\nSource: glibc\n",
+        )]);
+        let layer = |tar: &Vec<u8>| {
+            (
+                "application/vnd.oci.image.layer.v1.tar",
+                tar.clone(),
+                tar.clone(),
+            )
+        };
+        crate::image::tests::serve_image(&mut server, "foo", "v1", &[layer(&base), layer(&top)]);
+        let registry_url = Url::parse(&server.url())?;
+
+        let mut buf = Vec::new();
+        packages_handler(&mut buf, &registry_url, "foo:v1", false, None).await?;
+        assert_eq!(
+            String::from_utf8(buf)?,
+            "NAME        VERSION       ARCH   SOURCE      TYPE\n\
+             base-files  12.4+deb12u5  amd64  base-files  dpkg\n\
+             libc6       2.36-9        amd64  glibc       dpkg\n"
+        );
+
+        let mut buf = Vec::new();
+        packages_handler(&mut buf, &registry_url, "foo:v1", true, None).await?;
+        let json: serde_json::Value = serde_json::from_slice(&buf)?;
+        assert_eq!(json[1]["source"], "glibc");
+        assert_eq!(json[1]["type"], "dpkg");
+        Ok(())
+    }
+
+    /// Test that `append` mounts the base layer, uploads the new layer and
+    /// a configuration listing both diff IDs, and tags the new manifest.
+    #[tokio::test]
+    async fn test_append_handler() -> Result<(), Box<dyn Error>> {
+        let mut server = mockito::Server::new_async().await;
+        let base = crate::layer::tests::tarball(&[("etc/", ""), ("etc/hostname", "base")]);
+        crate::image::tests::serve_image(
+            &mut server,
+            "foo",
+            "v1",
+            &[(
+                "application/vnd.oci.image.layer.v1.tar",
+                base.clone(),
+                base.clone(),
+            )],
+        );
+        let tar = crate::layer::tests::tarball(&[("etc/ssl/", ""), ("etc/ssl/ca.pem", "cert")]);
+        let dir = std::env::temp_dir().join(format!("dredge-test-append-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("certs.tar");
+        std::fs::write(&path, &tar)?;
+
+        server
+            .mock(
+                "HEAD",
+                mockito::Matcher::Regex(String::from("^/v2/bar/blobs/")),
+            )
+            .with_status(404)
+            .create();
+        let mount = server
+            .mock("POST", "/v2/bar/blobs/uploads/")
+            .match_query(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("mount".into(), digest::sha256(&base)),
+                mockito::Matcher::UrlEncoded("from".into(), "foo".into()),
+            ]))
+            .with_status(201)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .create();
+        server
+            .mock("POST", "/v2/bar/blobs/uploads/")
+            .match_query(mockito::Matcher::Missing)
+            .with_status(202)
+            .with_header("location", "/v2/bar/blobs/uploads/session")
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .expect(2)
+            .create();
+        let config = server
+            .mock("PUT", "/v2/bar/blobs/uploads/session")
+            .match_query(mockito::Matcher::Any)
+            .match_body(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::Regex(format!(
+                    r#""diff_ids":\["{}","{}"\]"#,
+                    digest::sha256(&base),
+                    digest::sha256(&tar)
+                )),
+                mockito::Matcher::Regex(String::from(r#""created_by":"dredge append certs.tar""#)),
+            ]))
+            .with_status(201)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .create();
+        let layer = server
+            .mock("PUT", "/v2/bar/blobs/uploads/session")
+            .match_query(mockito::Matcher::Any)
+            .with_status(201)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .create();
+        let manifest = server
+            .mock("PUT", "/v2/bar/manifests/v2")
+            .match_header("content-type", "application/vnd.oci.image.manifest.v1+json")
+            .with_status(201)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .create();
+        let registry_url = Url::parse(&server.url())?;
+
+        let mut buf = Vec::new();
+        let result =
+            append_handler(&mut buf, &registry_url, "foo:v1", &path, "bar:v2", None, 2).await;
+        std::fs::remove_dir_all(&dir)?;
+        result?;
+
+        mount.assert();
+        config.assert();
+        layer.assert();
+        manifest.assert();
+        assert!(String::from_utf8(buf)?.starts_with("sha256:"));
+        Ok(())
+    }
+
+    /// Test that `mutate` reuses the layers, uploads only a changed
+    /// configuration, and tags the new manifest.
+    #[tokio::test]
+    async fn test_mutate_handler() -> Result<(), Box<dyn Error>> {
+        let mut server = mockito::Server::new_async().await;
+        let base = crate::layer::tests::tarball(&[("etc/", ""), ("etc/hostname", "base")]);
+        crate::image::tests::serve_image(
+            &mut server,
+            "foo",
+            "v1",
+            &[(
+                "application/vnd.oci.image.layer.v1.tar",
+                base.clone(),
+                base.clone(),
+            )],
+        );
+        server
+            .mock("HEAD", &*format!("/v2/foo/blobs/{}", digest::sha256(&base)))
+            .with_status(200)
+            .create();
+        server
+            .mock(
+                "HEAD",
+                mockito::Matcher::Regex(String::from("^/v2/foo/blobs/")),
+            )
+            .with_status(404)
+            .create();
+        server
+            .mock("POST", "/v2/foo/blobs/uploads/")
+            .with_status(202)
+            .with_header("location", "/v2/foo/blobs/uploads/session")
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .create();
+        let config = server
+            .mock("PUT", "/v2/foo/blobs/uploads/session")
+            .match_query(mockito::Matcher::Any)
+            .match_body(mockito::Matcher::PartialJson(serde_json::json!({
+                "created": "2024-01-02T03:04:05Z",
+                "config": {"Labels": {"org.example.owner": "web"}, "User": "app"},
+            })))
+            .with_status(201)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .create();
+        let manifest = server
+            .mock("PUT", "/v2/foo/manifests/v1-labelled")
+            .with_status(201)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .create();
+        let registry_url = Url::parse(&server.url())?;
+
+        let mutation = Mutation {
+            labels: vec!["org.example.owner=web".parse()?],
+            user: Some(String::from("app")),
+            ..Mutation::default()
+        };
+        let mut buf = Vec::new();
+        mutate_handler(
+            &mut buf,
+            &registry_url,
+            "foo:v1",
+            &mutation,
+            "foo:v1-labelled",
+            None,
+            2,
+        )
+        .await?;
+
+        config.assert();
+        manifest.assert();
+        Ok(())
+    }
+
+    /// Test that `flatten` uploads a single layer without the files a later
+    /// layer deleted, and a configuration with one diff ID and one history
+    /// entry.
+    #[tokio::test]
+    async fn test_flatten_handler() -> Result<(), Box<dyn Error>> {
+        let mut server = mockito::Server::new_async().await;
+        serve_layered_image(&mut server);
+        server
+            .mock(
+                "HEAD",
+                mockito::Matcher::Regex(String::from("^/v2/flat/blobs/")),
+            )
+            .with_status(404)
+            .create();
+        server
+            .mock("POST", "/v2/flat/blobs/uploads/")
+            .with_status(202)
+            .with_header("location", "/v2/flat/blobs/uploads/session")
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .expect(2)
+            .create();
+        let config = server
+            .mock("PUT", "/v2/flat/blobs/uploads/session")
+            .match_query(mockito::Matcher::Any)
+            .match_body(mockito::Matcher::Regex(String::from(
+                r#""history":\[\{"created":"[^"]+","created_by":"dredge flatten [^"]*/foo:v1"\}\].*"diff_ids":\["sha256:[0-9a-f]{64}"\]"#,
+            )))
+            .with_status(201)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .create();
+        let layer = server
+            .mock("PUT", "/v2/flat/blobs/uploads/session")
+            .match_query(mockito::Matcher::Any)
+            .with_status(201)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .create();
+        let manifest = server
+            .mock("PUT", "/v2/flat/manifests/v1")
+            .match_body(mockito::Matcher::Regex(String::from(
+                r#""layers":\[\{"mediaType":"application/vnd.oci.image.layer.v1.tar\+gzip","digest":"sha256:[0-9a-f]{64}","size":\d+\}\]"#,
+            )))
+            .with_status(201)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .create();
+        let registry_url = Url::parse(&server.url())?;
+
+        let mut buf = Vec::new();
+        flatten_handler(&mut buf, &registry_url, "foo:v1", "flat:v1", None, 2).await?;
+
+        config.assert();
+        layer.assert();
+        manifest.assert();
+        Ok(())
+    }
+
+    /// Test that the flattened layer lists directories before their
+    /// contents, keeps a hard link to an unchanged file, and gives a hard
+    /// link whose target a later layer replaced its original contents.
+    #[tokio::test]
+    async fn test_flatten_handler_layout() -> Result<(), Box<dyn Error>> {
+        let mut server = mockito::Server::new_async().await;
+        let base = crate::layer::tests::tarball(&[
+            ("usr/", ""),
+            ("usr/lib/", ""),
+            ("usr/lib/libc.so", "libc 1"),
+            ("usr/lib/libc.so.6", "=>usr/lib/libc.so"),
+            ("usr/lib/libm.so", "libm"),
+        ]);
+        let top = crate::layer::tests::tarball(&[
+            ("usr/lib/libc.so", "libc 2"),
+            ("usr/lib/libm.so.6", "=>usr/lib/libm.so"),
+            ("usr/share/", ""),
+            ("usr/share/doc/", ""),
+            ("usr/share/doc/README", "docs"),
+        ]);
+        let layer = |tar: &Vec<u8>| {
+            (
+                "application/vnd.oci.image.layer.v1.tar",
+                tar.clone(),
+                tar.clone(),
+            )
+        };
+        crate::image::tests::serve_image(&mut server, "foo", "v1", &[layer(&base), layer(&top)]);
+        server
+            .mock(
+                "HEAD",
+                mockito::Matcher::Regex(String::from("^/v2/flat/blobs/")),
+            )
+            .with_status(404)
+            .create();
+        server
+            .mock("POST", "/v2/flat/blobs/uploads/")
+            .with_status(202)
+            .with_header("location", "/v2/flat/blobs/uploads/session")
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .create();
+        let uploads = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let captured = uploads.clone();
+        server
+            .mock("PUT", "/v2/flat/blobs/uploads/session")
+            .match_query(mockito::Matcher::Any)
+            .with_status(201)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .with_body_from_request(move |request| {
+                captured
+                    .lock()
+                    .unwrap()
+                    .push(request.body().unwrap().clone());
+                Vec::new()
+            })
+            .create();
+        server
+            .mock("PUT", "/v2/flat/manifests/v1")
+            .with_status(201)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .create();
+        let registry_url = Url::parse(&server.url())?;
+
+        let mut buf = Vec::new();
+        flatten_handler(&mut buf, &registry_url, "foo:v1", "flat:v1", None, 2).await?;
+
+        let blob = uploads
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|body| body.starts_with(&[0x1f, 0x8b]))
+            .cloned()
+            .expect("the layer was uploaded");
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(blob.as_slice()));
+        let mut entries = Vec::new();
+        for item in archive.entries()? {
+            let mut item = item?;
+            let path = item.path()?.display().to_string();
+            if let Some(target) = item.link_name()? {
+                entries.push(format!("{path} => {}", target.display()));
+            } else {
+                let mut contents = String::new();
+                std::io::Read::read_to_string(&mut item, &mut contents)?;
+                entries.push(format!("{path} {contents}"));
+            }
+        }
+        assert_eq!(
+            entries,
+            [
+                "usr ",
+                "usr/lib ",
+                "usr/lib/libc.so libc 2",
+                "usr/lib/libc.so.6 libc 1",
+                "usr/lib/libm.so libm",
+                "usr/lib/libm.so.6 => usr/lib/libm.so",
+                "usr/share ",
+                "usr/share/doc ",
+                "usr/share/doc/README docs",
+            ]
+        );
+        Ok(())
+    }
+
+    /// Test that `rebase` refuses an image not built on the old base, and
+    /// otherwise mounts the new base layer, keeps the application layer,
+    /// and uploads a configuration listing the new diff IDs.
+    #[tokio::test]
+    async fn test_rebase_handler() -> Result<(), Box<dyn Error>> {
+        let mut server = mockito::Server::new_async().await;
+        let layer = |tar: &Vec<u8>| {
+            (
+                "application/vnd.oci.image.layer.v1.tar",
+                tar.clone(),
+                tar.clone(),
+            )
+        };
+        let old = crate::layer::tests::tarball(&[("etc/debian_version", "12.4")]);
+        let new = crate::layer::tests::tarball(&[("etc/debian_version", "12.5")]);
+        let app = crate::layer::tests::tarball(&[("app/serve", "binary")]);
+        crate::image::tests::serve_image(&mut server, "debian", "12.4", &[layer(&old)]);
+        crate::image::tests::serve_image(&mut server, "debian", "12.5", &[layer(&new)]);
+        crate::image::tests::serve_image(&mut server, "app", "v1", &[layer(&old), layer(&app)]);
+        server
+            .mock("HEAD", &*format!("/v2/app/blobs/{}", digest::sha256(&app)))
+            .with_status(200)
+            .create();
+        server
+            .mock(
+                "HEAD",
+                mockito::Matcher::Regex(String::from("^/v2/app/blobs/")),
+            )
+            .with_status(404)
+            .create();
+        let mount = server
+            .mock("POST", "/v2/app/blobs/uploads/")
+            .match_query(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("mount".into(), digest::sha256(&new)),
+                mockito::Matcher::UrlEncoded("from".into(), "debian".into()),
+            ]))
+            .with_status(201)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .create();
+        server
+            .mock("POST", "/v2/app/blobs/uploads/")
+            .match_query(mockito::Matcher::Missing)
+            .with_status(202)
+            .with_header("location", "/v2/app/blobs/uploads/session")
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .create();
+        let config = server
+            .mock("PUT", "/v2/app/blobs/uploads/session")
+            .match_query(mockito::Matcher::Any)
+            .match_body(mockito::Matcher::PartialJson(serde_json::json!({
+                "rootfs": {"diff_ids": [digest::sha256(&new), digest::sha256(&app)]},
+            })))
+            .with_status(201)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .create();
+        let manifest = server
+            .mock("PUT", "/v2/app/manifests/v1-patched")
+            .with_status(201)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .create();
+        let registry_url = Url::parse(&server.url())?;
+
+        let mut buf = Vec::new();
+        let result = rebase_handler(
+            &mut buf,
+            &registry_url,
+            "app:v1",
+            "debian:12.5",
+            "debian:12.5",
+            "app:v1-patched",
+            None,
+            2,
+        )
+        .await;
+        assert!(matches!(result, Err(ApiError::NotBasedOn(_))));
+
+        rebase_handler(
+            &mut buf,
+            &registry_url,
+            "app:v1",
+            "debian:12.4",
+            "debian:12.5",
+            "app:v1-patched",
+            None,
+            2,
+        )
+        .await?;
+        mount.assert();
+        config.assert();
+        manifest.assert();
+        Ok(())
+    }
+
+    /// Test that `tag` uploads the exact manifest bytes and media type under
+    /// the new tag, and rejects invalid tags.
     #[tokio::test]
-    async fn test_tags_handler_with_pagination() {
+    async fn test_tag_handler() -> Result<(), Box<dyn Error>> {
         let mut server = mockito::Server::new_async().await;
-        let path = "/v2/some_image/tags/list";
-        let path2 = "/v2/some_image/tags/list?n=2,last=tag2";
-
-        // Mock the HTTP response for the Docker Registry API
-        let registry_url = Url::parse(&server.url()).expect("Failed to parse registry URL");
-        let mock_response = server
-            .mock("GET", path)
-            .with_status(http::status::StatusCode::OK.as_u16().into())
-            .with_header(http::header::CONTENT_TYPE.as_str(), "application/json")
-            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+        let body = r#"{"schemaVersion": 2, "mediaType": "application/vnd.docker.distribution.manifest.v2+json", "config": {"mediaType": "application/vnd.docker.container.image.v1+json", "digest": "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855", "size": 0}, "layers": []}"#;
+        let digest = digest::sha256(body.as_bytes());
+        server
+            .mock("GET", "/v2/foo/manifests/rc")
+            .with_status(200)
             .with_header(
-                http::header::LINK.as_str(),
-                &format!(r"<{path2}>; rel=next"),
+                "content-type",
+                "application/vnd.docker.distribution.manifest.v2+json",
             )
-            .with_body(r#"{"tags": ["tag1", "tag2"]}"#)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .with_body(body)
             .create();
-
-        let mock_response2 = server
-            .mock("GET", path2)
-            .with_status(http::status::StatusCode::OK.as_u16().into())
-            .with_header(http::header::CONTENT_TYPE.as_str(), "application/json")
+        let put = server
+            .mock("PUT", "/v2/foo/manifests/stable")
+            .match_header(
+                "content-type",
+                "application/vnd.docker.distribution.manifest.v2+json",
+            )
+            .match_body(body)
+            .with_status(201)
             .with_header("Docker-Distribution-API-Version", "registry/2.0")
-            .with_body(r#"{"tags": ["tag3"]}"#)
+            .with_header("docker-content-digest", &digest)
             .create();
+        let registry_url = Url::parse(&server.url())?;
 
-        let mut buf: Vec<u8> = Vec::new();
-        let result = tags_handler(&mut buf, &registry_url, "some_image").await;
-        assert!(result.is_ok(), "{:?}", result.unwrap_err());
-        assert_eq!(String::from_utf8(buf).unwrap(), *"tag1\ntag2\ntag3\n");
+        let mut buf = Vec::new();
+        tag_handler(&mut buf, &registry_url, "foo:rc", "stable").await?;
+        put.assert();
+        assert_eq!(String::from_utf8(buf)?, format!("{digest}\n"));
 
-        mock_response.assert();
-        mock_response2.assert();
+        let result = tag_handler(&mut Vec::new(), &registry_url, "foo:rc", "-bad").await;
+        assert!(matches!(result, Err(ApiError::InvalidReference(_))));
+        Ok(())
     }
 
-    /// Validate the happy path for the check handler.
-    ///
-    /// This test spins up a mock server, and makes a request to the check
-    /// endpoint.  It checks that the handler both called the request the
-    /// expected number of times, and did not return an error.
+    /// Test that `move` copies tags, verifies them at the destination, and
+    /// deletes each source manifest once, even when tags share it.
     #[tokio::test]
-    async fn test_check_handler() {
+    async fn test_move_handler() -> Result<(), Box<dyn Error>> {
         let mut server = mockito::Server::new_async().await;
-        let path = "/v2";
-
-        // Mock the HTTP response for the Docker Registry API
-        let registry_url = Url::parse(&server.url()).expect("Failed to parse registry URL");
-        let mock_response = server
-            .mock("GET", path)
-            .with_status(http::status::StatusCode::OK.as_u16().into())
-            .with_header(http::header::CONTENT_TYPE.as_str(), "application/json")
+        let shared = digest::sha256(b"shared");
+        for repository in ["old", "new"] {
+            server
+                .mock("GET", &*format!("/v2/{repository}/tags/list"))
+                .with_status(200)
+                .with_header("Docker-Distribution-API-Version", "registry/2.0")
+                .with_body(format!(
+                    r#"{{"name":"{repository}","tags":["v1","latest"]}}"#
+                ))
+                .create();
+            for tag in ["v1", "latest"] {
+                server
+                    .mock("HEAD", &*format!("/v2/{repository}/manifests/{tag}"))
+                    .with_status(200)
+                    .with_header("Docker-Distribution-API-Version", "registry/2.0")
+                    .with_header("docker-content-digest", &shared)
+                    .create();
+            }
+        }
+        let delete = server
+            .mock("DELETE", &*format!("/v2/old/manifests/{shared}"))
+            .with_status(202)
             .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .expect(1)
             .create();
+        let registry_url = Url::parse(&server.url())?;
+
+        let mut buf = Vec::new();
+        move_handler(&mut buf, &registry_url, "old", "new", true, false, 1).await?;
+        delete.assert();
+        let host = registry_url.host_str().unwrap_or_default();
+        let port = registry_url.port().unwrap_or_default();
+        assert_eq!(
+            String::from_utf8(buf)?,
+            format!(
+                "skip  latest {shared}\nskip  v1 {shared}\n\
+                 0 tags copied, 2 up to date, 0 pruned, 0 kept\n\
+                 2 tags verified in {host}:{port}/new\n\
+                 1 manifests deleted from {host}:{port}/old\n"
+            )
+        );
 
-        let mut buf: Vec<u8> = Vec::new();
-        let result = check_handler(&mut buf, &registry_url).await;
-        assert!(result.is_ok(), "{:?}", result.unwrap_err());
-        assert_eq!(String::from_utf8(buf).unwrap(), *"Ok\n");
-
-        mock_response.assert();
+        let result =
+            move_handler(&mut Vec::new(), &registry_url, "old", "old", false, true, 1).await;
+        assert!(matches!(result, Err(ApiError::InvalidReference(_))));
+        Ok(())
     }
 
-    /// Validate the check handler when the API version header is missing.
-    ///
-    /// This validates that if the "Docker-Distribution-API-Version" header
-    /// is missing in the response, the appropriate error is returned.
+    /// Test that a bulk delete selects tags by name and pattern, deletes
+    /// each manifest once, and reports the tags it could not delete.
     #[tokio::test]
-    async fn test_check_handler_missing_api_version() -> Result<(), Box<dyn Error>> {
+    async fn test_delete_handler_bulk() -> Result<(), Box<dyn Error>> {
         let mut server = mockito::Server::new_async().await;
-        let path = "/v2";
-
-        // Mock the HTTP response for the Docker Registry API
-        let registry_url = Url::parse(&server.url()).expect("Failed to parse registry URL");
-        let mock_response = server
-            .mock("GET", path)
-            .with_status(http::status::StatusCode::OK.as_u16().into())
-            .with_header(http::header::CONTENT_TYPE.as_str(), "application/json")
+        let preview = digest::sha256(b"preview");
+        let release = digest::sha256(b"release");
+        for (tag, digest) in [("pr-1", &preview), ("pr-2", &preview), ("v1", &release)] {
+            server
+                .mock("HEAD", &*format!("/v2/foo/manifests/{tag}"))
+                .with_status(200)
+                .with_header("Docker-Distribution-API-Version", "registry/2.0")
+                .with_header("docker-content-digest", digest)
+                .create();
+        }
+        server
+            .mock("HEAD", "/v2/foo/manifests/missing")
+            .with_status(404)
             .create();
-
-        let mut buf: Vec<u8> = Vec::new();
-        let result = check_handler(&mut buf, &registry_url).await;
-
-        // Ensure that we got the correct error type.
-        assert!(result.is_err());
-        let err = result.unwrap_err();
-        match err {
-            error::ApiError::UnexpectedResponse(_) => Ok(()),
-            e => Err(e),
-        }?;
-
-        mock_response.assert();
+        server
+            .mock("GET", "/v2/foo/tags/list")
+            .with_status(200)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .with_body(r#"{"tags": ["pr-1", "pr-2", "v1", "v2"]}"#)
+            .create();
+        let deletes: Vec<mockito::Mock> = [&preview, &release]
+            .iter()
+            .map(|digest| {
+                server
+                    .mock("DELETE", &*format!("/v2/foo/manifests/{digest}"))
+                    .with_status(202)
+                    .with_header("Docker-Distribution-API-Version", "registry/2.0")
+                    .expect(1)
+                    .create()
+            })
+            .collect();
+        let registry_url = Url::parse(&server.url())?;
+
+        let tags = [String::from("v1"), String::from("missing")];
+        let options = DeleteOptions {
+            tag_only: false,
+            force: false,
+            cascade: false,
+            jobs: 2,
+        };
+        let mut buf = Vec::new();
+        let result = delete_handler(
+            &mut buf,
+            &registry_url,
+            "foo",
+            &tags,
+            &["pr-*".parse()?],
+            &options,
+        )
+        .await;
+        assert!(matches!(result, Err(ApiError::DeleteFailed(_))));
+        assert_eq!(
+            String::from_utf8(buf)?,
+            format!(
+                "failed  missing Resource not found\n\
+                 deleted pr-1 {preview}\n\
+                 deleted pr-2 {preview}\n\
+                 deleted v1 {release}\n\
+                 3 deleted, 1 failed\n"
+            )
+        );
+        for delete in deletes {
+            delete.assert();
+        }
         Ok(())
     }
 
-    /// Validate the check handler when the API version header has an unexpected value.
-    ///
-    /// This validates that if the "Docker-Distribution-API-Version" header
-    /// is present in the response but contains an unexpected value, the
-    /// appropriate error is returned.
+    /// Test that deleting an index by digest with `cascade` also deletes
+    /// the platform manifests no remaining index refers to.
     #[tokio::test]
-    async fn test_check_handler_invalid_api_version() -> Result<(), Box<dyn Error>> {
+    async fn test_delete_handler_cascade() -> Result<(), Box<dyn Error>> {
         let mut server = mockito::Server::new_async().await;
-        let path = "/v2";
-
-        // Mock the HTTP response for the Docker Registry API
-        let registry_url = Url::parse(&server.url()).expect("Failed to parse registry URL");
-        let mock_response = server
-            .mock("GET", path)
-            .with_status(http::status::StatusCode::OK.as_u16().into())
-            .with_header(http::header::CONTENT_TYPE.as_str(), "application/json")
-            .with_header("Docker-Distribution-API-Version", "registry/1.0")
+        let mut mock_manifest = |tags: &[&str], media_type: &str, body: &str| {
+            let digest = digest::sha256(body.as_bytes());
+            for tag in tags {
+                server
+                    .mock("HEAD", &*format!("/v2/foo/manifests/{tag}"))
+                    .with_status(200)
+                    .with_header("Docker-Distribution-API-Version", "registry/2.0")
+                    .with_header("docker-content-digest", &digest)
+                    .create();
+            }
+            server
+                .mock("GET", &*format!("/v2/foo/manifests/{digest}"))
+                .with_status(200)
+                .with_header("content-type", media_type)
+                .with_header("Docker-Distribution-API-Version", "registry/2.0")
+                .with_body(body)
+                .create();
+            digest
+        };
+        let oci_image = "application/vnd.oci.image.manifest.v1+json";
+        let oci_index = "application/vnd.oci.image.index.v1+json";
+        let index = |children: &[&str]| {
+            let manifests: Vec<String> = children
+                .iter()
+                .map(|d| format!(r#"{{"mediaType":"{oci_image}","digest":"{d}","size":1}}"#))
+                .collect();
+            format!(
+                r#"{{"schemaVersion":2,"mediaType":"{oci_index}","manifests":[{}]}}"#,
+                manifests.join(",")
+            )
+        };
+        let amd64 = digest::sha256(b"amd64");
+        let arm64 = digest::sha256(b"arm64");
+        let old = mock_manifest(&["v1"], oci_index, &index(&[&amd64, &arm64]));
+        mock_manifest(&["v2"], oci_index, &index(&[&arm64]));
+        server
+            .mock("GET", "/v2/foo/tags/list")
+            .with_status(200)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .with_body(r#"{"tags": ["v1", "v2"]}"#)
             .create();
+        let deletes: Vec<mockito::Mock> = [(&old, 1), (&amd64, 1), (&arm64, 0)]
+            .iter()
+            .map(|(digest, hits)| {
+                server
+                    .mock("DELETE", &*format!("/v2/foo/manifests/{digest}"))
+                    .with_status(202)
+                    .with_header("Docker-Distribution-API-Version", "registry/2.0")
+                    .expect(*hits)
+                    .create()
+            })
+            .collect();
+        let registry_url = Url::parse(&server.url())?;
+
+        let options = DeleteOptions {
+            tag_only: false,
+            force: false,
+            cascade: true,
+            jobs: 2,
+        };
+        let mut buf = Vec::new();
+        delete_handler(
+            &mut buf,
+            &registry_url,
+            &format!("foo@{old}"),
+            &[],
+            &[],
+            &options,
+        )
+        .await?;
+        let mut expected = [format!("deleted {old}"), format!("deleted {amd64}")];
+        expected.sort();
+        assert_eq!(
+            String::from_utf8(buf)?,
+            format!("{}\n2 deleted, 0 failed\n", expected.join("\n"))
+        );
+        for delete in deletes {
+            delete.assert();
+        }
 
-        let mut buf: Vec<u8> = Vec::new();
-        let result = check_handler(&mut buf, &registry_url).await;
+        let result =
+            delete_handler(&mut Vec::new(), &registry_url, "foo", &[], &[], &options).await;
+        assert!(matches!(result, Err(ApiError::InvalidReference(_))));
+        Ok(())
+    }
 
-        // Ensure that we got the correct error type.
-        assert!(result.is_err());
-        let err = result.unwrap_err();
-        match err {
-            error::ApiError::UnsupportedVersion(_) => Ok(()),
-            e => Err(e),
-        }?;
+    /// Test that `delete --tag-only` deletes the tag where the registry
+    /// supports it, and otherwise refuses to delete a manifest shared with
+    /// other tags unless forced.
+    #[tokio::test]
+    async fn test_delete_handler_tag_only() -> Result<(), Box<dyn Error>> {
+        let shared = digest::sha256(b"shared");
+        let mock_tag = |server: &mut mockito::Server, tag: &str, digest: &str| {
+            server
+                .mock("HEAD", &*format!("/v2/foo/manifests/{tag}"))
+                .with_status(200)
+                .with_header("Docker-Distribution-API-Version", "registry/2.0")
+                .with_header("docker-content-digest", digest)
+                .create();
+        };
 
-        mock_response.assert();
+        let mut server = mockito::Server::new_async().await;
+        mock_tag(&mut server, "v1", &shared);
+        let delete = server
+            .mock("DELETE", "/v2/foo/manifests/v1")
+            .with_status(202)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .create();
+        let registry_url = Url::parse(&server.url())?;
+        let tags = [String::from("v1")];
+        let mut options = DeleteOptions {
+            tag_only: true,
+            force: false,
+            cascade: false,
+            jobs: 2,
+        };
+        let mut buf = Vec::new();
+        delete_handler(&mut buf, &registry_url, "foo", &tags, &[], &options).await?;
+        delete.assert();
+        assert_eq!(
+            String::from_utf8(buf)?,
+            format!("deleted v1 {shared}\n1 deleted, 0 failed\n")
+        );
+
+        let mut server = mockito::Server::new_async().await;
+        mock_tag(&mut server, "v1", &shared);
+        mock_tag(&mut server, "stable", &shared);
+        mock_tag(&mut server, "v2", &digest::sha256(b"other"));
+        server
+            .mock("DELETE", "/v2/foo/manifests/v1")
+            .with_status(405)
+            .create();
+        server
+            .mock("GET", "/v2/foo/tags/list")
+            .with_status(200)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .with_body(r#"{"tags": ["stable", "v1", "v2"]}"#)
+            .create();
+        let delete = server
+            .mock("DELETE", &*format!("/v2/foo/manifests/{shared}"))
+            .with_status(202)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .expect(1)
+            .create();
+        let registry_url = Url::parse(&server.url())?;
+
+        let mut buf = Vec::new();
+        let result = delete_handler(&mut buf, &registry_url, "foo", &tags, &[], &options).await;
+        assert!(matches!(result, Err(ApiError::DeleteFailed(_))));
+        let output = String::from_utf8(buf)?;
+        assert!(output.starts_with("failed  v1 Manifest is shared with other tags: "));
+        assert!(output.contains(&format!("deleting {shared} would also delete stable (")));
+
+        options.force = true;
+        delete_handler(&mut Vec::new(), &registry_url, "foo", &tags, &[], &options).await?;
+        delete.assert();
         Ok(())
     }
 
-    /// Validate the happy path for the show handler.
-    ///
-    /// This test spins up a mock server, and makes a request to the image
-    /// manifests endpoint.  It checks that the handler both called the request
-    /// the expected number of times, and did not return an error.
+    /// Test that `prune` prints its plan, keeps tags sharing a kept
+    /// manifest, leaves repositories no rule matches alone, and deletes the
+    /// rest unless it is a dry run.
     #[tokio::test]
-    async fn test_show_handler() {
+    async fn test_prune_handler() -> Result<(), Box<dyn Error>> {
         let mut server = mockito::Server::new_async().await;
-        let path = "/v2/foo/manifests/latest";
-
-        let response_body = r#"
-        {
-               "schemaVersion": 1,
-               "name": "foo",
-               "tag": "latest",
-               "architecture": "amd64",
-               "fsLayers": [
-                  {
-                     "blobSum": "sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4"
-                  },
-                  {
-                     "blobSum": "sha256:7d97e254a0461b0a30b3f443f1daa0d620a3cc6ff4e2714cc1cfd96ace5b7a7e"
-                  }
-               ],
-               "history": [
-                  {
-                     "v1Compatibility": "{\"id\":\"7fe38ce3fe63caeaacf6be64933d0d55adc5c5f48762b20ec6129d1a41691a84\",\"parent\":\"8ca907037d044ff942e9c95562b786f1913d3b05a4bda16ad3ed3e7ee67e8c76\",\"created\":\"2023-09-07T00:21:13.838729514Z\",\"container_config\":{\"Cmd\":[\"/bin/sh -c #(nop)  CMD [\\\"bash\\\"]\"]},\"throwaway\":true}"
-                  },
-                  {
-                     "v1Compatibility": "{\"id\":\"8ca907037d044ff942e9c95562b786f1913d3b05a4bda16ad3ed3e7ee67e8c76\",\"created\":\"2023-09-07T00:21:13.444807009Z\",\"container_config\":{\"Cmd\":[\"/bin/sh -c #(nop) ADD file:cb5fcc80c057b356a31492a20c6e3a75b70ed70a663506c8e97ad730ae32a02d in / \"]}}"
-                  }
-               ],
-               "signatures": [
-                  {
-                     "header": {
-                        "jwk": {
-                           "crv": "P-256",
-                           "kid": "7ZLW:DJCO:GYG4:DCZD:TRO6:QW3Y:Q7Q3:PTXB:JDQX:4DLY:NB2B:4GJJ",
-                           "kty": "EC",
-                           "x": "LXquBoF1_XI3fawa-7UW9Y1Le7j7FiDGS3KB_4gF5hY",
-                           "y": "UT5SniKpELMqL-j9YwL2fZLUHmRIFwori9rUBG18b_k"
-                        },
-                        "alg": "ES256"
-                     },
-                     "signature": "5_paRRhUCmwkAZJrjBfbvOJ341atEjUQuhG7i4kITyG3e_U2yuDqs9X7bHHMtmUTbChSp59NHi124uauAjoxIg",
-                     "protected": "eyJmb3JtYXRMZW5ndGgiOjI3MDIsImZvcm1hdFRhaWwiOiJDbjAiLCJ0aW1lIjoiMjAyMy0wOS0yN1QxMzoyMTo1MloifQ"
-                  }
-               ]
-            }
-        "#;
-        // Mock the HTTP response for the Docker Registry API
-        let registry_url = Url::parse(&server.url()).expect("Failed to parse registry URL");
-        let mock_response = server
-            .mock("GET", path)
-            .with_status(http::status::StatusCode::OK.as_u16().into())
-            .with_header(http::header::CONTENT_TYPE.as_str(), "application/json")
+        server
+            .mock("GET", "/v2/_catalog")
+            .with_status(200)
             .with_header("Docker-Distribution-API-Version", "registry/2.0")
-            .with_header(
-                "docker-content-digest",
-                "sha256:0259571889ac87efbfca5b79a0abe9baf626d058ec5f9a5744bace2229d9ed50",
-            )
-            .with_header(
-                "etag",
-                "sha256:0259571889ac87efbfca5b79a0abe9baf626d058ec5f9a5744bace2229d9ed50",
+            .with_body(r#"{"repositories": ["other/tool", "team/app"]}"#)
+            .create();
+        server
+            .mock("GET", "/v2/team/app/tags/list")
+            .with_status(200)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .with_body(r#"{"tags": ["latest", "new", "old"]}"#)
+            .create();
+        let mut digests = BTreeMap::new();
+        for (tags, created) in [
+            (&["latest", "new"][..], "2024-03-01T00:00:00Z"),
+            (&["old"][..], "2023-01-01T00:00:00Z"),
+        ] {
+            let config = format!(
+                r#"{{"os":"linux","architecture":"amd64","created":"{created}","rootfs":{{"type":"layers","diff_ids":[]}}}}"#
+            );
+            let config_digest = digest::sha256(config.as_bytes());
+            server
+                .mock("GET", &*format!("/v2/team/app/blobs/{config_digest}"))
+                .with_status(200)
+                .with_body(config)
+                .create();
+            let manifest = format!(
+                r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","config":{{"mediaType":"application/vnd.oci.image.config.v1+json","digest":"{config_digest}","size":100}},"layers":[]}}"#
+            );
+            let digest = digest::sha256(manifest.as_bytes());
+            for tag in tags {
+                server
+                    .mock("HEAD", &*format!("/v2/team/app/manifests/{tag}"))
+                    .with_status(200)
+                    .with_header("Docker-Distribution-API-Version", "registry/2.0")
+                    .with_header("docker-content-digest", &digest)
+                    .create();
+                digests.insert(*tag, digest.clone());
+            }
+            server
+                .mock("GET", &*format!("/v2/team/app/manifests/{digest}"))
+                .with_status(200)
+                .with_header("content-type", "application/vnd.oci.image.manifest.v1+json")
+                .with_header("Docker-Distribution-API-Version", "registry/2.0")
+                .with_body(manifest)
+                .create();
+        }
+        let delete = server
+            .mock(
+                "DELETE",
+                &*format!("/v2/team/app/manifests/{}", digests["old"]),
             )
-            .with_body(response_body)
+            .with_status(202)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .expect(1)
             .create();
+        let registry_url = Url::parse(&server.url())?;
+        let policy: Policy =
+            serde_norway::from_str("rules:\n- repositories: 'team/*'\n  keep_last: 1\n")?;
+
+        let plan = format!(
+            "keep   team/app:latest (keep_last)\n\
+             keep   team/app:new (same manifest as latest)\n\
+             delete team/app:old {}\n\
+             2 tags kept, 1 to delete in 1 repositories\n",
+            digests["old"]
+        );
+        let mut buf = Vec::new();
+        prune_handler(&mut buf, &registry_url, &policy, true, false, false, 2).await?;
+        assert_eq!(String::from_utf8(buf)?, plan);
+
+        let mut buf = Vec::new();
+        prune_handler(&mut buf, &registry_url, &policy, false, true, false, 2).await?;
+        assert_eq!(
+            String::from_utf8(buf)?,
+            format!("{plan}deleted {}\n1 deleted, 0 failed\n", digests["old"])
+        );
+        delete.assert();
+        Ok(())
+    }
 
-        let mut buf: Vec<u8> = Vec::new();
-        let result = show_handler(&mut buf, &registry_url, "foo", "latest").await;
+    /// Test that `confirm` accepts only a yes, and refuses to ask when
+    /// nobody can answer.
+    #[test]
+    fn test_confirm() -> Result<(), ApiError> {
+        for (answer, expected) in [("y\n", true), ("YES\n", true), ("n\n", false), ("", false)] {
+            let mut input = std::io::Cursor::new(answer);
+            assert_eq!(
+                confirm(&mut input, true, "Delete?")?,
+                expected,
+                "{answer:?}"
+            );
+        }
+        let mut input = std::io::Cursor::new("y\n");
+        assert!(matches!(
+            confirm(&mut input, false, "Delete?"),
+            Err(ApiError::NotConfirmed(_))
+        ));
+        Ok(())
+    }
 
-        let expected_body = indoc! {"
-        name: foo
-        tag: latest
-        architecture: amd64
-        fsLayers:
-        - blobSum: sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4
-        - blobSum: sha256:7d97e254a0461b0a30b3f443f1daa0d620a3cc6ff4e2714cc1cfd96ace5b7a7e
-        digest: sha256:0259571889ac87efbfca5b79a0abe9baf626d058ec5f9a5744bace2229d9ed50
-        etag: sha256:0259571889ac87efbfca5b79a0abe9baf626d058ec5f9a5744bace2229d9ed50\n"
+    /// Test that prune deletes each planned manifest once by digest, and
+    /// not one that a kept tag has moved onto since.
+    #[tokio::test]
+    async fn test_prune_repository() -> Result<(), Box<dyn Error>> {
+        let (old, moved) = ("sha256:aaaa", "sha256:bbbb");
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("HEAD", "/v2/foo/manifests/latest")
+            .with_status(200)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .with_header("docker-content-digest", moved)
+            .create();
+        server
+            .mock("HEAD", "/v2/foo/manifests/gone")
+            .with_status(404)
+            .create();
+        let delete = server
+            .mock("DELETE", &*format!("/v2/foo/manifests/{old}"))
+            .with_status(202)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .expect(1)
+            .create();
+        let kept = server
+            .mock("DELETE", &*format!("/v2/foo/manifests/{moved}"))
+            .expect(0)
+            .create();
+        let registry_url = Url::parse(&server.url())?;
+        let endpoint = Endpoint {
+            client: reqwest::Client::new(),
+            image: ImageRef::parse("foo", &registry_url)?,
+        };
+        let options = DeleteOptions {
+            tag_only: false,
+            force: false,
+            cascade: false,
+            jobs: 2,
         };
 
-        assert!(result.is_ok(), "{:?}", result.unwrap_err());
-        assert_eq!(String::from_utf8(buf).unwrap(), *expected_body);
+        let report = prune_repository(
+            &endpoint,
+            &[String::from(old), String::from(moved)],
+            &[String::from("gone"), String::from("latest")],
+            &options,
+        )
+        .await?;
+        let mut buf = Vec::new();
+        report.write_to(&mut buf)?;
+        assert_eq!(
+            String::from_utf8(buf)?,
+            format!(
+                "deleted {old}\n\
+                 failed  {moved} latest now points at it, and is kept\n\
+                 1 deleted, 1 failed\n"
+            )
+        );
+        delete.assert();
+        kept.assert();
+        Ok(())
+    }
 
-        mock_response.assert();
+    /// Test that `aliases` finds the tags sharing a digest, and with
+    /// `children`, the indexes containing it and the platforms of an index.
+    #[tokio::test]
+    async fn test_aliases_handler() -> Result<(), Box<dyn Error>> {
+        let mut server = mockito::Server::new_async().await;
+        let mut mock_manifest = |tags: &[&str], media_type: &str, body: &str| {
+            let digest = digest::sha256(body.as_bytes());
+            for tag in tags.iter().copied().chain([digest.as_str()]) {
+                server
+                    .mock("HEAD", &*format!("/v2/foo/manifests/{tag}"))
+                    .with_status(200)
+                    .with_header("Docker-Distribution-API-Version", "registry/2.0")
+                    .with_header("docker-content-digest", &digest)
+                    .create();
+            }
+            server
+                .mock("GET", &*format!("/v2/foo/manifests/{digest}"))
+                .with_status(200)
+                .with_header("content-type", media_type)
+                .with_header("Docker-Distribution-API-Version", "registry/2.0")
+                .with_body(body)
+                .create();
+            digest
+        };
+        let image = |n: u8| {
+            format!(
+                r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","config":{{"mediaType":"application/vnd.oci.image.config.v1+json","digest":"{}","size":{n}}},"layers":[]}}"#,
+                digest::sha256(&[n])
+            )
+        };
+        let oci_image = "application/vnd.oci.image.manifest.v1+json";
+        let amd64 = mock_manifest(&["v1", "stable"], oci_image, &image(1));
+        let arm64 = mock_manifest(&["v1-arm64"], oci_image, &image(2));
+        mock_manifest(&["other"], oci_image, &image(3));
+        let index = format!(
+            r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.index.v1+json","manifests":[
+                {{"mediaType":"{oci_image}","digest":"{amd64}","size":1,"platform":{{"os":"linux","architecture":"amd64"}}}},
+                {{"mediaType":"{oci_image}","digest":"{arm64}","size":1,"platform":{{"os":"linux","architecture":"arm64","variant":"v8"}}}}]}}"#
+        );
+        let index = mock_manifest(
+            &["multi"],
+            "application/vnd.oci.image.index.v1+json",
+            &index,
+        );
+        server
+            .mock("GET", "/v2/foo/tags/list")
+            .with_status(200)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .with_body(r#"{"tags": ["v1", "v1-arm64", "multi", "other", "stable"]}"#)
+            .create();
+        let registry_url = Url::parse(&server.url())?;
+
+        let mut buf = Vec::new();
+        aliases_handler(&mut buf, &registry_url, "foo", "v1", false, 2).await?;
+        assert_eq!(String::from_utf8(buf)?, "stable\nv1\n");
+
+        let mut buf = Vec::new();
+        aliases_handler(&mut buf, &registry_url, "foo", "v1", true, 2).await?;
+        assert_eq!(
+            String::from_utf8(buf)?,
+            format!("multi (index {index})\nstable\nv1\n")
+        );
+
+        let mut buf = Vec::new();
+        aliases_handler(&mut buf, &registry_url, "foo", &index, true, 2).await?;
+        assert_eq!(
+            String::from_utf8(buf)?,
+            "multi\nstable (platform linux/amd64)\nv1 (platform linux/amd64)\n\
+             v1-arm64 (platform linux/arm64/v8)\n"
+        );
+
+        let result = aliases_handler(
+            &mut Vec::new(),
+            &registry_url,
+            "foo",
+            "sha256:xyz",
+            false,
+            2,
+        )
+        .await;
+        assert!(matches!(result, Err(ApiError::UnsupportedDigest(_))));
+        Ok(())
     }
 }
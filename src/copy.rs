@@ -0,0 +1,431 @@
+/*
+ * Copyright 2023 Anthony Oteri
+ *
+ * Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+ * http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+ * http://opensource.org/licenses/MIT>, at your option. This file may not be
+ * copied, modified, or distributed except according to those terms.
+ */
+
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use crate::api;
+use crate::api::RawManifest;
+use crate::blob;
+use crate::digest;
+use crate::error::ApiError;
+use crate::manifest::Descriptor;
+use crate::manifest::Manifest;
+use crate::progress::Progress;
+use crate::reference::ImageRef;
+use crate::reference::Reference;
+
+/// One side of a copy: an image reference and the client used to reach its
+/// registry with the right credentials.
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    /// HTTP client carrying this registry's credentials.
+    pub client: reqwest::Client,
+    /// The image reference on this registry.
+    pub image: ImageRef,
+}
+
+/// How a blob will reach the destination repository.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// The destination already has the blob.
+    Skip,
+    /// The blob is linked from the source repository on the same registry.
+    Mount,
+    /// The blob is streamed from the source registry.
+    Copy,
+}
+
+impl Action {
+    /// Short label used in plan output.
+    fn label(self) -> &'static str {
+        match self {
+            Self::Skip => "skip",
+            Self::Mount => "mount",
+            Self::Copy => "copy",
+        }
+    }
+}
+
+/// A blob referenced by the source image and how it will be transferred.
+#[derive(Debug, Clone)]
+pub struct PlannedBlob {
+    /// Descriptor of the blob in the source manifest.
+    pub descriptor: Descriptor,
+    /// How the blob will reach the destination.
+    pub action: Action,
+}
+
+/// Everything needed to copy one image, resolved before anything is written.
+#[derive(Debug, Clone)]
+pub struct CopyPlan {
+    /// The manifest the source reference points to.
+    pub top: RawManifest,
+    /// Per-platform manifests when `top` is an index; empty otherwise.
+    pub children: Vec<RawManifest>,
+    /// Every distinct blob referenced by the image, in manifest order.
+    pub blobs: Vec<PlannedBlob>,
+}
+
+impl CopyPlan {
+    /// Total size in bytes of the blobs with the given `action`.
+    pub fn bytes(&self, action: Action) -> u64 {
+        self.blobs
+            .iter()
+            .filter(|b| b.action == action)
+            .map(|b| b.descriptor.size)
+            .sum()
+    }
+
+    /// Number of blobs with the given `action`.
+    pub fn count(&self, action: Action) -> usize {
+        self.blobs.iter().filter(|b| b.action == action).count()
+    }
+
+    /// Write one line per blob followed by a summary line to `buf`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ApiError::IOError`] if writing to `buf` fails.
+    pub fn write_to(&self, buf: &mut dyn Write) -> Result<(), ApiError> {
+        for blob in &self.blobs {
+            writeln!(
+                buf,
+                "{:<5} {} {}",
+                blob.action.label(),
+                blob.descriptor.digest,
+                blob.descriptor.size
+            )?;
+        }
+        writeln!(
+            buf,
+            "{} blobs, {} bytes to transfer; {} blobs, {} bytes mounted; {} blobs already present",
+            self.count(Action::Copy),
+            self.bytes(Action::Copy),
+            self.count(Action::Mount),
+            self.bytes(Action::Mount),
+            self.count(Action::Skip),
+        )?;
+        Ok(())
+    }
+}
+
+/// Resolve the source image and decide how each of its blobs will reach the
+/// destination.
+///
+/// Every platform of an index is included.  Blobs already present in the
+/// destination repository are skipped; when both references live on the
+/// same registry the remaining blobs are mounted, otherwise they are copied.
+///
+/// # Errors
+///
+/// * [`ApiError::JsonError`] — a source manifest is malformed.
+/// * [`ApiError::DigestMismatch`] — a child manifest does not match the
+///   digest the index references it by.
+/// * Any error returned while fetching manifests or probing blobs.
+pub async fn plan(src: &Endpoint, dst: &Endpoint) -> Result<CopyPlan, ApiError> {
+    log::trace!("plan(src: {}, dst: {})", src.image, dst.image);
+
+    let url = src.image.manifest_url(&src.image.reference_or_latest())?;
+    let top = api::get_manifest(&src.client, &url).await?;
+
+    let mut children = Vec::new();
+    let images = match top.parse()? {
+        Manifest::Image(m) => vec![m],
+        Manifest::Index(index) => {
+            let mut images = Vec::with_capacity(index.manifests.len());
+            for child in &index.manifests {
+                let url = src.image.manifest_url(&child.digest)?;
+                let raw = api::get_manifest(&src.client, &url).await?;
+                digest::verify(&child.digest, &raw.digest)?;
+                match raw.parse()? {
+                    Manifest::Image(m) => images.push(m),
+                    Manifest::Index(_) => {
+                        return Err(ApiError::UnexpectedResponse(format!(
+                            "Nested index {} is not supported",
+                            raw.digest
+                        )))
+                    }
+                }
+                children.push(raw);
+            }
+            images
+        }
+    };
+
+    let mut unique: BTreeMap<&str, &Descriptor> = BTreeMap::new();
+    let mut ordered = Vec::new();
+    for desc in images
+        .iter()
+        .flat_map(|m| std::iter::once(&m.config).chain(&m.layers))
+    {
+        if unique.insert(&desc.digest, desc).is_none() {
+            ordered.push(desc);
+        }
+    }
+
+    let same_registry = src.image.same_registry(&dst.image);
+    let mut blobs = Vec::with_capacity(ordered.len());
+    for desc in ordered {
+        let action = if blob::exists(&dst.client, &dst.image, &desc.digest).await? {
+            Action::Skip
+        } else if same_registry {
+            Action::Mount
+        } else {
+            Action::Copy
+        };
+        blobs.push(PlannedBlob {
+            descriptor: desc.clone(),
+            action,
+        });
+    }
+
+    Ok(CopyPlan {
+        top,
+        children,
+        blobs,
+    })
+}
+
+/// Carry out `plan`: transfer the blobs, up to `jobs` at a time, then
+/// upload the per-platform manifests by digest and finally the top-level
+/// manifest.
+///
+/// Progress of the blob transfers is reported through a [`Progress`].
+///
+/// The top-level manifest is stored under the destination tag; when the
+/// destination names no tag, the source tag is reused, and when the source
+/// was referenced by digest the manifest is stored by digest only.  The
+/// manifest bytes are uploaded unmodified, so the digest is preserved.
+///
+/// Returns the digest of the top-level manifest.
+///
+/// # Errors
+///
+/// Returns any error raised while mounting, streaming, or uploading.
+pub async fn execute(
+    src: &Endpoint,
+    dst: &Endpoint,
+    plan: &CopyPlan,
+    jobs: usize,
+) -> Result<String, ApiError> {
+    log::trace!(
+        "execute(src: {}, dst: {}, jobs: {jobs})",
+        src.image,
+        dst.image
+    );
+
+    let pending: Vec<&PlannedBlob> = plan
+        .blobs
+        .iter()
+        .filter(|b| b.action != Action::Skip)
+        .collect();
+    let progress = Progress::new(
+        pending.len(),
+        pending.iter().map(|b| b.descriptor.size).sum(),
+    );
+    let tasks = pending
+        .into_iter()
+        .map(|blob| transfer(src.clone(), dst.clone(), blob.clone(), progress.clone()));
+    let result = blob::run_bounded(jobs, tasks).await;
+    progress.clear();
+    result?;
+
+    for child in &plan.children {
+        let url = dst.image.manifest_url(&child.digest)?;
+        api::put_manifest(
+            &dst.client,
+            &url,
+            &child.descriptor()?.media_type,
+            &child.bytes,
+        )
+        .await?;
+    }
+
+    let reference = destination_reference(&src.image, &dst.image, &plan.top.digest);
+    let url = dst.image.manifest_url(&reference)?;
+    let top = plan.top.descriptor()?;
+    api::put_manifest(&dst.client, &url, &top.media_type, &plan.top.bytes).await
+}
+
+/// Mount or stream a single blob from `src` to `dst`, reporting to
+/// `progress`.
+async fn transfer(
+    src: Endpoint,
+    dst: Endpoint,
+    blob: PlannedBlob,
+    progress: Progress,
+) -> Result<(), ApiError> {
+    let desc = &blob.descriptor;
+    let bar = progress.start(&desc.digest, desc.size);
+    let location = match blob.action {
+        Action::Skip => None,
+        Action::Mount => {
+            log::debug!("Mounting {} from {}", desc.digest, src.image.repository);
+            match blob::mount(&dst.client, &dst.image, &desc.digest, &src.image.repository).await? {
+                blob::Mount::Mounted => None,
+                blob::Mount::Upload(location) => {
+                    log::debug!("Mount declined; copying {}", desc.digest);
+                    Some(location)
+                }
+            }
+        }
+        Action::Copy => {
+            log::debug!("Copying {} ({} bytes)", desc.digest, desc.size);
+            Some(blob::start_upload(&dst.client, &dst.image).await?)
+        }
+    };
+    if let Some(location) = location {
+        blob::stream(&src.client, &src.image, &dst.client, location, desc, &bar).await?;
+    }
+    bar.finish();
+    Ok(())
+}
+
+/// Choose the tag or digest the copied manifest is stored under.
+fn destination_reference(src: &ImageRef, dst: &ImageRef, digest: &str) -> String {
+    if let Some(r) = &dst.reference {
+        return r.to_string();
+    }
+    match (src.tag(), &src.reference) {
+        (Some(t), _) => String::from(t),
+        (None, Some(Reference::Digest(_))) => String::from(digest),
+        _ => src.reference_or_latest(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use super::*;
+
+    const BLOB: &[u8] = b"layer";
+
+    fn endpoint(server: &mockito::Server, reference: &str) -> Endpoint {
+        let registry = Url::parse(&server.url()).unwrap();
+        Endpoint {
+            client: reqwest::Client::new(),
+            image: ImageRef::parse(reference, &registry).unwrap(),
+        }
+    }
+
+    fn manifest_body(config: &str, layer: &str) -> String {
+        format!(
+            r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","config":{{"mediaType":"application/vnd.oci.image.config.v1+json","digest":"{config}","size":2}},"layers":[{{"mediaType":"application/vnd.oci.image.layer.v1.tar+gzip","digest":"{layer}","size":5}}]}}"#
+        )
+    }
+
+    /// Test that the destination reference falls back to the source tag, or
+    /// to the digest when the source was referenced by digest.
+    #[test]
+    fn test_destination_reference() {
+        let registry = Url::parse("https://r.local").unwrap();
+        let digest = "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        let src_tag = ImageRef::parse("a:v1", &registry).unwrap();
+        let src_digest = ImageRef::parse(&format!("a@{digest}"), &registry).unwrap();
+        let dst_bare = ImageRef::parse("b", &registry).unwrap();
+        let dst_tag = ImageRef::parse("b:v2", &registry).unwrap();
+
+        assert_eq!(destination_reference(&src_tag, &dst_tag, digest), "v2");
+        assert_eq!(destination_reference(&src_tag, &dst_bare, digest), "v1");
+        assert_eq!(
+            destination_reference(&src_digest, &dst_bare, digest),
+            digest
+        );
+    }
+
+    /// Test that within one registry, missing blobs are mounted and present
+    /// blobs skipped, and that the manifest is uploaded byte-for-byte under
+    /// the destination tag.
+    #[tokio::test]
+    async fn test_copy_same_registry_mounts() -> Result<(), ApiError> {
+        let mut server = mockito::Server::new_async().await;
+        let config = digest::sha256(b"{}");
+        let layer = digest::sha256(BLOB);
+        let body = manifest_body(&config, &layer);
+
+        server
+            .mock("GET", "/v2/src/manifests/v1")
+            .with_status(200)
+            .with_header("content-type", "application/vnd.oci.image.manifest.v1+json")
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .with_body(&body)
+            .create();
+        server
+            .mock("HEAD", &*format!("/v2/dst/blobs/{config}"))
+            .with_status(200)
+            .create();
+        server
+            .mock("HEAD", &*format!("/v2/dst/blobs/{layer}"))
+            .with_status(404)
+            .create();
+        let mount = server
+            .mock("POST", "/v2/dst/blobs/uploads/")
+            .match_query(mockito::Matcher::UrlEncoded("mount".into(), layer.clone()))
+            .with_status(201)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .create();
+        let put = server
+            .mock("PUT", "/v2/dst/manifests/v2")
+            .match_body(body.as_str())
+            .with_status(201)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .create();
+
+        let src = endpoint(&server, "src:v1");
+        let dst = endpoint(&server, "dst:v2");
+        let plan = plan(&src, &dst).await?;
+        assert_eq!(plan.count(Action::Skip), 1);
+        assert_eq!(plan.count(Action::Mount), 1);
+        assert_eq!(plan.bytes(Action::Mount), 5);
+
+        let digest = execute(&src, &dst, &plan, 2).await?;
+        assert_eq!(digest, digest::sha256(body.as_bytes()));
+        mount.assert();
+        put.assert();
+        Ok(())
+    }
+
+    /// Test that the dry-run plan output lists each blob and totals the bytes
+    /// that would be transferred between registries.
+    #[tokio::test]
+    async fn test_plan_across_registries() -> Result<(), ApiError> {
+        let mut src_server = mockito::Server::new_async().await;
+        let mut dst_server = mockito::Server::new_async().await;
+        let config = digest::sha256(b"{}");
+        let layer = digest::sha256(BLOB);
+
+        src_server
+            .mock("GET", "/v2/src/manifests/latest")
+            .with_status(200)
+            .with_header("content-type", "application/vnd.oci.image.manifest.v1+json")
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .with_body(manifest_body(&config, &layer))
+            .create();
+        dst_server
+            .mock(
+                "HEAD",
+                mockito::Matcher::Regex(String::from("^/v2/dst/blobs/")),
+            )
+            .with_status(404)
+            .create();
+
+        let plan = plan(&endpoint(&src_server, "src"), &endpoint(&dst_server, "dst")).await?;
+        let mut buf = Vec::new();
+        plan.write_to(&mut buf)?;
+
+        let expected = format!(
+            "copy  {config} 2\ncopy  {layer} 5\n\
+             2 blobs, 7 bytes to transfer; 0 blobs, 0 bytes mounted; 0 blobs already present\n"
+        );
+        assert_eq!(String::from_utf8(buf).unwrap(), expected);
+        Ok(())
+    }
+}
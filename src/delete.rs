@@ -0,0 +1,461 @@
+/*
+ * Copyright 2023 Anthony Oteri
+ *
+ * Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+ * http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+ * http://opensource.org/licenses/MIT>, at your option. This file may not be
+ * copied, modified, or distributed except according to those terms.
+ */
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::io::BufRead;
+use std::io::Write;
+use std::path::Path;
+
+use reqwest::StatusCode;
+use url::Url;
+
+use crate::api;
+use crate::copy::Endpoint;
+use crate::error::ApiError;
+use crate::reference;
+use crate::sync;
+use crate::tags;
+
+/// How the selected tags are deleted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeleteOptions {
+    /// Delete only the tags, not the manifests they point at.
+    pub tag_only: bool,
+    /// With `tag_only`, delete a manifest other tags point at when the
+    /// registry cannot delete tags.
+    pub force: bool,
+    /// Also delete the children of deleted indexes that no remaining tag
+    /// refers to.
+    pub cascade: bool,
+    /// Maximum number of requests in flight.
+    pub jobs: usize,
+}
+
+/// What happened to one tag or manifest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    /// The tag name, or the digest of a manifest deleted by digest.
+    pub tag: String,
+    /// The digest the tag pointed at, once resolved.
+    pub digest: Option<String>,
+    /// Why the tag was not deleted, if it was not.
+    pub error: Option<String>,
+}
+
+impl Outcome {
+    /// The outcome of a manifest deleted by `digest`, so far successful.
+    fn manifest(digest: &str) -> Self {
+        Self {
+            tag: String::from(digest),
+            digest: None,
+            error: None,
+        }
+    }
+}
+
+/// The outcome of deleting a set of tags and manifests, one entry each in
+/// name order.
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    /// Every selected tag and manifest.
+    pub outcomes: Vec<Outcome>,
+}
+
+impl Report {
+    /// Number of tags and manifests that could not be deleted.
+    pub fn failed(&self) -> usize {
+        self.outcomes.iter().filter(|o| o.error.is_some()).count()
+    }
+
+    /// Write one line per tag or manifest followed by a summary line to
+    /// `buf`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ApiError::IOError`] if writing to `buf` fails.
+    pub fn write_to(&self, buf: &mut dyn Write) -> Result<(), ApiError> {
+        for o in &self.outcomes {
+            match (&o.error, &o.digest) {
+                (Some(error), _) => writeln!(buf, "failed  {} {error}", o.tag)?,
+                (None, Some(digest)) => writeln!(buf, "deleted {} {digest}", o.tag)?,
+                (None, None) => writeln!(buf, "deleted {}", o.tag)?,
+            }
+        }
+        let failed = self.failed();
+        writeln!(
+            buf,
+            "{} deleted, {failed} failed",
+            self.outcomes.len() - failed
+        )?;
+        Ok(())
+    }
+}
+
+/// Read tag names from `path`, one per line, or from standard input when
+/// `path` is `-`.  Blank lines and lines starting with `#` are skipped.
+///
+/// # Errors
+///
+/// Returns [`ApiError::IOError`] if the file cannot be read.
+pub fn read_tags(path: &Path) -> Result<Vec<String>, ApiError> {
+    let lines = if path == Path::new("-") {
+        std::io::stdin()
+            .lock()
+            .lines()
+            .collect::<Result<Vec<_>, _>>()?
+    } else {
+        std::io::BufReader::new(std::fs::File::open(path)?)
+            .lines()
+            .collect::<Result<Vec<_>, _>>()?
+    };
+    Ok(lines
+        .iter()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+/// Delete each of `tags` and `digests` from the repository of `endpoint`.
+///
+/// The tags are resolved to their digests up to `options.jobs` at a time,
+/// and each distinct manifest is then deleted once, however many of the
+/// tags point at it.  Every other tag of the manifest goes with it.
+///
+/// With `options.tag_only`, each tag is deleted by name instead.  When the
+/// registry answers `400 Bad Request` or `405 Method Not Allowed`, it does
+/// not support this, so the remaining tags of the repository are resolved,
+/// and a manifest is deleted by digest only when none of them points at it
+/// or with `options.force`.
+///
+/// With `options.cascade`, the children of each index deleted by digest
+/// are deleted too, once the index is gone, unless a remaining tag still
+/// refers to them as described for [`orphans`].
+///
+/// A tag or digest that cannot be resolved or deleted is recorded in the
+/// report and does not stop the others.
+///
+/// # Errors
+///
+/// Returns an error only when the remaining tags of the repository cannot
+/// be listed or resolved, or, with `options.cascade`, a manifest cannot be
+/// fetched.
+pub async fn delete_tags(
+    endpoint: &Endpoint,
+    tags: &[String],
+    digests: &[String],
+    options: &DeleteOptions,
+) -> Result<Report, ApiError> {
+    log::trace!(
+        "delete_tags(image: {}, tags: {}, digests: {digests:?}, options: {options:?})",
+        endpoint.image,
+        tags.len()
+    );
+
+    let resolved = tags::lookup_all(tags, options.jobs, |tag| {
+        let client = endpoint.client.clone();
+        let url = endpoint.image.manifest_url(&tag);
+        async move {
+            if !reference::is_valid_tag(&tag) {
+                return Ok(Err(ApiError::InvalidReference(format!(
+                    "{tag}: invalid tag"
+                ))
+                .to_string()));
+            }
+            Ok(api::resolve_digest(&client, &url?)
+                .await
+                .map_err(|e| e.to_string()))
+        }
+    })
+    .await?;
+
+    let mut outcomes: BTreeMap<String, Outcome> = BTreeMap::new();
+    let mut groups: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (tag, result) in resolved {
+        let (digest, error) = match result {
+            Ok(digest) => {
+                groups.entry(digest.clone()).or_default().push(tag.clone());
+                (Some(digest), None)
+            }
+            Err(error) => (None, Some(error)),
+        };
+        outcomes.insert(tag.clone(), Outcome { tag, digest, error });
+    }
+
+    let mut by_digest = if options.tag_only {
+        untag(endpoint, tags, &groups, options, &mut outcomes).await?
+    } else {
+        groups
+    };
+    for digest in digests {
+        by_digest
+            .entry(digest.clone())
+            .or_default()
+            .push(digest.clone());
+        outcomes.insert(digest.clone(), Outcome::manifest(digest));
+    }
+
+    let children = if options.cascade && !by_digest.is_empty() {
+        orphans(endpoint, &by_digest, options.jobs).await?
+    } else {
+        BTreeMap::new()
+    };
+
+    let failed = delete_manifests(endpoint, &by_digest, options.jobs, &mut outcomes).await?;
+    let children: BTreeMap<String, Vec<String>> = children
+        .into_iter()
+        .filter(|(child, parents)| {
+            let kept = parents.iter().any(|p| failed.contains(p));
+            if kept {
+                log::warn!("Not deleting {child}, since its index was not deleted");
+            }
+            !kept
+        })
+        .map(|(child, _)| (child.clone(), vec![child]))
+        .collect();
+    for child in children.keys() {
+        outcomes.insert(child.clone(), Outcome::manifest(child));
+    }
+    delete_manifests(endpoint, &children, options.jobs, &mut outcomes).await?;
+
+    Ok(Report {
+        outcomes: outcomes.into_values().collect(),
+    })
+}
+
+/// Delete each manifest of `by_digest`, up to `jobs` at a time, recording
+/// failures in `outcomes` for every name grouped under it, and return the
+/// digests that could not be deleted.
+async fn delete_manifests(
+    endpoint: &Endpoint,
+    by_digest: &BTreeMap<String, Vec<String>>,
+    jobs: usize,
+    outcomes: &mut BTreeMap<String, Outcome>,
+) -> Result<BTreeSet<String>, ApiError> {
+    let digests: Vec<String> = by_digest.keys().cloned().collect();
+    let deleted = tags::lookup_all(&digests, jobs, |digest| {
+        let endpoint = endpoint.clone();
+        async move {
+            Ok(sync::delete_manifest(&endpoint, &digest)
+                .await
+                .map_err(|e| e.to_string()))
+        }
+    })
+    .await?;
+
+    let mut failed = BTreeSet::new();
+    for (digest, result) in deleted {
+        if let Err(error) = result {
+            for name in &by_digest[&digest] {
+                if let Some(outcome) = outcomes.get_mut(name) {
+                    outcome.error = Some(error.clone());
+                }
+            }
+            failed.insert(digest);
+        }
+    }
+    Ok(failed)
+}
+
+/// Return the children of the indexes among `doomed` that nothing refers
+/// to once `doomed` is deleted, each with the indexes that list it.
+///
+/// A child stays when a remaining tag points at it, or at another index
+/// that lists it.  Only when some index has children to delete are the
+/// remaining tags resolved and their manifests fetched, up to `jobs` at a
+/// time.
+async fn orphans(
+    endpoint: &Endpoint,
+    doomed: &BTreeMap<String, Vec<String>>,
+    jobs: usize,
+) -> Result<BTreeMap<String, Vec<String>>, ApiError> {
+    let digests: Vec<String> = doomed.keys().cloned().collect();
+    let mut children: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (index, entries) in
+        tags::index_entries(&endpoint.client, &endpoint.image, &digests, jobs).await?
+    {
+        for entry in entries {
+            if !doomed.contains_key(&entry.digest) {
+                children
+                    .entry(entry.digest)
+                    .or_default()
+                    .push(index.clone());
+            }
+        }
+    }
+    if children.is_empty() {
+        return Ok(children);
+    }
+
+    let remaining: BTreeSet<String> = tags::resolve_all(
+        &endpoint.client,
+        &endpoint.image,
+        &all_tags(endpoint).await?,
+        jobs,
+    )
+    .await?
+    .into_values()
+    .filter(|d| !doomed.contains_key(d))
+    .collect();
+    let remaining: Vec<String> = remaining.into_iter().collect();
+    let kept = tags::index_entries(&endpoint.client, &endpoint.image, &remaining, jobs).await?;
+    for digest in remaining
+        .iter()
+        .chain(kept.values().flatten().map(|d| &d.digest))
+    {
+        if children.remove(digest).is_some() {
+            log::info!("Keeping {digest}, which a remaining tag still refers to");
+        }
+    }
+    Ok(children)
+}
+
+/// List the tags of the repository of `endpoint`, treating a repository
+/// without tags as having none.
+async fn all_tags(endpoint: &Endpoint) -> Result<Vec<String>, ApiError> {
+    match api::list_tags(
+        &endpoint.client,
+        &endpoint.image.registry,
+        &endpoint.image.repository,
+    )
+    .await
+    {
+        Err(ApiError::NotFound) => Ok(Vec::new()),
+        result => result,
+    }
+}
+
+/// Delete the tags of `groups` by name, recording failures in `outcomes`.
+///
+/// Where the registry cannot delete tags, return the groups whose manifest
+/// may be deleted by digest instead: those no tag outside `selected` points
+/// at, or all of them with `options.force`.  The tags of the other groups
+/// are recorded as failed.
+async fn untag(
+    endpoint: &Endpoint,
+    selected: &[String],
+    groups: &BTreeMap<String, Vec<String>>,
+    options: &DeleteOptions,
+    outcomes: &mut BTreeMap<String, Outcome>,
+) -> Result<BTreeMap<String, Vec<String>>, ApiError> {
+    let unsupported = delete_by_tag(endpoint, groups, options, outcomes).await?;
+    if unsupported.is_empty() {
+        return Ok(unsupported);
+    }
+    log::info!("The registry cannot delete tags; looking for other tags of the manifests");
+    let others = other_tags(endpoint, selected, options.jobs).await?;
+    Ok(unsupported
+        .into_iter()
+        .filter(|(digest, group)| {
+            let Some(shared) = others.get(digest) else {
+                return true;
+            };
+            if options.force {
+                log::warn!("Also deleting {}", shared.join(", "));
+                return true;
+            }
+            let error = ApiError::SharedManifest(format!(
+                "the registry cannot delete only the tag, and deleting {digest} would also delete {} (use --force to delete them)",
+                shared.join(", ")
+            ))
+            .to_string();
+            for tag in group {
+                if let Some(outcome) = outcomes.get_mut(tag) {
+                    outcome.error = Some(error.clone());
+                }
+            }
+            false
+        })
+        .collect())
+}
+
+/// Delete every tag of `groups` by name, recording failures in `outcomes`,
+/// and return the groups of tags the registry could not delete this way.
+async fn delete_by_tag(
+    endpoint: &Endpoint,
+    groups: &BTreeMap<String, Vec<String>>,
+    options: &DeleteOptions,
+    outcomes: &mut BTreeMap<String, Outcome>,
+) -> Result<BTreeMap<String, Vec<String>>, ApiError> {
+    let tags: Vec<String> = groups.values().flatten().cloned().collect();
+    let deleted = tags::lookup_all(&tags, options.jobs, |tag| {
+        let client = endpoint.client.clone();
+        let url = endpoint.image.manifest_url(&tag);
+        async move { Ok(delete_tag(&client, url?).await.map_err(|e| e.to_string())) }
+    })
+    .await?;
+
+    let mut unsupported: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (digest, group) in groups {
+        for tag in group {
+            match &deleted[tag] {
+                Ok(true) => {}
+                Ok(false) => unsupported
+                    .entry(digest.clone())
+                    .or_default()
+                    .push(tag.clone()),
+                Err(error) => {
+                    if let Some(outcome) = outcomes.get_mut(tag) {
+                        outcome.error = Some(error.clone());
+                    }
+                }
+            }
+        }
+    }
+    Ok(unsupported)
+}
+
+/// Delete the tag of the manifest at `url`, returning `false` when the
+/// registry does not support deleting tags.
+async fn delete_tag(client: &reqwest::Client, url: Url) -> Result<bool, ApiError> {
+    let resp = client.delete(url).send().await?;
+    match resp.status() {
+        StatusCode::BAD_REQUEST | StatusCode::METHOD_NOT_ALLOWED => Ok(false),
+        _ => api::parse_response_status(&resp).map(|()| true),
+    }
+}
+
+/// Resolve the tags of the repository of `endpoint` that are not among
+/// `selected`, up to `jobs` at a time, and group them by digest.
+async fn other_tags(
+    endpoint: &Endpoint,
+    selected: &[String],
+    jobs: usize,
+) -> Result<BTreeMap<String, Vec<String>>, ApiError> {
+    let selected: BTreeSet<&String> = selected.iter().collect();
+    let others: Vec<String> = all_tags(endpoint)
+        .await?
+        .into_iter()
+        .filter(|t| !selected.contains(t))
+        .collect();
+
+    let mut by_digest: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (tag, digest) in tags::resolve_all(&endpoint.client, &endpoint.image, &others, jobs).await?
+    {
+        by_digest.entry(digest).or_default().push(tag);
+    }
+    Ok(by_digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that tag lists skip blank lines and comments and trim
+    /// whitespace.
+    #[test]
+    fn test_read_tags() {
+        let path = std::env::temp_dir().join(format!("dredge-delete-{}.txt", std::process::id()));
+        std::fs::write(&path, "pr-1\n\n# stale previews\n  pr-2 \r\n").unwrap();
+        let tags = read_tags(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(tags, ["pr-1", "pr-2"]);
+    }
+}
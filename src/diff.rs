@@ -0,0 +1,400 @@
+/*
+ * Copyright 2023 Anthony Oteri
+ *
+ * Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+ * http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+ * http://opensource.org/licenses/MIT>, at your option. This file may not be
+ * copied, modified, or distributed except according to those terms.
+ */
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::io::Write;
+
+use serde::Serialize;
+
+use crate::error::ApiError;
+use crate::image::Image;
+use crate::layer::Entry;
+use crate::layer::Kind;
+use crate::manifest::ContainerConfig;
+use crate::manifest::ImageConfig;
+use crate::rootfs::RootFs;
+
+/// One of the two images being compared.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Side {
+    /// The reference as given.
+    pub reference: String,
+    /// Digest of the single-platform manifest.
+    pub digest: String,
+}
+
+/// Layers compared by diff ID, so that a layer compressed differently
+/// still counts as shared.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct LayerDiff {
+    /// Diff IDs present in both images, in the order of the first image.
+    pub shared: Vec<String>,
+    /// Diff IDs only present in the first image.
+    pub only_a: Vec<String>,
+    /// Diff IDs only present in the second image.
+    pub only_b: Vec<String>,
+}
+
+/// A configuration value that differs between the images.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConfigChange {
+    /// The field, followed by the variable or label name for `Env` and
+    /// `Labels`, e.g. `Env PATH`.
+    pub field: String,
+    /// The value in the first image, if set.
+    pub before: Option<String>,
+    /// The value in the second image, if set.
+    pub after: Option<String>,
+}
+
+/// A path present in both filesystems with different attributes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ModifiedFile {
+    /// Path of the file.
+    pub path: String,
+    /// The attributes that differ: `type`, `mode`, `owner`, `size`,
+    /// `content`, `link`, or `mtime`.
+    pub changes: Vec<&'static str>,
+}
+
+/// The differences between two merged filesystems.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct FileDiff {
+    /// Paths only present in the second image.
+    pub added: Vec<String>,
+    /// Paths only present in the first image.
+    pub removed: Vec<String>,
+    /// Paths present in both images with different attributes.
+    pub modified: Vec<ModifiedFile>,
+}
+
+/// Everything that differs between two images.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ImageDiff {
+    /// The first image.
+    pub a: Side,
+    /// The second image.
+    pub b: Side,
+    /// How the layers compare.
+    pub layers: LayerDiff,
+    /// Configuration values that differ.
+    pub config: Vec<ConfigChange>,
+    /// How the merged filesystems compare.
+    pub files: FileDiff,
+}
+
+impl ImageDiff {
+    /// Write the differences in a human-readable form to `buf`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ApiError::IOError`] if writing to `buf` fails.
+    pub fn write_to(&self, buf: &mut dyn Write) -> Result<(), ApiError> {
+        writeln!(buf, "--- a: {} ({})", self.a.reference, self.a.digest)?;
+        writeln!(buf, "+++ b: {} ({})", self.b.reference, self.b.digest)?;
+
+        let layers = &self.layers;
+        writeln!(buf)?;
+        writeln!(
+            buf,
+            "Layers: {} shared, {} only in a, {} only in b",
+            layers.shared.len(),
+            layers.only_a.len(),
+            layers.only_b.len()
+        )?;
+        for diff_id in &layers.shared {
+            writeln!(buf, "  = {diff_id}")?;
+        }
+        for diff_id in &layers.only_a {
+            writeln!(buf, "  - {diff_id}")?;
+        }
+        for diff_id in &layers.only_b {
+            writeln!(buf, "  + {diff_id}")?;
+        }
+
+        writeln!(buf)?;
+        writeln!(buf, "Config: {} changed", self.config.len())?;
+        for change in &self.config {
+            match (&change.before, &change.after) {
+                (None, Some(after)) => writeln!(buf, "  + {}: {after}", change.field)?,
+                (Some(before), None) => writeln!(buf, "  - {}: {before}", change.field)?,
+                (before, after) => writeln!(
+                    buf,
+                    "  ~ {}: {} -> {}",
+                    change.field,
+                    before.as_deref().unwrap_or_default(),
+                    after.as_deref().unwrap_or_default()
+                )?,
+            }
+        }
+
+        let files = &self.files;
+        writeln!(buf)?;
+        writeln!(
+            buf,
+            "Files: {} added, {} removed, {} modified",
+            files.added.len(),
+            files.removed.len(),
+            files.modified.len()
+        )?;
+        for path in &files.added {
+            writeln!(buf, "  + {path}")?;
+        }
+        for path in &files.removed {
+            writeln!(buf, "  - {path}")?;
+        }
+        for file in &files.modified {
+            writeln!(buf, "  ~ {} ({})", file.path, file.changes.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Compare the layers of two images by diff ID.
+pub fn compare_layers(a: &Image, b: &Image) -> LayerDiff {
+    let a_ids = &a.config.rootfs.diff_ids;
+    let b_ids = &b.config.rootfs.diff_ids;
+    let a_set: BTreeSet<&String> = a_ids.iter().collect();
+    let b_set: BTreeSet<&String> = b_ids.iter().collect();
+    LayerDiff {
+        shared: a_ids
+            .iter()
+            .filter(|d| b_set.contains(d))
+            .cloned()
+            .collect(),
+        only_a: a_ids
+            .iter()
+            .filter(|d| !b_set.contains(d))
+            .cloned()
+            .collect(),
+        only_b: b_ids
+            .iter()
+            .filter(|d| !a_set.contains(d))
+            .cloned()
+            .collect(),
+    }
+}
+
+/// Compare the creation time and the container defaults of two image
+/// configurations: `User`, `WorkingDir`, `Entrypoint`, `Cmd`, and each
+/// variable of `Env` and label of `Labels`.
+pub fn compare_config(a: &ImageConfig, b: &ImageConfig) -> Vec<ConfigChange> {
+    let empty = ContainerConfig::default();
+    let (ca, cb) = (
+        a.config.as_ref().unwrap_or(&empty),
+        b.config.as_ref().unwrap_or(&empty),
+    );
+    let json = |v: &Option<Vec<String>>| {
+        v.as_ref()
+            .map(|v| serde_json::to_string(v).unwrap_or_default())
+    };
+
+    let mut changes = Vec::new();
+    let mut compare = |field: String, before: Option<String>, after: Option<String>| {
+        if before != after {
+            changes.push(ConfigChange {
+                field,
+                before,
+                after,
+            });
+        }
+    };
+    compare(
+        String::from("Created"),
+        a.created.clone(),
+        b.created.clone(),
+    );
+    compare(String::from("User"), ca.user.clone(), cb.user.clone());
+    compare(
+        String::from("WorkingDir"),
+        ca.working_dir.clone(),
+        cb.working_dir.clone(),
+    );
+    compare(
+        String::from("Entrypoint"),
+        json(&ca.entrypoint),
+        json(&cb.entrypoint),
+    );
+    compare(String::from("Cmd"), json(&ca.cmd), json(&cb.cmd));
+
+    let env = |c: &ContainerConfig| -> BTreeMap<String, String> {
+        c.env
+            .iter()
+            .flatten()
+            .map(|v| match v.split_once('=') {
+                Some((name, value)) => (String::from(name), String::from(value)),
+                None => (v.clone(), String::new()),
+            })
+            .collect()
+    };
+    let labels = |c: &ContainerConfig| c.labels.clone().unwrap_or_default();
+    for (field, before, after) in [
+        ("Env", env(ca), env(cb)),
+        ("Labels", labels(ca), labels(cb)),
+    ] {
+        let keys: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+        for key in keys {
+            compare(
+                format!("{field} {key}"),
+                before.get(key).cloned(),
+                after.get(key).cloned(),
+            );
+        }
+    }
+    changes
+}
+
+/// The attributes of `b` that differ from `a`.
+fn changed_attributes(a: &Entry, b: &Entry) -> Vec<&'static str> {
+    let mut changes = Vec::new();
+    if a.kind != b.kind {
+        changes.push("type");
+    }
+    if a.mode != b.mode {
+        changes.push("mode");
+    }
+    if (a.uid, a.gid) != (b.uid, b.gid) {
+        changes.push("owner");
+    }
+    if a.kind == Kind::File && b.kind == Kind::File {
+        if a.size != b.size {
+            changes.push("size");
+        }
+        if a.digest != b.digest {
+            changes.push("content");
+        }
+    }
+    if a.link != b.link {
+        changes.push("link");
+    }
+    if a.mtime != b.mtime {
+        changes.push("mtime");
+    }
+    changes
+}
+
+/// Compare two merged filesystems path by path.  Files are compared by
+/// content when both were built with content digests.
+pub fn compare_files(a: &RootFs, b: &RootFs) -> FileDiff {
+    let index = |fs: &RootFs| -> BTreeMap<String, Entry> {
+        fs.root()
+            .descendants()
+            .into_iter()
+            .map(|n| (n.entry.path.clone(), n.entry.clone()))
+            .collect()
+    };
+    let (a, b) = (index(a), index(b));
+
+    let mut diff = FileDiff::default();
+    for (path, entry) in &a {
+        match b.get(path) {
+            None => diff.removed.push(path.clone()),
+            Some(other) => {
+                let changes = changed_attributes(entry, other);
+                if !changes.is_empty() {
+                    diff.modified.push(ModifiedFile {
+                        path: path.clone(),
+                        changes,
+                    });
+                }
+            }
+        }
+    }
+    diff.added = b.keys().filter(|p| !a.contains_key(*p)).cloned().collect();
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer;
+    use crate::layer::tests::tarball;
+
+    fn rootfs(files: &[(&str, &str)]) -> RootFs {
+        let tar = tarball(files);
+        let mut rootfs = RootFs::default();
+        rootfs.apply(&layer::hashed_entries(&mut tar.as_slice()).unwrap());
+        rootfs
+    }
+
+    fn config(json: serde_json::Value) -> ImageConfig {
+        let mut config = serde_json::json!({
+            "rootfs": {"type": "layers", "diff_ids": []},
+        });
+        config["config"] = json;
+        serde_json::from_value(config).unwrap()
+    }
+
+    /// Test that files are reported as added, removed, or modified, and
+    /// that equal sizes with different contents are detected.
+    #[test]
+    fn test_compare_files() {
+        let a = rootfs(&[
+            ("etc/", ""),
+            ("etc/hostname", "aaaa"),
+            ("etc/old", "x"),
+            ("bin/sh", "->busybox"),
+        ]);
+        let b = rootfs(&[
+            ("etc/", ""),
+            ("etc/hostname", "bbbb"),
+            ("etc/new", "y"),
+            ("bin/sh", "->bash"),
+        ]);
+        let diff = compare_files(&a, &b);
+        assert_eq!(diff.added, ["etc/new"]);
+        assert_eq!(diff.removed, ["etc/old"]);
+        assert_eq!(
+            diff.modified,
+            [
+                ModifiedFile {
+                    path: String::from("bin/sh"),
+                    changes: vec!["link"],
+                },
+                ModifiedFile {
+                    path: String::from("etc/hostname"),
+                    changes: vec!["content"],
+                },
+            ]
+        );
+    }
+
+    /// Test that config changes are reported per field, per variable, and
+    /// per label.
+    #[test]
+    fn test_compare_config() {
+        let a = config(serde_json::json!({
+            "User": "root",
+            "Env": ["PATH=/bin", "OLD=1"],
+            "Entrypoint": ["/app"],
+            "Labels": {"version": "1"},
+        }));
+        let b = config(serde_json::json!({
+            "User": "app",
+            "Env": ["PATH=/usr/bin:/bin"],
+            "Entrypoint": ["/app"],
+            "Cmd": ["--serve"],
+            "Labels": {"version": "1"},
+        }));
+        let changes: Vec<String> = compare_config(&a, &b)
+            .iter()
+            .map(|c| format!("{} {:?} {:?}", c.field, c.before, c.after))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                r#"User Some("root") Some("app")"#,
+                r#"Cmd None Some("[\"--serve\"]")"#,
+                r#"Env OLD Some("1") None"#,
+                r#"Env PATH Some("/bin") Some("/usr/bin:/bin")"#,
+            ]
+        );
+    }
+}
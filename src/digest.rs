@@ -0,0 +1,154 @@
+/*
+ * Copyright 2023 Anthony Oteri
+ *
+ * Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+ * http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+ * http://opensource.org/licenses/MIT>, at your option. This file may not be
+ * copied, modified, or distributed except according to those terms.
+ */
+
+use std::path::Path;
+
+use sha2::Digest as _;
+use sha2::Sha256;
+use tokio::io::AsyncReadExt;
+
+use crate::error::ApiError;
+
+/// The algorithm prefix of every digest `dredge` can verify.
+const SHA256_PREFIX: &str = "sha256:";
+
+/// Size of the buffer used when hashing files from disk.
+const READ_BUF_SIZE: usize = 64 * 1024;
+
+/// Incremental `sha256` hasher producing OCI-style digest strings.
+///
+/// Content can be fed in any number of chunks with [`Hasher::update`]; the
+/// final digest is returned as `sha256:<hex>` by [`Hasher::finish`].
+#[derive(Default, Clone)]
+pub struct Hasher(Sha256);
+
+impl Hasher {
+    /// Create a new, empty hasher.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed `bytes` into the hasher.
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    /// Consume the hasher and return the digest as `sha256:<hex>`.
+    pub fn finish(self) -> String {
+        format!("{SHA256_PREFIX}{:x}", self.0.finalize())
+    }
+}
+
+/// Compute the `sha256:<hex>` digest of an in-memory byte slice.
+pub fn sha256(bytes: &[u8]) -> String {
+    let mut hasher = Hasher::new();
+    hasher.update(bytes);
+    hasher.finish()
+}
+
+/// Return the hex-encoded portion of a `sha256:<hex>` digest.
+///
+/// This is the file name used for the blob inside an OCI image layout.
+///
+/// # Errors
+///
+/// Returns [`ApiError::UnsupportedDigest`] if `digest` is not a well-formed
+/// `sha256` digest.
+pub fn encoded(digest: &str) -> Result<&str, ApiError> {
+    match digest.strip_prefix(SHA256_PREFIX) {
+        Some(hex) if hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit()) => Ok(hex),
+        _ => Err(ApiError::UnsupportedDigest(String::from(digest))),
+    }
+}
+
+/// Check that `actual` matches the `expected` digest.
+///
+/// # Errors
+///
+/// Returns [`ApiError::DigestMismatch`] when the two digests differ.
+pub fn verify(expected: &str, actual: &str) -> Result<(), ApiError> {
+    if expected == actual {
+        Ok(())
+    } else {
+        Err(ApiError::DigestMismatch {
+            expected: String::from(expected),
+            actual: String::from(actual),
+        })
+    }
+}
+
+/// Hash the complete contents of the file at `path`.
+///
+/// # Errors
+///
+/// Returns [`ApiError::IOError`] if the file cannot be opened or read.
+pub async fn sha256_file(path: &Path) -> Result<String, ApiError> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Hasher::new();
+    let mut buf = vec![0u8; READ_BUF_SIZE];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The well-known digest of the empty byte string.
+    const EMPTY: &str = "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    /// Test that hashing the empty slice yields the well-known empty digest.
+    #[test]
+    fn test_sha256_empty() {
+        assert_eq!(sha256(b""), EMPTY);
+    }
+
+    /// Test that incremental hashing matches one-shot hashing.
+    #[test]
+    fn test_hasher_incremental() {
+        let mut hasher = Hasher::new();
+        hasher.update(b"hello ");
+        hasher.update(b"world");
+        assert_eq!(hasher.finish(), sha256(b"hello world"));
+    }
+
+    /// Test that `encoded` strips the algorithm prefix from a valid digest.
+    #[test]
+    fn test_encoded_valid() {
+        assert_eq!(encoded(EMPTY).unwrap(), &EMPTY[7..]);
+    }
+
+    /// Test that `encoded` rejects digests using other algorithms or
+    /// malformed hex, so they can never be used as file names.
+    #[test]
+    fn test_encoded_invalid() {
+        assert!(matches!(
+            encoded("sha512:abcd"),
+            Err(ApiError::UnsupportedDigest(_))
+        ));
+        assert!(matches!(
+            encoded("sha256:../../etc/passwd"),
+            Err(ApiError::UnsupportedDigest(_))
+        ));
+    }
+
+    /// Test that `verify` reports both digests on mismatch.
+    #[test]
+    fn test_verify_mismatch() {
+        let result = verify(EMPTY, &sha256(b"x"));
+        assert!(matches!(result, Err(ApiError::DigestMismatch { .. })));
+        assert!(verify(EMPTY, EMPTY).is_ok());
+    }
+}
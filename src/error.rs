@@ -62,8 +62,9 @@ pub enum ApiError {
     #[error("Unexpected response from API: {0}")]
     UnexpectedResponse(String),
 
-    /// The registry returned `401 Unauthorized`.  Authentication is not
-    /// currently supported; the request cannot be retried automatically.
+    /// The registry returned `401 Unauthorized`: no credentials were given,
+    /// or the given Basic credentials were rejected.  The request cannot be
+    /// retried automatically.
     #[error("HTTP Authorization failed")]
     AuthorizationFailed,
 
@@ -83,6 +84,95 @@ pub enum ApiError {
     /// storage deletion has not been enabled on the registry.
     #[error("Method not allowed")]
     MethodNotAllowed,
+
+    /// A JSON document (manifest, index, or image config) could not be
+    /// parsed or serialized.
+    #[error(transparent)]
+    JsonError(#[from] serde_json::Error),
+
+    /// Downloaded content did not hash to the digest it was requested by.
+    #[error("Digest mismatch: expected {expected}, got {actual}")]
+    DigestMismatch {
+        /// The digest the content was requested by.
+        expected: String,
+        /// The digest computed over the received content.
+        actual: String,
+    },
+
+    /// A digest uses an algorithm other than `sha256`, or is malformed.
+    #[error("Unsupported digest: {0}")]
+    UnsupportedDigest(String),
+
+    /// An image reference could not be parsed.  The inner `String` names the
+    /// reference and the problem with it.
+    #[error("Invalid image reference {0}")]
+    InvalidReference(String),
+
+    /// Credentials could not be encoded into an `Authorization` header.
+    #[error("Invalid credentials: {0}")]
+    InvalidCredentials(String),
+
+    /// A platform could not be parsed as `OS/ARCH[/VARIANT]`.
+    #[error("Invalid platform: {0}")]
+    InvalidPlatform(String),
+
+    /// A multi-platform image has no manifest for the requested platform.
+    /// The inner `String` lists the platforms that are available.
+    #[error("No manifest for platform {0}")]
+    PlatformNotFound(String),
+
+    /// A layer uses a media type `dredge` cannot decompress.
+    #[error("Unsupported layer media type: {0}")]
+    UnsupportedMediaType(String),
+
+    /// The image has no layer with the requested index or digest.
+    #[error("No layer {0} in image")]
+    LayerNotFound(String),
+
+    /// A name-matching pattern could not be parsed.
+    #[error("Invalid pattern: {0}")]
+    InvalidPattern(String),
+
+    /// A path does not exist in the filesystem of an image.
+    #[error("No such file or directory in image: {0}")]
+    PathNotFound(String),
+
+    /// A path in the filesystem of an image is not a regular file.
+    #[error("Not a regular file in image: {0}")]
+    NotAFile(String),
+
+    /// A measurement fell below the threshold required by the caller.
+    #[error("Below threshold: {0}")]
+    BelowThreshold(String),
+
+    /// A package database in an image could not be read.
+    #[error("Invalid package database: {0}")]
+    InvalidDatabase(String),
+
+    /// An image does not start with the layers of the base it was expected
+    /// to be built on.
+    #[error("Image is not based on {0}")]
+    NotBasedOn(String),
+
+    /// No tag satisfies the selection the caller asked for the latest of.
+    #[error("No tag matches {0}")]
+    NoMatchingTag(String),
+
+    /// Deleting a tag would delete a manifest that other tags point at.
+    #[error("Manifest is shared with other tags: {0}")]
+    SharedManifest(String),
+
+    /// Some of the tags a bulk delete selected could not be deleted.
+    #[error("Failed to delete {0}")]
+    DeleteFailed(String),
+
+    /// A destructive operation could not be confirmed.
+    #[error("Not confirmed: {0}")]
+    NotConfirmed(String),
+
+    /// A retention policy contains a value that cannot be understood.
+    #[error("Invalid retention policy: {0}")]
+    InvalidPolicy(String),
 }
 
 impl From<reqwest::header::ToStrError> for ApiError {
@@ -166,6 +256,19 @@ mod tests {
         );
     }
 
+    /// Test Display output for `ApiError::DigestMismatch`.
+    #[test]
+    fn test_api_error_digest_mismatch_display() {
+        let err = ApiError::DigestMismatch {
+            expected: String::from("sha256:aaa"),
+            actual: String::from("sha256:bbb"),
+        };
+        assert_eq!(
+            err.to_string(),
+            "Digest mismatch: expected sha256:aaa, got sha256:bbb"
+        );
+    }
+
     /// Test Display output for `DredgeError::RegistryUrlError`.
     #[test]
     fn test_dredge_error_registry_url_error_display() {
@@ -0,0 +1,245 @@
+/*
+ * Copyright 2023 Anthony Oteri
+ *
+ * Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+ * http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+ * http://opensource.org/licenses/MIT>, at your option. This file may not be
+ * copied, modified, or distributed except according to those terms.
+ */
+
+use crate::api;
+use crate::blob;
+use crate::digest;
+use crate::error::ApiError;
+use crate::manifest::Descriptor;
+use crate::manifest::ImageConfig;
+use crate::manifest::ImageManifest;
+use crate::manifest::Manifest;
+use crate::manifest::Platform;
+use crate::reference::ImageRef;
+
+/// The platform chosen from a multi-platform image when none is requested.
+pub const DEFAULT_PLATFORM: &str = "linux/amd64";
+
+/// A single-platform image resolved from a reference, with its parsed
+/// manifest and configuration.
+#[derive(Debug, Clone)]
+pub struct Image {
+    /// Digest of the single-platform manifest.
+    pub digest: String,
+    /// The parsed single-platform manifest.
+    pub manifest: ImageManifest,
+    /// The parsed image configuration.
+    pub config: ImageConfig,
+    /// The image configuration exactly as stored, including the fields
+    /// [`ImageConfig`] does not model.
+    pub raw_config: Vec<u8>,
+}
+
+impl Image {
+    /// Pair each layer descriptor with its diff ID, base layer first.
+    pub fn layers(&self) -> impl Iterator<Item = (&Descriptor, &str)> {
+        self.manifest
+            .layers
+            .iter()
+            .zip(self.config.rootfs.diff_ids.iter().map(String::as_str))
+    }
+}
+
+/// Resolve `reference` to a single-platform image.
+///
+/// When the reference points at a multi-platform index, the entry matching
+/// `platform` is chosen, or [`DEFAULT_PLATFORM`] when `platform` is `None`.
+/// The image configuration is downloaded and verified against its digest.
+///
+/// # Errors
+///
+/// * [`ApiError::PlatformNotFound`] — the index has no matching entry.
+/// * [`ApiError::DigestMismatch`] — a manifest or the configuration does
+///   not match its digest.
+/// * [`ApiError::JsonError`] — a manifest or the configuration is malformed.
+/// * [`ApiError::UnexpectedResponse`] — the configuration does not list one
+///   diff ID per layer.
+/// * Any error returned while fetching manifests or blobs.
+pub async fn resolve(
+    client: &reqwest::Client,
+    reference: &ImageRef,
+    platform: Option<&Platform>,
+) -> Result<Image, ApiError> {
+    log::trace!("resolve(reference: {reference}, platform: {platform:?})");
+
+    let url = reference.manifest_url(&reference.reference_or_latest())?;
+    let mut raw = api::get_manifest(client, &url).await?;
+    let manifest = match raw.parse()? {
+        Manifest::Image(m) => m,
+        Manifest::Index(index) => {
+            let default: Platform = DEFAULT_PLATFORM.parse()?;
+            let wanted = platform.unwrap_or(&default);
+            let child = index
+                .manifests
+                .iter()
+                .find(|d| d.platform.as_ref().is_some_and(|p| p.matches(wanted)))
+                .ok_or_else(|| {
+                    let available: Vec<String> = index
+                        .manifests
+                        .iter()
+                        .filter_map(|d| d.platform.as_ref().map(ToString::to_string))
+                        .collect();
+                    ApiError::PlatformNotFound(format!(
+                        "{wanted} (available: {})",
+                        available.join(", ")
+                    ))
+                })?;
+            log::debug!("Selected {} for {wanted}", child.digest);
+            raw = api::get_manifest(client, &reference.manifest_url(&child.digest)?).await?;
+            digest::verify(&child.digest, &raw.digest)?;
+            match raw.parse()? {
+                Manifest::Image(m) => m,
+                Manifest::Index(_) => {
+                    return Err(ApiError::UnexpectedResponse(format!(
+                        "Nested index {} is not supported",
+                        raw.digest
+                    )))
+                }
+            }
+        }
+    };
+
+    let config_bytes = blob::get(client, &reference.blob_url(&manifest.config.digest)?).await?;
+    digest::verify(&manifest.config.digest, &digest::sha256(&config_bytes))?;
+    let config: ImageConfig = serde_json::from_slice(&config_bytes)?;
+    if config.rootfs.diff_ids.len() != manifest.layers.len() {
+        return Err(ApiError::UnexpectedResponse(format!(
+            "Image config lists {} diff IDs for {} layers",
+            config.rootfs.diff_ids.len(),
+            manifest.layers.len()
+        )));
+    }
+
+    Ok(Image {
+        digest: raw.digest,
+        manifest,
+        config,
+        raw_config: config_bytes,
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use url::Url;
+
+    use super::*;
+
+    /// Serve an image with the given layers (as `(media_type, blob, tar)`)
+    /// from `repository` on `server` under `tag`, returning its manifest
+    /// body.
+    pub(crate) fn serve_image(
+        server: &mut mockito::Server,
+        repository: &str,
+        tag: &str,
+        layers: &[(&str, Vec<u8>, Vec<u8>)],
+    ) -> String {
+        let diff_ids: Vec<String> = layers
+            .iter()
+            .map(|(_, _, tar)| digest::sha256(tar))
+            .collect();
+        let config = serde_json::json!({
+            "architecture": "amd64",
+            "os": "linux",
+            "created": "2024-01-02T03:04:05Z",
+            "rootfs": {"type": "layers", "diff_ids": diff_ids},
+        })
+        .to_string();
+        let config_digest = digest::sha256(config.as_bytes());
+        server
+            .mock("GET", &*format!("/v2/{repository}/blobs/{config_digest}"))
+            .with_status(200)
+            .with_body(&config)
+            .create();
+
+        let mut descriptors = Vec::new();
+        for (media_type, blob, _) in layers {
+            let d = digest::sha256(blob);
+            server
+                .mock("GET", &*format!("/v2/{repository}/blobs/{d}"))
+                .with_status(200)
+                .with_body(blob)
+                .create();
+            descriptors.push(serde_json::json!({
+                "mediaType": media_type,
+                "digest": d,
+                "size": blob.len(),
+            }));
+        }
+        let manifest = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "config": {
+                "mediaType": "application/vnd.oci.image.config.v1+json",
+                "digest": config_digest,
+                "size": config.len(),
+            },
+            "layers": descriptors,
+        })
+        .to_string();
+        server
+            .mock("GET", &*format!("/v2/{repository}/manifests/{tag}"))
+            .with_status(200)
+            .with_header("content-type", "application/vnd.oci.image.manifest.v1+json")
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .with_body(&manifest)
+            .create();
+        manifest
+    }
+
+    /// Test that a platform is selected from an index and the configuration
+    /// of that platform is returned.
+    #[tokio::test]
+    async fn test_resolve_index_platform() -> Result<(), ApiError> {
+        let mut server = mockito::Server::new_async().await;
+        let manifest = serve_image(&mut server, "foo", "arm", &[]);
+        let child = digest::sha256(manifest.as_bytes());
+        server
+            .mock("GET", &*format!("/v2/foo/manifests/{child}"))
+            .with_status(200)
+            .with_header("content-type", "application/vnd.oci.image.manifest.v1+json")
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .with_body(&manifest)
+            .create();
+        let index = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.index.v1+json",
+            "manifests": [{
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "digest": child,
+                "size": manifest.len(),
+                "platform": {"os": "linux", "architecture": "arm64", "variant": "v8"},
+            }],
+        })
+        .to_string();
+        server
+            .mock("GET", "/v2/foo/manifests/latest")
+            .with_status(200)
+            .with_header("content-type", "application/vnd.oci.image.index.v1+json")
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .with_body(&index)
+            .create();
+
+        let registry = Url::parse(&server.url())?;
+        let reference = ImageRef::parse("foo", &registry)?;
+        let client = reqwest::Client::new();
+
+        let image = resolve(&client, &reference, Some(&"linux/arm64".parse()?)).await?;
+        assert_eq!(image.digest, child);
+        assert_eq!(image.config.architecture, "amd64");
+
+        let result = resolve(&client, &reference, None).await;
+        match result {
+            Err(ApiError::PlatformNotFound(msg)) => {
+                assert_eq!(msg, "linux/amd64 (available: linux/arm64/v8)");
+            }
+            other => panic!("expected PlatformNotFound, got {other:?}"),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,659 @@
+/*
+ * Copyright 2023 Anthony Oteri
+ *
+ * Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+ * http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+ * http://opensource.org/licenses/MIT>, at your option. This file may not be
+ * copied, modified, or distributed except according to those terms.
+ */
+
+use std::fmt;
+use std::io::Read;
+
+use flate2::read::MultiGzDecoder;
+use tar::EntryType;
+
+use crate::blob;
+use crate::digest;
+use crate::digest::Hasher;
+use crate::error::ApiError;
+use crate::manifest::Descriptor;
+use crate::reference::ImageRef;
+
+/// The compression applied to a layer's tar stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// A plain tar stream.
+    None,
+    /// A gzip-compressed tar stream.
+    Gzip,
+    /// A zstd-compressed tar stream.
+    Zstd,
+}
+
+impl Compression {
+    /// Determine the compression of a layer from its media type.
+    ///
+    /// Recognises the OCI `tar`, `tar+gzip`, and `tar+zstd` layer types
+    /// (including their non-distributable variants) and the Docker
+    /// `tar.gzip` layer types.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ApiError::UnsupportedMediaType`] for any other media type.
+    // Media type suffixes, not file extensions.
+    #[allow(clippy::case_sensitive_file_extension_comparisons)]
+    pub fn from_media_type(media_type: &str) -> Result<Self, ApiError> {
+        if media_type.ends_with(".tar") {
+            Ok(Self::None)
+        } else if media_type.ends_with(".tar+gzip") || media_type.ends_with(".tar.gzip") {
+            Ok(Self::Gzip)
+        } else if media_type.ends_with(".tar+zstd") {
+            Ok(Self::Zstd)
+        } else {
+            Err(ApiError::UnsupportedMediaType(String::from(media_type)))
+        }
+    }
+}
+
+/// A reader that feeds everything it reads into a [`Hasher`].
+struct HashingReader<'a, R> {
+    inner: R,
+    hasher: &'a mut Hasher,
+}
+
+impl<R: Read> Read for HashingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Decompress the layer blob read from `raw` and pass its tar stream to `f`.
+///
+/// The decompressor is chosen from the media type of `descriptor`.  Once
+/// `f` returns, the rest of the stream is drained so that the whole blob is
+/// hashed, and then both digests are verified: the compressed stream
+/// against the digest in `descriptor`, and the uncompressed stream against
+/// `diff_id` from the image configuration.  A result is only returned when
+/// both match.
+///
+/// This function blocks; call it from a blocking context.
+///
+/// # Errors
+///
+/// * [`ApiError::UnsupportedMediaType`] — the layer is not a tar stream
+///   compressed with a supported algorithm.
+/// * [`ApiError::IOError`] — reading or decompressing the stream failed.
+/// * [`ApiError::DigestMismatch`] — either digest does not match.
+/// * Any error returned by `f`.
+pub fn read<R, T>(
+    raw: R,
+    descriptor: &Descriptor,
+    diff_id: &str,
+    f: impl FnOnce(&mut dyn Read) -> Result<T, ApiError>,
+) -> Result<T, ApiError>
+where
+    R: Read,
+{
+    let compression = Compression::from_media_type(&descriptor.media_type)?;
+    let mut compressed_hasher = Hasher::new();
+    let mut diff_hasher = Hasher::new();
+
+    let result = {
+        let mut compressed = HashingReader {
+            inner: raw,
+            hasher: &mut compressed_hasher,
+        };
+        let result = {
+            let decoder: Box<dyn Read + '_> = match compression {
+                Compression::None => Box::new(&mut compressed),
+                Compression::Gzip => Box::new(MultiGzDecoder::new(&mut compressed)),
+                Compression::Zstd => Box::new(zstd::Decoder::new(&mut compressed)?),
+            };
+            let mut stream = HashingReader {
+                inner: decoder,
+                hasher: &mut diff_hasher,
+            };
+            let result = f(&mut stream)?;
+            std::io::copy(&mut stream, &mut std::io::sink())?;
+            result
+        };
+        std::io::copy(&mut compressed, &mut std::io::sink())?;
+        result
+    };
+
+    digest::verify(&descriptor.digest, &compressed_hasher.finish())?;
+    digest::verify(diff_id, &diff_hasher.finish())?;
+    Ok(result)
+}
+
+/// Fetch the layer described by `descriptor` from the repository of `image`
+/// and pass its tar stream to `f`, as described for [`read`].
+///
+/// The blob is streamed from the registry; `f` runs on a blocking thread
+/// while the download proceeds.
+///
+/// # Errors
+///
+/// Returns any error raised while fetching the blob, or by [`read`].
+pub async fn fetch<T, F>(
+    client: &reqwest::Client,
+    image: &ImageRef,
+    descriptor: &Descriptor,
+    diff_id: &str,
+    f: F,
+) -> Result<T, ApiError>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut dyn Read) -> Result<T, ApiError> + Send + 'static,
+{
+    log::trace!("fetch(image: {image}, digest: {})", descriptor.digest);
+    let reader = blob::open(client, &image.blob_url(&descriptor.digest)?).await?;
+    let (descriptor, diff_id) = (descriptor.clone(), String::from(diff_id));
+    tokio::task::spawn_blocking(move || read(reader, &descriptor, &diff_id, f))
+        .await
+        .map_err(|e| ApiError::UnexpectedResponse(e.to_string()))?
+}
+
+/// The kind of a tar entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// A regular file.
+    File,
+    /// A directory.
+    Directory,
+    /// A symbolic link.
+    Symlink,
+    /// A hard link to an earlier entry.
+    Hardlink,
+    /// A character device.
+    Char,
+    /// A block device.
+    Block,
+    /// A named pipe.
+    Fifo,
+    /// Any other entry type.
+    Other,
+}
+
+impl Kind {
+    /// The character `ls -l` shows for this kind.
+    fn symbol(self) -> char {
+        match self {
+            Self::File | Self::Other => '-',
+            Self::Directory => 'd',
+            Self::Symlink => 'l',
+            Self::Hardlink => 'h',
+            Self::Char => 'c',
+            Self::Block => 'b',
+            Self::Fifo => 'p',
+        }
+    }
+}
+
+impl From<EntryType> for Kind {
+    fn from(t: EntryType) -> Self {
+        match t {
+            EntryType::Regular | EntryType::Continuous => Self::File,
+            EntryType::Directory => Self::Directory,
+            EntryType::Symlink => Self::Symlink,
+            EntryType::Link => Self::Hardlink,
+            EntryType::Char => Self::Char,
+            EntryType::Block => Self::Block,
+            EntryType::Fifo => Self::Fifo,
+            _ => Self::Other,
+        }
+    }
+}
+
+/// The metadata of one entry in a layer's tar stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Path relative to the root, without leading `./` or `/` and without a
+    /// trailing `/`.
+    pub path: String,
+    /// The kind of entry.
+    pub kind: Kind,
+    /// Permission bits, including set-id and sticky bits.
+    pub mode: u32,
+    /// Owner user ID.
+    pub uid: u64,
+    /// Owner group ID.
+    pub gid: u64,
+    /// Size of the file contents in bytes; `0` for non-files.
+    pub size: u64,
+    /// Target of a symbolic or hard link.
+    pub link: Option<String>,
+    /// Modification time in seconds since the Unix epoch.
+    pub mtime: u64,
+    /// Digest of the contents of a regular file, when computed by
+    /// [`hashed_entries`].
+    pub digest: Option<String>,
+}
+
+impl Entry {
+    /// Read the metadata of a tar entry.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ApiError::IOError`] if the header is malformed.
+    pub fn from_tar<R: Read>(entry: &tar::Entry<'_, R>) -> Result<Self, ApiError> {
+        let header = entry.header();
+        let kind = Kind::from(header.entry_type());
+        Ok(Self {
+            path: normalize(&entry.path()?.to_string_lossy()),
+            kind,
+            mode: header.mode()? & 0o7777,
+            uid: header.uid()?,
+            gid: header.gid()?,
+            size: if kind == Kind::File { entry.size() } else { 0 },
+            link: entry
+                .link_name()?
+                .map(|l| String::from(l.to_string_lossy())),
+            mtime: header.mtime()?,
+            digest: None,
+        })
+    }
+
+    /// The `ls -l` style mode string, e.g. `drwxr-xr-x`.
+    pub fn mode_string(&self) -> String {
+        let mut s = String::with_capacity(10);
+        s.push(self.kind.symbol());
+        for (shift, special, set, unset) in [
+            (6, 0o4000, 's', 'S'),
+            (3, 0o2000, 's', 'S'),
+            (0, 0o1000, 't', 'T'),
+        ] {
+            let bits = (self.mode >> shift) & 0o7;
+            s.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+            s.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+            s.push(match (bits & 0o1 != 0, self.mode & special != 0) {
+                (true, true) => set,
+                (false, true) => unset,
+                (true, false) => 'x',
+                (false, false) => '-',
+            });
+        }
+        s
+    }
+
+    /// Format the entry like `ls -l`: mode, owner, size, modification time,
+    /// and `name` in place of the full path.
+    pub fn long(&self, name: &str) -> String {
+        format!(
+            "{} {:>11} {:>10} {} {name}{}",
+            self.mode_string(),
+            format!("{}/{}", self.uid, self.gid),
+            self.size,
+            format_time(self.mtime),
+            self.link_suffix()
+        )
+    }
+
+    /// The ` -> target` or ` link to target` suffix of a link, or nothing.
+    fn link_suffix(&self) -> String {
+        match (&self.kind, &self.link) {
+            (Kind::Symlink, Some(target)) => format!(" -> {target}"),
+            (Kind::Hardlink, Some(target)) => format!(" link to {}", normalize(target)),
+            _ => String::new(),
+        }
+    }
+}
+
+impl fmt::Display for Entry {
+    /// Format the entry like `tar -tv`: mode, owner, size, and path.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {:>11} {:>10} {}{}",
+            self.mode_string(),
+            format!("{}/{}", self.uid, self.gid),
+            self.size,
+            self.path,
+            self.link_suffix()
+        )
+    }
+}
+
+/// Split seconds since the Unix epoch into a UTC date and time of day:
+/// `(year, month, day, hour, minute, second)`.
+fn civil(secs: u64) -> (u64, u64, u64, u64, u64, u64) {
+    // Civil date from a day count, after Howard Hinnant's `civil_from_days`.
+    let days = secs / 86_400 + 719_468;
+    let era = days / 146_097;
+    let doe = days % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    let rem = secs % 86_400;
+    (year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+/// Format seconds since the Unix epoch as a UTC `YYYY-MM-DD HH:MM` string.
+pub fn format_time(secs: u64) -> String {
+    let (year, month, day, hour, minute, _) = civil(secs);
+    format!("{year:04}-{month:02}-{day:02} {hour:02}:{minute:02}")
+}
+
+/// Format seconds since the Unix epoch as an RFC 3339 UTC timestamp, as
+/// used in image configurations.
+pub fn format_rfc3339(secs: u64) -> String {
+    let (year, month, day, hour, minute, second) = civil(secs);
+    format!("{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}Z")
+}
+
+/// Parse an RFC 3339 timestamp, as found in image configurations, into
+/// seconds since the Unix epoch.
+///
+/// Fractions of a second are dropped and a numeric offset is converted to
+/// UTC.  Returns `None` for malformed timestamps and those before 1970.
+pub fn parse_rfc3339(s: &str) -> Option<u64> {
+    let number = |range: std::ops::Range<usize>| -> Option<i64> {
+        let digits = s.get(range)?;
+        digits
+            .bytes()
+            .all(|b| b.is_ascii_digit())
+            .then(|| digits.parse().ok())?
+    };
+    let separators = [(4, b'-'), (7, b'-'), (13, b':'), (16, b':')];
+    if separators
+        .iter()
+        .any(|&(i, c)| s.as_bytes().get(i) != Some(&c))
+        || !matches!(s.as_bytes().get(10), Some(b'T' | b't' | b' '))
+    {
+        return None;
+    }
+    let (year, month, day) = (number(0..4)?, number(5..7)?, number(8..10)?);
+    let (hour, minute, second) = (number(11..13)?, number(14..16)?, number(17..19)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+        return None;
+    }
+
+    let rest = &s[19..];
+    let zone = rest.trim_start_matches(|c: char| c == '.' || c.is_ascii_digit());
+    let offset = match zone.as_bytes() {
+        [b'Z' | b'z'] => 0,
+        [sign @ (b'+' | b'-'), h1, h2, b':', m1, m2] => {
+            let digits = [*h1, *h2, *m1, *m2];
+            if !digits.iter().all(u8::is_ascii_digit) {
+                return None;
+            }
+            let [h1, h2, m1, m2] = digits.map(|d| i64::from(d - b'0'));
+            let offset = (h1 * 10 + h2) * 3600 + (m1 * 10 + m2) * 60;
+            if *sign == b'+' {
+                offset
+            } else {
+                -offset
+            }
+        }
+        _ => return None,
+    };
+
+    // Day count from a civil date, after Howard Hinnant's `days_from_civil`.
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    let secs = days * 86_400 + hour * 3600 + minute * 60 + second - offset;
+    u64::try_from(secs).ok()
+}
+
+/// Strip a leading `./` or `/` and a trailing `/` from a tar path.
+pub fn normalize(path: &str) -> String {
+    let path = path.trim_start_matches("./").trim_start_matches('/');
+    String::from(path.trim_end_matches('/'))
+}
+
+/// Read every entry of the tar stream `tar`, skipping file contents.
+///
+/// # Errors
+///
+/// Returns [`ApiError::IOError`] if the stream is not a valid tar archive.
+pub fn entries(tar: &mut dyn Read) -> Result<Vec<Entry>, ApiError> {
+    let mut archive = tar::Archive::new(tar);
+    let mut entries = Vec::new();
+    for entry in archive.entries()? {
+        let entry = Entry::from_tar(&entry?)?;
+        if !entry.path.is_empty() {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// Read every entry of the tar stream `tar` like [`entries`], also computing
+/// the `sha256` digest of the contents of each regular file.
+///
+/// # Errors
+///
+/// Returns [`ApiError::IOError`] if the stream is not a valid tar archive.
+pub fn hashed_entries(tar: &mut dyn Read) -> Result<Vec<Entry>, ApiError> {
+    let mut archive = tar::Archive::new(tar);
+    let mut entries = Vec::new();
+    for item in archive.entries()? {
+        let mut item = item?;
+        let mut entry = Entry::from_tar(&item)?;
+        if entry.path.is_empty() {
+            continue;
+        }
+        if entry.kind == Kind::File {
+            let mut hasher = Hasher::new();
+            let mut contents = HashingReader {
+                inner: &mut item,
+                hasher: &mut hasher,
+            };
+            std::io::copy(&mut contents, &mut std::io::sink())?;
+            entry.digest = Some(hasher.finish());
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    /// Build a tar archive from `(path, contents)` pairs.  Paths ending in
+    /// `/` become directories; contents starting with `->` become symlinks
+    /// and contents starting with `=>` hard links.
+    pub(crate) fn tarball(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(0);
+            if path.ends_with('/') {
+                header.set_entry_type(EntryType::Directory);
+                header.set_mode(0o755);
+                header.set_size(0);
+                builder
+                    .append_data(&mut header, path, std::io::empty())
+                    .unwrap();
+            } else if let Some(target) = contents.strip_prefix("->") {
+                header.set_entry_type(EntryType::Symlink);
+                header.set_mode(0o777);
+                header.set_size(0);
+                builder.append_link(&mut header, path, target).unwrap();
+            } else if let Some(target) = contents.strip_prefix("=>") {
+                header.set_entry_type(EntryType::Link);
+                header.set_mode(0o644);
+                header.set_size(0);
+                builder.append_link(&mut header, path, target).unwrap();
+            } else {
+                header.set_entry_type(EntryType::Regular);
+                header.set_mode(0o644);
+                header.set_size(contents.len() as u64);
+                builder
+                    .append_data(&mut header, path, contents.as_bytes())
+                    .unwrap();
+            }
+        }
+        builder.into_inner().unwrap()
+    }
+
+    /// Gzip-compress `bytes`.
+    pub(crate) fn gzip(bytes: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn descriptor(media_type: &str, blob: &[u8]) -> Descriptor {
+        Descriptor {
+            media_type: String::from(media_type),
+            digest: digest::sha256(blob),
+            size: blob.len() as u64,
+            platform: None,
+            annotations: None,
+            urls: None,
+        }
+    }
+
+    /// Test that compression is derived from OCI and Docker media types.
+    #[test]
+    fn test_compression_from_media_type() {
+        for (media_type, expected) in [
+            ("application/vnd.oci.image.layer.v1.tar", Compression::None),
+            (
+                "application/vnd.oci.image.layer.v1.tar+gzip",
+                Compression::Gzip,
+            ),
+            (
+                "application/vnd.oci.image.layer.nondistributable.v1.tar+zstd",
+                Compression::Zstd,
+            ),
+            (
+                "application/vnd.docker.image.rootfs.diff.tar.gzip",
+                Compression::Gzip,
+            ),
+        ] {
+            assert_eq!(Compression::from_media_type(media_type).unwrap(), expected);
+        }
+        assert!(matches!(
+            Compression::from_media_type("application/vnd.in-toto+json"),
+            Err(ApiError::UnsupportedMediaType(_))
+        ));
+    }
+
+    /// Test that every supported compression decodes to the same entries and
+    /// that both digests are verified.
+    #[test]
+    fn test_read_all_compressions() {
+        let tar = tarball(&[
+            ("etc/", ""),
+            ("etc/hostname", "box\n"),
+            ("bin/sh", "->/bin/busybox"),
+        ]);
+        let diff_id = digest::sha256(&tar);
+        let zstd = zstd::encode_all(tar.as_slice(), 0).unwrap();
+
+        for (media_type, blob) in [
+            ("application/vnd.oci.image.layer.v1.tar", tar.clone()),
+            ("application/vnd.oci.image.layer.v1.tar+gzip", gzip(&tar)),
+            ("application/vnd.oci.image.layer.v1.tar+zstd", zstd),
+        ] {
+            let desc = descriptor(media_type, &blob);
+            let entries = read(blob.as_slice(), &desc, &diff_id, entries).unwrap();
+            let listing: Vec<String> = entries.iter().map(ToString::to_string).collect();
+            assert_eq!(
+                listing,
+                vec![
+                    "drwxr-xr-x         0/0          0 etc",
+                    "-rw-r--r--         0/0          4 etc/hostname",
+                    "lrwxrwxrwx         0/0          0 bin/sh -> /bin/busybox",
+                ],
+                "{media_type}"
+            );
+        }
+    }
+
+    /// Test that a mismatching diff ID is reported even when the compressed
+    /// digest matches.
+    #[test]
+    fn test_read_diff_id_mismatch() {
+        let tar = tarball(&[("a", "1")]);
+        let blob = gzip(&tar);
+        let desc = descriptor("application/vnd.oci.image.layer.v1.tar+gzip", &blob);
+        let result = read(blob.as_slice(), &desc, &digest::sha256(b"other"), entries);
+        assert!(matches!(result, Err(ApiError::DigestMismatch { .. })));
+    }
+
+    /// Test that the rest of the blob is hashed even if `f` stops early.
+    #[test]
+    fn test_read_drains_stream() {
+        let tar = tarball(&[("a", "1"), ("b", "2")]);
+        let blob = gzip(&tar);
+        let desc = descriptor("application/vnd.oci.image.layer.v1.tar+gzip", &blob);
+        let first = read(blob.as_slice(), &desc, &digest::sha256(&tar), |r| {
+            let mut archive = tar::Archive::new(r);
+            let entry = archive.entries()?.next().unwrap()?;
+            Ok(normalize(&entry.path()?.to_string_lossy()))
+        })
+        .unwrap();
+        assert_eq!(first, "a");
+    }
+
+    /// Test mode strings including set-id and sticky bits.
+    #[test]
+    fn test_mode_string() {
+        let mut entry = Entry {
+            path: String::from("tmp"),
+            kind: Kind::Directory,
+            mode: 0o1777,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            link: None,
+            mtime: 0,
+            digest: None,
+        };
+        assert_eq!(entry.mode_string(), "drwxrwxrwt");
+        entry.kind = Kind::File;
+        entry.mode = 0o4755;
+        assert_eq!(entry.mode_string(), "-rwsr-xr-x");
+        entry.mode = 0o2644;
+        assert_eq!(entry.mode_string(), "-rw-r-Sr--");
+    }
+
+    /// Test that modification times are formatted as UTC dates, including
+    /// leap days.
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time(0), "1970-01-01 00:00");
+        assert_eq!(format_time(1_704_164_645), "2024-01-02 03:04");
+        assert_eq!(format_time(951_782_400), "2000-02-29 00:00");
+        assert_eq!(format_rfc3339(1_704_164_645), "2024-01-02T03:04:05Z");
+    }
+
+    /// Test that RFC 3339 timestamps round-trip, honour offsets, drop
+    /// fractions, and reject malformed input.
+    #[test]
+    fn test_parse_rfc3339() {
+        assert_eq!(parse_rfc3339("2024-01-02T03:04:05Z"), Some(1_704_164_645));
+        assert_eq!(
+            parse_rfc3339("2024-01-02T03:04:05.123456789Z"),
+            Some(1_704_164_645)
+        );
+        assert_eq!(
+            parse_rfc3339("2024-01-02T05:34:05+02:30"),
+            Some(1_704_164_645)
+        );
+        assert_eq!(parse_rfc3339("2000-02-29T00:00:00Z"), Some(951_782_400));
+        assert_eq!(parse_rfc3339("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(parse_rfc3339("1969-12-31T23:59:59Z"), None);
+        assert_eq!(parse_rfc3339("2024-01-02"), None);
+        assert_eq!(parse_rfc3339("2024-13-02T03:04:05Z"), None);
+        assert_eq!(parse_rfc3339("2024-01-02T03:04:05"), None);
+    }
+}
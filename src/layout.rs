@@ -0,0 +1,148 @@
+/*
+ * Copyright 2023 Anthony Oteri
+ *
+ * Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+ * http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+ * http://opensource.org/licenses/MIT>, at your option. This file may not be
+ * copied, modified, or distributed except according to those terms.
+ */
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::digest;
+use crate::error::ApiError;
+use crate::manifest;
+use crate::manifest::Descriptor;
+use crate::manifest::ImageIndex;
+
+/// Annotation naming the reference (tag) of an `index.json` entry.
+const REF_NAME: &str = "org.opencontainers.image.ref.name";
+
+/// Contents of the `oci-layout` marker file.
+const OCI_LAYOUT: &str = r#"{"imageLayoutVersion":"1.0.0"}"#;
+
+/// A directory in [OCI Image Layout] format.
+///
+/// Blobs are stored content-addressed under `blobs/sha256/<hex>`, and the
+/// top-level `index.json` records which manifest each reference points to.
+///
+/// [OCI Image Layout]: https://github.com/opencontainers/image-spec/blob/main/image-layout.md
+pub struct OciLayout {
+    root: PathBuf,
+}
+
+impl OciLayout {
+    /// Open the layout at `root`, creating the directory structure and the
+    /// `oci-layout` marker file when they do not exist yet.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ApiError::IOError`] if the directories or marker file cannot
+    /// be created.
+    pub async fn create(root: &Path) -> Result<Self, ApiError> {
+        tokio::fs::create_dir_all(root.join("blobs").join("sha256")).await?;
+        let marker = root.join("oci-layout");
+        if !tokio::fs::try_exists(&marker).await? {
+            tokio::fs::write(&marker, OCI_LAYOUT).await?;
+        }
+        Ok(Self {
+            root: root.to_path_buf(),
+        })
+    }
+
+    /// Return the path at which the blob with `digest` is stored.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ApiError::UnsupportedDigest`] if `digest` is not a valid
+    /// `sha256` digest.
+    pub fn blob_path(&self, digest: &str) -> Result<PathBuf, ApiError> {
+        Ok(self
+            .root
+            .join("blobs")
+            .join("sha256")
+            .join(digest::encoded(digest)?))
+    }
+
+    /// Store an in-memory blob (typically a manifest) under its digest.
+    ///
+    /// # Errors
+    ///
+    /// * [`ApiError::UnsupportedDigest`] — `digest` is not a valid `sha256`
+    ///   digest.
+    /// * [`ApiError::IOError`] — the blob could not be written.
+    pub async fn write_blob(&self, digest: &str, bytes: &[u8]) -> Result<(), ApiError> {
+        tokio::fs::write(self.blob_path(digest)?, bytes).await?;
+        Ok(())
+    }
+
+    /// Record `descriptor` in `index.json` under the reference `name`,
+    /// replacing any previous entry with the same reference.
+    ///
+    /// # Errors
+    ///
+    /// * [`ApiError::IOError`] — `index.json` could not be read or written.
+    /// * [`ApiError::JsonError`] — an existing `index.json` is malformed.
+    pub async fn tag(&self, mut descriptor: Descriptor, name: &str) -> Result<(), ApiError> {
+        let path = self.root.join("index.json");
+        let mut index = match tokio::fs::read(&path).await {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => ImageIndex {
+                schema_version: 2,
+                media_type: Some(String::from(manifest::OCI_INDEX)),
+                manifests: Vec::new(),
+                annotations: None,
+            },
+            Err(e) => return Err(e.into()),
+        };
+
+        index.manifests.retain(|d| {
+            d.annotations
+                .as_ref()
+                .and_then(|a| a.get(REF_NAME))
+                .is_none_or(|n| n != name)
+        });
+        descriptor
+            .annotations
+            .get_or_insert_with(BTreeMap::new)
+            .insert(String::from(REF_NAME), String::from(name));
+        index.manifests.push(descriptor);
+
+        tokio::fs::write(&path, serde_json::to_vec_pretty(&index)?).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that tagging twice under the same name replaces the old entry
+    /// while leaving other references untouched.
+    #[tokio::test]
+    async fn test_tag_replaces_existing_reference() -> Result<(), ApiError> {
+        let root = std::env::temp_dir().join(format!("dredge-layout-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let layout = OciLayout::create(&root).await?;
+
+        let descriptor = |digest: &str| Descriptor {
+            media_type: String::from(manifest::OCI_MANIFEST),
+            digest: String::from(digest),
+            size: 1,
+            platform: None,
+            annotations: None,
+            urls: None,
+        };
+        layout.tag(descriptor("sha256:a"), "v1").await?;
+        layout.tag(descriptor("sha256:b"), "v2").await?;
+        layout.tag(descriptor("sha256:c"), "v1").await?;
+
+        let index: ImageIndex = serde_json::from_slice(&std::fs::read(root.join("index.json"))?)?;
+        let digests: Vec<&str> = index.manifests.iter().map(|d| d.digest.as_str()).collect();
+        assert_eq!(digests, vec!["sha256:b", "sha256:c"]);
+        assert!(root.join("oci-layout").exists());
+        Ok(())
+    }
+}
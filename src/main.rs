@@ -17,12 +17,39 @@ use url::Url;
 
 use crate::cli::Cli;
 use crate::cli::Commands;
+use crate::delete::DeleteOptions;
+use crate::error::ApiError;
 use crate::error::DredgeError;
+use crate::mutate::Mutation;
+use crate::pattern::Filter;
+use crate::retention::Policy;
+use crate::tags::Query;
 
+mod analyze;
 mod api;
+mod blob;
 pub(crate) mod cli;
 mod commands;
+mod copy;
+mod delete;
+mod diff;
+mod digest;
 mod error;
+mod image;
+mod layer;
+mod layout;
+mod manifest;
+mod mutate;
+mod packages;
+mod pattern;
+mod progress;
+mod push;
+mod rebase;
+mod reference;
+mod retention;
+mod rootfs;
+mod sync;
+mod tags;
 
 /// The default image tag used when no tag is specified by the caller.
 const LATEST: &str = "latest";
@@ -65,7 +92,9 @@ fn parse_registry_arg(host: &str) -> Result<Url, DredgeError> {
     Url::parse(&host).or(Err(DredgeError::RegistryUrlError(host.clone())))
 }
 
-#[tokio::main(flavor = "current_thread")]
+// One dispatch arm per subcommand.
+#[allow(clippy::too_many_lines)]
+#[tokio::main]
 async fn main() -> Result<(), DredgeError> {
     let args = Cli::parse();
 
@@ -83,25 +112,413 @@ async fn main() -> Result<(), DredgeError> {
 
     // -- Dispatch control to the appropriate command handler.
     let mut buf: Vec<u8> = Vec::new();
-    match args.command {
-        Commands::Catalog => commands::catalog_handler(&mut buf, &registry_url).await?,
-        Commands::Tags { name } => commands::tags_handler(&mut buf, &registry_url, &name).await?,
-        Commands::Show { image, tag } => {
-            commands::show_handler(
-                &mut buf,
-                &registry_url,
-                &image,
-                tag.as_deref().unwrap_or(LATEST),
-            )
-            .await?;
+    let result: Result<(), ApiError> = async {
+        match args.command {
+            Commands::Catalog {
+                include,
+                exclude,
+                namespace,
+                count,
+            } => {
+                commands::catalog_handler(
+                    &mut buf,
+                    &registry_url,
+                    &Filter { include, exclude },
+                    namespace.as_deref(),
+                    count,
+                )
+                .await?;
+            }
+            Commands::Tags {
+                name,
+                include,
+                exclude,
+                semver,
+                sort,
+                latest,
+                long,
+                count,
+                jobs,
+            } => {
+                let query = Query {
+                    filter: Filter { include, exclude },
+                    semver,
+                    sort,
+                    latest,
+                };
+                commands::tags_handler(
+                    &mut buf,
+                    &registry_url,
+                    &name,
+                    &query,
+                    long,
+                    count,
+                    jobs.into(),
+                )
+                .await?;
+            }
+            Commands::Show { image, tag } => {
+                commands::show_handler(
+                    &mut buf,
+                    &registry_url,
+                    &image,
+                    tag.as_deref().unwrap_or(LATEST),
+                )
+                .await?;
+            }
+            Commands::Delete {
+                image,
+                mut tags,
+                include,
+                from_file,
+                tag_only,
+                force,
+                cascade,
+                jobs,
+            } => {
+                if let Some(path) = from_file {
+                    tags.extend(delete::read_tags(&path)?);
+                }
+                let options = DeleteOptions {
+                    tag_only,
+                    force,
+                    cascade,
+                    jobs: jobs.into(),
+                };
+                commands::delete_handler(
+                    &mut buf,
+                    &registry_url,
+                    &image,
+                    &tags,
+                    &include,
+                    &options,
+                )
+                .await?;
+            }
+            Commands::Pull {
+                image,
+                tag,
+                output,
+                parallel,
+                jobs,
+            } => {
+                commands::pull_handler(
+                    &mut buf,
+                    &registry_url,
+                    &image,
+                    tag.as_deref().unwrap_or(LATEST),
+                    &output,
+                    parallel.into(),
+                    jobs.into(),
+                )
+                .await?;
+            }
+            Commands::Copy {
+                source,
+                destination,
+                src_creds,
+                dest_creds,
+                dry_run,
+                jobs,
+            } => {
+                commands::copy_handler(
+                    &mut buf,
+                    &registry_url,
+                    &source,
+                    &destination,
+                    src_creds.as_ref(),
+                    dest_creds.as_ref(),
+                    dry_run,
+                    jobs.into(),
+                )
+                .await?;
+            }
+            Commands::Sync {
+                source,
+                destination,
+                include,
+                exclude,
+                prune,
+                src_creds,
+                dest_creds,
+                dry_run,
+                jobs,
+            } => {
+                commands::sync_handler(
+                    &mut buf,
+                    &registry_url,
+                    &source,
+                    &destination,
+                    &Filter { include, exclude },
+                    prune,
+                    src_creds.as_ref(),
+                    dest_creds.as_ref(),
+                    dry_run,
+                    jobs.into(),
+                )
+                .await?;
+            }
+            Commands::Layer {
+                image,
+                layer,
+                platform,
+            } => {
+                commands::layer_handler(&mut buf, &registry_url, &image, &layer, platform.as_ref())
+                    .await?;
+            }
+            Commands::Ls {
+                image,
+                path,
+                long,
+                recursive,
+                platform,
+            } => {
+                commands::ls_handler(
+                    &mut buf,
+                    &registry_url,
+                    &image,
+                    path.as_deref(),
+                    long,
+                    recursive,
+                    platform.as_ref(),
+                )
+                .await?;
+            }
+            Commands::Cat {
+                image,
+                path,
+                platform,
+            } => {
+                commands::cat_handler(&mut buf, &registry_url, &image, &path, platform.as_ref())
+                    .await?;
+            }
+            Commands::Extract {
+                image,
+                path,
+                to,
+                platform,
+            } => {
+                commands::extract_handler(
+                    &mut buf,
+                    &registry_url,
+                    &image,
+                    &path,
+                    &to,
+                    platform.as_ref(),
+                )
+                .await?;
+            }
+            Commands::Export {
+                image,
+                output,
+                platform,
+            } => {
+                commands::export_handler(
+                    &mut buf,
+                    &registry_url,
+                    &image,
+                    &output,
+                    platform.as_ref(),
+                )
+                .await?;
+            }
+            Commands::Analyze {
+                image,
+                top,
+                min_efficiency,
+                platform,
+            } => {
+                commands::analyze_handler(
+                    &mut buf,
+                    &registry_url,
+                    &image,
+                    top,
+                    min_efficiency,
+                    platform.as_ref(),
+                )
+                .await?;
+            }
+            Commands::Diff {
+                image_a,
+                image_b,
+                json,
+                platform,
+            } => {
+                commands::diff_handler(
+                    &mut buf,
+                    &registry_url,
+                    &image_a,
+                    &image_b,
+                    json,
+                    platform.as_ref(),
+                )
+                .await?;
+            }
+            Commands::Packages {
+                image,
+                json,
+                platform,
+            } => {
+                commands::packages_handler(
+                    &mut buf,
+                    &registry_url,
+                    &image,
+                    json,
+                    platform.as_ref(),
+                )
+                .await?;
+            }
+            Commands::Append {
+                base,
+                layer,
+                tag,
+                platform,
+                jobs,
+            } => {
+                commands::append_handler(
+                    &mut buf,
+                    &registry_url,
+                    &base,
+                    &layer,
+                    &tag,
+                    platform.as_ref(),
+                    jobs.into(),
+                )
+                .await?;
+            }
+            Commands::Mutate {
+                image,
+                label,
+                env,
+                entrypoint,
+                cmd,
+                user,
+                workdir,
+                tag,
+                platform,
+                jobs,
+            } => {
+                let mutation = Mutation {
+                    labels: label,
+                    env,
+                    entrypoint,
+                    cmd,
+                    user,
+                    working_dir: workdir,
+                };
+                commands::mutate_handler(
+                    &mut buf,
+                    &registry_url,
+                    &image,
+                    &mutation,
+                    &tag,
+                    platform.as_ref(),
+                    jobs.into(),
+                )
+                .await?;
+            }
+            Commands::Flatten {
+                image,
+                tag,
+                platform,
+                jobs,
+            } => {
+                commands::flatten_handler(
+                    &mut buf,
+                    &registry_url,
+                    &image,
+                    &tag,
+                    platform.as_ref(),
+                    jobs.into(),
+                )
+                .await?;
+            }
+            Commands::Rebase {
+                image,
+                old_base,
+                new_base,
+                tag,
+                platform,
+                jobs,
+            } => {
+                commands::rebase_handler(
+                    &mut buf,
+                    &registry_url,
+                    &image,
+                    &old_base,
+                    &new_base,
+                    &tag,
+                    platform.as_ref(),
+                    jobs.into(),
+                )
+                .await?;
+            }
+            Commands::Prune {
+                policy,
+                dry_run,
+                yes,
+                cascade,
+                jobs,
+            } => {
+                // -- The plan must be seen before the deletes are confirmed
+                // -- and run, so it is not buffered.
+                commands::prune_handler(
+                    &mut io::stdout(),
+                    &registry_url,
+                    &Policy::load(&policy)?,
+                    dry_run,
+                    yes,
+                    cascade,
+                    jobs.into(),
+                )
+                .await?;
+            }
+            Commands::Aliases {
+                name,
+                reference,
+                children,
+                jobs,
+            } => {
+                commands::aliases_handler(
+                    &mut buf,
+                    &registry_url,
+                    &name,
+                    &reference,
+                    children,
+                    jobs.into(),
+                )
+                .await?;
+            }
+            Commands::Move {
+                source,
+                destination,
+                delete,
+                dry_run,
+                jobs,
+            } => {
+                commands::move_handler(
+                    &mut buf,
+                    &registry_url,
+                    &source,
+                    &destination,
+                    delete,
+                    dry_run,
+                    jobs.into(),
+                )
+                .await?;
+            }
+            Commands::Tag { image, tag } => {
+                commands::tag_handler(&mut buf, &registry_url, &image, &tag).await?;
+            }
+            Commands::Check => commands::check_handler(&mut buf, &registry_url).await?,
         }
-        Commands::Delete { image, tag } => {
-            commands::delete_handler(&mut buf, &registry_url, &image, &tag).await?;
-        }
-        Commands::Check => commands::check_handler(&mut buf, &registry_url).await?,
+        Ok(())
     }
+    .await;
 
+    // -- Write whatever was produced, even when the handler failed part way.
     io::stdout().write_all(&buf)?;
+    result?;
 
     Ok(())
 }
@@ -0,0 +1,445 @@
+/*
+ * Copyright 2023 Anthony Oteri
+ *
+ * Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+ * http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+ * http://opensource.org/licenses/MIT>, at your option. This file may not be
+ * copied, modified, or distributed except according to those terms.
+ */
+
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::error::ApiError;
+
+/// Docker Image Manifest V2, Schema 2.
+pub const DOCKER_MANIFEST_V2: &str = "application/vnd.docker.distribution.manifest.v2+json";
+
+/// Docker Manifest List (multi-platform image).
+pub const DOCKER_MANIFEST_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+
+/// OCI Image Manifest.
+pub const OCI_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+
+/// OCI Image Index (multi-platform image).
+pub const OCI_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+
+/// Docker gzip-compressed layer.
+pub const DOCKER_LAYER_GZIP: &str = "application/vnd.docker.image.rootfs.diff.tar.gzip";
+
+/// OCI gzip-compressed layer.
+pub const OCI_LAYER_GZIP: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+
+/// `Accept` header value listing every manifest media type `dredge` can parse.
+pub const ACCEPT_ALL: &str = "application/vnd.oci.image.index.v1+json, \
+     application/vnd.oci.image.manifest.v1+json, \
+     application/vnd.docker.distribution.manifest.list.v2+json, \
+     application/vnd.docker.distribution.manifest.v2+json";
+
+/// A content descriptor referencing a blob or manifest by digest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Descriptor {
+    /// Media type of the referenced content.
+    pub media_type: String,
+
+    /// Digest of the referenced content (e.g. `sha256:…`).
+    pub digest: String,
+
+    /// Size of the referenced content in bytes.
+    pub size: u64,
+
+    /// Target platform; only present on index entries.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<Platform>,
+
+    /// Arbitrary annotations attached to the descriptor.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<BTreeMap<String, String>>,
+
+    /// Alternate download locations for non-distributable content.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub urls: Option<Vec<String>>,
+}
+
+/// The platform an image manifest was built for.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Platform {
+    /// CPU architecture (e.g. `amd64`, `arm64`).
+    pub architecture: String,
+
+    /// Operating system (e.g. `linux`).
+    pub os: String,
+
+    /// Operating system version, used by Windows images.
+    #[serde(
+        rename = "os.version",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub os_version: Option<String>,
+
+    /// CPU variant (e.g. `v8` for `arm64`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+}
+
+impl std::fmt::Display for Platform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{variant}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for Platform {
+    type Err = ApiError;
+
+    /// Parse `OS/ARCH[/VARIANT]`, e.g. `linux/arm64/v8`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split('/');
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(os), Some(arch), variant, None) if !os.is_empty() && !arch.is_empty() => {
+                Ok(Self {
+                    architecture: String::from(arch),
+                    os: String::from(os),
+                    os_version: None,
+                    variant: variant.filter(|v| !v.is_empty()).map(String::from),
+                })
+            }
+            _ => Err(ApiError::InvalidPlatform(String::from(s))),
+        }
+    }
+}
+
+impl Platform {
+    /// Return `true` if this platform satisfies `wanted`.
+    ///
+    /// The operating system and architecture must be equal; the variant is
+    /// only compared when `wanted` names one.
+    pub fn matches(&self, wanted: &Platform) -> bool {
+        self.os == wanted.os
+            && self.architecture == wanted.architecture
+            && (wanted.variant.is_none() || self.variant == wanted.variant)
+    }
+}
+
+/// A single-platform image manifest (Docker V2 Schema 2 or OCI).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageManifest {
+    /// Always `2` for the formats supported here.
+    pub schema_version: u32,
+
+    /// Media type of the manifest itself; optional in OCI manifests.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+
+    /// Descriptor of the image configuration blob.
+    pub config: Descriptor,
+
+    /// Descriptors of the filesystem layers, base layer first.
+    pub layers: Vec<Descriptor>,
+
+    /// Arbitrary annotations attached to the manifest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<BTreeMap<String, String>>,
+}
+
+/// A multi-platform image index (OCI index or Docker manifest list).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageIndex {
+    /// Always `2` for the formats supported here.
+    pub schema_version: u32,
+
+    /// Media type of the index itself; optional in OCI indexes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+
+    /// Descriptors of the per-platform manifests.
+    pub manifests: Vec<Descriptor>,
+
+    /// Arbitrary annotations attached to the index.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<BTreeMap<String, String>>,
+}
+
+/// The image configuration blob referenced by an image manifest.
+///
+/// Only the fields `dredge` inspects are modelled; unknown fields are
+/// ignored.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageConfig {
+    /// CPU architecture the image was built for.
+    #[serde(default)]
+    pub architecture: String,
+
+    /// Operating system the image was built for.
+    #[serde(default)]
+    pub os: String,
+
+    /// Creation time as an RFC 3339 timestamp.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+
+    /// Defaults for containers started from the image.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config: Option<ContainerConfig>,
+
+    /// The uncompressed layer digests.  Missing from the configuration of
+    /// artifacts that are not container images, such as Helm charts.
+    #[serde(default)]
+    pub rootfs: RootFs,
+
+    /// How each layer was created, base layer first.  Entries with
+    /// `empty_layer` set do not correspond to a layer.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<History>,
+}
+
+/// The `config` section of an image configuration: the defaults for
+/// containers started from the image.
+///
+/// Only the fields `dredge` inspects are modelled; unknown fields are
+/// ignored.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ContainerConfig {
+    /// User, and optionally group, the process runs as.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+
+    /// Environment variables as `NAME=VALUE`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env: Option<Vec<String>>,
+
+    /// Command run when the container starts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entrypoint: Option<Vec<String>>,
+
+    /// Default arguments to the entrypoint.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cmd: Option<Vec<String>>,
+
+    /// Working directory of the process.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub working_dir: Option<String>,
+
+    /// Arbitrary metadata.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub labels: Option<BTreeMap<String, String>>,
+}
+
+/// The `rootfs` section of an image configuration.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RootFs {
+    /// Always `layers`.
+    #[serde(rename = "type")]
+    pub kind: String,
+
+    /// Digest of each layer's uncompressed tar stream, base layer first.
+    pub diff_ids: Vec<String>,
+}
+
+/// One entry of an image configuration's build history.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct History {
+    /// Creation time as an RFC 3339 timestamp.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+
+    /// The command that created the layer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_by: Option<String>,
+
+    /// A free-form comment.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+
+    /// `true` if this step did not produce a layer.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub empty_layer: bool,
+}
+
+/// A parsed manifest of any supported media type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[allow(clippy::large_enum_variant)]
+pub enum Manifest {
+    /// A single-platform image manifest.
+    Image(ImageManifest),
+    /// A multi-platform index whose entries are themselves manifests.
+    Index(ImageIndex),
+}
+
+/// Return `true` if `media_type` names a multi-platform index.
+pub fn is_index(media_type: &str) -> bool {
+    media_type == OCI_INDEX || media_type == DOCKER_MANIFEST_LIST
+}
+
+impl Manifest {
+    /// Parse a manifest body according to its `media_type`.
+    ///
+    /// When `media_type` is not one of the known manifest types (some
+    /// registries answer with a generic `application/json`), the body's own
+    /// `mediaType` field is consulted, and failing that the presence of a
+    /// `manifests` array identifies an index.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ApiError::JsonError`] if the body is not a valid manifest.
+    pub fn from_slice(media_type: &str, bytes: &[u8]) -> Result<Self, ApiError> {
+        let index = match media_type {
+            OCI_INDEX | DOCKER_MANIFEST_LIST => true,
+            OCI_MANIFEST | DOCKER_MANIFEST_V2 => false,
+            _ => {
+                #[derive(Deserialize)]
+                #[serde(rename_all = "camelCase")]
+                struct Probe {
+                    media_type: Option<String>,
+                    manifests: Option<serde_json::Value>,
+                }
+                let probe: Probe = serde_json::from_slice(bytes)?;
+                probe
+                    .media_type
+                    .as_deref()
+                    .map_or_else(|| probe.manifests.is_some(), is_index)
+            }
+        };
+
+        if index {
+            Ok(Self::Index(serde_json::from_slice(bytes)?))
+        } else {
+            Ok(Self::Image(serde_json::from_slice(bytes)?))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IMAGE: &str = r#"{
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.manifest.v1+json",
+        "config": {
+            "mediaType": "application/vnd.oci.image.config.v1+json",
+            "digest": "sha256:1111111111111111111111111111111111111111111111111111111111111111",
+            "size": 100
+        },
+        "layers": [
+            {
+                "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip",
+                "digest": "sha256:2222222222222222222222222222222222222222222222222222222222222222",
+                "size": 2000
+            }
+        ]
+    }"#;
+
+    const INDEX: &str = r#"{
+        "schemaVersion": 2,
+        "manifests": [
+            {
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "digest": "sha256:3333333333333333333333333333333333333333333333333333333333333333",
+                "size": 500,
+                "platform": {"architecture": "arm64", "os": "linux", "variant": "v8"}
+            }
+        ]
+    }"#;
+
+    /// Test that an OCI image manifest is parsed by its media type.
+    #[test]
+    fn test_from_slice_image() {
+        let manifest = Manifest::from_slice(OCI_MANIFEST, IMAGE.as_bytes()).unwrap();
+        let Manifest::Image(image) = manifest else {
+            panic!("Expected an image manifest");
+        };
+        assert_eq!(image.layers.len(), 1);
+        assert_eq!(image.layers[0].size, 2000);
+        assert_eq!(image.config.size, 100);
+    }
+
+    /// Test that an index served as generic JSON is still recognised by the
+    /// presence of its `manifests` array.
+    #[test]
+    fn test_from_slice_index_generic_media_type() {
+        let manifest = Manifest::from_slice("application/json", INDEX.as_bytes()).unwrap();
+        let Manifest::Index(index) = manifest else {
+            panic!("Expected an index");
+        };
+        let platform = index.manifests[0].platform.as_ref().unwrap();
+        assert_eq!(platform.to_string(), "linux/arm64/v8");
+    }
+
+    /// Test that an image manifest served as generic JSON falls back to its
+    /// embedded `mediaType` field.
+    #[test]
+    fn test_from_slice_image_generic_media_type() {
+        let manifest = Manifest::from_slice("application/json", IMAGE.as_bytes()).unwrap();
+        assert!(matches!(manifest, Manifest::Image(_)));
+    }
+
+    /// Test that a malformed body surfaces a JSON error.
+    #[test]
+    fn test_from_slice_invalid() {
+        let result = Manifest::from_slice(OCI_MANIFEST, b"{}");
+        assert!(matches!(result, Err(ApiError::JsonError(_))));
+    }
+
+    /// Test that platforms parse with and without a variant and match
+    /// ignoring the variant only when none is requested.
+    #[test]
+    fn test_platform_parse_and_match() {
+        let arm: Platform = "linux/arm64/v8".parse().unwrap();
+        assert_eq!(arm.to_string(), "linux/arm64/v8");
+        let any_arm: Platform = "linux/arm64".parse().unwrap();
+        assert!(arm.matches(&any_arm));
+        assert!(!any_arm.matches(&arm));
+        assert!(!arm.matches(&"linux/amd64".parse().unwrap()));
+        for bad in ["linux", "/amd64", "a/b/c/d"] {
+            assert!(matches!(
+                bad.parse::<Platform>(),
+                Err(ApiError::InvalidPlatform(_))
+            ));
+        }
+    }
+
+    /// Test that an image configuration yields its diff IDs and history.
+    #[test]
+    fn test_image_config() {
+        let config: ImageConfig = serde_json::from_str(
+            r#"{
+                "architecture": "amd64",
+                "os": "linux",
+                "config": {"Env": ["PATH=/bin"], "Labels": null, "ExposedPorts": {"80/tcp": {}}},
+                "rootfs": {"type": "layers", "diff_ids": ["sha256:aa"]},
+                "history": [
+                    {"created_by": "ADD rootfs.tar /"},
+                    {"created_by": "ENV PATH=/bin", "empty_layer": true}
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.rootfs.diff_ids, vec!["sha256:aa"]);
+        assert_eq!(config.history.len(), 2);
+        assert!(config.history[1].empty_layer);
+        let container = config.config.unwrap();
+        assert_eq!(container.env, Some(vec![String::from("PATH=/bin")]));
+        assert_eq!(container.labels, None);
+    }
+
+    /// Test that the configuration of an artifact that is not an image,
+    /// such as a Helm chart, parses without `rootfs`.
+    #[test]
+    fn test_image_config_without_rootfs() {
+        let config: ImageConfig =
+            serde_json::from_str(r#"{"name": "nginx", "version": "1.2.0"}"#).unwrap();
+        assert!(config.rootfs.diff_ids.is_empty());
+        assert!(config.os.is_empty());
+    }
+}
@@ -0,0 +1,215 @@
+/*
+ * Copyright 2023 Anthony Oteri
+ *
+ * Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+ * http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+ * http://opensource.org/licenses/MIT>, at your option. This file may not be
+ * copied, modified, or distributed except according to those terms.
+ */
+
+use serde_json::Value;
+
+use crate::error::ApiError;
+
+/// A `KEY=VALUE` pair given on the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyValue {
+    /// The part before the first `=`.
+    pub key: String,
+    /// The part after the first `=`, possibly empty.
+    pub value: String,
+}
+
+impl std::str::FromStr for KeyValue {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((key, value)) if !key.is_empty() => Ok(Self {
+                key: String::from(key),
+                value: String::from(value),
+            }),
+            _ => Err(String::from("expected KEY=VALUE")),
+        }
+    }
+}
+
+/// A command line for `Entrypoint` or `Cmd`: either a JSON array of
+/// strings, or words separated by whitespace.  An empty string clears the
+/// value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Argv(pub Vec<String>);
+
+impl std::str::FromStr for Argv {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim_start().starts_with('[') {
+            serde_json::from_str(s)
+                .map(Self)
+                .map_err(|e| format!("expected a JSON array of strings: {e}"))
+        } else {
+            Ok(Self(s.split_whitespace().map(String::from).collect()))
+        }
+    }
+}
+
+/// Changes to the container defaults of an image configuration.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Mutation {
+    /// Labels to add or replace.
+    pub labels: Vec<KeyValue>,
+    /// Environment variables to add or replace.
+    pub env: Vec<KeyValue>,
+    /// New entrypoint; empty to clear it.
+    pub entrypoint: Option<Argv>,
+    /// New default arguments; empty to clear them.
+    pub cmd: Option<Argv>,
+    /// New user.
+    pub user: Option<String>,
+    /// New working directory.
+    pub working_dir: Option<String>,
+}
+
+impl Mutation {
+    /// Return `true` if the mutation changes nothing.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Apply the mutation to the `config` section of the image
+    /// configuration `image_config`, creating the section if needed.
+    ///
+    /// Only the fields being changed are touched.  A variable that is
+    /// already set is replaced in place, keeping the order of `Env`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ApiError::UnexpectedResponse`] if `config`, `Env`, or
+    /// `Labels` exist with the wrong JSON type.
+    pub fn apply(&self, image_config: &mut Value) -> Result<(), ApiError> {
+        let config = object(&mut image_config["config"], "config")?;
+
+        if !self.labels.is_empty() {
+            let labels = object(
+                config.entry("Labels").or_insert(Value::Null),
+                "config.Labels",
+            )?;
+            for KeyValue { key, value } in &self.labels {
+                labels.insert(key.clone(), Value::from(value.as_str()));
+            }
+        }
+
+        if !self.env.is_empty() {
+            let env = config.entry("Env").or_insert(Value::Null);
+            if env.is_null() {
+                *env = Value::Array(Vec::new());
+            }
+            let env = env.as_array_mut().ok_or_else(|| {
+                ApiError::UnexpectedResponse(String::from("Image config.Env is not an array"))
+            })?;
+            for KeyValue { key, value } in &self.env {
+                let entry = Value::from(format!("{key}={value}"));
+                let prefix = format!("{key}=");
+                match env.iter_mut().find(|v| {
+                    v.as_str()
+                        .is_some_and(|v| v.starts_with(&prefix) || v == key)
+                }) {
+                    Some(existing) => *existing = entry,
+                    None => env.push(entry),
+                }
+            }
+        }
+
+        for (field, argv) in [("Entrypoint", &self.entrypoint), ("Cmd", &self.cmd)] {
+            if let Some(Argv(argv)) = argv {
+                let value = if argv.is_empty() {
+                    Value::Null
+                } else {
+                    Value::from(argv.clone())
+                };
+                config.insert(String::from(field), value);
+            }
+        }
+        if let Some(user) = &self.user {
+            config.insert(String::from("User"), Value::from(user.as_str()));
+        }
+        if let Some(working_dir) = &self.working_dir {
+            config.insert(
+                String::from("WorkingDir"),
+                Value::from(working_dir.as_str()),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Return `value` as a JSON object, turning `null` into an empty one.
+fn object<'a>(
+    value: &'a mut Value,
+    name: &str,
+) -> Result<&'a mut serde_json::Map<String, Value>, ApiError> {
+    if value.is_null() {
+        *value = Value::Object(serde_json::Map::new());
+    }
+    value
+        .as_object_mut()
+        .ok_or_else(|| ApiError::UnexpectedResponse(format!("Image {name} is not an object")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that command lines are parsed from JSON arrays and from words.
+    #[test]
+    fn test_argv_from_str() {
+        let argv: Argv = r#"["/bin/sh", "-c", "echo hi"]"#.parse().unwrap();
+        assert_eq!(argv.0, ["/bin/sh", "-c", "echo hi"]);
+        let argv: Argv = "/app --serve".parse().unwrap();
+        assert_eq!(argv.0, ["/app", "--serve"]);
+        assert!("".parse::<Argv>().unwrap().0.is_empty());
+        assert!("[1]".parse::<Argv>().is_err());
+        assert!("=x".parse::<KeyValue>().is_err());
+    }
+
+    /// Test that changes replace existing values in place, add new ones,
+    /// clear emptied commands, and leave unknown fields alone.
+    #[test]
+    fn test_apply() {
+        let mut config = serde_json::json!({
+            "architecture": "amd64",
+            "moby.buildkit.buildinfo.v1": "e30=",
+            "config": {
+                "Env": ["PATH=/bin", "LANG=C"],
+                "Cmd": ["nginx"],
+                "StopSignal": "SIGQUIT",
+            },
+        });
+        let mutation = Mutation {
+            labels: vec!["team=web".parse().unwrap()],
+            env: vec![
+                "PATH=/usr/bin:/bin".parse().unwrap(),
+                "TZ=UTC".parse().unwrap(),
+            ],
+            cmd: Some("".parse().unwrap()),
+            user: Some(String::from("nginx")),
+            ..Mutation::default()
+        };
+        mutation.apply(&mut config).unwrap();
+        assert_eq!(
+            config,
+            serde_json::json!({
+                "architecture": "amd64",
+                "moby.buildkit.buildinfo.v1": "e30=",
+                "config": {
+                    "Env": ["PATH=/usr/bin:/bin", "LANG=C", "TZ=UTC"],
+                    "Cmd": null,
+                    "StopSignal": "SIGQUIT",
+                    "Labels": {"team": "web"},
+                    "User": "nginx",
+                },
+            })
+        );
+    }
+}
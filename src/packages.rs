@@ -0,0 +1,562 @@
+/*
+ * Copyright 2023 Anthony Oteri
+ *
+ * Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+ * http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+ * http://opensource.org/licenses/MIT>, at your option. This file may not be
+ * copied, modified, or distributed except according to those terms.
+ */
+
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use serde::Serialize;
+
+use crate::error::ApiError;
+
+/// The dpkg database of installed packages.
+const DPKG_STATUS: &str = "var/lib/dpkg/status";
+
+/// Directory holding one dpkg status file per package, as used by
+/// distroless images.
+const DPKG_STATUS_DIR: &str = "var/lib/dpkg/status.d/";
+
+/// Locations of the apk database of installed packages.
+const APK_INSTALLED: [&str; 2] = ["lib/apk/db/installed", "usr/lib/apk/db/installed"];
+
+/// Locations of the RPM sqlite database.
+const RPM_SQLITE: [&str; 2] = [
+    "var/lib/rpm/rpmdb.sqlite",
+    "usr/lib/sysimage/rpm/rpmdb.sqlite",
+];
+
+/// Locations of the RPM ndb database.
+const RPM_NDB: [&str; 2] = [
+    "var/lib/rpm/Packages.db",
+    "usr/lib/sysimage/rpm/Packages.db",
+];
+
+/// RPM header tags read from each package.
+const RPMTAG_NAME: u32 = 1000;
+const RPMTAG_VERSION: u32 = 1001;
+const RPMTAG_RELEASE: u32 = 1002;
+const RPMTAG_EPOCH: u32 = 1003;
+const RPMTAG_ARCH: u32 = 1022;
+const RPMTAG_SOURCERPM: u32 = 1044;
+
+/// Magic numbers of the RPM ndb format, little-endian.
+const NDB_HEADER_MAGIC: u32 = u32::from_le_bytes(*b"RpmP");
+const NDB_SLOT_MAGIC: u32 = u32::from_le_bytes(*b"Slot");
+const NDB_BLOB_MAGIC: u32 = u32::from_le_bytes(*b"BlbS");
+
+/// Size of an ndb slot page, and of the blocks blob offsets count in.
+const NDB_PAGE_SIZE: usize = 4096;
+const NDB_BLOCK_SIZE: usize = 16;
+
+/// The package manager whose database listed a package.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Database {
+    /// Debian's dpkg.
+    Dpkg,
+    /// Alpine's apk.
+    Apk,
+    /// RPM, from a sqlite or ndb database.
+    Rpm,
+}
+
+impl Database {
+    /// Short name used in listings.
+    fn label(self) -> &'static str {
+        match self {
+            Self::Dpkg => "dpkg",
+            Self::Apk => "apk",
+            Self::Rpm => "rpm",
+        }
+    }
+}
+
+/// An installed package.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct Package {
+    /// The database that lists the package.
+    #[serde(rename = "type")]
+    pub database: Database,
+    /// Package name.
+    pub name: String,
+    /// Installed version, including the epoch and release where the
+    /// package manager has them.
+    pub version: String,
+    /// Architecture the package was built for.
+    pub architecture: String,
+    /// Name of the source package, when recorded.
+    pub source: Option<String>,
+}
+
+/// Return `true` if `path` is the location of a supported package
+/// database.
+pub fn is_database(path: &str) -> bool {
+    path == DPKG_STATUS
+        || path
+            .strip_prefix(DPKG_STATUS_DIR)
+            .is_some_and(|name| !name.contains('/') && !name.ends_with(".md5sums"))
+        || APK_INSTALLED.contains(&path)
+        || RPM_SQLITE.contains(&path)
+        || RPM_NDB.contains(&path)
+}
+
+/// Parse the package databases in `files`, keyed by the paths selected by
+/// [`is_database`], into a sorted list without duplicates.
+///
+/// RPM `gpg-pubkey` entries, which record imported signing keys rather
+/// than software, are skipped.
+///
+/// # Errors
+///
+/// Returns [`ApiError::InvalidDatabase`] if an RPM database cannot be read.
+pub fn parse(files: &BTreeMap<String, Vec<u8>>) -> Result<Vec<Package>, ApiError> {
+    let mut packages = Vec::new();
+    for (path, contents) in files {
+        log::debug!("Reading package database {path}");
+        if RPM_SQLITE.contains(&path.as_str()) {
+            packages.extend(parse_rpm_sqlite(contents)?);
+        } else if RPM_NDB.contains(&path.as_str()) {
+            packages.extend(parse_rpm_ndb(contents)?);
+        } else if APK_INSTALLED.contains(&path.as_str()) {
+            packages.extend(parse_apk(&String::from_utf8_lossy(contents)));
+        } else {
+            packages.extend(parse_dpkg(&String::from_utf8_lossy(contents)));
+        }
+    }
+    packages.retain(|p| !(p.database == Database::Rpm && p.name == "gpg-pubkey"));
+    packages.sort();
+    packages.dedup();
+    Ok(packages)
+}
+
+/// Write `packages` as an aligned table to `buf`.
+///
+/// # Errors
+///
+/// Returns [`ApiError::IOError`] if writing to `buf` fails.
+pub fn write_to(packages: &[Package], buf: &mut dyn Write) -> Result<(), ApiError> {
+    let width = |header: &str, f: &dyn Fn(&Package) -> usize| {
+        packages.iter().map(f).max().unwrap_or(0).max(header.len())
+    };
+    let name = width("NAME", &|p| p.name.len());
+    let version = width("VERSION", &|p| p.version.len());
+    let arch = width("ARCH", &|p| p.architecture.len());
+    let source = width("SOURCE", &|p| p.source.as_deref().unwrap_or("-").len());
+
+    writeln!(
+        buf,
+        "{:<name$}  {:<version$}  {:<arch$}  {:<source$}  TYPE",
+        "NAME", "VERSION", "ARCH", "SOURCE"
+    )?;
+    for p in packages {
+        writeln!(
+            buf,
+            "{:<name$}  {:<version$}  {:<arch$}  {:<source$}  {}",
+            p.name,
+            p.version,
+            p.architecture,
+            p.source.as_deref().unwrap_or("-"),
+            p.database.label()
+        )?;
+    }
+    Ok(())
+}
+
+/// Split a control-file style database into paragraphs of `(key, value)`
+/// fields, separated by blank lines.  Continuation lines are skipped.
+fn paragraphs(text: &str, separator: char) -> Vec<BTreeMap<&str, &str>> {
+    let mut paragraphs = Vec::new();
+    let mut fields = BTreeMap::new();
+    for line in text.lines() {
+        if line.trim().is_empty() {
+            if !fields.is_empty() {
+                paragraphs.push(std::mem::take(&mut fields));
+            }
+        } else if !line.starts_with([' ', '\t']) {
+            if let Some((key, value)) = line.split_once(separator) {
+                fields.insert(key, value.trim());
+            }
+        }
+    }
+    if !fields.is_empty() {
+        paragraphs.push(fields);
+    }
+    paragraphs
+}
+
+/// Parse a dpkg `status` file, keeping only installed packages.  A package
+/// without a `Source` field is its own source.
+fn parse_dpkg(text: &str) -> Vec<Package> {
+    paragraphs(text, ':')
+        .into_iter()
+        .filter(|f| f.get("Status").is_none_or(|s| s.ends_with(" installed")))
+        .filter_map(|f| {
+            let name = *f.get("Package")?;
+            let source = f
+                .get("Source")
+                .and_then(|s| s.split_whitespace().next())
+                .unwrap_or(name);
+            Some(Package {
+                database: Database::Dpkg,
+                name: String::from(name),
+                version: String::from(*f.get("Version").unwrap_or(&"")),
+                architecture: String::from(*f.get("Architecture").unwrap_or(&"")),
+                source: Some(String::from(source)),
+            })
+        })
+        .collect()
+}
+
+/// Parse an apk `installed` file.  The origin is the source package.
+fn parse_apk(text: &str) -> Vec<Package> {
+    paragraphs(text, ':')
+        .into_iter()
+        .filter_map(|f| {
+            Some(Package {
+                database: Database::Apk,
+                name: String::from(*f.get("P")?),
+                version: String::from(*f.get("V").unwrap_or(&"")),
+                architecture: String::from(*f.get("A").unwrap_or(&"")),
+                source: f.get("o").map(|o| String::from(*o)),
+            })
+        })
+        .collect()
+}
+
+/// An error for a malformed RPM database.
+fn invalid(what: &str) -> ApiError {
+    ApiError::InvalidDatabase(format!("RPM {what}"))
+}
+
+/// Read a big-endian `u32` at `offset` of `bytes`.
+fn be32(bytes: &[u8], offset: usize) -> Result<u32, ApiError> {
+    bytes
+        .get(offset..offset + 4)
+        .and_then(|b| b.try_into().ok())
+        .map(u32::from_be_bytes)
+        .ok_or_else(|| invalid("header is truncated"))
+}
+
+/// Read a little-endian `u32` at `offset` of `bytes`.
+fn le32(bytes: &[u8], offset: usize) -> Result<u32, ApiError> {
+    bytes
+        .get(offset..offset + 4)
+        .and_then(|b| b.try_into().ok())
+        .map(u32::from_le_bytes)
+        .ok_or_else(|| invalid("ndb database is truncated"))
+}
+
+/// Parse an RPM header blob: an index of tags pointing into a data store.
+fn parse_rpm_header(blob: &[u8]) -> Result<Package, ApiError> {
+    let count = be32(blob, 0)? as usize;
+    let data_len = be32(blob, 4)? as usize;
+    let data_start = count
+        .checked_mul(16)
+        .and_then(|n| n.checked_add(8))
+        .ok_or_else(|| invalid("header is truncated"))?;
+    let data = blob
+        .get(data_start..data_start.saturating_add(data_len))
+        .ok_or_else(|| invalid("header is truncated"))?;
+
+    let mut strings = BTreeMap::new();
+    let mut epoch = None;
+    for i in 0..count {
+        let entry = 8 + i * 16;
+        let tag = be32(blob, entry)?;
+        let offset = be32(blob, entry + 8)? as usize;
+        match tag {
+            RPMTAG_EPOCH => epoch = Some(be32(data, offset)?),
+            RPMTAG_NAME | RPMTAG_VERSION | RPMTAG_RELEASE | RPMTAG_ARCH | RPMTAG_SOURCERPM => {
+                let bytes = data
+                    .get(offset..)
+                    .ok_or_else(|| invalid("header is truncated"))?;
+                let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+                strings.insert(tag, String::from_utf8_lossy(&bytes[..end]).into_owned());
+            }
+            _ => {}
+        }
+    }
+
+    let mut get = |tag| strings.remove(&tag).unwrap_or_default();
+    let name = get(RPMTAG_NAME);
+    let mut version = format!("{}-{}", get(RPMTAG_VERSION), get(RPMTAG_RELEASE));
+    if let Some(epoch) = epoch.filter(|e| *e != 0) {
+        version = format!("{epoch}:{version}");
+    }
+    // `<name>-<version>-<release>.src.rpm`
+    let source = Some(get(RPMTAG_SOURCERPM))
+        .filter(|s| !s.is_empty())
+        .and_then(|s| {
+            s.trim_end_matches(".src.rpm")
+                .trim_end_matches(".nosrc.rpm")
+                .rsplitn(3, '-')
+                .nth(2)
+                .map(String::from)
+        });
+    Ok(Package {
+        database: Database::Rpm,
+        name,
+        version,
+        architecture: get(RPMTAG_ARCH),
+        source,
+    })
+}
+
+/// Parse an RPM sqlite database, as used by RPM 4.16 and later.
+fn parse_rpm_sqlite(bytes: &[u8]) -> Result<Vec<Package>, ApiError> {
+    // SQLite only opens files, so work on a private copy.  The directory is
+    // created securely and readable by the current user only, holds any
+    // journal SQLite writes next to the database, and is removed with
+    // everything in it when dropped, whatever happens below.
+    let dir = tempfile::Builder::new().prefix("dredge-rpmdb-").tempdir()?;
+    let path = dir.path().join("rpmdb.sqlite");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(&path)?.write_all(bytes)?;
+
+    let blobs = (|| -> rusqlite::Result<Vec<Vec<u8>>> {
+        let conn = rusqlite::Connection::open(&path)?;
+        let mut statement = conn.prepare("SELECT blob FROM Packages")?;
+        let rows = statement.query_map([], |row| row.get(0))?;
+        rows.collect()
+    })();
+
+    blobs
+        .map_err(|e| ApiError::InvalidDatabase(format!("RPM sqlite: {e}")))?
+        .iter()
+        .map(|blob| parse_rpm_header(blob))
+        .collect()
+}
+
+/// Parse an RPM ndb database (`Packages.db`), as used by SUSE.
+///
+/// The file starts with a header naming the number of slot pages.  Each
+/// slot points, in 16-byte blocks, at a blob holding one package header.
+fn parse_rpm_ndb(bytes: &[u8]) -> Result<Vec<Package>, ApiError> {
+    if le32(bytes, 0)? != NDB_HEADER_MAGIC || le32(bytes, 4)? != 0 {
+        return Err(invalid("ndb database has an unsupported header"));
+    }
+    let slot_pages = le32(bytes, 12)? as usize;
+    // The 32-byte header takes the place of the first two slots.
+    let slots = (slot_pages * NDB_PAGE_SIZE / 16).saturating_sub(2);
+
+    let mut packages = Vec::new();
+    for slot in 0..slots {
+        let at = 32 + slot * 16;
+        if le32(bytes, at)? != NDB_SLOT_MAGIC {
+            return Err(invalid("ndb slot is corrupt"));
+        }
+        let index = le32(bytes, at + 4)?;
+        if index == 0 {
+            continue;
+        }
+        let offset = le32(bytes, at + 8)? as usize * NDB_BLOCK_SIZE;
+        if le32(bytes, offset)? != NDB_BLOB_MAGIC || le32(bytes, offset + 4)? != index {
+            return Err(invalid("ndb blob is corrupt"));
+        }
+        let len = le32(bytes, offset + 12)? as usize;
+        let blob = bytes
+            .get(offset + 16..offset + 16 + len)
+            .ok_or_else(|| invalid("ndb database is truncated"))?;
+        packages.push(parse_rpm_header(blob)?);
+    }
+    Ok(packages)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Build an RPM header blob with the given string tags and epoch.
+    pub(crate) fn rpm_header(strings: &[(u32, &str)], epoch: Option<u32>) -> Vec<u8> {
+        let mut index = Vec::new();
+        let mut data = Vec::new();
+        for (tag, value) in strings {
+            index.extend([*tag, 6, u32::try_from(data.len()).unwrap(), 1]);
+            data.extend(value.as_bytes());
+            data.push(0);
+        }
+        if let Some(epoch) = epoch {
+            while data.len() % 4 != 0 {
+                data.push(0);
+            }
+            index.extend([RPMTAG_EPOCH, 4, u32::try_from(data.len()).unwrap(), 1]);
+            data.extend(epoch.to_be_bytes());
+        }
+        let mut blob = Vec::new();
+        blob.extend(u32::try_from(index.len() / 4).unwrap().to_be_bytes());
+        blob.extend(u32::try_from(data.len()).unwrap().to_be_bytes());
+        for n in index {
+            blob.extend(n.to_be_bytes());
+        }
+        blob.extend(data);
+        blob
+    }
+
+    fn bash() -> Vec<u8> {
+        rpm_header(
+            &[
+                (RPMTAG_NAME, "bash"),
+                (RPMTAG_VERSION, "5.1.8"),
+                (RPMTAG_RELEASE, "6.el9"),
+                (RPMTAG_ARCH, "x86_64"),
+                (RPMTAG_SOURCERPM, "bash-5.1.8-6.el9.src.rpm"),
+            ],
+            Some(1),
+        )
+    }
+
+    /// Build an ndb database with one slot page holding `blobs`.
+    fn ndb(blobs: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for n in [NDB_HEADER_MAGIC, 0, 1, 1, 0, 0, 0, 0] {
+            bytes.extend(n.to_le_bytes());
+        }
+        let mut storage = Vec::new();
+        let slots = NDB_PAGE_SIZE / 16 - 2;
+        for slot in 0..slots {
+            let (index, offset) = match blobs.get(slot) {
+                Some(blob) => {
+                    let offset = (NDB_PAGE_SIZE + storage.len()) / NDB_BLOCK_SIZE;
+                    let index = u32::try_from(slot + 1).unwrap();
+                    let len = u32::try_from(blob.len()).unwrap();
+                    for n in [NDB_BLOB_MAGIC, index, 0, len] {
+                        storage.extend(n.to_le_bytes());
+                    }
+                    storage.extend(blob);
+                    while storage.len() % NDB_BLOCK_SIZE != 0 {
+                        storage.push(0);
+                    }
+                    (index, u32::try_from(offset).unwrap())
+                }
+                None => (0, 0),
+            };
+            for n in [NDB_SLOT_MAGIC, index, offset, 0] {
+                bytes.extend(n.to_le_bytes());
+            }
+        }
+        bytes.extend(storage);
+        bytes
+    }
+
+    /// Test that installed dpkg packages are listed, with the source name
+    /// stripped of its version, and that removed packages are skipped.
+    #[test]
+    fn test_parse_dpkg() {
+        let status = "\
+Package: libc6
+Status: install ok installed
+Architecture: amd64
+Source: glibc (2.36-9)
+Version: 2.36-9+deb12u4
+Description: GNU C Library
+ Contains the standard libraries.
+
+Package: old
+Status: deinstall ok config-files
+Version: 1.0
+
+Package: bash
+Status: install ok installed
+Architecture: amd64
+Version: 5.2.15-2+b2
+";
+        let packages = parse_dpkg(status);
+        assert_eq!(packages.len(), 2);
+        assert_eq!(packages[0].source.as_deref(), Some("glibc"));
+        assert_eq!(packages[1].name, "bash");
+        assert_eq!(packages[1].source.as_deref(), Some("bash"));
+    }
+
+    /// Test that apk packages are listed with their origin as the source.
+    #[test]
+    fn test_parse_apk() {
+        let installed = "C:Q1abc=\nP:musl\nV:1.2.4-r2\nA:x86_64\no:musl\n\nP:busybox-binsh\nV:1.36.1-r15\nA:x86_64\no:busybox\n";
+        let packages = parse_apk(installed);
+        assert_eq!(
+            packages[1],
+            Package {
+                database: Database::Apk,
+                name: String::from("busybox-binsh"),
+                version: String::from("1.36.1-r15"),
+                architecture: String::from("x86_64"),
+                source: Some(String::from("busybox")),
+            }
+        );
+    }
+
+    /// Test that an RPM header yields the epoch, version, release, and
+    /// source package name.
+    #[test]
+    fn test_parse_rpm_header() {
+        let package = parse_rpm_header(&bash()).unwrap();
+        assert_eq!(package.name, "bash");
+        assert_eq!(package.version, "1:5.1.8-6.el9");
+        assert_eq!(package.architecture, "x86_64");
+        assert_eq!(package.source.as_deref(), Some("bash"));
+
+        assert!(matches!(
+            parse_rpm_header(&bash()[..20]),
+            Err(ApiError::InvalidDatabase(_))
+        ));
+    }
+
+    /// Test that packages are read from an ndb database and that corrupt
+    /// slots are rejected.
+    #[test]
+    fn test_parse_rpm_ndb() {
+        let packages = parse_rpm_ndb(&ndb(&[bash()])).unwrap();
+        assert_eq!(packages.len(), 1);
+        assert_eq!(packages[0].name, "bash");
+
+        let mut corrupt = ndb(&[bash()]);
+        corrupt[32] = b'X';
+        assert!(matches!(
+            parse_rpm_ndb(&corrupt),
+            Err(ApiError::InvalidDatabase(_))
+        ));
+    }
+
+    /// Test that packages are read from an RPM sqlite database.
+    #[test]
+    fn test_parse_rpm_sqlite() {
+        let path =
+            std::env::temp_dir().join(format!("dredge-test-rpmdb-{}.sqlite", std::process::id()));
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute(
+            "CREATE TABLE Packages (hnum INTEGER PRIMARY KEY AUTOINCREMENT, blob BLOB NOT NULL)",
+            [],
+        )
+        .unwrap();
+        conn.execute("INSERT INTO Packages (blob) VALUES (?1)", [bash()])
+            .unwrap();
+        drop(conn);
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let packages = parse_rpm_sqlite(&bytes).unwrap();
+        assert_eq!(packages.len(), 1);
+        assert_eq!(packages[0].version, "1:5.1.8-6.el9");
+    }
+
+    /// Test which paths are recognised as package databases.
+    #[test]
+    fn test_is_database() {
+        assert!(is_database("var/lib/dpkg/status"));
+        assert!(is_database("var/lib/dpkg/status.d/base-files"));
+        assert!(!is_database("var/lib/dpkg/status.d/base-files.md5sums"));
+        assert!(is_database("lib/apk/db/installed"));
+        assert!(is_database("usr/lib/sysimage/rpm/rpmdb.sqlite"));
+        assert!(!is_database("var/lib/dpkg/status-old"));
+    }
+}
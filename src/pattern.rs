@@ -0,0 +1,217 @@
+/*
+ * Copyright 2023 Anthony Oteri
+ *
+ * Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+ * http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+ * http://opensource.org/licenses/MIT>, at your option. This file may not be
+ * copied, modified, or distributed except according to those terms.
+ */
+
+use std::str::FromStr;
+
+use regex::Regex;
+
+use crate::error::ApiError;
+
+/// A shell-style wildcard pattern.
+///
+/// `*` matches any run of characters (including none) and `?` matches
+/// exactly one character; every other character matches itself.  A pattern
+/// must match the whole name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Glob(String);
+
+impl Glob {
+    /// Return `true` if `name` matches this pattern in full.
+    pub fn matches(&self, name: &str) -> bool {
+        let pattern: Vec<char> = self.0.chars().collect();
+        let name: Vec<char> = name.chars().collect();
+
+        // Iterative wildcard matching with single-star backtracking.
+        let (mut p, mut n) = (0, 0);
+        let mut star: Option<(usize, usize)> = None;
+        while n < name.len() {
+            match pattern.get(p) {
+                Some('*') => {
+                    star = Some((p, n));
+                    p += 1;
+                }
+                Some('?') => {
+                    p += 1;
+                    n += 1;
+                }
+                Some(c) if *c == name[n] => {
+                    p += 1;
+                    n += 1;
+                }
+                _ => match star {
+                    Some((sp, sn)) => {
+                        p = sp + 1;
+                        n = sn + 1;
+                        star = Some((sp, sn + 1));
+                    }
+                    None => return false,
+                },
+            }
+        }
+        pattern[p..].iter().all(|c| *c == '*')
+    }
+}
+
+impl FromStr for Glob {
+    type Err = ApiError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err(ApiError::InvalidPattern(String::from(
+                "pattern must not be empty",
+            )));
+        }
+        Ok(Self(String::from(s)))
+    }
+}
+
+/// A name pattern given on the command line or in a retention policy: a
+/// regular expression when written between slashes, as in `/^pr-[0-9]+$/`,
+/// and a [`Glob`] otherwise.
+///
+/// Unlike a glob, a regular expression matches anywhere in the name unless
+/// it is anchored with `^` and `$`.
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    /// A shell-style wildcard pattern.
+    Glob(Glob),
+    /// A regular expression.
+    Regex(Regex),
+}
+
+impl Pattern {
+    /// Return `true` if `name` matches this pattern.
+    pub fn matches(&self, name: &str) -> bool {
+        match self {
+            Self::Glob(glob) => glob.matches(name),
+            Self::Regex(regex) => regex.is_match(name),
+        }
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Glob(a), Self::Glob(b)) => a == b,
+            (Self::Regex(a), Self::Regex(b)) => a.as_str() == b.as_str(),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Pattern {}
+
+impl FromStr for Pattern {
+    type Err = ApiError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix('/').and_then(|s| s.strip_suffix('/')) {
+            Some(regex) => Regex::new(regex)
+                .map(Self::Regex)
+                .map_err(|e| ApiError::InvalidPattern(format!("{s}: {e}"))),
+            None => s.parse().map(Self::Glob),
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Pattern {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Include and exclude patterns selecting a subset of names.
+///
+/// A name is selected when it matches at least one include pattern (or no
+/// include patterns were given) and matches none of the exclude patterns.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Filter {
+    /// Patterns a name must match one of; empty selects every name.
+    pub include: Vec<Pattern>,
+    /// Patterns a name must not match.
+    pub exclude: Vec<Pattern>,
+}
+
+impl Filter {
+    /// Return `true` if `name` is selected by this filter.
+    pub fn matches(&self, name: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|g| g.matches(name)))
+            && !self.exclude.iter().any(|g| g.matches(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob(s: &str) -> Glob {
+        s.parse().unwrap()
+    }
+
+    fn pattern(s: &str) -> Pattern {
+        s.parse().unwrap()
+    }
+
+    /// Test literal, `?`, and `*` matching against whole names.
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob("v1.0").matches("v1.0"));
+        assert!(!glob("v1.0").matches("v1.0.1"));
+        assert!(glob("v?.0").matches("v2.0"));
+        assert!(glob("v1.*").matches("v1."));
+        assert!(glob("v1.*").matches("v1.2.3"));
+        assert!(!glob("v1.*").matches("v2.0"));
+        assert!(glob("*-rc*").matches("v2.0.0-rc1"));
+        assert!(glob("*a*b").matches("xaaab"));
+        assert!(!glob("*a*b").matches("xaaa"));
+        assert!(glob("*").matches(""));
+    }
+
+    /// Test that an empty pattern is rejected.
+    #[test]
+    fn test_glob_empty() {
+        assert!(matches!(
+            "".parse::<Glob>(),
+            Err(ApiError::InvalidPattern(_))
+        ));
+    }
+
+    /// Test that excludes win over includes and that no includes selects
+    /// everything.
+    #[test]
+    fn test_filter_matches() {
+        let all = Filter::default();
+        assert!(all.matches("anything"));
+
+        let filter = Filter {
+            include: vec![pattern("v*")],
+            exclude: vec![pattern("*-rc*")],
+        };
+        assert!(filter.matches("v1.0.0"));
+        assert!(!filter.matches("v1.0.0-rc1"));
+        assert!(!filter.matches("latest"));
+    }
+
+    /// Test that patterns between slashes are unanchored regular
+    /// expressions, and that invalid ones are rejected.
+    #[test]
+    fn test_pattern_regex() {
+        assert_eq!(pattern("v1.*"), Pattern::Glob(glob("v1.*")));
+        assert!(pattern("/^pr-[0-9]+$/").matches("pr-42"));
+        assert!(!pattern("/^pr-[0-9]+$/").matches("pr-42-fix"));
+        assert!(pattern("/rc/").matches("v2.0.0-rc1"));
+        assert!(!pattern("/rc/").matches("v2.0.0"));
+        assert!(matches!(
+            "/(/".parse::<Pattern>(),
+            Err(ApiError::InvalidPattern(_))
+        ));
+    }
+}
@@ -0,0 +1,291 @@
+/*
+ * Copyright 2023 Anthony Oteri
+ *
+ * Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+ * http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+ * http://opensource.org/licenses/MIT>, at your option. This file may not be
+ * copied, modified, or distributed except according to those terms.
+ */
+
+use std::fmt::Write as _;
+use std::io::IsTerminal;
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Minimum time between two redraws of the progress bars.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Width of a bar, in characters, between the brackets.
+const BAR_WIDTH: usize = 30;
+
+/// How progress is reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// Redraw one bar per blob plus an aggregate bar on stderr.
+    Bars,
+    /// Write a log line when each blob starts and finishes.
+    Log,
+}
+
+/// Progress of one blob transfer.
+#[derive(Debug)]
+struct BarState {
+    label: String,
+    size: u64,
+    done: u64,
+}
+
+/// Shared state behind a [`Progress`].
+#[derive(Debug)]
+struct State {
+    /// Active bars by id; finished bars leave a `None` behind.
+    bars: Vec<Option<BarState>>,
+    count: usize,
+    finished: usize,
+    total: u64,
+    done: u64,
+    /// Number of lines drawn by the previous redraw.
+    drawn: usize,
+    last_draw: Option<Instant>,
+}
+
+/// Progress reporting for a set of concurrent blob transfers.
+///
+/// When stderr is a terminal and info-level logging is enabled, each active
+/// transfer gets its own bar, followed by an aggregate bar over all blobs.
+/// Otherwise, a plain log line is written as each blob starts and finishes,
+/// so that output captured by CI systems stays readable.
+///
+/// A `Progress` is cheap to clone; clones report into the same display.
+#[derive(Debug, Clone)]
+pub struct Progress {
+    mode: Mode,
+    state: Arc<Mutex<State>>,
+}
+
+impl Progress {
+    /// Start reporting progress for `count` blobs totalling `total` bytes.
+    pub fn new(count: usize, total: u64) -> Self {
+        let mode = if std::io::stderr().is_terminal() && log::max_level() >= log::Level::Info {
+            Mode::Bars
+        } else {
+            Mode::Log
+        };
+        Self {
+            mode,
+            state: Arc::new(Mutex::new(State {
+                bars: Vec::new(),
+                count,
+                finished: 0,
+                total,
+                done: 0,
+                drawn: 0,
+                last_draw: None,
+            })),
+        }
+    }
+
+    /// Add a bar for a blob of `size` bytes identified by `label`.
+    pub fn start(&self, label: &str, size: u64) -> Bar {
+        let mut state = self.lock();
+        if self.mode == Mode::Log {
+            log::info!("Transferring {label} ({})", human_bytes(size));
+        }
+        state.bars.push(Some(BarState {
+            label: String::from(label),
+            size,
+            done: 0,
+        }));
+        let id = state.bars.len() - 1;
+        self.draw(&mut state, true);
+        Bar {
+            progress: self.clone(),
+            id,
+        }
+    }
+
+    /// Erase the bars from the terminal.  Does nothing in log mode.
+    pub fn clear(&self) {
+        let mut state = self.lock();
+        if self.mode == Mode::Bars && state.drawn > 0 {
+            let mut stderr = std::io::stderr().lock();
+            let _ = write!(stderr, "\x1b[{}A\x1b[J", state.drawn);
+            let _ = stderr.flush();
+            state.drawn = 0;
+        }
+    }
+
+    /// Lock the shared state, recovering it if another thread panicked.
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// Redraw the bars, at most once per [`REDRAW_INTERVAL`] unless `force`.
+    fn draw(&self, state: &mut State, force: bool) {
+        if self.mode != Mode::Bars {
+            return;
+        }
+        let now = Instant::now();
+        if !force
+            && state
+                .last_draw
+                .is_some_and(|t| now.duration_since(t) < REDRAW_INTERVAL)
+        {
+            return;
+        }
+        state.last_draw = Some(now);
+
+        let mut out = String::new();
+        if state.drawn > 0 {
+            let _ = write!(out, "\x1b[{}A", state.drawn);
+        }
+        out.push_str("\x1b[J");
+        let mut lines = 0;
+        for bar in state.bars.iter().flatten() {
+            let label: String = bar.label.chars().take(19).collect();
+            let _ = writeln!(
+                out,
+                "{label:<19} {} {:>10} / {}",
+                render(bar.done, bar.size),
+                human_bytes(bar.done),
+                human_bytes(bar.size)
+            );
+            lines += 1;
+        }
+        let _ = writeln!(
+            out,
+            "{:<19} {} {:>10} / {} ({}/{} blobs)",
+            "total",
+            render(state.done, state.total),
+            human_bytes(state.done),
+            human_bytes(state.total),
+            state.finished,
+            state.count
+        );
+        state.drawn = lines + 1;
+
+        let mut stderr = std::io::stderr().lock();
+        let _ = stderr.write_all(out.as_bytes());
+        let _ = stderr.flush();
+    }
+}
+
+/// The progress of a single blob transfer within a [`Progress`].
+#[derive(Debug, Clone)]
+pub struct Bar {
+    progress: Progress,
+    id: usize,
+}
+
+impl Bar {
+    /// Record that `n` more bytes were transferred.
+    pub fn inc(&self, n: u64) {
+        let mut state = self.progress.lock();
+        if let Some(Some(bar)) = state.bars.get_mut(self.id) {
+            bar.done += n;
+        }
+        state.done += n;
+        self.progress.draw(&mut state, false);
+    }
+
+    /// Mark the transfer as complete and remove its bar.
+    ///
+    /// Bytes not reported through [`Bar::inc`], e.g. for a blob that was
+    /// mounted rather than transferred, are added to the aggregate.
+    pub fn finish(&self) {
+        let mut state = self.progress.lock();
+        let Some(bar) = state.bars.get_mut(self.id).and_then(Option::take) else {
+            return;
+        };
+        state.done += bar.size.saturating_sub(bar.done);
+        state.finished += 1;
+        if self.progress.mode == Mode::Log {
+            log::info!(
+                "[{}/{}] {} done ({} of {})",
+                state.finished,
+                state.count,
+                bar.label,
+                human_bytes(state.done),
+                human_bytes(state.total)
+            );
+        }
+        self.progress.draw(&mut state, true);
+    }
+}
+
+/// Render a bar of [`BAR_WIDTH`] characters showing `done` out of `total`.
+fn render(done: u64, total: u64) -> String {
+    let filled = done
+        .min(total)
+        .saturating_mul(BAR_WIDTH as u64)
+        .checked_div(total)
+        .map_or(BAR_WIDTH, |f| usize::try_from(f).unwrap_or(BAR_WIDTH));
+    format!("[{}{}]", "#".repeat(filled), "-".repeat(BAR_WIDTH - filled))
+}
+
+/// Format `bytes` with a binary unit suffix, e.g. `12.3 MiB`.
+pub fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    #[allow(clippy::cast_precision_loss)]
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that byte counts are formatted with binary units.
+    #[test]
+    fn test_human_bytes() {
+        assert_eq!(human_bytes(0), "0 B");
+        assert_eq!(human_bytes(1023), "1023 B");
+        assert_eq!(human_bytes(1536), "1.5 KiB");
+        assert_eq!(human_bytes(5 * 1024 * 1024 * 1024), "5.0 GiB");
+    }
+
+    /// Test that bars fill proportionally and never overflow.
+    #[test]
+    fn test_render() {
+        assert_eq!(render(0, 10), format!("[{}]", "-".repeat(BAR_WIDTH)));
+        assert_eq!(render(10, 10), format!("[{}]", "#".repeat(BAR_WIDTH)));
+        assert_eq!(render(20, 10), format!("[{}]", "#".repeat(BAR_WIDTH)));
+        assert_eq!(render(0, 0), format!("[{}]", "#".repeat(BAR_WIDTH)));
+        assert_eq!(
+            render(5, 10),
+            format!("[{}{}]", "#".repeat(15), "-".repeat(15))
+        );
+    }
+
+    /// Test that finishing a bar credits its remaining bytes to the
+    /// aggregate and counts it once.
+    #[test]
+    fn test_finish_accounts_remaining_bytes() {
+        let progress = Progress::new(2, 30);
+        let a = progress.start("a", 10);
+        let b = progress.start("b", 20);
+        a.inc(4);
+        a.finish();
+        a.finish();
+        b.finish();
+
+        let state = progress.lock();
+        assert_eq!(state.done, 30);
+        assert_eq!(state.finished, 2);
+        assert!(state.bars.iter().all(Option::is_none));
+    }
+}
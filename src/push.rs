@@ -0,0 +1,562 @@
+/*
+ * Copyright 2023 Anthony Oteri
+ *
+ * Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+ * http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+ * http://opensource.org/licenses/MIT>, at your option. This file may not be
+ * copied, modified, or distributed except according to those terms.
+ */
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use flate2::write::GzEncoder;
+use tempfile::TempPath;
+
+use crate::api;
+use crate::blob;
+use crate::digest;
+use crate::digest::Hasher;
+use crate::error::ApiError;
+use crate::image::Image;
+use crate::layer;
+use crate::manifest;
+use crate::manifest::Descriptor;
+use crate::manifest::History;
+use crate::manifest::ImageManifest;
+use crate::progress::Progress;
+use crate::reference::ImageRef;
+use crate::reference::Reference;
+
+/// A gzip-compressed layer written to a temporary file, which is removed
+/// when the layer is dropped.
+#[derive(Debug)]
+pub struct LocalLayer {
+    /// Descriptor of the compressed blob.
+    pub descriptor: Descriptor,
+    /// Digest of the uncompressed tar stream.
+    pub diff_id: String,
+    /// Location of the compressed blob.
+    path: TempPath,
+}
+
+/// A writer that hashes and counts everything written through it.
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Hasher,
+    size: u64,
+}
+
+impl<W: Write> HashingWriter<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Hasher::new(),
+            size: 0,
+        }
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The current time as an RFC 3339 timestamp, for `created` fields.
+pub fn now() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    layer::format_rfc3339(secs)
+}
+
+/// Return the gzip layer media type matching the format of `manifest`.
+pub fn gzip_layer_type(manifest: &ImageManifest) -> &'static str {
+    if manifest.media_type.as_deref() == Some(manifest::DOCKER_MANIFEST_V2) {
+        manifest::DOCKER_LAYER_GZIP
+    } else {
+        manifest::OCI_LAYER_GZIP
+    }
+}
+
+/// A [`Write`] sink that gzips a tar stream into a temporary file,
+/// computing both the digest of the blob and the diff ID of the stream on
+/// the way.  [`LayerWriter::finish`] turns it into a [`LocalLayer`].
+///
+/// Writing blocks; use it from a blocking context.
+pub struct LayerWriter {
+    encoder: GzEncoder<HashingWriter<BufWriter<File>>>,
+    stream: Hasher,
+    layer: LocalLayer,
+}
+
+impl LayerWriter {
+    /// Create the temporary file for a layer of type `media_type`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ApiError::IOError`] if the file cannot be created.
+    pub fn create(media_type: &str) -> Result<Self, ApiError> {
+        let (file, path) = tempfile::Builder::new()
+            .prefix("dredge-layer-")
+            .suffix(".tar.gz")
+            .tempfile()?
+            .into_parts();
+        let layer = LocalLayer {
+            descriptor: Descriptor {
+                media_type: String::from(media_type),
+                digest: String::new(),
+                size: 0,
+                platform: None,
+                annotations: None,
+                urls: None,
+            },
+            diff_id: String::new(),
+            path,
+        };
+        let file = BufWriter::new(file);
+        Ok(Self {
+            encoder: GzEncoder::new(HashingWriter::new(file), flate2::Compression::default()),
+            stream: Hasher::new(),
+            layer,
+        })
+    }
+
+    /// Complete the compressed stream and describe the layer.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ApiError::IOError`] if writing the temporary file fails.
+    pub fn finish(self) -> Result<LocalLayer, ApiError> {
+        let Self {
+            encoder,
+            stream,
+            mut layer,
+        } = self;
+        let mut blob = encoder.finish()?;
+        blob.flush()?;
+        layer.descriptor.digest = blob.hasher.finish();
+        layer.descriptor.size = blob.size;
+        layer.diff_id = stream.finish();
+        log::debug!(
+            "Compressed layer {} ({} bytes, diff ID {})",
+            layer.descriptor.digest,
+            layer.descriptor.size,
+            layer.diff_id
+        );
+        Ok(layer)
+    }
+}
+
+impl Write for LayerWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.encoder.write(buf)?;
+        self.stream.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.encoder.flush()
+    }
+}
+
+/// Gzip the tar stream `tar` into a temporary file with a [`LayerWriter`].
+///
+/// This function blocks; call it from a blocking context.
+///
+/// # Errors
+///
+/// Returns [`ApiError::IOError`] if reading `tar` or writing the temporary
+/// file fails.
+pub fn compress(tar: &mut dyn Read, media_type: &str) -> Result<LocalLayer, ApiError> {
+    let mut writer = LayerWriter::create(media_type)?;
+    std::io::copy(tar, &mut writer)?;
+    writer.finish()
+}
+
+/// Where the content of a layer of a [`Draft`] comes from.
+#[derive(Debug)]
+enum Source {
+    /// A blob in a repository, mounted or streamed to the target.
+    Remote(ImageRef),
+    /// A blob compressed locally, uploaded to the target.
+    Local(LocalLayer),
+}
+
+/// A new image assembled from an existing one, ready to be pushed.
+///
+/// The configuration is kept as JSON rather than as an [`ImageConfig`], so
+/// that fields `dredge` does not model survive unchanged.
+///
+/// [`ImageConfig`]: crate::manifest::ImageConfig
+#[derive(Debug)]
+pub struct Draft {
+    /// Manifest of the new image.  The configuration descriptor is filled
+    /// in by [`Draft::push`].
+    pub manifest: ImageManifest,
+    /// Configuration of the new image.
+    pub config: serde_json::Value,
+    /// Where each layer can be found, by digest.
+    sources: BTreeMap<String, Source>,
+}
+
+impl Draft {
+    /// Start a draft from `image`, resolved from `reference`, whose layers
+    /// all live in the repository of `reference`.
+    ///
+    /// # Errors
+    ///
+    /// * [`ApiError::JsonError`] — the configuration is not valid JSON.
+    /// * [`ApiError::UnexpectedResponse`] — the configuration is not a JSON
+    ///   object.
+    pub fn new(reference: &ImageRef, image: &Image) -> Result<Self, ApiError> {
+        let config: serde_json::Value = serde_json::from_slice(&image.raw_config)?;
+        if !config.is_object() {
+            return Err(ApiError::UnexpectedResponse(format!(
+                "Image config {} is not a JSON object",
+                image.manifest.config.digest
+            )));
+        }
+        let sources = image
+            .manifest
+            .layers
+            .iter()
+            .map(|d| (d.digest.clone(), Source::Remote(reference.clone())))
+            .collect();
+        Ok(Self {
+            manifest: image.manifest.clone(),
+            config,
+            sources,
+        })
+    }
+
+    /// Add `layer` on top of the image, recording it in `rootfs.diff_ids`
+    /// and adding `history` to the build history.
+    ///
+    /// Tools pair the history entries that are not marked `empty_layer`
+    /// with the layers, so when the configuration has no history, one
+    /// empty entry is first added for each existing layer.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ApiError::UnexpectedResponse`] if the configuration has no
+    /// `rootfs.diff_ids` array, or a `history` that is not an array.
+    pub fn append_layer(&mut self, layer: LocalLayer, history: &History) -> Result<(), ApiError> {
+        let ids = diff_ids(&mut self.config)?;
+        let existing = ids.len();
+        ids.push(serde_json::Value::from(layer.diff_id.as_str()));
+        if self.config["history"].is_null() {
+            self.config["history"] =
+                serde_json::Value::Array(vec![serde_json::json!({}); existing]);
+        }
+        self.config["history"]
+            .as_array_mut()
+            .ok_or_else(|| {
+                ApiError::UnexpectedResponse(String::from("Image config history is not an array"))
+            })?
+            .push(serde_json::to_value(history)?);
+        self.manifest.layers.push(layer.descriptor.clone());
+        self.sources
+            .insert(layer.descriptor.digest.clone(), Source::Local(layer));
+        Ok(())
+    }
+
+    /// Remove every layer, its diff ID, and the whole build history, so
+    /// that the layers added next make up the image on their own.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ApiError::UnexpectedResponse`] if the configuration has no
+    /// `rootfs.diff_ids` array.
+    pub fn clear_layers(&mut self) -> Result<(), ApiError> {
+        diff_ids(&mut self.config)?.clear();
+        self.config["history"] = serde_json::json!([]);
+        self.manifest.layers.clear();
+        self.sources.clear();
+        Ok(())
+    }
+
+    /// Replace the bottom `count` layers with the layers of `base`, resolved
+    /// from `reference`, along with their diff IDs.  Gzip layers take the
+    /// media type matching the format of the manifest.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ApiError::UnexpectedResponse`] if the configuration has no
+    /// `rootfs.diff_ids` array, or fewer than `count` layers.
+    pub fn replace_base(
+        &mut self,
+        count: usize,
+        reference: &ImageRef,
+        base: &Image,
+    ) -> Result<(), ApiError> {
+        let ids = diff_ids(&mut self.config)?;
+        if ids.len() < count || self.manifest.layers.len() < count {
+            return Err(ApiError::UnexpectedResponse(format!(
+                "Cannot replace {count} base layers of an image with {}",
+                self.manifest.layers.len()
+            )));
+        }
+        ids.splice(
+            ..count,
+            base.config
+                .rootfs
+                .diff_ids
+                .iter()
+                .map(|id| serde_json::Value::from(id.as_str())),
+        );
+
+        let gzip = gzip_layer_type(&self.manifest);
+        let layers = base.manifest.layers.iter().map(|d| {
+            let mut d = d.clone();
+            if d.media_type == manifest::DOCKER_LAYER_GZIP
+                || d.media_type == manifest::OCI_LAYER_GZIP
+            {
+                d.media_type = String::from(gzip);
+            }
+            d
+        });
+        self.manifest.layers.splice(..count, layers);
+        for desc in &base.manifest.layers {
+            self.sources
+                .insert(desc.digest.clone(), Source::Remote(reference.clone()));
+        }
+        let layers = &self.manifest.layers;
+        self.sources
+            .retain(|digest, _| layers.iter().any(|d| &d.digest == digest));
+        Ok(())
+    }
+
+    /// Push the draft as `target`.
+    ///
+    /// Layers already present in the target repository are skipped; the
+    /// others are mounted from their repository when it is on the same
+    /// registry, and streamed or uploaded otherwise, up to `jobs` at a time
+    /// with [`blob::run_bounded`], each with its own progress bar.  Then the
+    /// configuration is uploaded and the manifest is stored under the tag
+    /// of `target`, or `latest` when it names none.
+    ///
+    /// Returns the digest of the new manifest.
+    ///
+    /// # Errors
+    ///
+    /// * [`ApiError::InvalidReference`] — `target` names a digest rather
+    ///   than a tag.
+    /// * [`ApiError::IOError`] — a local layer could not be read.
+    /// * Any error raised while mounting, streaming, or uploading.
+    pub async fn push(
+        &self,
+        client: &reqwest::Client,
+        target: &ImageRef,
+        jobs: usize,
+    ) -> Result<String, ApiError> {
+        log::trace!("push(target: {target}, jobs: {jobs})");
+        if let Some(Reference::Digest(_)) = target.reference {
+            return Err(ApiError::InvalidReference(format!(
+                "{target}: the new image needs a tag, not a digest"
+            )));
+        }
+
+        let mut pending = Vec::new();
+        for desc in &self.manifest.layers {
+            if blob::exists(client, target, &desc.digest).await? {
+                log::debug!("{} is already present in {target}", desc.digest);
+            } else {
+                pending.push(desc);
+            }
+        }
+        let progress = Progress::new(pending.len(), pending.iter().map(|d| d.size).sum());
+        let tasks = pending
+            .into_iter()
+            .map(|desc| {
+                let upload = Upload {
+                    client: client.clone(),
+                    target: target.clone(),
+                    descriptor: desc.clone(),
+                    from: self.origin(desc)?,
+                };
+                Ok(upload.run(progress.clone()))
+            })
+            .collect::<Result<Vec<_>, ApiError>>()?;
+        let result = blob::run_bounded(jobs, tasks).await;
+        progress.clear();
+        result?;
+
+        let config = serde_json::to_vec(&self.config)?;
+        let mut manifest = self.manifest.clone();
+        manifest.config.digest = digest::sha256(&config);
+        manifest.config.size = config.len() as u64;
+        if !blob::exists(client, target, &manifest.config.digest).await? {
+            let location = blob::start_upload(client, target).await?;
+            let size = manifest.config.size;
+            blob::finish_upload(client, location, &manifest.config.digest, config, size).await?;
+        }
+
+        let media_type = manifest
+            .media_type
+            .clone()
+            .unwrap_or_else(|| String::from(manifest::OCI_MANIFEST));
+        let url = target.manifest_url(&target.reference_or_latest())?;
+        api::put_manifest(client, &url, &media_type, &serde_json::to_vec(&manifest)?).await
+    }
+
+    /// Where the layer `desc` can be read from.
+    fn origin(&self, desc: &Descriptor) -> Result<Origin, ApiError> {
+        match self.sources.get(&desc.digest) {
+            Some(Source::Local(layer)) => Ok(Origin::Local(layer.path.to_path_buf())),
+            Some(Source::Remote(from)) => Ok(Origin::Remote(from.clone())),
+            None => Err(ApiError::UnexpectedResponse(format!(
+                "No source for layer {}",
+                desc.digest
+            ))),
+        }
+    }
+}
+
+/// Where [`Upload`] reads a layer from.  Local layers stay owned by the
+/// [`Draft`], which outlives the upload.
+#[derive(Debug, Clone)]
+enum Origin {
+    /// A blob in a repository.
+    Remote(ImageRef),
+    /// A compressed blob in a local file.
+    Local(PathBuf),
+}
+
+/// The transfer of one layer into the repository of `target`, owning
+/// everything it needs so that it can run as a task of its own.
+struct Upload {
+    client: reqwest::Client,
+    target: ImageRef,
+    descriptor: Descriptor,
+    from: Origin,
+}
+
+impl Upload {
+    /// Transfer the layer, reporting to `progress`.
+    async fn run(self, progress: Progress) -> Result<(), ApiError> {
+        let Self {
+            client,
+            target,
+            descriptor: desc,
+            from,
+        } = self;
+        let bar = progress.start(&desc.digest, desc.size);
+        match from {
+            Origin::Local(path) => {
+                log::debug!("Uploading {} ({} bytes)", desc.digest, desc.size);
+                let file = tokio::fs::File::open(&path).await?;
+                let location = blob::start_upload(&client, &target).await?;
+                blob::finish_upload(&client, location, &desc.digest, file, desc.size).await?;
+                bar.inc(desc.size);
+            }
+            Origin::Remote(from) => {
+                let location = if from.same_registry(&target) {
+                    log::debug!("Mounting {} from {}", desc.digest, from.repository);
+                    match blob::mount(&client, &target, &desc.digest, &from.repository).await? {
+                        blob::Mount::Mounted => None,
+                        blob::Mount::Upload(location) => Some(location),
+                    }
+                } else {
+                    Some(blob::start_upload(&client, &target).await?)
+                };
+                if let Some(location) = location {
+                    log::debug!("Copying {} ({} bytes)", desc.digest, desc.size);
+                    blob::stream(&client, &from, &client, location, &desc, &bar).await?;
+                }
+            }
+        }
+        bar.finish();
+        Ok(())
+    }
+}
+
+/// The `rootfs.diff_ids` array of the image configuration `config`.
+fn diff_ids(config: &mut serde_json::Value) -> Result<&mut Vec<serde_json::Value>, ApiError> {
+    config
+        .pointer_mut("/rootfs/diff_ids")
+        .and_then(serde_json::Value::as_array_mut)
+        .ok_or_else(|| {
+            ApiError::UnexpectedResponse(String::from("Image config has no rootfs.diff_ids"))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::read::GzDecoder;
+
+    use super::*;
+    use crate::layer::tests::tarball;
+
+    /// Test that a compressed layer decompresses to the original stream and
+    /// that its digests describe the blob and the stream, and that the
+    /// temporary file goes away with the layer.
+    #[test]
+    fn test_compress() {
+        let tar = tarball(&[("etc/", ""), ("etc/motd", "hello")]);
+        let layer = compress(&mut tar.as_slice(), manifest::OCI_LAYER_GZIP).unwrap();
+        assert_eq!(layer.diff_id, digest::sha256(&tar));
+
+        let blob = std::fs::read(&layer.path).unwrap();
+        assert_eq!(layer.descriptor.digest, digest::sha256(&blob));
+        assert_eq!(layer.descriptor.size, blob.len() as u64);
+        let mut plain = Vec::new();
+        GzDecoder::new(blob.as_slice())
+            .read_to_end(&mut plain)
+            .unwrap();
+        assert_eq!(plain, tar);
+
+        let path = layer.path.to_path_buf();
+        drop(layer);
+        assert!(!path.exists());
+    }
+
+    /// Test that appending to a configuration without history first adds
+    /// an empty entry for each existing layer, so that history entries and
+    /// layers stay paired.
+    #[test]
+    fn test_append_layer_without_history() {
+        let mut draft = Draft {
+            manifest: serde_json::from_value(serde_json::json!({
+                "schemaVersion": 2,
+                "config": {"mediaType": "application/vnd.oci.image.config.v1+json", "digest": "sha256:c", "size": 2},
+                "layers": [],
+            }))
+            .unwrap(),
+            config: serde_json::json!({
+                "rootfs": {"type": "layers", "diff_ids": ["sha256:a", "sha256:b"]},
+            }),
+            sources: BTreeMap::new(),
+        };
+        let tar = tarball(&[("etc/motd", "hello")]);
+        let layer = compress(&mut tar.as_slice(), manifest::OCI_LAYER_GZIP).unwrap();
+        let history = History {
+            created_by: Some(String::from("dredge append motd.tar")),
+            ..History::default()
+        };
+        draft.append_layer(layer, &history).unwrap();
+
+        assert_eq!(
+            draft.config["rootfs"]["diff_ids"].as_array().unwrap().len(),
+            3
+        );
+        assert_eq!(
+            draft.config["history"],
+            serde_json::json!([{}, {}, {"created_by": "dredge append motd.tar"}])
+        );
+    }
+}
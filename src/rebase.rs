@@ -0,0 +1,257 @@
+/*
+ * Copyright 2023 Anthony Oteri
+ *
+ * Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+ * http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+ * http://opensource.org/licenses/MIT>, at your option. This file may not be
+ * copied, modified, or distributed except according to those terms.
+ */
+
+use std::collections::BTreeSet;
+
+use serde_json::Map;
+use serde_json::Value;
+
+use crate::error::ApiError;
+use crate::image::Image;
+
+/// Check that the layers of `image` start with the layers of `base`,
+/// comparing diff IDs so that recompressed layers still match.
+///
+/// # Errors
+///
+/// Returns [`ApiError::NotBasedOn`] naming `base_name` otherwise.
+pub fn check_base(image: &Image, base: &Image, base_name: &str) -> Result<(), ApiError> {
+    let ids = &image.config.rootfs.diff_ids;
+    let base_ids = &base.config.rootfs.diff_ids;
+    if ids.len() > base_ids.len() && ids.starts_with(base_ids) {
+        Ok(())
+    } else {
+        Err(ApiError::NotBasedOn(format!(
+            "{base_name} ({} layers, the image has {})",
+            base_ids.len(),
+            ids.len()
+        )))
+    }
+}
+
+/// Return how many leading history entries of `image` describe the layers
+/// of `base`.
+///
+/// When the history of the image starts with that of the base, as a build
+/// leaves it, that is the length of the base history.  Otherwise the
+/// entries up to and including the one that created the last base layer
+/// are counted.
+pub fn base_history_len(image: &Image, base: &Image) -> usize {
+    let history = &image.config.history;
+    let base_history = &base.config.history;
+    if !base_history.is_empty() && history.starts_with(base_history) {
+        return base_history.len();
+    }
+    log::warn!("The image history does not start with the history of the old base");
+    let layers = base.manifest.layers.len();
+    let mut seen = 0;
+    for (i, entry) in history.iter().enumerate() {
+        if seen == layers {
+            return i;
+        }
+        if !entry.empty_layer {
+            seen += 1;
+        }
+    }
+    history.len()
+}
+
+/// Return the history entries of the new base, whose configuration is
+/// `config` and which has `layers` layers, to put in place of those of the
+/// old base.
+///
+/// Tools pair the history entries that are not marked `empty_layer` with
+/// the layers, so when the new base has no history, one empty entry is
+/// returned for each of its layers instead.
+pub fn base_history(config: &Value, layers: usize) -> Vec<Value> {
+    match config["history"].as_array() {
+        Some(history) if !history.is_empty() => history.clone(),
+        _ => vec![serde_json::json!({}); layers],
+    }
+}
+
+/// Merge the container defaults of the new base into the image
+/// configuration `config`, which was built on the old base.
+///
+/// Each value of the `config` section, and each variable of `Env` and
+/// label of `Labels`, that the image inherited unchanged from the old base
+/// takes its value from the new base, or is removed if the new base does
+/// not set it.  Values the image set itself are kept.  Values the new
+/// base introduces are added.
+pub fn merge_config(config: &mut Value, old_base: &Value, new_base: &Value) {
+    let section = |v: &Value| v.get("config").and_then(Value::as_object).cloned();
+    let old = section(old_base).unwrap_or_default();
+    let new = section(new_base).unwrap_or_default();
+    let mut merged = section(config).unwrap_or_default();
+
+    let keys: BTreeSet<String> = old.keys().chain(new.keys()).cloned().collect();
+    for key in keys {
+        let (before, after) = (old.get(&key), new.get(&key));
+        match key.as_str() {
+            "Env" => {
+                let env = merge_env(merged.get(&key), before, after);
+                set(&mut merged, &key, env);
+            }
+            "Labels" => {
+                let object =
+                    |v: Option<&Value>| v.and_then(Value::as_object).cloned().unwrap_or_default();
+                let labels = merge_map(&object(merged.get(&key)), &object(before), &object(after));
+                set(
+                    &mut merged,
+                    &key,
+                    (!labels.is_empty()).then_some(Value::Object(labels)),
+                );
+            }
+            _ if merged.get(&key) == before => set(&mut merged, &key, after.cloned()),
+            _ => {}
+        }
+    }
+    config["config"] = Value::Object(merged);
+}
+
+/// Set `key` of `map` to `value`, removing it for `None`.
+fn set(map: &mut Map<String, Value>, key: &str, value: Option<Value>) {
+    match value {
+        Some(value) => map.insert(String::from(key), value),
+        None => map.remove(key),
+    };
+}
+
+/// Three-way merge of `current` against a change from `before` to `after`.
+fn merge_map(
+    current: &Map<String, Value>,
+    before: &Map<String, Value>,
+    after: &Map<String, Value>,
+) -> Map<String, Value> {
+    let mut merged = Map::new();
+    for (key, value) in current {
+        if before.get(key) != Some(value) {
+            merged.insert(key.clone(), value.clone());
+        } else if let Some(value) = after.get(key) {
+            merged.insert(key.clone(), value.clone());
+        }
+    }
+    for (key, value) in after {
+        if !current.contains_key(key) && !before.contains_key(key) {
+            merged.insert(key.clone(), value.clone());
+        }
+    }
+    merged
+}
+
+/// Three-way merge of `Env` arrays by variable name, keeping the order of
+/// `current` and appending variables the new base introduces.
+fn merge_env(
+    current: Option<&Value>,
+    before: Option<&Value>,
+    after: Option<&Value>,
+) -> Option<Value> {
+    let split = |v: Option<&Value>| -> Vec<(String, Value)> {
+        v.and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+            .map(|s| {
+                let (name, value) = s.split_once('=').unwrap_or((s, ""));
+                (String::from(name), Value::from(value))
+            })
+            .collect()
+    };
+    let (current, before, after) = (split(current), split(before), split(after));
+    let lookup = |list: &[(String, Value)], name: &str| {
+        list.iter().find(|(n, _)| n == name).map(|(_, v)| v.clone())
+    };
+
+    let mut merged = Vec::new();
+    for (name, value) in &current {
+        if lookup(&before, name).as_ref() != Some(value) {
+            merged.push((name.clone(), value.clone()));
+        } else if let Some(value) = lookup(&after, name) {
+            merged.push((name.clone(), value));
+        }
+    }
+    for (name, value) in &after {
+        if lookup(&current, name).is_none() && lookup(&before, name).is_none() {
+            merged.push((name.clone(), value.clone()));
+        }
+    }
+    (!merged.is_empty()).then(|| {
+        merged
+            .into_iter()
+            .map(|(name, value)| {
+                Value::from(format!("{name}={}", value.as_str().unwrap_or_default()))
+            })
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that the history of the new base is used as it is, and that a
+    /// base without history gets one empty entry per layer.
+    #[test]
+    fn test_base_history() {
+        let history = serde_json::json!([
+            {"created_by": "ADD rootfs.tar /"},
+            {"created_by": "CMD [\"bash\"]", "empty_layer": true},
+        ]);
+        let config = serde_json::json!({"history": history});
+        assert_eq!(Value::from(base_history(&config, 1)), history);
+
+        let padded = serde_json::json!([{}, {}]);
+        assert_eq!(Value::from(base_history(&serde_json::json!({}), 2)), padded);
+        let empty = serde_json::json!({"history": []});
+        assert_eq!(Value::from(base_history(&empty, 2)), padded);
+    }
+
+    /// Test that inherited values follow the new base, values the image set
+    /// are kept, and values only the new base sets are added.
+    #[test]
+    fn test_merge_config() {
+        let old_base = serde_json::json!({"config": {
+            "Env": ["PATH=/usr/bin", "DEBIAN_VERSION=12.4"],
+            "Cmd": ["bash"],
+            "Labels": {"vendor": "debian", "version": "12.4"},
+        }});
+        let new_base = serde_json::json!({"config": {
+            "Env": ["PATH=/usr/bin", "DEBIAN_VERSION=12.5", "LANG=C.UTF-8"],
+            "Cmd": ["bash"],
+            "StopSignal": "SIGTERM",
+            "Labels": {"vendor": "debian", "version": "12.5"},
+        }});
+        let mut config = serde_json::json!({
+            "architecture": "amd64",
+            "config": {
+                "Env": ["PATH=/app/bin:/usr/bin", "DEBIAN_VERSION=12.4", "APP=1"],
+                "Cmd": ["/app/serve"],
+                "Labels": {"vendor": "debian", "version": "12.4", "app": "web"},
+            },
+        });
+        merge_config(&mut config, &old_base, &new_base);
+        assert_eq!(
+            config,
+            serde_json::json!({
+                "architecture": "amd64",
+                "config": {
+                    "Env": [
+                        "PATH=/app/bin:/usr/bin",
+                        "DEBIAN_VERSION=12.5",
+                        "APP=1",
+                        "LANG=C.UTF-8",
+                    ],
+                    "Cmd": ["/app/serve"],
+                    "StopSignal": "SIGTERM",
+                    "Labels": {"vendor": "debian", "version": "12.5", "app": "web"},
+                },
+            })
+        );
+    }
+}
@@ -0,0 +1,286 @@
+/*
+ * Copyright 2023 Anthony Oteri
+ *
+ * Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+ * http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+ * http://opensource.org/licenses/MIT>, at your option. This file may not be
+ * copied, modified, or distributed except according to those terms.
+ */
+
+use std::fmt;
+
+use url::Url;
+
+use crate::digest;
+use crate::error::ApiError;
+
+/// The tag assumed when a reference names neither a tag nor a digest.
+const LATEST: &str = "latest";
+
+/// The tag or digest part of an image reference.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reference {
+    /// A mutable tag such as `v1.0.0`.
+    Tag(String),
+    /// An immutable manifest digest such as `sha256:…`.
+    Digest(String),
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tag(t) => f.write_str(t),
+            Self::Digest(d) => f.write_str(d),
+        }
+    }
+}
+
+/// A fully-resolved image reference: registry, repository, and optionally a
+/// tag or digest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageRef {
+    /// Base URL of the registry hosting the repository.
+    pub registry: Url,
+    /// Repository name (e.g. `myorg/backend`).
+    pub repository: String,
+    /// Tag or digest, when one was given.
+    pub reference: Option<Reference>,
+}
+
+impl ImageRef {
+    /// Parse `[REGISTRY/]REPOSITORY[:TAG][@DIGEST]`.
+    ///
+    /// The leading component is treated as a registry when it carries an
+    /// explicit `http://` or `https://` scheme, or when it contains a `.` or
+    /// `:` or is `localhost` — the same heuristic the Docker CLI uses.
+    /// Otherwise the repository lives on `default_registry`.  As with the
+    /// `<REGISTRY>` argument, `https://` is assumed when no scheme is given.
+    ///
+    /// When both a tag and a digest are present, the digest wins.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ApiError::InvalidReference`] if the registry is not a valid
+    /// URL, the repository name is empty or contains invalid characters, the
+    /// tag is malformed, or the digest is not a valid `sha256` digest.
+    pub fn parse(input: &str, default_registry: &Url) -> Result<Self, ApiError> {
+        log::trace!("ImageRef::parse(input: {input}, default_registry: {default_registry})");
+        let invalid = |why: &str| ApiError::InvalidReference(format!("{input}: {why}"));
+
+        let explicit = if input.starts_with("http://") || input.starts_with("https://") {
+            let (scheme, after) = input.split_once("://").unwrap_or_default();
+            after
+                .split_once('/')
+                .map(|(host, rest)| (format!("{scheme}://{host}"), rest))
+        } else {
+            input
+                .split_once('/')
+                .filter(|(first, _)| {
+                    first.contains('.') || first.contains(':') || *first == "localhost"
+                })
+                .map(|(host, rest)| (format!("https://{host}"), rest))
+        };
+        let (registry, rest) = match explicit {
+            Some((host, rest)) => (
+                Url::parse(&host).map_err(|_| invalid("invalid registry"))?,
+                rest,
+            ),
+            None => (default_registry.clone(), input),
+        };
+
+        let (name, digest) = match rest.split_once('@') {
+            Some((name, digest)) => (name, Some(digest)),
+            None => (rest, None),
+        };
+        let (repository, tag) = match name.rsplit_once(':') {
+            Some((repo, tag)) if !tag.contains('/') => (repo, Some(tag)),
+            _ => (name, None),
+        };
+
+        if repository.is_empty()
+            || repository.starts_with('/')
+            || repository.ends_with('/')
+            || !repository
+                .bytes()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b"._-/".contains(&b))
+        {
+            return Err(invalid("invalid repository name"));
+        }
+
+        let reference = match (tag, digest) {
+            (_, Some(d)) => {
+                digest::encoded(d).map_err(|_| invalid("invalid digest"))?;
+                Some(Reference::Digest(String::from(d)))
+            }
+            (Some(t), None) => {
+                if !is_valid_tag(t) {
+                    return Err(invalid("invalid tag"));
+                }
+                Some(Reference::Tag(String::from(t)))
+            }
+            (None, None) => None,
+        };
+
+        Ok(Self {
+            registry,
+            repository: String::from(repository),
+            reference,
+        })
+    }
+
+    /// Return the tag or digest, or `latest` when neither was given.
+    pub fn reference_or_latest(&self) -> String {
+        self.reference
+            .as_ref()
+            .map_or_else(|| String::from(LATEST), ToString::to_string)
+    }
+
+    /// Return the tag, if the reference names one.
+    pub fn tag(&self) -> Option<&str> {
+        match &self.reference {
+            Some(Reference::Tag(t)) => Some(t),
+            _ => None,
+        }
+    }
+
+    /// Return a copy of this reference pointing at `reference` instead.
+    pub fn with_reference(&self, reference: Reference) -> Self {
+        Self {
+            reference: Some(reference),
+            ..self.clone()
+        }
+    }
+
+    /// Return `true` if both references live on the same registry.
+    pub fn same_registry(&self, other: &Self) -> bool {
+        self.registry.origin() == other.registry.origin()
+    }
+
+    /// URL of the manifest for `reference` (a tag or digest).
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ApiError::UrlParseError`] if the URL cannot be constructed.
+    pub fn manifest_url(&self, reference: &str) -> Result<Url, ApiError> {
+        Ok(self
+            .registry
+            .join(&format!("/v2/{}/manifests/{reference}", self.repository))?)
+    }
+
+    /// URL of the blob with `digest`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ApiError::UrlParseError`] if the URL cannot be constructed.
+    pub fn blob_url(&self, digest: &str) -> Result<Url, ApiError> {
+        Ok(self
+            .registry
+            .join(&format!("/v2/{}/blobs/{digest}", self.repository))?)
+    }
+}
+
+impl fmt::Display for ImageRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(host) = self.registry.host_str() {
+            f.write_str(host)?;
+            if let Some(port) = self.registry.port() {
+                write!(f, ":{port}")?;
+            }
+            f.write_str("/")?;
+        }
+        f.write_str(&self.repository)?;
+        match &self.reference {
+            Some(Reference::Tag(t)) => write!(f, ":{t}"),
+            Some(Reference::Digest(d)) => write!(f, "@{d}"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Return `true` if `tag` is a valid tag: up to 128 characters from
+/// `[A-Za-z0-9_.-]`, not starting with `.` or `-`.
+pub fn is_valid_tag(tag: &str) -> bool {
+    !tag.is_empty()
+        && tag.len() <= 128
+        && !tag.starts_with(['.', '-'])
+        && tag
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"_.-".contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &str = "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    fn default_registry() -> Url {
+        Url::parse("https://registry.local").unwrap()
+    }
+
+    /// Test that a bare repository uses the default registry and no tag.
+    #[test]
+    fn test_parse_bare_repository() {
+        let r = ImageRef::parse("myorg/backend", &default_registry()).unwrap();
+        assert_eq!(r.registry, default_registry());
+        assert_eq!(r.repository, "myorg/backend");
+        assert_eq!(r.reference, None);
+        assert_eq!(r.reference_or_latest(), "latest");
+    }
+
+    /// Test that a registry with a port is recognised and the tag split off.
+    #[test]
+    fn test_parse_registry_with_port_and_tag() {
+        let r = ImageRef::parse(
+            "prod.example.com:5000/myorg/backend:v1.2",
+            &default_registry(),
+        )
+        .unwrap();
+        assert_eq!(r.registry.as_str(), "https://prod.example.com:5000/");
+        assert_eq!(r.repository, "myorg/backend");
+        assert_eq!(r.tag(), Some("v1.2"));
+        assert_eq!(r.to_string(), "prod.example.com:5000/myorg/backend:v1.2");
+    }
+
+    /// Test that an explicit `http://` scheme is preserved.
+    #[test]
+    fn test_parse_explicit_scheme() {
+        let r = ImageRef::parse("http://localhost:5000/foo", &default_registry()).unwrap();
+        assert_eq!(r.registry.scheme(), "http");
+        assert_eq!(r.registry.port(), Some(5000));
+        assert_eq!(r.repository, "foo");
+    }
+
+    /// Test that a digest reference wins over a tag.
+    #[test]
+    fn test_parse_digest() {
+        let r = ImageRef::parse(&format!("foo:v1@{DIGEST}"), &default_registry()).unwrap();
+        assert_eq!(r.reference, Some(Reference::Digest(String::from(DIGEST))));
+        assert_eq!(r.reference_or_latest(), DIGEST);
+    }
+
+    /// Test that malformed references are rejected.
+    #[test]
+    fn test_parse_invalid() {
+        for input in ["", "Foo/Bar", "foo:-bad", "foo@sha256:abc", "registry.io/"] {
+            assert!(
+                matches!(
+                    ImageRef::parse(input, &default_registry()),
+                    Err(ApiError::InvalidReference(_))
+                ),
+                "{input} should be rejected"
+            );
+        }
+    }
+
+    /// Test that references on the same host and port compare as the same
+    /// registry regardless of how they were written.
+    #[test]
+    fn test_same_registry() {
+        let a = ImageRef::parse("registry.local/foo", &default_registry()).unwrap();
+        let b = ImageRef::parse("bar", &default_registry()).unwrap();
+        let c = ImageRef::parse("registry.local:5000/foo", &default_registry()).unwrap();
+        assert!(a.same_registry(&b));
+        assert!(!a.same_registry(&c));
+    }
+}
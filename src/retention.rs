@@ -0,0 +1,543 @@
+/*
+ * Copyright 2023 Anthony Oteri
+ *
+ * Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+ * http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+ * http://opensource.org/licenses/MIT>, at your option. This file may not be
+ * copied, modified, or distributed except according to those terms.
+ */
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+use semver::Version;
+use serde::Deserialize;
+
+use crate::error::ApiError;
+use crate::pattern::Pattern;
+use crate::tags;
+use crate::tags::TagInfo;
+
+/// A retention policy: which tags to keep in which repositories.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    /// The rules, in order.  A repository follows the first rule whose
+    /// pattern matches it; repositories no rule matches are left alone.
+    pub rules: Vec<Rule>,
+}
+
+impl Policy {
+    /// Read a policy from the YAML file at `path`.
+    ///
+    /// # Errors
+    ///
+    /// * [`ApiError::IOError`] — the file cannot be read.
+    /// * Any error returned by [`Policy::from_str`].
+    pub fn load(path: &Path) -> Result<Self, ApiError> {
+        std::fs::read_to_string(path)?.parse()
+    }
+
+    /// Return the first rule that applies to `repository`.
+    pub fn rule_for(&self, repository: &str) -> Option<&Rule> {
+        self.rules
+            .iter()
+            .find(|rule| rule.repositories.matches(repository))
+    }
+}
+
+impl FromStr for Policy {
+    type Err = ApiError;
+
+    /// Parse a policy from YAML.
+    ///
+    /// # Errors
+    ///
+    /// * [`ApiError::SerializerError`] — `s` is not a valid policy.
+    /// * [`ApiError::InvalidPolicy`] — a rule has no `keep_*` condition.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let policy: Self = serde_norway::from_str(s)?;
+        if let Some(n) = policy.rules.iter().position(Rule::keeps_nothing) {
+            return Err(ApiError::InvalidPolicy(format!(
+                "rule {} has no keep_* condition; write keep_last: 0 to delete every tag",
+                n + 1
+            )));
+        }
+        Ok(policy)
+    }
+}
+
+/// The tags to keep in the repositories matching `repositories`.  A tag
+/// is kept when any of the `keep_*` conditions holds; every other tag is
+/// deleted.  A rule needs at least one condition, so that deleting every
+/// tag is always written out as `keep_last: 0`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// The repositories the rule applies to, as a wildcard or `/regex/`.
+    pub repositories: Pattern,
+    /// Always keep the tags matching one of these patterns.
+    #[serde(default)]
+    pub keep_tags: Vec<Pattern>,
+    /// Keep the highest release of each major or minor version.
+    #[serde(default)]
+    pub keep_semver: Option<Series>,
+    /// Keep the tags of images created less than this long ago.
+    #[serde(default)]
+    pub keep_newer_than: Option<Age>,
+    /// Keep the tags of the newest images, this many of them.
+    #[serde(default)]
+    pub keep_last: Option<usize>,
+}
+
+/// The version series within which [`Rule::keep_semver`] keeps the highest
+/// release.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Series {
+    /// One release per major version, such as `2.4.1` for `2`.
+    Major,
+    /// One release per minor version, such as `2.4.1` for `2.4`.
+    Minor,
+}
+
+/// A duration in seconds, written as a number followed by `s`, `m`, `h`,
+/// `d`, or `w`, as in `30d`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Age(pub u64);
+
+impl FromStr for Age {
+    type Err = ApiError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ApiError::InvalidPolicy(format!("{s}: expected an age such as 30d"));
+        let unit = match s.chars().last().ok_or_else(invalid)? {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        let count: u64 = s[..s.len() - 1].parse().map_err(|_| invalid())?;
+        count.checked_mul(unit).map(Self).ok_or_else(invalid)
+    }
+}
+
+impl<'de> Deserialize<'de> for Age {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Why a tag is kept.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Keep {
+    /// It matches one of [`Rule::keep_tags`].
+    Tag,
+    /// It is the highest release of its series.
+    Semver,
+    /// Its image is newer than [`Rule::keep_newer_than`].
+    Newer,
+    /// Its image is among the [`Rule::keep_last`] newest.
+    Last,
+    /// Its creation time is unknown, so its age cannot be judged, or it
+    /// could not be described at all.
+    Undated,
+    /// It points at the same manifest as this kept tag, and deleting the
+    /// manifest would delete both.
+    Shared(String),
+}
+
+impl fmt::Display for Keep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tag => f.write_str("keep_tags"),
+            Self::Semver => f.write_str("keep_semver"),
+            Self::Newer => f.write_str("keep_newer_than"),
+            Self::Last => f.write_str("keep_last"),
+            Self::Undated => f.write_str("undated"),
+            Self::Shared(tag) => write!(f, "same manifest as {tag}"),
+        }
+    }
+}
+
+/// What a rule decided for one tag.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decision {
+    /// The tag name.
+    pub tag: String,
+    /// The digest the tag points at, unless it could not be described.
+    pub digest: Option<String>,
+    /// Why the tag is kept, or `None` if it is to be deleted.
+    pub keep: Option<Keep>,
+}
+
+impl Rule {
+    /// Return `true` if the rule has none of the `keep_*` conditions.
+    fn keeps_nothing(&self) -> bool {
+        self.keep_tags.is_empty()
+            && self.keep_semver.is_none()
+            && self.keep_newer_than.is_none()
+            && self.keep_last.is_none()
+    }
+
+    /// Decide which of the tags described by `infos` to keep, at time `now`
+    /// in seconds since the Unix epoch.  Returns one decision per tag in
+    /// name order.
+    ///
+    /// Each tag is kept for the first of the `keep_*` conditions it meets,
+    /// in the order they are declared on [`Rule`].  When the rule judges
+    /// tags by age, tags whose creation time is unknown are kept.  A tag
+    /// that could not be described, with no entry in `infos`, is always
+    /// kept as undated.  Since deleting a tag deletes its manifest, a tag
+    /// that points at the same manifest as a kept tag is kept too.
+    pub fn decide(&self, infos: &BTreeMap<String, Option<TagInfo>>, now: u64) -> Vec<Decision> {
+        let mut keep: BTreeMap<&String, Keep> = BTreeMap::new();
+        let mut mark = |tag, reason| {
+            keep.entry(tag).or_insert(reason);
+        };
+
+        for tag in infos.keys() {
+            if self.keep_tags.iter().any(|p| p.matches(tag)) {
+                mark(tag, Keep::Tag);
+            }
+        }
+        if let Some(series) = self.keep_semver {
+            let mut highest: BTreeMap<(u64, Option<u64>), Version> = BTreeMap::new();
+            let versions: Vec<(&String, Version)> = infos
+                .keys()
+                .filter_map(|t| tags::parse_version(t).map(|v| (t, v)))
+                .filter(|(_, v)| v.pre.is_empty())
+                .collect();
+            let key = |v: &Version| match series {
+                Series::Major => (v.major, None),
+                Series::Minor => (v.major, Some(v.minor)),
+            };
+            for (_, version) in &versions {
+                let best = highest
+                    .entry(key(version))
+                    .or_insert_with(|| version.clone());
+                if version.cmp_precedence(best).is_gt() {
+                    *best = version.clone();
+                }
+            }
+            for (tag, version) in &versions {
+                if highest[&key(version)].cmp_precedence(version).is_eq() {
+                    mark(*tag, Keep::Semver);
+                }
+            }
+        }
+        if let Some(Age(age)) = self.keep_newer_than {
+            let since = now.saturating_sub(age);
+            for (tag, info) in infos {
+                if info
+                    .as_ref()
+                    .and_then(|i| i.created)
+                    .is_some_and(|c| c >= since)
+                {
+                    mark(tag, Keep::Newer);
+                }
+            }
+        }
+        if let Some(count) = self.keep_last {
+            let mut dated: Vec<(u64, &String)> = infos
+                .iter()
+                .filter_map(|(t, i)| i.as_ref()?.created.map(|c| (c, t)))
+                .collect();
+            dated.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(b.1)));
+            for (_, tag) in dated.into_iter().take(count) {
+                mark(tag, Keep::Last);
+            }
+        }
+        let by_age = self.keep_newer_than.is_some() || self.keep_last.is_some();
+        for (tag, info) in infos {
+            let undated = match info {
+                Some(info) => by_age && info.created.is_none(),
+                None => true,
+            };
+            if undated {
+                mark(tag, Keep::Undated);
+            }
+        }
+
+        let mut kept_digests: BTreeMap<&String, &String> = BTreeMap::new();
+        for tag in keep.keys() {
+            if let Some(info) = &infos[*tag] {
+                kept_digests.entry(&info.digest).or_insert(tag);
+            }
+        }
+        infos
+            .iter()
+            .map(|(tag, info)| {
+                let digest = info.as_ref().map(|i| i.digest.clone());
+                let keep = keep.get(tag).cloned().or_else(|| {
+                    kept_digests
+                        .get(digest.as_ref()?)
+                        .map(|t| Keep::Shared((*t).clone()))
+                });
+                Decision {
+                    tag: tag.clone(),
+                    digest,
+                    keep,
+                }
+            })
+            .collect()
+    }
+}
+
+/// The manifests of one repository that a [`PrunePlan`] deletes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Deletions<'a> {
+    /// The repository name.
+    pub repository: &'a str,
+    /// The digest of each manifest to delete, once each in order.
+    pub digests: Vec<String>,
+    /// The tags kept in the repository.
+    pub kept: Vec<String>,
+}
+
+/// The decisions of a policy for every repository it applies to.
+#[derive(Debug, Clone, Default)]
+pub struct PrunePlan {
+    /// Each repository with the decisions for its tags, in name order.
+    pub repositories: Vec<(String, Vec<Decision>)>,
+}
+
+impl PrunePlan {
+    /// The manifests that the tags to delete from each repository pointed
+    /// at when planned, and the tags kept there, leaving out repositories
+    /// with nothing to delete.
+    pub fn deletions(&self) -> Vec<Deletions<'_>> {
+        self.repositories
+            .iter()
+            .map(|(repository, decisions)| {
+                let (kept, doomed): (Vec<&Decision>, Vec<&Decision>) =
+                    decisions.iter().partition(|d| d.keep.is_some());
+                let digests: BTreeSet<String> = doomed
+                    .into_iter()
+                    .filter_map(|d| d.digest.clone())
+                    .collect();
+                Deletions {
+                    repository,
+                    digests: digests.into_iter().collect(),
+                    kept: kept.into_iter().map(|d| d.tag.clone()).collect(),
+                }
+            })
+            .filter(|d| !d.digests.is_empty())
+            .collect()
+    }
+
+    /// Write one line per tag followed by a summary line to `buf`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ApiError::IOError`] if writing to `buf` fails.
+    pub fn write_to(&self, buf: &mut dyn Write) -> Result<(), ApiError> {
+        let (mut kept, mut deleted) = (0, 0);
+        for (repository, decisions) in &self.repositories {
+            for d in decisions {
+                if let Some(reason) = &d.keep {
+                    kept += 1;
+                    writeln!(buf, "keep   {repository}:{} ({reason})", d.tag)?;
+                } else {
+                    deleted += 1;
+                    let digest = d.digest.as_deref().unwrap_or("-");
+                    writeln!(buf, "delete {repository}:{} {digest}", d.tag)?;
+                }
+            }
+        }
+        writeln!(
+            buf,
+            "{kept} tags kept, {deleted} to delete in {} repositories",
+            self.repositories.len()
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Describe tags by name, digest, and creation time.
+    fn infos(tags: &[(&str, &str, Option<u64>)]) -> BTreeMap<String, Option<TagInfo>> {
+        tags.iter()
+            .map(|(tag, digest, created)| {
+                let info = TagInfo {
+                    digest: String::from(*digest),
+                    size: 0,
+                    platforms: Vec::new(),
+                    created: *created,
+                };
+                (String::from(*tag), Some(info))
+            })
+            .collect()
+    }
+
+    /// Map each tag to why it is kept, or `"-"` if it is deleted.
+    fn reasons(decisions: &[Decision]) -> Vec<(String, String)> {
+        decisions
+            .iter()
+            .map(|d| {
+                let reason = d
+                    .keep
+                    .as_ref()
+                    .map_or_else(|| String::from("-"), Keep::to_string);
+                (d.tag.clone(), reason)
+            })
+            .collect()
+    }
+
+    /// Test that ages parse with each unit and reject anything else.
+    #[test]
+    fn test_age_from_str() {
+        assert_eq!("90s".parse::<Age>().unwrap(), Age(90));
+        assert_eq!("12h".parse::<Age>().unwrap(), Age(12 * 3600));
+        assert_eq!("30d".parse::<Age>().unwrap(), Age(30 * 86400));
+        assert_eq!("2w".parse::<Age>().unwrap(), Age(14 * 86400));
+        for invalid in ["", "d", "30", "30y", "-1d", "1.5d"] {
+            assert!(invalid.parse::<Age>().is_err(), "{invalid}");
+        }
+    }
+
+    /// Test that a policy parses from YAML, that the first matching rule
+    /// applies, and that unknown keys are rejected.
+    #[test]
+    fn test_policy_yaml() {
+        let policy: Policy = serde_norway::from_str(
+            "rules:\n\
+             - repositories: myorg/frontend\n  keep_last: 5\n\
+             - repositories: 'myorg/*'\n  keep_tags: [latest, '/^release-/']\n  keep_semver: minor\n  keep_newer_than: 30d\n",
+        )
+        .unwrap();
+        assert_eq!(
+            policy.rule_for("myorg/frontend").unwrap().keep_last,
+            Some(5)
+        );
+        let rule = policy.rule_for("myorg/backend").unwrap();
+        assert_eq!(rule.keep_semver, Some(Series::Minor));
+        assert_eq!(rule.keep_newer_than, Some(Age(30 * 86400)));
+        assert_eq!(rule.keep_tags.len(), 2);
+        assert!(policy.rule_for("other/backend").is_none());
+
+        let result = serde_norway::from_str::<Policy>("rules:\n- repositories: '*'\n  keep: 1\n");
+        assert!(result.is_err());
+    }
+
+    /// Test that a rule without any condition is rejected, and that
+    /// `keep_last: 0` deletes every dated tag.
+    #[test]
+    fn test_policy_without_condition() {
+        let result = "rules:\n- repositories: 'a/*'\n  keep_last: 1\n- repositories: '*'\n"
+            .parse::<Policy>();
+        assert!(
+            matches!(&result, Err(ApiError::InvalidPolicy(m)) if m.starts_with("rule 2 ")),
+            "{result:?}"
+        );
+        let result = "rules:\n- repositories: '*'\n  keep_tags: []\n".parse::<Policy>();
+        assert!(matches!(result, Err(ApiError::InvalidPolicy(_))));
+
+        let policy: Policy = "rules:\n- repositories: '*'\n  keep_last: 0\n"
+            .parse()
+            .unwrap();
+        let infos = infos(&[("a", "sha256:a", Some(1)), ("b", "sha256:b", Some(2))]);
+        let decisions = policy.rule_for("foo").unwrap().decide(&infos, 3);
+        assert!(decisions.iter().all(|d| d.keep.is_none()));
+    }
+
+    /// Test each condition, that undated tags are kept when judging by
+    /// age, and that tags sharing a kept manifest are kept.
+    #[test]
+    fn test_decide() {
+        let day = 86400;
+        let now = 100 * day;
+        let infos = infos(&[
+            ("latest", "sha256:d", Some(99 * day)),
+            ("pr-1", "sha256:a", Some(10 * day)),
+            ("pr-2", "sha256:b", Some(80 * day)),
+            ("pr-3", "sha256:c", None),
+            ("v1.2.0", "sha256:e", Some(20 * day)),
+            ("v1.2.1", "sha256:f", Some(30 * day)),
+            ("v1.3.0", "sha256:g", Some(40 * day)),
+            ("v1.4.0-rc.1", "sha256:h", Some(50 * day)),
+            ("v2.0.0", "sha256:d", Some(99 * day)),
+        ]);
+        let rule = Rule {
+            repositories: "*".parse().unwrap(),
+            keep_tags: vec!["latest".parse().unwrap()],
+            keep_semver: Some(Series::Minor),
+            keep_newer_than: Some(Age(30 * day)),
+            keep_last: Some(1),
+        };
+        let expected = [
+            ("latest", "keep_tags"),
+            ("pr-1", "-"),
+            ("pr-2", "keep_newer_than"),
+            ("pr-3", "undated"),
+            ("v1.2.0", "-"),
+            ("v1.2.1", "keep_semver"),
+            ("v1.3.0", "keep_semver"),
+            ("v1.4.0-rc.1", "-"),
+            ("v2.0.0", "keep_semver"),
+        ];
+        let expected: Vec<(String, String)> = expected
+            .iter()
+            .map(|(t, r)| (String::from(*t), String::from(*r)))
+            .collect();
+        assert_eq!(reasons(&rule.decide(&infos, now)), expected);
+
+        let rule = Rule {
+            repositories: "*".parse().unwrap(),
+            keep_tags: Vec::new(),
+            keep_semver: Some(Series::Major),
+            keep_newer_than: None,
+            keep_last: None,
+        };
+        let decisions = reasons(&rule.decide(&infos, now));
+        let kept: Vec<String> = decisions
+            .iter()
+            .filter(|(_, r)| r != "-")
+            .map(|(t, r)| format!("{t} {r}"))
+            .collect();
+        assert_eq!(
+            kept,
+            [
+                "latest same manifest as v2.0.0",
+                "v1.3.0 keep_semver",
+                "v2.0.0 keep_semver",
+            ]
+        );
+    }
+
+    /// Test that a tag that could not be described is kept, even by a rule
+    /// that does not judge tags by age.
+    #[test]
+    fn test_decide_undescribed() {
+        let mut infos = infos(&[("latest", "sha256:a", None), ("pr-1", "sha256:b", None)]);
+        infos.insert(String::from("chart"), None);
+        let rule = Rule {
+            repositories: "*".parse().unwrap(),
+            keep_tags: vec!["latest".parse().unwrap()],
+            keep_semver: None,
+            keep_newer_than: None,
+            keep_last: None,
+        };
+        let decisions = rule.decide(&infos, 0);
+        assert_eq!(
+            reasons(&decisions),
+            [
+                (String::from("chart"), String::from("undated")),
+                (String::from("latest"), String::from("keep_tags")),
+                (String::from("pr-1"), String::from("-")),
+            ]
+        );
+        assert_eq!(decisions[0].digest, None);
+    }
+}
@@ -0,0 +1,1095 @@
+/*
+ * Copyright 2023 Anthony Oteri
+ *
+ * Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+ * http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+ * http://opensource.org/licenses/MIT>, at your option. This file may not be
+ * copied, modified, or distributed except according to those terms.
+ */
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+
+use crate::error::ApiError;
+use crate::image::Image;
+use crate::layer;
+use crate::layer::Entry;
+use crate::layer::Kind;
+use crate::reference::ImageRef;
+
+/// Prefix of a whiteout entry, which deletes the file named by the rest of
+/// its name from the layers below.
+pub const WHITEOUT_PREFIX: &str = ".wh.";
+
+/// Name of the entry marking its directory as opaque: everything the
+/// layers below put in the directory is hidden.
+pub const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// Maximum number of symbolic links followed while resolving one path.
+const MAX_SYMLINKS: usize = 40;
+
+/// A file or directory in the merged filesystem of an image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    /// The metadata of the entry from the topmost layer providing it.
+    /// Directories only implied by the paths of other entries get a
+    /// `0755` directory owned by root.
+    pub entry: Entry,
+    /// The children of a directory, by name.
+    pub children: BTreeMap<String, Node>,
+}
+
+impl Node {
+    /// A node for an entry, without children.
+    fn new(entry: Entry) -> Self {
+        Self {
+            entry,
+            children: BTreeMap::new(),
+        }
+    }
+
+    /// A directory implied by the path of another entry.
+    fn implied(path: &str) -> Self {
+        Self::new(Entry {
+            path: String::from(path),
+            kind: Kind::Directory,
+            mode: 0o755,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            link: None,
+            mtime: 0,
+            digest: None,
+        })
+    }
+
+    /// Return `true` if this node is a directory.
+    pub fn is_dir(&self) -> bool {
+        self.entry.kind == Kind::Directory
+    }
+
+    /// Every node below this one, depth first with children sorted by name.
+    pub fn descendants(&self) -> Vec<&Node> {
+        let mut nodes = Vec::new();
+        for child in self.children.values() {
+            nodes.push(child);
+            nodes.extend(child.descendants());
+        }
+        nodes
+    }
+}
+
+/// The filesystem of an image, built by applying its layers in order
+/// without extracting anything to disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RootFs {
+    root: Node,
+}
+
+impl Default for RootFs {
+    fn default() -> Self {
+        Self {
+            root: Node::implied(""),
+        }
+    }
+}
+
+impl RootFs {
+    /// The root directory.
+    pub fn root(&self) -> &Node {
+        &self.root
+    }
+
+    /// Apply the entries of one layer on top of the filesystem.
+    ///
+    /// Whiteouts are applied first, since they only ever hide entries from
+    /// the layers below: `.wh.<name>` removes `<name>` from its directory
+    /// and `.wh..wh..opq` empties its directory.  The remaining entries are
+    /// then added in order.  A directory replacing a directory keeps its
+    /// children; any other entry replaces what was at its path, and a
+    /// non-directory in the way of an entry's parent is replaced by a
+    /// directory.
+    pub fn apply(&mut self, entries: &[Entry]) {
+        for entry in entries {
+            let (dir, name) = split(&entry.path);
+            if name == OPAQUE_WHITEOUT {
+                if let Some(node) = self.node_mut(dir) {
+                    node.children.clear();
+                }
+            } else if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+                if let Some(node) = self.node_mut(dir) {
+                    node.children.remove(hidden);
+                }
+            }
+        }
+        for entry in entries {
+            if !split(&entry.path).1.starts_with(WHITEOUT_PREFIX) {
+                self.insert(entry);
+            }
+        }
+    }
+
+    /// Add `entry`, creating or replacing its parent directories as needed.
+    fn insert(&mut self, entry: &Entry) {
+        let (dir, name) = split(&entry.path);
+        let mut node = &mut self.root;
+        let mut path = String::new();
+        for part in components(dir) {
+            path = join(&path, part);
+            let child = node
+                .children
+                .entry(String::from(part))
+                .or_insert_with(|| Node::implied(&path));
+            if !child.is_dir() {
+                *child = Node::implied(&path);
+            }
+            node = child;
+        }
+        match node.children.get_mut(name) {
+            Some(existing) if existing.is_dir() && entry.kind == Kind::Directory => {
+                existing.entry = entry.clone();
+            }
+            _ => {
+                node.children
+                    .insert(String::from(name), Node::new(entry.clone()));
+            }
+        }
+    }
+
+    /// The node at the literal `path`, without following symbolic links.
+    fn node_mut(&mut self, path: &str) -> Option<&mut Node> {
+        components(path).try_fold(&mut self.root, |node, part| node.children.get_mut(part))
+    }
+
+    /// The node at the literal `path`, without following symbolic links.
+    fn node(&self, path: &[String]) -> Option<&Node> {
+        path.iter()
+            .try_fold(&self.root, |node, part| node.children.get(part))
+    }
+
+    /// Look up `path`, resolving `.`, `..`, and symbolic links in its
+    /// directory components.  A symbolic link in the last component is
+    /// only followed when `follow` is `true`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ApiError::PathNotFound`] if the path does not exist, or
+    /// more than 40 symbolic links are followed.
+    pub fn lookup(&self, path: &str, follow: bool) -> Result<&Node, ApiError> {
+        let not_found = || ApiError::PathNotFound(String::from(path));
+        let mut pending: VecDeque<String> = components(path).map(String::from).collect();
+        let mut resolved: Vec<String> = Vec::new();
+        let mut links = 0;
+        while let Some(part) = pending.pop_front() {
+            match part.as_str() {
+                "." => continue,
+                ".." => {
+                    resolved.pop();
+                    continue;
+                }
+                _ => {}
+            }
+            let parent = self.node(&resolved).ok_or_else(not_found)?;
+            let child = parent.children.get(&part).ok_or_else(not_found)?;
+            match &child.entry.link {
+                Some(target)
+                    if child.entry.kind == Kind::Symlink && (follow || !pending.is_empty()) =>
+                {
+                    links += 1;
+                    if links > MAX_SYMLINKS {
+                        return Err(ApiError::PathNotFound(format!(
+                            "{path} (too many levels of symbolic links)"
+                        )));
+                    }
+                    if target.starts_with('/') {
+                        resolved.clear();
+                    }
+                    for part in components(target).rev() {
+                        pending.push_front(String::from(part));
+                    }
+                }
+                _ => resolved.push(part),
+            }
+        }
+        self.node(&resolved).ok_or_else(not_found)
+    }
+}
+
+/// Split `path` into its parent directory and its final component.
+fn split(path: &str) -> (&str, &str) {
+    path.rsplit_once('/').unwrap_or(("", path))
+}
+
+/// The non-empty components of `path`.
+fn components(path: &str) -> impl DoubleEndedIterator<Item = &str> {
+    path.split('/').filter(|c| !c.is_empty())
+}
+
+/// Join `name` onto the directory `dir`.
+fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        String::from(name)
+    } else {
+        format!("{dir}/{name}")
+    }
+}
+
+/// Resolve `path` relative to the directory `dir`, removing `.` and `..`
+/// components.  An absolute `path` ignores `dir`.
+fn resolve(dir: &str, path: &str) -> String {
+    let mut parts: Vec<&str> = if path.starts_with('/') {
+        Vec::new()
+    } else {
+        components(dir).collect()
+    };
+    for part in components(path) {
+        match part {
+            "." => {}
+            ".." => {
+                parts.pop();
+            }
+            _ => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+/// What one layer says about a path when scanning from the top layer down.
+enum Scan<T> {
+    /// The layer contains the file; its entry and the result of the reader.
+    Found(Entry, T),
+    /// The path, or one of its directories, is a link in this layer; the
+    /// path to look up instead.
+    Link(String),
+    /// The layer deletes the path, or replaces one of its directories with
+    /// something else.
+    Hidden,
+    /// The layer does not mention the path.
+    Absent,
+}
+
+/// Scan the tar stream of one layer for `path`, passing the contents of a
+/// regular file at `path` to `f`.
+fn scan<T>(
+    tar: &mut dyn Read,
+    path: &str,
+    f: &mut impl FnMut(&Entry, &mut dyn Read) -> Result<T, ApiError>,
+) -> Result<Scan<T>, ApiError> {
+    let mut hidden = false;
+    let mut archive = tar::Archive::new(tar);
+    for item in archive.entries()? {
+        let mut item = item?;
+        let entry = Entry::from_tar(&item)?;
+        let (dir, name) = split(&entry.path);
+        let covers = |p: &str| p.is_empty() || path == p || path.starts_with(&format!("{p}/"));
+
+        if name == OPAQUE_WHITEOUT {
+            hidden |= covers(dir) && path != dir;
+        } else if let Some(deleted) = name.strip_prefix(WHITEOUT_PREFIX) {
+            hidden |= covers(&join(dir, deleted));
+        } else if entry.path == path {
+            return match (entry.kind, &entry.link) {
+                (Kind::File, _) => {
+                    let result = f(&entry, &mut item)?;
+                    Ok(Scan::Found(entry, result))
+                }
+                (Kind::Symlink, Some(target)) => Ok(Scan::Link(resolve(dir, target))),
+                (Kind::Hardlink, Some(target)) => Ok(Scan::Link(layer::normalize(target))),
+                _ => Err(ApiError::NotAFile(String::from(path))),
+            };
+        } else if let Some(rest) = path.strip_prefix(&format!("{}/", entry.path)) {
+            match (entry.kind, &entry.link) {
+                (Kind::Directory, _) => {}
+                (Kind::Symlink, Some(target)) => {
+                    return Ok(Scan::Link(join(&resolve(dir, target), rest)));
+                }
+                _ => return Ok(Scan::Hidden),
+            }
+        }
+    }
+    Ok(if hidden { Scan::Hidden } else { Scan::Absent })
+}
+
+/// Find the regular file at `path` in the filesystem of `image` and pass
+/// its entry and contents to `f`, returning the entry and what `f`
+/// returned.
+///
+/// Layers are streamed from the top down, stopping at the topmost layer
+/// that contains the file, so lower layers are never downloaded.  A layer
+/// that deletes the path with a whiteout, makes one of its directories
+/// opaque, or replaces one of its directories with a file ends the search.
+/// Symbolic and hard links, in the path or any of its directories, are
+/// followed by restarting the search from the top layer.
+///
+/// # Errors
+///
+/// * [`ApiError::PathNotFound`] — the path does not exist in the image, or
+///   more than 40 links are followed.
+/// * [`ApiError::NotAFile`] — the path is a directory or a special file.
+/// * Any error returned by [`layer::fetch`] or `f`.
+pub async fn open<T, F>(
+    client: &reqwest::Client,
+    reference: &ImageRef,
+    image: &Image,
+    path: &str,
+    mut f: F,
+) -> Result<(Entry, T), ApiError>
+where
+    T: Send + 'static,
+    F: FnMut(&Entry, &mut dyn Read) -> Result<T, ApiError> + Send + 'static,
+{
+    log::trace!("open(reference: {reference}, path: {path})");
+
+    let layers: Vec<_> = image.layers().collect();
+    let mut target = resolve("", path);
+    let mut links = 0;
+    'search: loop {
+        if target.is_empty() {
+            return Err(ApiError::NotAFile(String::from(path)));
+        }
+        for (index, (descriptor, diff_id)) in layers.iter().enumerate().rev() {
+            log::debug!(
+                "Looking for {target} in layer {index} ({})",
+                descriptor.digest
+            );
+            let wanted = target.clone();
+            let (scan, returned) =
+                layer::fetch(client, reference, descriptor, diff_id, move |tar| {
+                    let scan = scan(tar, &wanted, &mut f)?;
+                    Ok((scan, f))
+                })
+                .await?;
+            f = returned;
+            match scan {
+                Scan::Found(entry, result) => return Ok((entry, result)),
+                Scan::Link(next) => {
+                    links += 1;
+                    if links > MAX_SYMLINKS {
+                        return Err(ApiError::PathNotFound(format!(
+                            "{path} (too many levels of symbolic links)"
+                        )));
+                    }
+                    log::debug!("{target} links to {next}");
+                    target = next;
+                    continue 'search;
+                }
+                Scan::Hidden => break,
+                Scan::Absent => {}
+            }
+        }
+        return Err(ApiError::PathNotFound(String::from(path)));
+    }
+}
+
+/// Decides which entries are visible in the merged filesystem when layers
+/// are read from the top layer down, so each layer is streamed only once.
+///
+/// An entry is visible unless an upper layer provides the same path, deletes
+/// it or one of its directories with a whiteout, makes one of its
+/// directories opaque, or replaces one of its directories with something
+/// other than a directory.  Whiteouts only hide lower layers, so those of
+/// the current layer take effect at [`Overlay::next_layer`].  When a layer
+/// lists a path more than once, the first entry wins.
+#[derive(Debug, Default)]
+pub struct Overlay {
+    /// Paths provided by upper layers, and whether each is a directory.
+    seen: BTreeMap<String, bool>,
+    /// Paths deleted by upper layers, along with everything below them.
+    deleted: BTreeSet<String>,
+    /// Directories whose lower contents are hidden by upper layers.
+    opaque: BTreeSet<String>,
+    /// Whiteouts of the current layer, as `(path, opaque)`.
+    pending: Vec<(String, bool)>,
+}
+
+impl Overlay {
+    /// Return `true` if `entry`, from the current layer, is visible.
+    /// Whiteout entries are recorded and never visible themselves.
+    pub fn admit(&mut self, entry: &Entry) -> bool {
+        let (dir, name) = split(&entry.path);
+        if name == OPAQUE_WHITEOUT {
+            self.pending.push((String::from(dir), true));
+            return false;
+        }
+        if let Some(deleted) = name.strip_prefix(WHITEOUT_PREFIX) {
+            self.pending.push((join(dir, deleted), false));
+            return false;
+        }
+        if self.deleted.contains(&entry.path) {
+            return false;
+        }
+        if self.seen.contains_key(&entry.path) {
+            // Even when replaced from above, a non-directory still replaced
+            // whatever the layers below had under its path.
+            if entry.kind != Kind::Directory {
+                self.pending.push((entry.path.clone(), false));
+            }
+            return false;
+        }
+        let mut path = entry.path.as_str();
+        while let Some((parent, _)) = path.rsplit_once('/') {
+            if self.opaque.contains(parent)
+                || self.deleted.contains(parent)
+                || self.seen.get(parent) == Some(&false)
+            {
+                return false;
+            }
+            path = parent;
+        }
+        if self.opaque.contains("") {
+            return false;
+        }
+        self.seen
+            .insert(entry.path.clone(), entry.kind == Kind::Directory);
+        true
+    }
+
+    /// Finish the current layer, applying its whiteouts to the layers below.
+    pub fn next_layer(&mut self) {
+        for (path, opaque) in self.pending.drain(..) {
+            if opaque {
+                self.opaque.insert(path);
+            } else {
+                self.deleted.insert(path);
+            }
+        }
+    }
+}
+
+/// A range of bytes in a [`Spool`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Extent {
+    offset: u64,
+    len: u64,
+}
+
+/// An anonymous temporary file holding file contents until they are
+/// written out.  The file is created on first use and removed by the
+/// operating system once closed.
+#[derive(Debug, Default)]
+struct Spool {
+    file: Option<File>,
+    len: u64,
+}
+
+impl Spool {
+    /// The spool file, created if needed.
+    fn file(&mut self) -> Result<&mut File, ApiError> {
+        if self.file.is_none() {
+            self.file = Some(tempfile::tempfile()?);
+        }
+        Ok(self.file.as_mut().expect("spool file was just created"))
+    }
+
+    /// Append everything `data` yields and return where it was stored.
+    fn append(&mut self, data: &mut dyn Read) -> Result<Extent, ApiError> {
+        let offset = self.len;
+        let file = self.file()?;
+        file.seek(SeekFrom::Start(offset))?;
+        let len = std::io::copy(data, file)?;
+        self.len += len;
+        Ok(Extent { offset, len })
+    }
+
+    /// A reader over the bytes of `extent`.
+    fn read(&mut self, extent: Extent) -> Result<impl Read + '_, ApiError> {
+        let file = self.file()?;
+        file.seek(SeekFrom::Start(extent.offset))?;
+        Ok(file.take(extent.len))
+    }
+
+    /// Discard the contents.
+    fn clear(&mut self) -> Result<(), ApiError> {
+        if let Some(file) = &mut self.file {
+            file.set_len(0)?;
+        }
+        self.len = 0;
+        Ok(())
+    }
+}
+
+/// What an exported entry carries besides its header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Content {
+    /// Nothing: a directory or a special file.
+    Empty,
+    /// A regular file, whose contents are spooled.
+    File(Extent),
+    /// A symbolic link to this target.
+    Symlink(String),
+    /// A hard link to the entry at this path.
+    Hardlink(String),
+}
+
+/// An entry of the merged filesystem, waiting to be exported.
+#[derive(Debug, Clone)]
+struct Exported {
+    /// The original tar header.
+    header: tar::Header,
+    /// Index of the layer providing the entry, counted from the base.
+    layer: usize,
+    content: Content,
+}
+
+/// The state of [`export`] while its layers are read from the top down.
+///
+/// Each visible entry is recorded with its header, and the contents of
+/// regular files go to a spool.  A hard link stays one when its target is
+/// visible and comes from the same layer or one below, so it still has the
+/// contents the link was made to.  Otherwise the link becomes a regular
+/// file holding those contents: hidden files of the current layer are kept
+/// in a scratch spool until the layer ends, in case a link refers to them,
+/// and a target missing from the layer of its link is captured from the
+/// first layer below that has it.
+#[derive(Debug, Default)]
+struct Export {
+    overlay: Overlay,
+    entries: BTreeMap<String, Exported>,
+    spool: Spool,
+    /// Hidden regular files of the current layer.
+    scratch: Spool,
+    hidden: BTreeMap<String, Extent>,
+    /// Hard link targets to capture from lower layers, with the layers of
+    /// the links waiting for them.
+    wanted: BTreeMap<String, Vec<usize>>,
+    /// Contents of hard link targets, by layer of the link and target.
+    captured: BTreeMap<(usize, String), Extent>,
+}
+
+impl Export {
+    /// Record the visible entries of the layer at `index`, whose tar stream
+    /// is `tar`.  Layers must be read from the top down.
+    fn read_layer(&mut self, index: usize, tar: &mut dyn Read) -> Result<(), ApiError> {
+        let mut archive = tar::Archive::new(tar);
+        for item in archive.entries()? {
+            let mut item = item?;
+            let entry = Entry::from_tar(&item)?;
+            if entry.path.is_empty() {
+                continue;
+            }
+            let visible = self.overlay.admit(&entry);
+            let content = match (entry.kind, entry.link) {
+                (Kind::File, _) => {
+                    let extent = if visible {
+                        self.spool.append(&mut item)?
+                    } else {
+                        let extent = self.scratch.append(&mut item)?;
+                        self.hidden.insert(entry.path.clone(), extent);
+                        extent
+                    };
+                    if let Some(layers) = self.wanted.remove(&entry.path) {
+                        let extent = if visible {
+                            extent
+                        } else {
+                            self.spool.append(&mut self.scratch.read(extent)?)?
+                        };
+                        for layer in layers {
+                            self.captured.insert((layer, entry.path.clone()), extent);
+                        }
+                    }
+                    Content::File(extent)
+                }
+                (Kind::Hardlink, Some(target)) if visible => {
+                    let target = layer::normalize(&target);
+                    self.capture(index, &target)?;
+                    Content::Hardlink(target)
+                }
+                (Kind::Symlink, Some(target)) => Content::Symlink(target),
+                _ => Content::Empty,
+            };
+            if visible {
+                let header = item.header().clone();
+                self.entries.insert(
+                    entry.path,
+                    Exported {
+                        header,
+                        layer: index,
+                        content,
+                    },
+                );
+            }
+        }
+        self.overlay.next_layer();
+        self.scratch.clear()?;
+        self.hidden.clear();
+        Ok(())
+    }
+
+    /// Make sure the contents a hard link in the layer at `index` refers
+    /// to through `target` will be available.
+    fn capture(&mut self, index: usize, target: &str) -> Result<(), ApiError> {
+        if let Some(&extent) = self.hidden.get(target) {
+            let extent = self.spool.append(&mut self.scratch.read(extent)?)?;
+            self.captured.insert((index, String::from(target)), extent);
+        } else if self.entries.get(target).is_none_or(|e| e.layer != index) {
+            self.wanted
+                .entry(String::from(target))
+                .or_default()
+                .push(index);
+        }
+        Ok(())
+    }
+
+    /// Settle every hard link: links to the same visible file form a group
+    /// whose first path in order holds the contents, while the other paths
+    /// link to it; links whose target is gone hold the captured contents.
+    fn resolve_hardlinks(&mut self) {
+        let links: Vec<(String, String, usize)> = self
+            .entries
+            .iter()
+            .filter_map(|(path, e)| match &e.content {
+                Content::Hardlink(target) => Some((path.clone(), target.clone(), e.layer)),
+                _ => None,
+            })
+            .collect();
+
+        let mut groups: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        for (path, target, layer) in links {
+            match self.entries.get(&target) {
+                Some(t) if matches!(t.content, Content::File(_)) && t.layer <= layer => {
+                    groups
+                        .entry(target.clone())
+                        .or_insert_with(|| BTreeSet::from([target]))
+                        .insert(path);
+                }
+                _ => {
+                    if let Some(&extent) = self.captured.get(&(layer, target.clone())) {
+                        let e = self.entries.get_mut(&path).expect("link is exported");
+                        e.header.set_entry_type(tar::EntryType::Regular);
+                        e.header.set_size(extent.len);
+                        e.header.as_old_mut().linkname = [0; 100];
+                        e.content = Content::File(extent);
+                    } else {
+                        log::warn!("Skipping {path}: hard link target {target} not found");
+                        self.entries.remove(&path);
+                    }
+                }
+            }
+        }
+
+        for (file, members) in groups {
+            let leader = members.first().expect("group has its file").clone();
+            if leader != file {
+                let source = self.entries[&file].clone();
+                let e = self.entries.get_mut(&leader).expect("link is exported");
+                e.header = source.header;
+                e.content = source.content;
+                let f = self.entries.get_mut(&file).expect("file is exported");
+                f.header.set_entry_type(tar::EntryType::Link);
+                f.header.set_size(0);
+            }
+            for member in members.into_iter().skip(1) {
+                let e = self.entries.get_mut(&member).expect("link is exported");
+                e.content = Content::Hardlink(leader.clone());
+            }
+        }
+    }
+
+    /// Write the recorded entries to `out` as a tar stream in path order,
+    /// so every directory precedes its contents and every hard link its
+    /// target.  Returns the number of entries written and `out`, flushed.
+    fn finish<W: Write>(mut self, out: W) -> Result<(usize, W), ApiError> {
+        self.resolve_hardlinks();
+        let mut builder = tar::Builder::new(out);
+        for (path, e) in &self.entries {
+            let mut header = e.header.clone();
+            match &e.content {
+                Content::Empty => builder.append_data(&mut header, path, std::io::empty())?,
+                Content::File(extent) => {
+                    builder.append_data(&mut header, path, self.spool.read(*extent)?)?;
+                }
+                Content::Symlink(target) | Content::Hardlink(target) => {
+                    builder.append_link(&mut header, path, target)?;
+                }
+            }
+        }
+        let mut out = builder.into_inner()?;
+        out.flush()?;
+        Ok((self.entries.len(), out))
+    }
+}
+
+/// Write the merged filesystem of `image` to `out` as a single tar stream.
+///
+/// Layers are streamed from the repository of `reference` from the top
+/// layer down, so every layer is downloaded once and nothing is extracted
+/// to disk.  Only the entries an [`Overlay`] finds visible are kept, with
+/// their original headers, while the contents of regular files wait in an
+/// anonymous temporary file.  The entries are then written in path order,
+/// like `docker export`, so directories come before their contents.  Hard
+/// links keep the contents they were made to, even when an upper layer
+/// replaced their target.  Extended attributes are not preserved.
+///
+/// Returns the number of entries written and `out`, flushed.
+///
+/// # Errors
+///
+/// * [`ApiError::IOError`] — a layer is not a valid tar stream, the
+///   temporary file could not be used, or writing to `out` failed.
+/// * Any error returned by [`layer::fetch`].
+pub async fn export<W>(
+    client: &reqwest::Client,
+    reference: &ImageRef,
+    image: &Image,
+    out: W,
+) -> Result<(usize, W), ApiError>
+where
+    W: Write + Send + 'static,
+{
+    log::trace!("export(reference: {reference}, image: {})", image.digest);
+
+    let mut state = Export::default();
+    let layers: Vec<_> = image.layers().collect();
+    for (index, (descriptor, diff_id)) in layers.into_iter().enumerate().rev() {
+        log::debug!("Exporting layer {index} ({})", descriptor.digest);
+        state = layer::fetch(client, reference, descriptor, diff_id, move |tar| {
+            state.read_layer(index, tar)?;
+            Ok(state)
+        })
+        .await?;
+    }
+    tokio::task::spawn_blocking(move || state.finish(out))
+        .await
+        .map_err(|e| ApiError::UnexpectedResponse(e.to_string()))?
+}
+
+/// Read the contents of every regular file in the merged filesystem of
+/// `image` whose path `wanted` selects, keyed by path.
+///
+/// Like [`export`], layers are streamed once from the top layer down and
+/// an [`Overlay`] decides which entries are visible.  Symbolic links are
+/// not followed, so `wanted` should select every location a file may
+/// live at.
+///
+/// # Errors
+///
+/// Returns any error raised by [`layer::fetch`] while reading a layer.
+pub async fn collect<F>(
+    client: &reqwest::Client,
+    reference: &ImageRef,
+    image: &Image,
+    wanted: F,
+) -> Result<BTreeMap<String, Vec<u8>>, ApiError>
+where
+    F: Fn(&str) -> bool + Send + 'static,
+{
+    log::trace!("collect(reference: {reference}, image: {})", image.digest);
+
+    let mut state = (Overlay::default(), BTreeMap::new(), wanted);
+    let layers: Vec<_> = image.layers().collect();
+    for (index, (descriptor, diff_id)) in layers.into_iter().enumerate().rev() {
+        log::debug!("Scanning layer {index} ({})", descriptor.digest);
+        state = layer::fetch(client, reference, descriptor, diff_id, move |tar| {
+            let (mut overlay, mut files, wanted) = state;
+            let mut archive = tar::Archive::new(tar);
+            for item in archive.entries()? {
+                let mut item = item?;
+                let entry = Entry::from_tar(&item)?;
+                if entry.path.is_empty() || !overlay.admit(&entry) {
+                    continue;
+                }
+                if entry.kind == Kind::File && wanted(&entry.path) {
+                    let mut contents = Vec::new();
+                    item.read_to_end(&mut contents)?;
+                    files.insert(entry.path, contents);
+                }
+            }
+            overlay.next_layer();
+            Ok((overlay, files, wanted))
+        })
+        .await?;
+    }
+    Ok(state.1)
+}
+
+/// Build the filesystem of `image` by streaming each of its layers from the
+/// repository of `reference` and applying them base layer first.
+///
+/// With `hash`, the contents of every regular file are hashed with
+/// [`layer::hashed_entries`] so that files can be compared by content.
+///
+/// # Errors
+///
+/// Returns any error raised by [`layer::fetch`] while reading a layer.
+pub async fn build(
+    client: &reqwest::Client,
+    reference: &ImageRef,
+    image: &Image,
+    hash: bool,
+) -> Result<RootFs, ApiError> {
+    log::trace!(
+        "build(reference: {reference}, image: {}, hash: {hash})",
+        image.digest
+    );
+
+    let mut rootfs = RootFs::default();
+    for (index, (descriptor, diff_id)) in image.layers().enumerate() {
+        log::debug!("Applying layer {index} ({})", descriptor.digest);
+        let read = if hash {
+            layer::hashed_entries
+        } else {
+            layer::entries
+        };
+        let entries = layer::fetch(client, reference, descriptor, diff_id, read).await?;
+        rootfs.apply(&entries);
+    }
+    Ok(rootfs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer::tests::tarball;
+
+    fn apply(rootfs: &mut RootFs, files: &[(&str, &str)]) {
+        let tar = tarball(files);
+        rootfs.apply(&layer::entries(&mut tar.as_slice()).unwrap());
+    }
+
+    fn paths(rootfs: &RootFs) -> Vec<&str> {
+        rootfs
+            .root
+            .descendants()
+            .into_iter()
+            .map(|n| n.entry.path.as_str())
+            .collect()
+    }
+
+    /// Test that whiteouts remove files from lower layers, that opaque
+    /// directories hide their lower contents but keep entries from the same
+    /// layer, and that missing parents are implied.
+    #[test]
+    fn test_apply_whiteouts() {
+        let mut rootfs = RootFs::default();
+        apply(
+            &mut rootfs,
+            &[
+                ("etc/", ""),
+                ("etc/passwd", "root"),
+                ("etc/shadow", "x"),
+                ("var/cache/", ""),
+                ("var/cache/a", "a"),
+            ],
+        );
+        apply(
+            &mut rootfs,
+            &[
+                ("etc/.wh.shadow", ""),
+                ("var/cache/b", "b"),
+                ("var/cache/.wh..wh..opq", ""),
+                ("usr/bin/env", "env"),
+            ],
+        );
+        assert_eq!(
+            paths(&rootfs),
+            [
+                "etc",
+                "etc/passwd",
+                "usr",
+                "usr/bin",
+                "usr/bin/env",
+                "var",
+                "var/cache",
+                "var/cache/b",
+            ]
+        );
+    }
+
+    /// Test that a file replaces a directory and its children, and that a
+    /// directory replacing a directory keeps the children.
+    #[test]
+    fn test_apply_replacements() {
+        let mut rootfs = RootFs::default();
+        apply(
+            &mut rootfs,
+            &[("opt/", ""), ("opt/a", "a"), ("srv/", ""), ("srv/b", "b")],
+        );
+        apply(&mut rootfs, &[("opt", "file"), ("srv/", "")]);
+        assert_eq!(paths(&rootfs), ["opt", "srv", "srv/b"]);
+        assert_eq!(rootfs.lookup("opt", false).unwrap().entry.size, 4);
+
+        apply(&mut rootfs, &[("opt/c", "c")]);
+        assert!(rootfs.lookup("opt", false).unwrap().is_dir());
+    }
+
+    /// Test that symbolic links are resolved in directory components, and in
+    /// the last component only when asked to.
+    #[test]
+    fn test_lookup_symlinks() {
+        let mut rootfs = RootFs::default();
+        apply(
+            &mut rootfs,
+            &[
+                ("usr/lib/os-release", "ID=test"),
+                ("etc/os-release", "->../usr/lib/os-release"),
+                ("lib", "->/usr/lib"),
+                ("loop", "->loop"),
+            ],
+        );
+        let link = rootfs.lookup("/etc/os-release", false).unwrap();
+        assert_eq!(link.entry.kind, Kind::Symlink);
+        let target = rootfs.lookup("etc/os-release", true).unwrap();
+        assert_eq!(target.entry.path, "usr/lib/os-release");
+        let via_dir = rootfs.lookup("lib/./os-release", false).unwrap();
+        assert_eq!(via_dir.entry.path, "usr/lib/os-release");
+
+        assert!(matches!(
+            rootfs.lookup("etc/missing", false),
+            Err(ApiError::PathNotFound(_))
+        ));
+        assert!(matches!(
+            rootfs.lookup("loop", true),
+            Err(ApiError::PathNotFound(_))
+        ));
+    }
+
+    /// Test that link targets are resolved relative to the link's directory
+    /// and that absolute targets start from the root.
+    #[test]
+    fn test_resolve() {
+        assert_eq!(
+            resolve("etc", "../usr/lib/os-release"),
+            "usr/lib/os-release"
+        );
+        assert_eq!(resolve("usr/bin", "/bin/busybox"), "bin/busybox");
+        assert_eq!(resolve("", "./a/../b/"), "b");
+        assert_eq!(resolve("", "../../a"), "a");
+    }
+
+    /// Read the layers of an export from the top down and return each
+    /// written entry as `path`, `path contents`, or `path => target`.
+    fn export(layers: &[Vec<u8>]) -> Vec<String> {
+        let mut state = Export::default();
+        for (index, tar) in layers.iter().enumerate().rev() {
+            state.read_layer(index, &mut tar.as_slice()).unwrap();
+        }
+        let (count, tar) = state.finish(Vec::new()).unwrap();
+
+        let mut archive = tar::Archive::new(tar.as_slice());
+        let mut written = Vec::new();
+        for item in archive.entries().unwrap() {
+            let mut item = item.unwrap();
+            let path = item.path().unwrap().display().to_string();
+            written.push(match item.link_name().unwrap() {
+                Some(target) => format!("{path} => {}", target.display()),
+                None if item.header().entry_type().is_file() => {
+                    let mut contents = String::new();
+                    item.read_to_string(&mut contents).unwrap();
+                    format!("{path} {contents}")
+                }
+                None => path,
+            });
+        }
+        assert_eq!(written.len(), count);
+        written
+    }
+
+    /// Test that an export lists directories before their contents, even
+    /// when an upper layer adds files to a directory of a lower one.
+    #[test]
+    fn test_export_order() {
+        let written = export(&[
+            tarball(&[("usr/", ""), ("usr/lib/", ""), ("usr/lib/a", "a")]),
+            tarball(&[("usr/lib/b", "b"), ("usr/bin/", ""), ("usr/bin/env", "env")]),
+        ]);
+        assert_eq!(
+            written,
+            [
+                "usr",
+                "usr/bin",
+                "usr/bin/env env",
+                "usr/lib",
+                "usr/lib/a a",
+                "usr/lib/b b",
+            ]
+        );
+    }
+
+    /// Test that hard links follow their target, link to the visible target
+    /// when it is unchanged, and otherwise hold the contents they were made
+    /// to.
+    #[test]
+    fn test_export_hardlinks() {
+        let written = export(&[
+            tarball(&[
+                ("bin/", ""),
+                ("bin/python3.11", "3.11.0"),
+                ("bin/python3", "=>bin/python3.11"),
+                ("bin/sh", "dash"),
+            ]),
+            tarball(&[
+                ("bin/python3.11", "3.11.9"),
+                ("bin/ash", "=>bin/sh"),
+                ("bin/b", "=>bin/sh"),
+            ]),
+        ]);
+        assert_eq!(
+            written,
+            [
+                "bin",
+                "bin/ash dash",
+                "bin/b => bin/ash",
+                "bin/python3 3.11.0",
+                "bin/python3.11 3.11.9",
+                "bin/sh => bin/ash",
+            ]
+        );
+    }
+
+    /// Test that reading layers from the top down admits the same entries
+    /// that applying them from the bottom up leaves in place.
+    #[test]
+    fn test_overlay_matches_apply() {
+        let layers = [
+            tarball(&[
+                ("etc/", ""),
+                ("etc/passwd", "root"),
+                ("etc/shadow", "x"),
+                ("opt/", ""),
+                ("opt/a", "a"),
+                ("var/", ""),
+                ("var/cache/", ""),
+                ("var/cache/a", "a"),
+            ]),
+            tarball(&[
+                ("etc/.wh.shadow", ""),
+                ("etc/passwd", "root,app"),
+                ("opt", "file"),
+                ("var/cache/b", "b"),
+                ("var/cache/.wh..wh..opq", ""),
+            ]),
+            tarball(&[("etc/shadow", "y"), ("opt/", ""), ("opt/c", "c")]),
+        ];
+        let entries: Vec<Vec<Entry>> = layers
+            .iter()
+            .map(|tar| layer::entries(&mut tar.as_slice()).unwrap())
+            .collect();
+
+        let mut rootfs = RootFs::default();
+        for layer in &entries {
+            rootfs.apply(layer);
+        }
+        let mut expected: Vec<&Entry> = rootfs
+            .root
+            .descendants()
+            .into_iter()
+            .map(|n| &n.entry)
+            .collect();
+        expected.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let mut overlay = Overlay::default();
+        let mut admitted = Vec::new();
+        for layer in entries.iter().rev() {
+            admitted.extend(layer.iter().filter(|e| overlay.admit(e)));
+            overlay.next_layer();
+        }
+        admitted.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(admitted, expected);
+    }
+}
@@ -0,0 +1,483 @@
+/*
+ * Copyright 2023 Anthony Oteri
+ *
+ * Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+ * http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+ * http://opensource.org/licenses/MIT>, at your option. This file may not be
+ * copied, modified, or distributed except according to those terms.
+ */
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::io::Write;
+
+use crate::api;
+use crate::copy;
+use crate::copy::Endpoint;
+use crate::digest;
+use crate::error::ApiError;
+use crate::pattern::Filter;
+use crate::reference::Reference;
+
+/// What a sync does with one tag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncAction {
+    /// The tag is missing or differs at the destination and is copied.
+    Copy,
+    /// The destination tag already points at the source digest.
+    Skip,
+    /// The tag no longer exists at the source and is deleted from the
+    /// destination.
+    Prune,
+    /// The tag no longer exists at the source, but its manifest is still
+    /// referenced by another destination tag, so it is left in place.
+    Keep,
+}
+
+impl SyncAction {
+    /// Short label used in plan output.
+    fn label(self) -> &'static str {
+        match self {
+            Self::Copy => "copy",
+            Self::Skip => "skip",
+            Self::Prune => "prune",
+            Self::Keep => "keep",
+        }
+    }
+}
+
+/// A tag considered by a sync and what will happen to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedTag {
+    /// The tag name.
+    pub tag: String,
+    /// The source digest for copied and skipped tags; the destination digest
+    /// for pruned and kept tags.
+    pub digest: String,
+    /// What will happen to the tag.
+    pub action: SyncAction,
+}
+
+/// The tags a sync will copy, skip, and prune, resolved before anything is
+/// written.
+#[derive(Debug, Clone, Default)]
+pub struct SyncPlan {
+    /// Every selected tag, source tags first, each group sorted by name.
+    pub tags: Vec<PlannedTag>,
+}
+
+impl SyncPlan {
+    /// Number of tags with the given `action`.
+    pub fn count(&self, action: SyncAction) -> usize {
+        self.tags.iter().filter(|t| t.action == action).count()
+    }
+
+    /// Write one line per tag followed by a summary line to `buf`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ApiError::IOError`] if writing to `buf` fails.
+    pub fn write_to(&self, buf: &mut dyn Write) -> Result<(), ApiError> {
+        for t in &self.tags {
+            writeln!(buf, "{:<5} {} {}", t.action.label(), t.tag, t.digest)?;
+        }
+        writeln!(
+            buf,
+            "{} tags copied, {} up to date, {} pruned, {} kept",
+            self.count(SyncAction::Copy),
+            self.count(SyncAction::Skip),
+            self.count(SyncAction::Prune),
+            self.count(SyncAction::Keep),
+        )?;
+        Ok(())
+    }
+}
+
+/// List the tags of the repository at `endpoint`.
+async fn tags(endpoint: &Endpoint) -> Result<Vec<String>, ApiError> {
+    api::list_tags(
+        &endpoint.client,
+        &endpoint.image.registry,
+        &endpoint.image.repository,
+    )
+    .await
+}
+
+/// List the tags of the destination repository at `endpoint`, treating a
+/// repository that does not exist yet as having no tags.
+async fn dst_tags(endpoint: &Endpoint) -> Result<Vec<String>, ApiError> {
+    match tags(endpoint).await {
+        Err(ApiError::NotFound) => Ok(Vec::new()),
+        result => result,
+    }
+}
+
+/// Resolve the manifest digest `tag` points at in the repository of
+/// `endpoint`.
+async fn digest_of(endpoint: &Endpoint, tag: &str) -> Result<String, ApiError> {
+    api::resolve_digest(&endpoint.client, &endpoint.image.manifest_url(tag)?).await
+}
+
+/// Compare the tags selected by `filter` in the `src` and `dst`
+/// repositories and decide what to do with each.
+///
+/// Source tags are copied unless the destination tag already resolves to
+/// the same digest.  With `prune`, selected destination tags that no longer
+/// exist at the source are deleted.  Since the registry deletes manifests by
+/// digest, a tag whose digest is also referenced by a tag that stays is
+/// kept instead.  Tags not selected by `filter` are never touched.
+///
+/// A destination repository that does not exist yet has no tags, but the
+/// source repository must exist: otherwise a mistyped source name would
+/// prune every tag of the destination.
+///
+/// # Errors
+///
+/// * [`ApiError::NotFound`] — the source repository does not exist.
+/// * Any error raised while listing tags or resolving digests on either
+///   registry.
+pub async fn plan(
+    src: &Endpoint,
+    dst: &Endpoint,
+    filter: &Filter,
+    prune: bool,
+) -> Result<SyncPlan, ApiError> {
+    log::trace!(
+        "plan(src: {}, dst: {}, prune: {prune})",
+        src.image,
+        dst.image
+    );
+
+    let mut src_tags: Vec<String> = tags(src)
+        .await?
+        .into_iter()
+        .filter(|t| filter.matches(t))
+        .collect();
+    src_tags.sort();
+    let dst_tags: BTreeSet<String> = dst_tags(dst).await?.into_iter().collect();
+
+    let mut plan = SyncPlan::default();
+    for tag in &src_tags {
+        let digest = digest_of(src, tag).await?;
+        let action = if dst_tags.contains(tag) && digest_of(dst, tag).await? == digest {
+            SyncAction::Skip
+        } else {
+            SyncAction::Copy
+        };
+        log::debug!("{tag}: {digest} ({})", action.label());
+        plan.tags.push(PlannedTag {
+            tag: tag.clone(),
+            digest,
+            action,
+        });
+    }
+
+    if !prune {
+        return Ok(plan);
+    }
+
+    let src_set: BTreeSet<&String> = src_tags.iter().collect();
+    let mut kept: BTreeSet<String> = plan.tags.iter().map(|t| t.digest.clone()).collect();
+    let mut candidates = BTreeMap::new();
+    for tag in &dst_tags {
+        if src_set.contains(tag) {
+            continue;
+        }
+        let digest = digest_of(dst, tag).await?;
+        if filter.matches(tag) {
+            candidates.insert(tag.clone(), digest);
+        } else {
+            kept.insert(digest);
+        }
+    }
+
+    for (tag, digest) in candidates {
+        let action = if kept.contains(&digest) {
+            log::warn!("Keeping {tag}: {digest} is still referenced by another tag");
+            SyncAction::Keep
+        } else {
+            SyncAction::Prune
+        };
+        plan.tags.push(PlannedTag {
+            tag,
+            digest,
+            action,
+        });
+    }
+
+    Ok(plan)
+}
+
+/// Carry out `plan`: copy every outdated tag with [`copy::plan`] and
+/// [`copy::execute`], transferring up to `jobs` blobs at a time, then
+/// delete the manifests of pruned tags, each distinct digest once.
+///
+/// Copies run before deletions, so an interrupted sync never leaves the
+/// destination with fewer images than it started with.
+///
+/// # Errors
+///
+/// Returns the first error raised while copying or deleting.
+pub async fn execute(
+    src: &Endpoint,
+    dst: &Endpoint,
+    plan: &SyncPlan,
+    jobs: usize,
+) -> Result<(), ApiError> {
+    log::trace!(
+        "execute(src: {}, dst: {}, jobs: {jobs})",
+        src.image,
+        dst.image
+    );
+
+    for t in plan.tags.iter().filter(|t| t.action == SyncAction::Copy) {
+        log::info!("Copying {}:{}", src.image.repository, t.tag);
+        let reference = Reference::Tag(t.tag.clone());
+        let src = Endpoint {
+            client: src.client.clone(),
+            image: src.image.with_reference(reference.clone()),
+        };
+        let dst = Endpoint {
+            client: dst.client.clone(),
+            image: dst.image.with_reference(reference),
+        };
+        let copy_plan = copy::plan(&src, &dst).await?;
+        copy::execute(&src, &dst, &copy_plan, jobs).await?;
+    }
+
+    let mut deleted = BTreeSet::new();
+    for t in plan.tags.iter().filter(|t| t.action == SyncAction::Prune) {
+        log::info!("Pruning {}:{} ({})", dst.image.repository, t.tag, t.digest);
+        if deleted.insert(&t.digest) {
+            delete_manifest(dst, &t.digest).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that every copied or skipped tag of `plan` now resolves to its
+/// source digest in the repository of `dst`, and return how many were
+/// checked.
+///
+/// # Errors
+///
+/// Returns [`ApiError::DigestMismatch`] for the first tag that resolves to
+/// another digest, or any error raised while resolving.
+pub async fn verify(dst: &Endpoint, plan: &SyncPlan) -> Result<usize, ApiError> {
+    let mut count = 0;
+    for t in plan
+        .tags
+        .iter()
+        .filter(|t| matches!(t.action, SyncAction::Copy | SyncAction::Skip))
+    {
+        digest::verify(&t.digest, &digest_of(dst, &t.tag).await?)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Delete the manifest `digest` from the repository of `endpoint`, and with
+/// it every tag that points at it.
+///
+/// # Errors
+///
+/// Returns any error raised by the request or by
+/// [`api::parse_response_status`].
+pub async fn delete_manifest(endpoint: &Endpoint, digest: &str) -> Result<(), ApiError> {
+    let resp = endpoint
+        .client
+        .delete(endpoint.image.manifest_url(digest)?)
+        .send()
+        .await?;
+    api::parse_response_status(&resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use super::*;
+    use crate::reference::ImageRef;
+
+    fn endpoint(server: &mockito::Server, repository: &str) -> Endpoint {
+        let registry = Url::parse(&server.url()).unwrap();
+        Endpoint {
+            client: reqwest::Client::new(),
+            image: ImageRef::parse(repository, &registry).unwrap(),
+        }
+    }
+
+    fn mock_tags(server: &mut mockito::Server, repository: &str, tags: &str) {
+        server
+            .mock("GET", &*format!("/v2/{repository}/tags/list"))
+            .with_status(200)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .with_body(format!(r#"{{"name":"{repository}","tags":{tags}}}"#))
+            .create();
+    }
+
+    fn mock_digest(server: &mut mockito::Server, repository: &str, tag: &str, digest: &str) {
+        server
+            .mock("HEAD", &*format!("/v2/{repository}/manifests/{tag}"))
+            .with_status(200)
+            .with_header("Docker-Distribution-API-Version", "registry/2.0")
+            .with_header("docker-content-digest", digest)
+            .create();
+    }
+
+    /// Test that filtered source tags are copied or skipped by digest, and
+    /// that stale destination tags are pruned unless their digest is shared.
+    #[tokio::test]
+    async fn test_plan() -> Result<(), ApiError> {
+        let mut src_server = mockito::Server::new_async().await;
+        let mut dst_server = mockito::Server::new_async().await;
+        let (a, b, c) = (
+            digest::sha256(b"a"),
+            digest::sha256(b"b"),
+            digest::sha256(b"c"),
+        );
+
+        mock_tags(&mut src_server, "src", r#"["v2","v1","v1-rc1","latest"]"#);
+        mock_digest(&mut src_server, "src", "v1", &a);
+        mock_digest(&mut src_server, "src", "v2", &b);
+        mock_tags(&mut dst_server, "dst", r#"["v1","v0","v0.9","dev"]"#);
+        mock_digest(&mut dst_server, "dst", "v1", &a);
+        mock_digest(&mut dst_server, "dst", "v0", &c);
+        mock_digest(&mut dst_server, "dst", "v0.9", &a);
+        mock_digest(&mut dst_server, "dst", "dev", &c);
+
+        let filter = Filter {
+            include: vec!["v*".parse()?],
+            exclude: vec!["*-rc*".parse()?],
+        };
+        let plan = plan(
+            &endpoint(&src_server, "src"),
+            &endpoint(&dst_server, "dst"),
+            &filter,
+            true,
+        )
+        .await?;
+
+        let mut buf = Vec::new();
+        plan.write_to(&mut buf)?;
+        let expected = format!(
+            "skip  v1 {a}\ncopy  v2 {b}\nkeep  v0 {c}\nkeep  v0.9 {a}\n\
+             1 tags copied, 1 up to date, 0 pruned, 2 kept\n"
+        );
+        assert_eq!(String::from_utf8(buf).unwrap(), expected);
+        Ok(())
+    }
+
+    /// Test that a missing destination repository is treated as empty, but
+    /// that a missing source repository fails the sync and deletes nothing.
+    #[tokio::test]
+    async fn test_plan_missing_repository() -> Result<(), ApiError> {
+        let mut src_server = mockito::Server::new_async().await;
+        let mut dst_server = mockito::Server::new_async().await;
+        let (a, old) = (digest::sha256(b"a"), digest::sha256(b"old"));
+
+        src_server
+            .mock("GET", "/v2/missing/tags/list")
+            .with_status(404)
+            .create();
+        mock_tags(&mut src_server, "src", r#"["v1"]"#);
+        mock_digest(&mut src_server, "src", "v1", &a);
+        dst_server
+            .mock("GET", "/v2/new/tags/list")
+            .with_status(404)
+            .create();
+        mock_tags(&mut dst_server, "dst", r#"["old"]"#);
+        mock_digest(&mut dst_server, "dst", "old", &old);
+        let delete = dst_server
+            .mock("DELETE", mockito::Matcher::Any)
+            .expect(0)
+            .create();
+
+        let plan = plan(
+            &endpoint(&src_server, "src"),
+            &endpoint(&dst_server, "new"),
+            &Filter::default(),
+            true,
+        )
+        .await?;
+        assert_eq!(plan.count(SyncAction::Copy), 1);
+
+        let result = super::plan(
+            &endpoint(&src_server, "missing"),
+            &endpoint(&dst_server, "dst"),
+            &Filter::default(),
+            true,
+        )
+        .await;
+        assert!(matches!(result, Err(ApiError::NotFound)));
+        delete.assert();
+        Ok(())
+    }
+
+    /// Test that pruned tags are deleted by digest, once per digest even
+    /// when several pruned tags share it.
+    #[tokio::test]
+    async fn test_execute_prunes() -> Result<(), ApiError> {
+        let mut src_server = mockito::Server::new_async().await;
+        let mut dst_server = mockito::Server::new_async().await;
+        let (old, stale) = (digest::sha256(b"old"), digest::sha256(b"stale"));
+
+        mock_tags(&mut src_server, "src", "[]");
+        mock_tags(&mut dst_server, "dst", r#"["old","old-alias","stale"]"#);
+        mock_digest(&mut dst_server, "dst", "old", &old);
+        mock_digest(&mut dst_server, "dst", "old-alias", &old);
+        mock_digest(&mut dst_server, "dst", "stale", &stale);
+        let deletes: Vec<_> = [&old, &stale]
+            .iter()
+            .map(|digest| {
+                dst_server
+                    .mock("DELETE", &*format!("/v2/dst/manifests/{digest}"))
+                    .with_status(202)
+                    .with_header("Docker-Distribution-API-Version", "registry/2.0")
+                    .expect(1)
+                    .create()
+            })
+            .collect();
+
+        let src = endpoint(&src_server, "src");
+        let dst = endpoint(&dst_server, "dst");
+        let plan = plan(&src, &dst, &Filter::default(), true).await?;
+        assert_eq!(plan.count(SyncAction::Prune), 3);
+
+        execute(&src, &dst, &plan, 1).await?;
+        for delete in deletes {
+            delete.assert();
+        }
+        Ok(())
+    }
+
+    /// Test that a destination tag resolving to another digest fails
+    /// verification.
+    #[tokio::test]
+    async fn test_verify() -> Result<(), ApiError> {
+        let mut server = mockito::Server::new_async().await;
+        let (a, b) = (digest::sha256(b"a"), digest::sha256(b"b"));
+        mock_digest(&mut server, "dst", "v1", &a);
+        mock_digest(&mut server, "dst", "v2", &a);
+
+        let planned = |tag: &str, digest: &str, action| PlannedTag {
+            tag: String::from(tag),
+            digest: String::from(digest),
+            action,
+        };
+        let dst = endpoint(&server, "dst");
+        let mut plan = SyncPlan {
+            tags: vec![
+                planned("v1", &a, SyncAction::Copy),
+                planned("v0", &b, SyncAction::Keep),
+            ],
+        };
+        assert_eq!(verify(&dst, &plan).await?, 1);
+
+        plan.tags.push(planned("v2", &b, SyncAction::Skip));
+        let result = verify(&dst, &plan).await;
+        assert!(matches!(result, Err(ApiError::DigestMismatch { .. })));
+        Ok(())
+    }
+}
@@ -0,0 +1,592 @@
+/*
+ * Copyright 2023 Anthony Oteri
+ *
+ * Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+ * http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+ * http://opensource.org/licenses/MIT>, at your option. This file may not be
+ * copied, modified, or distributed except according to those terms.
+ */
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use semver::BuildMetadata;
+use semver::Prerelease;
+use semver::Version;
+use semver::VersionReq;
+
+use crate::api;
+use crate::blob;
+use crate::digest;
+use crate::error::ApiError;
+use crate::layer;
+use crate::manifest::Descriptor;
+use crate::manifest::ImageConfig;
+use crate::manifest::ImageManifest;
+use crate::manifest::Manifest;
+use crate::pattern::Filter;
+use crate::progress;
+use crate::reference::ImageRef;
+use crate::reference::Reference;
+
+/// How the tags of a repository are ordered.
+#[derive(clap::ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SortOrder {
+    /// By name, byte by byte.
+    Lexical,
+    /// By semantic version, lowest first; other tags follow by name.
+    Semver,
+    /// By image creation time, oldest first; tags whose creation time is
+    /// unknown follow by name.
+    Date,
+}
+
+/// Parse `tag` as a semantic version, leniently.
+///
+/// A leading `v` is allowed and missing minor and patch numbers count as
+/// `0`, so `v1.4` is version `1.4.0`.  Anything after the first `-` is a
+/// pre-release and anything after `+` is build metadata, as in
+/// `1.4.0-rc.1+build.5`.  Returns `None` for tags that are not versions,
+/// such as `latest`.
+pub fn parse_version(tag: &str) -> Option<Version> {
+    let tag = tag.strip_prefix(['v', 'V']).unwrap_or(tag);
+    let (tag, build) = tag.split_once('+').unwrap_or((tag, ""));
+    let (core, pre) = tag.split_once('-').unwrap_or((tag, ""));
+
+    let mut numbers = [0; 3];
+    let mut parts = core.split('.');
+    for (i, part) in parts.by_ref().take(3).enumerate() {
+        if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        numbers[i] = part.parse().ok()?;
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+
+    let [major, minor, patch] = numbers;
+    Some(Version {
+        major,
+        minor,
+        patch,
+        pre: Prerelease::new(pre).ok()?,
+        build: BuildMetadata::new(build).ok()?,
+    })
+}
+
+/// Which tags of a repository to list, and in what order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Query {
+    /// Match and exclude patterns selecting tags.
+    pub filter: Filter,
+    /// Only select tags that are versions satisfying this constraint.
+    pub semver: Option<VersionReq>,
+    /// Order of the output; `None` keeps the order of the registry.
+    pub sort: Option<SortOrder>,
+    /// Select only the last tag in order: the highest version, or the most
+    /// recently created image.
+    pub latest: bool,
+}
+
+impl Query {
+    /// Return `true` if `tag` is selected by the patterns and the version
+    /// constraint.
+    pub fn matches(&self, tag: &str) -> bool {
+        self.filter.matches(tag)
+            && self
+                .semver
+                .as_ref()
+                .is_none_or(|req| parse_version(tag).is_some_and(|version| req.matches(&version)))
+    }
+
+    /// The order to arrange tags in, if any: the requested one, or by
+    /// version when only the latest tag is wanted.
+    pub fn order(&self) -> Option<SortOrder> {
+        self.sort
+            .or_else(|| self.latest.then_some(SortOrder::Semver))
+    }
+
+    /// Arrange the selected `tags` according to the query.
+    ///
+    /// Tags the order does not apply to (those that are not versions, or
+    /// whose creation time in `created` is unknown) follow the others,
+    /// sorted by name.  With `latest`, only the last ordered tag is
+    /// returned, never one of those.  When ordering by version without a
+    /// constraint, pre-releases are never the latest; a constraint decides
+    /// for itself whether it admits them.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ApiError::NoMatchingTag`] when `latest` is set and no tag
+    /// qualifies.
+    pub fn arrange(
+        &self,
+        mut tags: Vec<String>,
+        created: &BTreeMap<String, Option<u64>>,
+    ) -> Result<Vec<String>, ApiError> {
+        let Some(order) = self.order() else {
+            return Ok(tags);
+        };
+        tags.sort();
+
+        let (mut ordered, rest): (Vec<String>, Vec<String>) = match order {
+            SortOrder::Lexical => (tags, Vec::new()),
+            SortOrder::Semver => {
+                let (versions, rest): (Vec<_>, Vec<_>) = tags
+                    .into_iter()
+                    .map(|tag| (parse_version(&tag), tag))
+                    .partition(|(version, _)| version.is_some());
+                let mut versions: Vec<(Version, String)> = versions
+                    .into_iter()
+                    .filter_map(|(version, tag)| Some((version?, tag)))
+                    .collect();
+                versions.sort();
+                if self.latest && self.semver.is_none() {
+                    versions.retain(|(version, _)| version.pre.is_empty());
+                }
+                (
+                    versions.into_iter().map(|(_, tag)| tag).collect(),
+                    rest.into_iter().map(|(_, tag)| tag).collect(),
+                )
+            }
+            SortOrder::Date => {
+                let (mut dated, rest): (Vec<_>, Vec<_>) = tags
+                    .into_iter()
+                    .map(|tag| (created.get(&tag).copied().flatten(), tag))
+                    .partition(|(time, _)| time.is_some());
+                dated.sort();
+                (
+                    dated.into_iter().map(|(_, tag)| tag).collect(),
+                    rest.into_iter().map(|(_, tag)| tag).collect(),
+                )
+            }
+        };
+
+        if self.latest {
+            return ordered
+                .pop()
+                .map(|tag| vec![tag])
+                .ok_or_else(|| ApiError::NoMatchingTag(self.describe()));
+        }
+        ordered.extend(rest);
+        Ok(ordered)
+    }
+
+    /// Describe the selection for error messages.
+    fn describe(&self) -> String {
+        match &self.semver {
+            Some(req) => format!("version {req}"),
+            None => String::from("the query"),
+        }
+    }
+}
+
+/// What a tag points at, as shown by `tags --long`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TagInfo {
+    /// Digest of the manifest or index the tag points at.
+    pub digest: String,
+    /// Compressed size of the configuration and layers; for an index, the
+    /// sum over its platforms, counting shared blobs once per platform.
+    pub size: u64,
+    /// The platforms of the image, as `OS/ARCH[/VARIANT]`.
+    pub platforms: Vec<String>,
+    /// Creation time of the image, in seconds since the Unix epoch; for an
+    /// index, that of its first platform.
+    pub created: Option<u64>,
+}
+
+/// Describe what each of `tags` points at in the repository `repository`,
+/// up to `jobs` tags at a time.
+///
+/// A tag that cannot be described, such as one deleted meanwhile or one
+/// that points at an artifact [`describe`] does not understand, maps to
+/// `None`, with a warning, and does not stop the others.
+///
+/// # Errors
+///
+/// Returns [`ApiError::UnexpectedResponse`] if the results cannot be
+/// collected.
+pub async fn describe_all(
+    client: &reqwest::Client,
+    repository: &ImageRef,
+    tags: &[String],
+    jobs: usize,
+) -> Result<BTreeMap<String, Option<TagInfo>>, ApiError> {
+    log::trace!(
+        "describe_all(repository: {repository}, tags: {})",
+        tags.len()
+    );
+    lookup_all(tags, jobs, |tag| {
+        let client = client.clone();
+        let image = repository.with_reference(Reference::Tag(tag));
+        async move {
+            Ok(describe(&client, &image)
+                .await
+                .inspect_err(|e| log::warn!("Cannot describe {image}: {e}"))
+                .ok())
+        }
+    })
+    .await
+}
+
+/// Resolve each of `tags` in the repository `repository` to the digest of
+/// the manifest or index it points at, up to `jobs` tags at a time.
+///
+/// # Errors
+///
+/// Returns the first error raised by [`api::resolve_digest`].
+pub async fn resolve_all(
+    client: &reqwest::Client,
+    repository: &ImageRef,
+    tags: &[String],
+    jobs: usize,
+) -> Result<BTreeMap<String, String>, ApiError> {
+    log::trace!(
+        "resolve_all(repository: {repository}, tags: {})",
+        tags.len()
+    );
+    lookup_all(tags, jobs, |tag| {
+        let client = client.clone();
+        let url = repository.manifest_url(&tag);
+        async move { api::resolve_digest(&client, &url?).await }
+    })
+    .await
+}
+
+/// Fetch each of the manifests `digests` in the repository `repository`
+/// and return the entries of those that are indexes, up to `jobs` at a
+/// time.  Image manifests map to no entries.
+///
+/// # Errors
+///
+/// Returns the first error raised while fetching or parsing a manifest.
+pub async fn index_entries(
+    client: &reqwest::Client,
+    repository: &ImageRef,
+    digests: &[String],
+    jobs: usize,
+) -> Result<BTreeMap<String, Vec<Descriptor>>, ApiError> {
+    log::trace!(
+        "index_entries(repository: {repository}, digests: {})",
+        digests.len()
+    );
+    lookup_all(digests, jobs, |digest| {
+        let client = client.clone();
+        let url = repository.manifest_url(&digest);
+        async move {
+            let raw = api::get_manifest(&client, &url?).await?;
+            digest::verify(&digest, &raw.digest)?;
+            Ok(match raw.parse()? {
+                Manifest::Index(index) => index.manifests,
+                Manifest::Image(_) => Vec::new(),
+            })
+        }
+    })
+    .await
+}
+
+/// Run `lookup` for each of `keys`, up to `jobs` at a time, and collect
+/// the results by key.
+///
+/// # Errors
+///
+/// Returns the first error raised by `lookup`.
+pub async fn lookup_all<T, F, Fut>(
+    keys: &[String],
+    jobs: usize,
+    lookup: F,
+) -> Result<BTreeMap<String, T>, ApiError>
+where
+    T: std::fmt::Debug + Send + 'static,
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<T, ApiError>> + Send + 'static,
+{
+    let results = Arc::new(Mutex::new(BTreeMap::new()));
+    let tasks = keys.iter().map(|key| {
+        let results = Arc::clone(&results);
+        let key = key.clone();
+        let lookup = lookup(key.clone());
+        async move {
+            let value = lookup.await?;
+            log::debug!("{key}: {value:?}");
+            results
+                .lock()
+                .map_err(|e| ApiError::UnexpectedResponse(e.to_string()))?
+                .insert(key, value);
+            Ok(())
+        }
+    });
+    blob::run_bounded(jobs, tasks).await?;
+
+    let mut results = results
+        .lock()
+        .map_err(|e| ApiError::UnexpectedResponse(e.to_string()))?;
+    Ok(std::mem::take(&mut *results))
+}
+
+/// Describe the manifest `image` points at.
+///
+/// The tag is resolved with a `HEAD` request first, and the manifest is
+/// then fetched by that digest, so the description is consistent even if
+/// the tag moves meanwhile.  [`api::resolve_digest`] accepts every
+/// manifest type: accepting only a Docker V2 manifest, a registry would
+/// answer for a multi-platform tag with one platform's manifest, or not at
+/// all for an OCI index, and the other platforms would be missing from the
+/// listing.  Index entries for an `unknown` platform, such as build attestations,
+/// are skipped.
+///
+/// # Errors
+///
+/// Returns any error raised while resolving the tag or fetching a manifest
+/// or configuration.
+pub async fn describe(client: &reqwest::Client, image: &ImageRef) -> Result<TagInfo, ApiError> {
+    let digest =
+        api::resolve_digest(client, &image.manifest_url(&image.reference_or_latest())?).await?;
+    let raw = api::get_manifest(client, &image.manifest_url(&digest)?).await?;
+    digest::verify(&digest, &raw.digest)?;
+
+    let children = match raw.parse()? {
+        Manifest::Image(manifest) => {
+            let config = config(client, image, &manifest).await?;
+            return Ok(TagInfo {
+                digest,
+                size: size(&manifest),
+                platforms: (!config.os.is_empty())
+                    .then(|| format!("{}/{}", config.os, config.architecture))
+                    .into_iter()
+                    .collect(),
+                created: created(&config),
+            });
+        }
+        Manifest::Index(index) => index.manifests,
+    };
+
+    let mut info = TagInfo {
+        digest,
+        size: 0,
+        platforms: Vec::new(),
+        created: None,
+    };
+    for child in children {
+        let Some(platform) = child.platform.filter(|p| p.os != "unknown") else {
+            continue;
+        };
+        let raw = api::get_manifest(client, &image.manifest_url(&child.digest)?).await?;
+        digest::verify(&child.digest, &raw.digest)?;
+        let Manifest::Image(manifest) = raw.parse()? else {
+            continue;
+        };
+        if info.platforms.is_empty() {
+            info.created = created(&config(client, image, &manifest).await?);
+        }
+        info.size += size(&manifest);
+        info.platforms.push(platform.to_string());
+    }
+    Ok(info)
+}
+
+/// Download and parse the configuration of `manifest`.
+async fn config(
+    client: &reqwest::Client,
+    image: &ImageRef,
+    manifest: &ImageManifest,
+) -> Result<ImageConfig, ApiError> {
+    let bytes = blob::get(client, &image.blob_url(&manifest.config.digest)?).await?;
+    digest::verify(&manifest.config.digest, &digest::sha256(&bytes))?;
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+/// Compressed size of the configuration and layers of `manifest`.
+fn size(manifest: &ImageManifest) -> u64 {
+    manifest.config.size + manifest.layers.iter().map(|l| l.size).sum::<u64>()
+}
+
+/// Creation time of `config` in seconds since the Unix epoch.
+fn created(config: &ImageConfig) -> Option<u64> {
+    config.created.as_deref().and_then(layer::parse_rfc3339)
+}
+
+/// Write one line per tag of `tags` with what it points at, as described in
+/// `infos`, under a header line.  A tag that could not be described is
+/// written with `-` in every other column.
+///
+/// # Errors
+///
+/// Returns [`ApiError::IOError`] if writing to `buf` fails.
+pub fn write_long(
+    tags: &[String],
+    infos: &BTreeMap<String, Option<TagInfo>>,
+    buf: &mut dyn Write,
+) -> Result<(), ApiError> {
+    let dash = || String::from("-");
+    let rows: Vec<[String; 5]> = tags
+        .iter()
+        .map(|tag| match infos.get(tag).and_then(Option::as_ref) {
+            Some(info) => [
+                tag.clone(),
+                info.digest.clone(),
+                progress::human_bytes(info.size),
+                Some(info.platforms.join(","))
+                    .filter(|p| !p.is_empty())
+                    .unwrap_or_else(dash),
+                info.created.map_or_else(dash, layer::format_time),
+            ],
+            None => [tag.clone(), dash(), dash(), dash(), dash()],
+        })
+        .collect();
+    let header = ["TAG", "DIGEST", "SIZE", "PLATFORMS", "CREATED"];
+    let width = |i: usize| {
+        rows.iter()
+            .map(|row| row[i].len())
+            .max()
+            .unwrap_or(0)
+            .max(header[i].len())
+    };
+    let (tag, digest, size, platforms) = (width(0), width(1), width(2), width(3));
+
+    for row in std::iter::once(header.map(String::from)).chain(rows) {
+        writeln!(
+            buf,
+            "{:<tag$}  {:<digest$}  {:>size$}  {:<platforms$}  {}",
+            row[0], row[1], row[2], row[3], row[4]
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(names: &[&str]) -> Vec<String> {
+        names.iter().map(|s| String::from(*s)).collect()
+    }
+
+    /// Test that tags are parsed leniently as versions.
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("v1.4"), Some(Version::new(1, 4, 0)));
+        assert_eq!(parse_version("2"), Some(Version::new(2, 0, 0)));
+        assert_eq!(
+            parse_version("1.4.0-rc.1+build.5"),
+            Version::parse("1.4.0-rc.1+build.5").ok()
+        );
+        assert_eq!(parse_version("latest"), None);
+        assert_eq!(parse_version("1.2.3.4"), None);
+        assert_eq!(parse_version("1..2"), None);
+        assert_eq!(parse_version("v"), None);
+    }
+
+    /// Test that versions sort numerically, with pre-releases before their
+    /// release and other tags in a bucket at the end.
+    #[test]
+    fn test_arrange_semver() {
+        let query = Query {
+            sort: Some(SortOrder::Semver),
+            ..Query::default()
+        };
+        let arranged = query
+            .arrange(
+                tags(&["v1.10.0", "latest", "v1.9.0", "v1.10.0-rc.1", "edge", "v2"]),
+                &BTreeMap::new(),
+            )
+            .unwrap();
+        assert_eq!(
+            arranged,
+            ["v1.9.0", "v1.10.0-rc.1", "v1.10.0", "v2", "edge", "latest"]
+        );
+    }
+
+    /// Test that the latest tag skips pre-releases unless a constraint
+    /// admits them, and that no match is an error.
+    #[test]
+    fn test_arrange_latest() {
+        let names = tags(&["1.4.2", "1.5.0-rc.1", "2.0.0", "latest"]);
+        let query = Query {
+            latest: true,
+            ..Query::default()
+        };
+        assert_eq!(
+            query.arrange(names.clone(), &BTreeMap::new()).unwrap(),
+            ["2.0.0"]
+        );
+
+        let query = Query {
+            semver: Some(">=1.4, <2".parse().unwrap()),
+            latest: true,
+            ..Query::default()
+        };
+        let selected: Vec<String> = names.into_iter().filter(|t| query.matches(t)).collect();
+        assert_eq!(selected, ["1.4.2"]);
+        assert_eq!(
+            query.arrange(selected, &BTreeMap::new()).unwrap(),
+            ["1.4.2"]
+        );
+        assert!(matches!(
+            query.arrange(Vec::new(), &BTreeMap::new()),
+            Err(ApiError::NoMatchingTag(_))
+        ));
+    }
+
+    /// Test that tags sort by creation time, with unknown times last.
+    #[test]
+    fn test_arrange_date() {
+        let created = BTreeMap::from([
+            (String::from("a"), Some(300)),
+            (String::from("b"), Some(100)),
+            (String::from("c"), None),
+        ]);
+        let query = Query {
+            sort: Some(SortOrder::Date),
+            ..Query::default()
+        };
+        assert_eq!(
+            query.arrange(tags(&["c", "a", "b"]), &created).unwrap(),
+            ["b", "a", "c"]
+        );
+    }
+
+    /// Test that the long listing aligns columns and shows unknown creation
+    /// times as `-`.
+    #[test]
+    fn test_write_long() {
+        let info = |digest: &str, size, platforms: &[&str], created| {
+            Some(TagInfo {
+                digest: String::from(digest),
+                size,
+                platforms: platforms.iter().map(|p| String::from(*p)).collect(),
+                created,
+            })
+        };
+        let infos = BTreeMap::from([
+            (
+                String::from("v1"),
+                info(
+                    "sha256:aa",
+                    2048,
+                    &["linux/amd64", "linux/arm64/v8"],
+                    Some(1_704_164_645),
+                ),
+            ),
+            (
+                String::from("latest"),
+                info("sha256:b", 512, &["linux/amd64"], None),
+            ),
+            (String::from("chart"), None),
+        ]);
+        let mut buf = Vec::new();
+        write_long(&tags(&["chart", "latest", "v1"]), &infos, &mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "TAG     DIGEST        SIZE  PLATFORMS                   CREATED\n\
+             chart   -                -  -                           -\n\
+             latest  sha256:b     512 B  linux/amd64                 -\n\
+             v1      sha256:aa  2.0 KiB  linux/amd64,linux/arm64/v8  2024-01-02 03:04\n"
+        );
+    }
+}